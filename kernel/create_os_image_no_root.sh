@@ -10,8 +10,16 @@ if ! [ -e disk_image.bin ]; then
     mformat -i disk_image.bin@@$[2048*512] -v "EFI System" -F
     # Create a /EFI/BOOT dir in the ESP
     mmd -i disk_image.bin@@$[2048*512] ::EFI ::EFI/BOOT
+    # Create a Linux file system partition from the end of the ESP until the end of the disk, and format it as ext2,
+    # which the kernel mounts on /mnt. mkfs.ext2 writes into the middle of the image with the offset option, and
+    # needs the size of the file system as it'd use the size of the whole image otherwise
+    sgdisk disk_image.bin -n 2:65M:127M -t 2:0FC63DAF-8483-4772-8E79-3D69D8477DE4
+    mkfs.ext2 -q -L data -E offset=$[65*1024*1024] disk_image.bin 62M
 fi
 
+# Pack the contents of the initramfs directory, which the kernel unpacks into its root file system
+tar --format=ustar --owner=0 --group=0 -cf initramfs.tar -C initramfs .
+
 # And finally copy all the needed files into the ESP
 # The -D O and -D o options specify that the copied files should overwrite the old ones in the case of secondary
 # and primary name conflicts, respectively
@@ -20,3 +28,4 @@ mcopy -i disk_image.bin@@$[2048*512] -D O -D o target/bare_metal_x86_64_target/d
 # whether file names are even case sensitive in FAT32
 mcopy -i disk_image.bin@@$[2048*512] -D O -D o dependencies/limine/BOOTX64.EFI ::EFI/BOOT/BOOTx64.EFI # Bootloader executable
 mcopy -i disk_image.bin@@$[2048*512] -D O -D o limine.cfg :: # Limine configuration file
+mcopy -i disk_image.bin@@$[2048*512] -D O -D o initramfs.tar :: # Initial root file system
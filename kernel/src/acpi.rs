@@ -0,0 +1,188 @@
+use core::mem::size_of;
+use core::ptr;
+
+use arrayvec::ArrayVec;
+use spin::Lazy;
+
+use crate::memory::physical_to_virtual_ptr;
+
+#[repr(C, packed)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+
+    /* Only present if revision >= 2 */
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    pub reserved: [u8; 3]
+}
+
+/// The header common to every ACPI system description table.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32
+}
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum AcpiError {
+    NoRsdp,
+    InvalidChecksum,
+    TableNotFound
+}
+
+/// Either the XSDT, whose entries are 64-bit physical addresses, or on ACPI 1.0 systems the RSDT, whose
+/// entries are 32-bit.
+struct RootTable {
+    header: *const SdtHeader,
+    entry_size: usize
+}
+
+unsafe impl Sync for RootTable {}
+unsafe impl Send for RootTable {}
+
+static ROOT_TABLE: Lazy<Result<RootTable, AcpiError>> = Lazy::new(|| {
+    let response = crate::LIMINE_RSDP_REQUEST.response;
+    if response.is_null() {
+        return Err(AcpiError::NoRsdp);
+    }
+    let rsdp = unsafe { &*((*response).address as *const Rsdp) };
+    if !is_checksum_valid(rsdp as *const Rsdp as *const u8, 20) {
+        return Err(AcpiError::InvalidChecksum);
+    }
+    let root_table = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        RootTable {
+            header: physical_to_virtual_ptr(rsdp.xsdt_address),
+            entry_size: size_of::<u64>()
+        }
+    }
+    else {
+        RootTable {
+            header: physical_to_virtual_ptr(rsdp.rsdt_address as u64),
+            entry_size: size_of::<u32>()
+        }
+    };
+    let length = unsafe { ptr::read_unaligned(ptr::addr_of!((*root_table.header).length)) };
+    if !is_checksum_valid(root_table.header as *const u8, length as usize) {
+        return Err(AcpiError::InvalidChecksum);
+    }
+    Ok(root_table)
+});
+
+/// All bytes of an ACPI structure must sum to zero (mod 256).
+fn is_checksum_valid(address: *const u8, length: usize) -> bool {
+    let mut sum: u8 = 0;
+    for i in 0..length {
+        sum = sum.wrapping_add(unsafe { *address.add(i) });
+    }
+    sum == 0
+}
+
+/// Returns a pointer to the header of the first table with the given signature, e.g. `b"MCFG"`.
+pub fn find_table(signature: &[u8; 4]) -> Result<*const SdtHeader, AcpiError> {
+    let root_table = ROOT_TABLE.as_ref().map_err(|e| *e)?;
+    let root_header = unsafe { ptr::read_unaligned(root_table.header) };
+    let entry_count = (root_header.length as usize - size_of::<SdtHeader>()) / root_table.entry_size;
+    let entries_base = unsafe { (root_table.header as *const u8).add(size_of::<SdtHeader>()) };
+    for i in 0..entry_count {
+        let entry_ptr = unsafe { entries_base.add(i * root_table.entry_size) };
+        let table_address = match root_table.entry_size {
+            4 => unsafe { ptr::read_unaligned(entry_ptr as *const u32) as u64 },
+            _ => unsafe { ptr::read_unaligned(entry_ptr as *const u64) }
+        };
+        let table: *const SdtHeader = physical_to_virtual_ptr(table_address);
+        let header = unsafe { ptr::read_unaligned(table) };
+        if &header.signature == signature {
+            if !is_checksum_valid(table as *const u8, header.length as usize) {
+                return Err(AcpiError::InvalidChecksum);
+            }
+            return Ok(table);
+        }
+    }
+    Err(AcpiError::TableNotFound)
+}
+
+/// An entry of the MCFG table, describing the memory mapped configuration space (ECAM) of one PCI segment
+/// group.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    pub reserved: u32
+}
+
+/// Returns the entries of the MCFG table. The table has 8 reserved bytes between the header and the entries.
+pub fn mcfg_entries() -> Result<&'static [McfgEntry], AcpiError> {
+    let table = find_table(b"MCFG")?;
+    let length = unsafe { ptr::read_unaligned(ptr::addr_of!((*table).length)) } as usize;
+    let entries_offset = size_of::<SdtHeader>() + 8;
+    let entry_count = length.saturating_sub(entries_offset) / size_of::<McfgEntry>();
+    let entries = unsafe { (table as *const u8).add(entries_offset) as *const McfgEntry };
+    Ok(unsafe { core::slice::from_raw_parts(entries, entry_count) })
+}
+
+pub const MAX_PROCESSORS: usize = 64;
+
+const MADT_ENTRY_TYPE_LOCAL_APIC: u8 = 0;
+const MADT_ENTRY_TYPE_LOCAL_X2APIC: u8 = 9;
+const MADT_PROCESSOR_ENABLED: u32 = 1 << 0;
+
+/// Returns the local APIC ids of the enabled processors listed in the MADT. The MADT has the local APIC
+/// address and flags between the header and the entries.
+pub fn processor_apic_ids() -> Result<ArrayVec<u32, MAX_PROCESSORS>, AcpiError> {
+    let table = find_table(b"APIC")?;
+    let length = unsafe { ptr::read_unaligned(ptr::addr_of!((*table).length)) } as usize;
+    let base = table as *const u8;
+    let mut ids = ArrayVec::new();
+    let mut offset = size_of::<SdtHeader>() + 8;
+    while offset + 2 <= length {
+        let entry = unsafe { base.add(offset) };
+        let (entry_type, entry_length) = unsafe { (*entry, *entry.add(1) as usize) };
+        if entry_length < 2 || offset + entry_length > length {
+            break;
+        }
+        let processor = match entry_type {
+            // Processor id, APIC id, flags
+            MADT_ENTRY_TYPE_LOCAL_APIC if entry_length >= 8 => unsafe {
+                Some((
+                    *entry.add(3) as u32,
+                    ptr::read_unaligned(entry.add(4) as *const u32)
+                ))
+            },
+            // Reserved, x2APIC id, flags, processor uid
+            MADT_ENTRY_TYPE_LOCAL_X2APIC if entry_length >= 16 => unsafe {
+                Some((
+                    ptr::read_unaligned(entry.add(4) as *const u32),
+                    ptr::read_unaligned(entry.add(8) as *const u32)
+                ))
+            },
+            _ => None
+        };
+        if let Some((apic_id, flags)) = processor {
+            if flags & MADT_PROCESSOR_ENABLED != 0
+                && !ids.contains(&apic_id)
+                && ids.try_push(apic_id).is_err()
+            {
+                break;
+            }
+        }
+        offset += entry_length;
+    }
+    Ok(ids)
+}
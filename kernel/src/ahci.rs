@@ -0,0 +1,778 @@
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::{Mutex, Once};
+
+use crate::apic::local_apic_id;
+use crate::block::{self, BlockDevice, BlockError, BlockOperation, RequestQueue, MAX_BLOCK_SIZE};
+use crate::interrupts_general::{
+    are_interrupts_enabled, disable_interrupts, enable_interrupts, enable_interrupts_and_halt
+};
+use crate::memory::{allocate_frames, free_frames, mmio_to_virtual, PhysicalFrames, FRAME_SIZE};
+use crate::msi::Msi;
+use crate::pci::{Bar, PciDevice, PciDeviceId, PciDriver, ProbeError};
+
+const MAX_CONTROLLERS: usize = 4;
+const MAX_DISKS: usize = 16;
+/// Commands are issued through at most this many of the 32 command slots of a port, each of which has its
+/// own command table and DMA buffer. Without NCQ, only the first one is used.
+const MAX_COMMAND_SLOTS: usize = 8;
+/// Transfers are bounced through this many frames of DMA memory per command slot, and split into commands of
+/// at most this size.
+const SLOT_BUFFER_FRAMES: u64 = 16;
+const SLOT_BUFFER_SIZE: usize = (SLOT_BUFFER_FRAMES * FRAME_SIZE) as usize;
+/// How many times the registers are polled before an operation is considered to have timed out. When the
+/// controller's interrupts are enabled, every iteration waits for an interrupt instead of spinning.
+const POLL_ITERATIONS: u32 = 10000000;
+/// How many times the link status of a port is polled before concluding that nothing is connected to it.
+/// Much lower than `POLL_ITERATIONS`, as every empty port would otherwise stall the boot for seconds.
+const LINK_POLL_ITERATIONS: u32 = 100000;
+
+const HBA_REG_CAP: u64 = 0x00;
+const HBA_REG_GHC: u64 = 0x04;
+const HBA_REG_IS: u64 = 0x08;
+const HBA_REG_PI: u64 = 0x0c;
+const HBA_REG_CAP2: u64 = 0x24;
+const HBA_REG_BOHC: u64 = 0x28;
+const PORT_REGISTERS_BASE: u64 = 0x100;
+const PORT_REGISTERS_SIZE: u64 = 0x80;
+
+const CAP_COMMAND_SLOTS_SHIFT: u32 = 8;
+const CAP_STAGGERED_SPIN_UP: u32 = 1 << 27;
+const CAP_NATIVE_COMMAND_QUEUING: u32 = 1 << 30;
+const CAP_64_BIT_ADDRESSING: u32 = 1 << 31;
+const CAP2_BIOS_HANDOFF: u32 = 1 << 0;
+const GHC_HBA_RESET: u32 = 1 << 0;
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+const BOHC_BIOS_OWNED: u32 = 1 << 0;
+const BOHC_OS_OWNED: u32 = 1 << 1;
+const BOHC_BIOS_BUSY: u32 = 1 << 4;
+
+const PORT_REG_CLB: u64 = 0x00;
+const PORT_REG_FB: u64 = 0x08;
+const PORT_REG_IS: u64 = 0x10;
+const PORT_REG_IE: u64 = 0x14;
+const PORT_REG_CMD: u64 = 0x18;
+const PORT_REG_TFD: u64 = 0x20;
+const PORT_REG_SIG: u64 = 0x24;
+const PORT_REG_SSTS: u64 = 0x28;
+const PORT_REG_SCTL: u64 = 0x2c;
+const PORT_REG_SERR: u64 = 0x30;
+const PORT_REG_SACT: u64 = 0x34;
+const PORT_REG_CI: u64 = 0x38;
+
+const PORT_CMD_START: u32 = 1 << 0;
+const PORT_CMD_SPIN_UP_DEVICE: u32 = 1 << 1;
+const PORT_CMD_POWER_ON_DEVICE: u32 = 1 << 2;
+const PORT_CMD_FIS_RECEIVE_ENABLE: u32 = 1 << 4;
+const PORT_CMD_FIS_RECEIVE_RUNNING: u32 = 1 << 14;
+const PORT_CMD_COMMAND_LIST_RUNNING: u32 = 1 << 15;
+const PORT_IS_TASK_FILE_ERROR: u32 = 1 << 30;
+/// The interrupts for every kind of FIS received and for descriptors being processed, along with every error
+/// interrupt
+const PORT_IE_ALL: u32 = 0b10_1111 | (0b1111 << 27) | (1 << 22) | (1 << 24) | (1 << 26);
+const SSTS_DEVICE_DETECTION_MASK: u32 = 0xf;
+const SSTS_DEVICE_PRESENT: u32 = 3;
+const SSTS_POWER_MANAGEMENT_SHIFT: u32 = 8;
+const SSTS_POWER_MANAGEMENT_ACTIVE: u32 = 1;
+const SCTL_DEVICE_DETECTION_INITIALIZE: u32 = 1;
+const TFD_ERROR: u32 = 1 << 0;
+const TFD_DATA_REQUEST: u32 = 1 << 3;
+const TFD_BUSY: u32 = 1 << 7;
+/// The signature of a plain ATA disk. ATAPI devices, port multipliers and the like aren't supported.
+const SIGNATURE_ATA: u32 = 0x00000101;
+
+/// Where the parts of a port's command memory are. The command list has room for all 32 command headers,
+/// and each command table holds a single physical region descriptor.
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLES_OFFSET: usize = 0x800;
+const COMMAND_TABLE_SIZE: usize = 0x100;
+const COMMAND_TABLE_PRDT_OFFSET: usize = 0x80;
+
+const COMMAND_HEADER_WRITE: u16 = 1 << 6;
+const FIS_TYPE_REGISTER_HOST_TO_DEVICE: u8 = 0x27;
+const FIS_FLAG_COMMAND: u8 = 1 << 7;
+const PRD_INTERRUPT_ON_COMPLETION: u32 = 1 << 31;
+
+const ATA_COMMAND_READ_DMA_EXT: u8 = 0x25;
+const ATA_COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_COMMAND_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_COMMAND_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_COMMAND_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_COMMAND_IDENTIFY_DEVICE: u8 = 0xec;
+const ATA_DEVICE_LBA: u8 = 1 << 6;
+const ATA_SECTOR_SIZE: usize = 512;
+
+/// Word indices of the data returned by IDENTIFY DEVICE
+const IDENTIFY_SECTORS_28: usize = 60;
+const IDENTIFY_QUEUE_DEPTH: usize = 75;
+const IDENTIFY_SATA_CAPABILITIES: usize = 76;
+const IDENTIFY_COMMAND_SETS_SUPPORTED: usize = 83;
+const IDENTIFY_SECTORS_48: usize = 100;
+const IDENTIFY_SECTOR_SIZE_INFO: usize = 106;
+const IDENTIFY_LOGICAL_SECTOR_SIZE: usize = 117;
+const SATA_CAPABILITY_NCQ: u16 = 1 << 8;
+const COMMAND_SET_48_BIT_ADDRESS: u16 = 1 << 10;
+/// Word 106 is only valid if bit 14 is set and bit 15 is clear
+const SECTOR_SIZE_INFO_VALID_MASK: u16 = 0b11 << 14;
+const SECTOR_SIZE_INFO_VALID: u16 = 0b01 << 14;
+const SECTOR_SIZE_INFO_LARGE_LOGICAL_SECTORS: u16 = 1 << 12;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CommandHeader {
+    /// The length of the command FIS in dwords along with flags such as `COMMAND_HEADER_WRITE`
+    flags: u16,
+    prdt_length: u16,
+    /// How many bytes the controller has transferred, written back by the controller
+    transferred_bytes: u32,
+    table_address: u64,
+    reserved: [u32; 4]
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct PhysicalRegionDescriptor {
+    address: u64,
+    reserved: u32,
+    /// The byte count minus one along with `PRD_INTERRUPT_ON_COMPLETION`
+    byte_count: u32
+}
+
+/// A register host to device FIS, which carries an ATA command.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct RegisterFis {
+    fis_type: u8,
+    flags: u8,
+    command: u8,
+    features_low: u8,
+    lba_low: [u8; 3],
+    device: u8,
+    lba_high: [u8; 3],
+    features_high: u8,
+    count: u16,
+    isochronous_command_completion: u8,
+    control: u8,
+    reserved: [u8; 4]
+}
+
+impl RegisterFis {
+    fn new(command: u8, lba: u64, count: u16) -> RegisterFis {
+        let lba = lba.to_le_bytes();
+        RegisterFis {
+            fis_type: FIS_TYPE_REGISTER_HOST_TO_DEVICE,
+            flags: FIS_FLAG_COMMAND,
+            command,
+            lba_low: [lba[0], lba[1], lba[2]],
+            device: ATA_DEVICE_LBA,
+            lba_high: [lba[3], lba[4], lba[5]],
+            count,
+            ..Default::default()
+        }
+    }
+
+    /// A first-party DMA queued command, which has the sector count in the features field and the tag in the
+    /// count field instead.
+    fn queued(command: u8, lba: u64, count: u16, tag: u8) -> RegisterFis {
+        RegisterFis {
+            features_low: count as u8,
+            features_high: (count >> 8) as u8,
+            ..RegisterFis::new(command, lba, (tag as u16) << 3)
+        }
+    }
+}
+
+#[inline]
+fn read_u32(address: u64) -> u32 {
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
+
+#[inline]
+fn write_u32(address: u64, value: u32) {
+    unsafe { ptr::write_volatile(address as *mut u32, value) }
+}
+
+/// 64-bit registers are written as two dwords, low half first, which works whether or not the controller
+/// supports 64-bit accesses.
+#[inline]
+fn write_u64(address: u64, value: u64) {
+    write_u32(address, value as u32);
+    write_u32(address + 4, (value >> 32) as u32);
+}
+
+fn poll_until(iterations: u32, mut condition: impl FnMut() -> bool) -> Result<(), BlockError> {
+    for _ in 0..iterations {
+        if condition() {
+            return Ok(());
+        }
+    }
+    Err(BlockError::Timeout)
+}
+
+#[inline]
+fn port_registers(hba_base: u64, port: u8) -> u64 {
+    hba_base + PORT_REGISTERS_BASE + port as u64 * PORT_REGISTERS_SIZE
+}
+
+struct AhciController {
+    hba_base: u64,
+    capabilities: u32,
+    /// Without MSI, commands are waited for by polling
+    msi: Option<Msi>
+}
+
+/// A port with an ATA device attached. Locked as a whole while commands are in progress.
+struct Port {
+    controller: &'static AhciController,
+    registers: u64,
+    /// The command list, received FIS area and command tables
+    command_memory: PhysicalFrames,
+    /// `SLOT_BUFFER_SIZE` bytes for each command slot in use
+    buffers: PhysicalFrames,
+    command_slots: u8,
+    /// Whether commands are issued as NCQ commands, several at once
+    native_command_queuing: bool
+}
+
+unsafe impl Send for Port {}
+
+impl Port {
+    /// Sets up the command memory of a port and starts it, if a device is attached to it.
+    fn new(controller: &'static AhciController, port: u8) -> Result<Port, BlockError> {
+        let command_slots = ((controller.capabilities >> CAP_COMMAND_SLOTS_SHIFT) & 0x1f) as usize + 1;
+        let command_slots = command_slots.min(MAX_COMMAND_SLOTS) as u8;
+        let command_memory = allocate_frames(1).ok_or(BlockError::OutOfMemory)?;
+        let Some(buffers) = allocate_frames(SLOT_BUFFER_FRAMES * command_slots as u64)
+        else {
+            free_frames(command_memory);
+            return Err(BlockError::OutOfMemory);
+        };
+        let mut port = Port {
+            controller,
+            registers: port_registers(controller.hba_base, port),
+            command_memory,
+            buffers,
+            command_slots,
+            native_command_queuing: false
+        };
+        if let Err(e) = port.initialize() {
+            port.release();
+            return Err(e);
+        }
+        Ok(port)
+    }
+
+    /// Stops the port and frees its memory, for when the device turns out to be unusable.
+    fn release(self) {
+        let _ = self.stop();
+        free_frames(self.command_memory);
+        free_frames(self.buffers);
+    }
+
+    fn initialize(&mut self) -> Result<(), BlockError> {
+        // Controllers without 64-bit addressing ignore the upper halves of addresses
+        let end = self.buffers.physical_address + self.buffers.size();
+        if self.controller.capabilities & CAP_64_BIT_ADDRESSING == 0 && end > 1 << 32 {
+            return Err(BlockError::OutOfMemory);
+        }
+        self.stop()?;
+        for slot in 0..self.command_slots as usize {
+            let table_address = self.command_memory.physical_address
+                + (COMMAND_TABLES_OFFSET + slot * COMMAND_TABLE_SIZE) as u64;
+            unsafe {
+                ptr::write_volatile(
+                    ptr::addr_of_mut!((*self.command_header(slot)).table_address),
+                    table_address
+                )
+            };
+        }
+        write_u64(
+            self.registers + PORT_REG_CLB,
+            self.command_memory.physical_address + COMMAND_LIST_OFFSET as u64
+        );
+        write_u64(
+            self.registers + PORT_REG_FB,
+            self.command_memory.physical_address + RECEIVED_FIS_OFFSET as u64
+        );
+        self.write(
+            PORT_REG_CMD,
+            self.read(PORT_REG_CMD) | PORT_CMD_FIS_RECEIVE_ENABLE
+        );
+        if self.controller.capabilities & CAP_STAGGERED_SPIN_UP != 0 {
+            self.write(
+                PORT_REG_CMD,
+                self.read(PORT_REG_CMD) | PORT_CMD_SPIN_UP_DEVICE | PORT_CMD_POWER_ON_DEVICE
+            );
+        }
+        poll_until(LINK_POLL_ITERATIONS, || {
+            let status = self.read(PORT_REG_SSTS);
+            status & SSTS_DEVICE_DETECTION_MASK == SSTS_DEVICE_PRESENT
+                && (status >> SSTS_POWER_MANAGEMENT_SHIFT) & 0xf == SSTS_POWER_MANAGEMENT_ACTIVE
+        })
+        .map_err(|_| BlockError::NoMedium)?;
+        // The signature arrives with the first FIS the device sends after the link comes up
+        poll_until(POLL_ITERATIONS, || {
+            self.read(PORT_REG_TFD) & (TFD_BUSY | TFD_DATA_REQUEST) == 0
+        })?;
+        if self.read(PORT_REG_SIG) != SIGNATURE_ATA {
+            return Err(BlockError::NoMedium);
+        }
+        self.clear_errors();
+        self.write(PORT_REG_IE, PORT_IE_ALL);
+        self.start()
+    }
+
+    #[inline]
+    fn read(&self, register: u64) -> u32 {
+        read_u32(self.registers + register)
+    }
+
+    #[inline]
+    fn write(&self, register: u64, value: u32) {
+        write_u32(self.registers + register, value)
+    }
+
+    fn clear_errors(&self) {
+        // Both registers are cleared by writing one to their bits
+        self.write(PORT_REG_SERR, u32::MAX);
+        self.write(PORT_REG_IS, u32::MAX);
+    }
+
+    /// Stops the port from processing the command list and receiving FISes, which is required before
+    /// changing where they are.
+    fn stop(&self) -> Result<(), BlockError> {
+        self.write(PORT_REG_CMD, self.read(PORT_REG_CMD) & !PORT_CMD_START);
+        poll_until(POLL_ITERATIONS, || {
+            self.read(PORT_REG_CMD) & PORT_CMD_COMMAND_LIST_RUNNING == 0
+        })?;
+        self.write(
+            PORT_REG_CMD,
+            self.read(PORT_REG_CMD) & !PORT_CMD_FIS_RECEIVE_ENABLE
+        );
+        poll_until(POLL_ITERATIONS, || {
+            self.read(PORT_REG_CMD) & PORT_CMD_FIS_RECEIVE_RUNNING == 0
+        })
+    }
+
+    fn start(&self) -> Result<(), BlockError> {
+        poll_until(POLL_ITERATIONS, || {
+            self.read(PORT_REG_CMD) & PORT_CMD_COMMAND_LIST_RUNNING == 0
+        })?;
+        self.write(
+            PORT_REG_CMD,
+            self.read(PORT_REG_CMD) | PORT_CMD_FIS_RECEIVE_ENABLE
+        );
+        self.write(PORT_REG_CMD, self.read(PORT_REG_CMD) | PORT_CMD_START);
+        Ok(())
+    }
+
+    /// Gets the port going again after a command has failed or timed out, which aborts every command in
+    /// progress. If the device is still busy, it's reset through the link.
+    fn recover(&self) {
+        let _ = self.stop();
+        self.clear_errors();
+        if self.read(PORT_REG_TFD) & (TFD_BUSY | TFD_DATA_REQUEST) != 0 {
+            // The device needs to see the reset for at least a millisecond, which the polling takes care of
+            // without a timer
+            self.write(PORT_REG_SCTL, SCTL_DEVICE_DETECTION_INITIALIZE);
+            let _ = poll_until(LINK_POLL_ITERATIONS, || false);
+            self.write(PORT_REG_SCTL, 0);
+            let _ = poll_until(LINK_POLL_ITERATIONS, || {
+                self.read(PORT_REG_SSTS) & SSTS_DEVICE_DETECTION_MASK == SSTS_DEVICE_PRESENT
+            });
+            self.clear_errors();
+        }
+        let _ = self.start();
+    }
+
+    #[inline]
+    fn command_header(&self, slot: usize) -> *mut CommandHeader {
+        unsafe {
+            self.command_memory
+                .as_ptr::<u8>()
+                .add(COMMAND_LIST_OFFSET + slot * size_of::<CommandHeader>())
+                as *mut CommandHeader
+        }
+    }
+
+    #[inline]
+    fn command_table(&self, slot: usize) -> *mut u8 {
+        unsafe {
+            self.command_memory
+                .as_ptr::<u8>()
+                .add(COMMAND_TABLES_OFFSET + slot * COMMAND_TABLE_SIZE)
+        }
+    }
+
+    #[inline]
+    fn slot_buffer(&self, slot: usize) -> *mut u8 {
+        unsafe { self.buffers.as_ptr::<u8>().add(slot * SLOT_BUFFER_SIZE) }
+    }
+
+    /// Issues a command in `slot`, transferring `length` bytes through the slot's buffer.
+    fn issue(&self, slot: usize, fis: RegisterFis, write: bool, length: usize) {
+        let table = self.command_table(slot);
+        let prd = PhysicalRegionDescriptor {
+            address: self.buffers.physical_address + (slot * SLOT_BUFFER_SIZE) as u64,
+            reserved: 0,
+            byte_count: (length as u32).wrapping_sub(1) | PRD_INTERRUPT_ON_COMPLETION
+        };
+        let header = self.command_header(slot);
+        let mut flags = (size_of::<RegisterFis>() / 4) as u16;
+        if write {
+            flags |= COMMAND_HEADER_WRITE;
+        }
+        unsafe {
+            ptr::write_volatile(table as *mut RegisterFis, fis);
+            ptr::write_volatile(
+                table.add(COMMAND_TABLE_PRDT_OFFSET) as *mut PhysicalRegionDescriptor,
+                prd
+            );
+            ptr::write_volatile(ptr::addr_of_mut!((*header).flags), flags);
+            ptr::write_volatile(ptr::addr_of_mut!((*header).prdt_length), (length != 0) as u16);
+            ptr::write_volatile(ptr::addr_of_mut!((*header).transferred_bytes), 0);
+        }
+        // The command has to be in memory before the controller is told about it
+        fence(Ordering::SeqCst);
+        let queued = matches!(
+            fis.command,
+            ATA_COMMAND_READ_FPDMA_QUEUED | ATA_COMMAND_WRITE_FPDMA_QUEUED
+        );
+        if queued {
+            self.write(PORT_REG_SACT, 1 << slot);
+        }
+        self.write(PORT_REG_CI, 1 << slot);
+    }
+
+    /// Waits until every command in the slots set in `slots` has completed. With interrupts, the CPU is
+    /// halted in between checks, which it is woken up from by the interrupt the port raises on completion.
+    fn wait_for_commands(&self, slots: u32) -> Result<(), BlockError> {
+        let halt = self.controller.msi.is_some() && are_interrupts_enabled();
+        let check = || {
+            let failed = self.read(PORT_REG_TFD) & TFD_ERROR != 0
+                || self.read(PORT_REG_IS) & PORT_IS_TASK_FILE_ERROR != 0;
+            if failed {
+                return Some(Err(BlockError::DeviceError));
+            }
+            let in_progress = self.read(PORT_REG_CI) | self.read(PORT_REG_SACT);
+            (in_progress & slots == 0).then_some(Ok(()))
+        };
+        for _ in 0..POLL_ITERATIONS {
+            if !halt {
+                if let Some(result) = check() {
+                    return result.inspect_err(|_| self.recover());
+                }
+                continue;
+            }
+            // The interrupt handler doesn't touch anything checked here apart from the interrupt status,
+            // but checking with interrupts disabled makes sure the interrupt can't arrive before halting
+            disable_interrupts();
+            if let Some(result) = check() {
+                enable_interrupts();
+                return result.inspect_err(|_| self.recover());
+            }
+            enable_interrupts_and_halt();
+        }
+        self.recover();
+        Err(BlockError::Timeout)
+    }
+
+    /// Runs a single non-queued command in the first slot.
+    fn run_command(&self, fis: RegisterFis, write: bool, length: usize) -> Result<(), BlockError> {
+        self.issue(0, fis, write, length);
+        self.wait_for_commands(1)
+    }
+
+    /// Reads or writes `length` bytes at `buffer` starting from `lba`. The transfer is split into a command
+    /// per `SLOT_BUFFER_SIZE` bytes, and with NCQ, as many of them as there are command slots are in progress
+    /// at once.
+    fn transfer(
+        &self,
+        operation: BlockOperation,
+        lba: u64,
+        buffer: *mut u8,
+        length: usize,
+        sector_size: usize
+    ) -> Result<(), BlockError> {
+        let slots = match self.native_command_queuing {
+            true => self.command_slots as usize,
+            false => 1
+        };
+        let write = operation == BlockOperation::Write;
+        let mut done = 0;
+        while done < length {
+            let mut chunks = ArrayVec::<(usize, usize), MAX_COMMAND_SLOTS>::new();
+            let mut issued = 0;
+            for slot in 0..slots {
+                if done >= length {
+                    break;
+                }
+                let chunk_length = (length - done).min(SLOT_BUFFER_SIZE);
+                let chunk_lba = lba + (done / sector_size) as u64;
+                let count = (chunk_length / sector_size) as u16;
+                if write {
+                    unsafe {
+                        ptr::copy_nonoverlapping(buffer.add(done), self.slot_buffer(slot), chunk_length)
+                    };
+                }
+                let fis = match (self.native_command_queuing, operation) {
+                    (true, BlockOperation::Read) => {
+                        RegisterFis::queued(ATA_COMMAND_READ_FPDMA_QUEUED, chunk_lba, count, slot as u8)
+                    },
+                    (true, BlockOperation::Write) => {
+                        RegisterFis::queued(ATA_COMMAND_WRITE_FPDMA_QUEUED, chunk_lba, count, slot as u8)
+                    },
+                    (false, BlockOperation::Read) => {
+                        RegisterFis::new(ATA_COMMAND_READ_DMA_EXT, chunk_lba, count)
+                    },
+                    (false, BlockOperation::Write) => {
+                        RegisterFis::new(ATA_COMMAND_WRITE_DMA_EXT, chunk_lba, count)
+                    },
+                };
+                self.issue(slot, fis, write, chunk_length);
+                issued |= 1 << slot;
+                chunks.push((done, chunk_length));
+                done += chunk_length;
+            }
+            self.wait_for_commands(issued)?;
+            if !write {
+                for (slot, &(offset, chunk_length)) in chunks.iter().enumerate() {
+                    unsafe {
+                        ptr::copy_nonoverlapping(self.slot_buffer(slot), buffer.add(offset), chunk_length)
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What IDENTIFY DEVICE tells about a disk.
+struct DeviceInfo {
+    sector_size: u32,
+    sector_count: u64,
+    /// The number of commands the device can have queued, if it supports NCQ
+    queue_depth: Option<u8>
+}
+
+fn identify(port: &Port) -> Result<DeviceInfo, BlockError> {
+    let fis = RegisterFis {
+        device: 0,
+        ..RegisterFis::new(ATA_COMMAND_IDENTIFY_DEVICE, 0, 0)
+    };
+    port.run_command(fis, false, ATA_SECTOR_SIZE)?;
+    let mut words = [0u16; ATA_SECTOR_SIZE / 2];
+    unsafe { ptr::copy_nonoverlapping(port.slot_buffer(0) as *const u16, words.as_mut_ptr(), words.len()) };
+    // Every command used for transfers takes a 48-bit address
+    if words[IDENTIFY_COMMAND_SETS_SUPPORTED] & COMMAND_SET_48_BIT_ADDRESS == 0 {
+        return Err(BlockError::DeviceError);
+    }
+    let sector_count = match words[IDENTIFY_SECTORS_48..IDENTIFY_SECTORS_48 + 4]
+        .iter()
+        .rev()
+        .fold(0, |count, &word| count << 16 | word as u64)
+    {
+        0 => (words[IDENTIFY_SECTORS_28 + 1] as u64) << 16 | words[IDENTIFY_SECTORS_28] as u64,
+        count => count
+    };
+    let sector_size_info = words[IDENTIFY_SECTOR_SIZE_INFO];
+    let sector_size = if sector_size_info & SECTOR_SIZE_INFO_VALID_MASK == SECTOR_SIZE_INFO_VALID
+        && sector_size_info & SECTOR_SIZE_INFO_LARGE_LOGICAL_SECTORS != 0
+    {
+        // Given in words
+        let words_per_sector = (words[IDENTIFY_LOGICAL_SECTOR_SIZE + 1] as u32) << 16
+            | words[IDENTIFY_LOGICAL_SECTOR_SIZE] as u32;
+        words_per_sector * 2
+    }
+    else {
+        ATA_SECTOR_SIZE as u32
+    };
+    let queue_depth = (words[IDENTIFY_SATA_CAPABILITIES] & SATA_CAPABILITY_NCQ != 0)
+        .then_some((words[IDENTIFY_QUEUE_DEPTH] & 0x1f) as u8 + 1);
+    Ok(DeviceInfo {
+        sector_size,
+        sector_count,
+        queue_depth
+    })
+}
+
+/// An ATA disk attached to a port of an AHCI controller.
+pub struct AhciDisk {
+    name: ArrayString<16>,
+    port: Mutex<Port>,
+    sector_size: u32,
+    sector_count: u64,
+    request_queue: RequestQueue
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        self.port.lock().transfer(
+            BlockOperation::Read,
+            first_block,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            self.sector_size as usize
+        )
+    }
+
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        // Only read from, despite the pointer being mutable
+        self.port.lock().transfer(
+            BlockOperation::Write,
+            first_block,
+            buffer.as_ptr() as *mut u8,
+            buffer.len(),
+            self.sector_size as usize
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let fis = RegisterFis::new(ATA_COMMAND_FLUSH_CACHE_EXT, 0, 0);
+        self.port.lock().run_command(fis, false, 0)
+    }
+
+    fn request_queue(&self) -> &RequestQueue {
+        &self.request_queue
+    }
+}
+
+static CONTROLLERS: [Once<AhciController>; MAX_CONTROLLERS] = [const { Once::new() }; MAX_CONTROLLERS];
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+static DISKS: [Once<AhciDisk>; MAX_DISKS] = [const { Once::new() }; MAX_DISKS];
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Only acknowledges the interrupt, as whoever is waiting for a command checks its status by themselves
+/// once the interrupt has woken them up.
+fn handle_interrupt(_vector: u8, hba_base: u64) {
+    let pending_ports = read_u32(hba_base + HBA_REG_IS);
+    for port in (0..32).filter(|p| pending_ports & (1 << p) != 0) {
+        let registers = port_registers(hba_base, port);
+        write_u32(registers + PORT_REG_IS, read_u32(registers + PORT_REG_IS));
+    }
+    // Cleared after the ports, as the bit of a port is set again as long as its interrupt status isn't clear
+    write_u32(hba_base + HBA_REG_IS, pending_ports);
+}
+
+/// Takes the controller over from the firmware, if the firmware uses it.
+fn take_ownership(hba_base: u64) -> Result<(), BlockError> {
+    if read_u32(hba_base + HBA_REG_CAP2) & CAP2_BIOS_HANDOFF == 0 {
+        return Ok(());
+    }
+    let handoff = hba_base + HBA_REG_BOHC;
+    write_u32(handoff, read_u32(handoff) | BOHC_OS_OWNED);
+    poll_until(POLL_ITERATIONS, || read_u32(handoff) & BOHC_BIOS_OWNED == 0)?;
+    poll_until(POLL_ITERATIONS, || read_u32(handoff) & BOHC_BIOS_BUSY == 0)
+}
+
+fn reset(hba_base: u64) -> Result<(), BlockError> {
+    let control = hba_base + HBA_REG_GHC;
+    write_u32(control, read_u32(control) | GHC_AHCI_ENABLE);
+    write_u32(control, read_u32(control) | GHC_HBA_RESET);
+    poll_until(POLL_ITERATIONS, || read_u32(control) & GHC_HBA_RESET == 0)?;
+    // The reset clears the AHCI enable bit on controllers which also support legacy IDE operation
+    write_u32(control, read_u32(control) | GHC_AHCI_ENABLE);
+    Ok(())
+}
+
+fn probe_port(controller: &'static AhciController, port_number: u8) -> Result<(), BlockError> {
+    let mut port = Port::new(controller, port_number)?;
+    let info = match identify(&port) {
+        Ok(info)
+            if info.sector_size as usize <= MAX_BLOCK_SIZE
+                && info.sector_size as usize % ATA_SECTOR_SIZE == 0 =>
+        {
+            info
+        },
+        Ok(_) => {
+            port.release();
+            return Err(BlockError::DeviceError);
+        },
+        Err(e) => {
+            port.release();
+            return Err(e);
+        }
+    };
+    if let Some(queue_depth) = info.queue_depth {
+        if controller.capabilities & CAP_NATIVE_COMMAND_QUEUING != 0 {
+            port.native_command_queuing = true;
+            port.command_slots = port.command_slots.min(queue_depth);
+        }
+    }
+    let index = DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+    let Some(slot) = DISKS.get(index)
+    else {
+        port.release();
+        return Err(BlockError::OutOfMemory);
+    };
+    let mut name = ArrayString::new();
+    let _ = write!(name, "ahci{}", index);
+    let disk = slot.call_once(|| AhciDisk {
+        name,
+        port: Mutex::new(port),
+        sector_size: info.sector_size,
+        sector_count: info.sector_count,
+        request_queue: RequestQueue::new()
+    });
+    block::register_device(disk)
+}
+
+/// Resets the controller and registers a block device for every ATA disk attached to it. Devices attached
+/// later on aren't noticed.
+fn probe(pci_device: &'static PciDevice) -> Result<(), ProbeError> {
+    // The registers are in BAR 5, with the others being for legacy IDE emulation, if any
+    let Some(Bar::Memory { address, size, .. }) = pci_device.bars[5]
+    else {
+        return Err(ProbeError::Unsupported);
+    };
+    let hba_base = mmio_to_virtual(address, size).ok_or(ProbeError::Unsupported)?;
+    let index = CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed);
+    let slot = CONTROLLERS.get(index).ok_or(ProbeError::Unsupported)?;
+    pci_device.enable_memory_and_bus_mastering();
+    take_ownership(hba_base).map_err(|_| ProbeError::InitializationFailed)?;
+    reset(hba_base).map_err(|_| ProbeError::InitializationFailed)?;
+
+    // Commands are waited for by polling if MSI isn't available
+    let msi = Msi::enable(pci_device, 1, local_apic_id(), handle_interrupt, hba_base).ok();
+    if msi.is_some() {
+        write_u32(
+            hba_base + HBA_REG_GHC,
+            read_u32(hba_base + HBA_REG_GHC) | GHC_INTERRUPT_ENABLE
+        );
+    }
+    let controller = slot.call_once(|| AhciController {
+        hba_base,
+        capabilities: read_u32(hba_base + HBA_REG_CAP),
+        msi
+    });
+    let implemented_ports = read_u32(hba_base + HBA_REG_PI);
+    for port in (0..32).filter(|p| implemented_ports & (1 << p) != 0) {
+        let _ = probe_port(controller, port);
+    }
+    Ok(())
+}
+
+pub static AHCI_DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    ids: &[PciDeviceId::class(0x01, 0x06, Some(0x01))],
+    probe
+};
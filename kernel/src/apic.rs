@@ -0,0 +1,102 @@
+use core::ptr;
+
+use spin::Lazy;
+
+use crate::memory::physical_to_virtual;
+use crate::msr::{read_msr, write_msr};
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_BOOTSTRAP_PROCESSOR: u64 = 1 << 8;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+/// In x2APIC mode the registers are accessed as MSRs starting from this one, instead of through MMIO.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const REG_ID: u32 = 0x20;
+const REG_VERSION: u32 = 0x30;
+const REG_END_OF_INTERRUPT: u32 = 0xb0;
+const REG_SPURIOUS_INTERRUPT_VECTOR: u32 = 0xf0;
+
+const SPURIOUS_VECTOR_APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
+
+enum LocalApicAccess {
+    Mmio(u64),
+    X2Apic
+}
+
+static LOCAL_APIC: Lazy<LocalApicAccess> = Lazy::new(|| {
+    let apic_base = read_msr(IA32_APIC_BASE_MSR);
+    if apic_base & APIC_BASE_X2APIC_ENABLE != 0 {
+        LocalApicAccess::X2Apic
+    }
+    else {
+        LocalApicAccess::Mmio(physical_to_virtual(base_address()))
+    }
+});
+
+fn read_register(register: u32) -> u32 {
+    match *LOCAL_APIC {
+        LocalApicAccess::Mmio(base) => unsafe { ptr::read_volatile((base + register as u64) as *const u32) },
+        LocalApicAccess::X2Apic => read_msr(X2APIC_MSR_BASE + (register >> 4)) as u32
+    }
+}
+
+fn write_register(register: u32, value: u32) {
+    match *LOCAL_APIC {
+        LocalApicAccess::Mmio(base) => unsafe {
+            ptr::write_volatile((base + register as u64) as *mut u32, value)
+        },
+        LocalApicAccess::X2Apic => write_msr(X2APIC_MSR_BASE + (register >> 4), value as u64)
+    }
+}
+
+/// Makes sure the local APIC of the current CPU is enabled, both globally through the APIC base MSR and in
+/// software through the spurious interrupt vector register. The 8259 PIC keeps working after this, as the
+/// firmware leaves its output connected to the local APIC's LINT0 pin.
+pub fn init() {
+    let apic_base = read_msr(IA32_APIC_BASE_MSR);
+    if apic_base & APIC_BASE_GLOBAL_ENABLE == 0 {
+        write_msr(IA32_APIC_BASE_MSR, apic_base | APIC_BASE_GLOBAL_ENABLE);
+    }
+    let spurious = read_register(REG_SPURIOUS_INTERRUPT_VECTOR) & !0xff;
+    write_register(
+        REG_SPURIOUS_INTERRUPT_VECTOR,
+        spurious | SPURIOUS_VECTOR_APIC_SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_VECTOR as u32
+    );
+}
+
+/// The id of the current CPU's local APIC, which is what interrupts are targeted with.
+pub fn local_apic_id() -> u32 {
+    match *LOCAL_APIC {
+        LocalApicAccess::Mmio(_) => read_register(REG_ID) >> 24,
+        LocalApicAccess::X2Apic => read_register(REG_ID)
+    }
+}
+
+/// The version register of the current CPU's local APIC. Versions below 0x10 are the discrete 82489DX
+/// rather than an APIC integrated into the CPU.
+pub fn version() -> u32 {
+    read_register(REG_VERSION) & 0xff
+}
+
+pub fn is_x2apic() -> bool {
+    matches!(*LOCAL_APIC, LocalApicAccess::X2Apic)
+}
+
+/// Whether the current CPU is the one the firmware booted on, rather than one started later.
+pub fn is_bootstrap_processor() -> bool {
+    read_msr(IA32_APIC_BASE_MSR) & APIC_BASE_BOOTSTRAP_PROCESSOR != 0
+}
+
+/// The physical address of the local APIC's registers, which are only used in xAPIC mode.
+pub fn base_address() -> u64 {
+    read_msr(IA32_APIC_BASE_MSR) & 0x000ffffffffff000
+}
+
+/// Must be called at the end of the handler of every interrupt delivered by the local APIC (i.e. anything
+/// other than the 8259 PIC, such as MSIs).
+#[inline]
+pub fn send_end_of_interrupt() {
+    write_register(REG_END_OF_INTERRUPT, 0);
+}
@@ -0,0 +1,295 @@
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use crate::interrupts_general::without_interrupts;
+
+const MAX_BLOCK_DEVICES: usize = 32;
+/// How many requests can wait in a device's request queue before submitting another one carries out the
+/// ones already queued
+const REQUEST_QUEUE_CAPACITY: usize = 64;
+/// Adjacent requests are merged into a single transfer of at most this many bytes
+const MAX_MERGED_REQUEST_SIZE: usize = 64 * 1024;
+
+/// No device may have blocks larger than this, which is also the size of the buffer cache's buffers.
+pub const MAX_BLOCK_SIZE: usize = 4096;
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum BlockError {
+    /// The request goes past the last block of the device
+    OutOfRange,
+    /// The length of the buffer isn't a multiple of the block size
+    InvalidBufferSize,
+    NoMedium,
+    WriteProtected,
+    Timeout,
+    /// The device reported an error, or the driver couldn't talk to it
+    DeviceError,
+    OutOfMemory,
+    /// There's no room left for another device in the table of block devices
+    TooManyDevices
+}
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum BlockOperation {
+    Read,
+    Write
+}
+
+/// Called once a submitted request has been carried out, with the context given in the request.
+pub type CompletionHandler = fn(result: Result<(), BlockError>, context: u64);
+
+/// A read or write of `block_count` blocks, which is carried out some time after being submitted.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockRequest {
+    pub operation: BlockOperation,
+    pub first_block: u64,
+    pub block_count: u32,
+    /// Where the blocks are read into or written from. Must stay valid until the request has completed.
+    pub buffer: *mut u8,
+    pub completion: CompletionHandler,
+    pub context: u64
+}
+
+unsafe impl Send for BlockRequest {}
+
+impl BlockRequest {
+    #[inline]
+    fn end_block(&self) -> u64 {
+        self.first_block + self.block_count as u64
+    }
+}
+
+/// Requests waiting to be carried out by a device. Requests are carried out in order of their first block
+/// rather than in the order they were submitted, with requests for adjacent blocks being merged into a
+/// single transfer, as every transfer has a considerable fixed cost on most devices.
+pub struct RequestQueue {
+    pending: Mutex<ArrayVec<BlockRequest, REQUEST_QUEUE_CAPACITY>>
+}
+
+/// Merged requests are bounced through this buffer. Only one queue is run at a time.
+static MERGE_BUFFER: Mutex<[u8; MAX_MERGED_REQUEST_SIZE]> = Mutex::new([0; MAX_MERGED_REQUEST_SIZE]);
+
+impl RequestQueue {
+    pub const fn new() -> RequestQueue {
+        RequestQueue {
+            pending: Mutex::new(ArrayVec::new_const())
+        }
+    }
+
+    /// Adds a request to the queue, first carrying out the requests already in it if it's full.
+    pub fn push(
+        &self,
+        device: &(impl BlockDevice + ?Sized),
+        request: BlockRequest
+    ) -> Result<(), BlockError> {
+        let block_size = device.block_size() as usize;
+        validate_request(
+            device,
+            request.first_block,
+            request.block_count as usize * block_size
+        )?;
+        loop {
+            if without_interrupts(|| self.pending.lock().try_push(request)).is_ok() {
+                return Ok(());
+            }
+            self.run(device);
+        }
+    }
+
+    /// Carries out every queued request and calls their completion handlers.
+    pub fn run(&self, device: &(impl BlockDevice + ?Sized)) {
+        let mut requests = without_interrupts(|| core::mem::take(&mut *self.pending.lock()));
+        if requests.is_empty() {
+            return;
+        }
+        // Requests writing to the same block as another request have to be carried out in the order they were
+        // submitted, which sorting could change unless both start at the same block
+        let overlaps = |a: &BlockRequest, b: &BlockRequest| {
+            a.first_block != b.first_block && a.first_block < b.end_block() && b.first_block < a.end_block()
+        };
+        let has_conflicts = requests.iter().enumerate().any(|(i, a)| {
+            requests[i + 1..].iter().any(|b| {
+                (a.operation == BlockOperation::Write || b.operation == BlockOperation::Write)
+                    && overlaps(a, b)
+            })
+        });
+        if !has_conflicts {
+            // An insertion sort, which is stable and fast enough for a queue this short
+            for i in 1..requests.len() {
+                let mut j = i;
+                while j > 0 && requests[j - 1].first_block > requests[j].first_block {
+                    requests.swap(j - 1, j);
+                    j -= 1;
+                }
+            }
+        }
+        let block_size = device.block_size() as usize;
+        let max_merged_blocks = (MAX_MERGED_REQUEST_SIZE / block_size) as u64;
+
+        let mut merge_buffer = MERGE_BUFFER.lock();
+        let mut start = 0;
+        while start < requests.len() {
+            let first = requests[start];
+            let mut end = start + 1;
+            while end < requests.len()
+                && requests[end].operation == first.operation
+                && requests[end].first_block == requests[end - 1].end_block()
+                && requests[end].end_block() - first.first_block <= max_merged_blocks
+            {
+                end += 1;
+            }
+            let group = &requests[start..end];
+            let result = match group.len() {
+                1 => Self::carry_out_single(device, &first, block_size),
+                _ => Self::carry_out_merged(device, group, block_size, &mut *merge_buffer)
+            };
+            for request in group {
+                (request.completion)(result, request.context);
+            }
+            start = end;
+        }
+    }
+
+    fn carry_out_single(
+        device: &(impl BlockDevice + ?Sized),
+        request: &BlockRequest,
+        block_size: usize
+    ) -> Result<(), BlockError> {
+        let length = request.block_count as usize * block_size;
+        let buffer = unsafe { core::slice::from_raw_parts_mut(request.buffer, length) };
+        match request.operation {
+            BlockOperation::Read => device.read_blocks(request.first_block, buffer),
+            BlockOperation::Write => device.write_blocks(request.first_block, buffer)
+        }
+    }
+
+    fn carry_out_merged(
+        device: &(impl BlockDevice + ?Sized),
+        group: &[BlockRequest],
+        block_size: usize,
+        merge_buffer: &mut [u8]
+    ) -> Result<(), BlockError> {
+        let first_block = group[0].first_block;
+        let length = (group[group.len() - 1].end_block() - first_block) as usize * block_size;
+        let merged = &mut merge_buffer[..length];
+        let offset = |request: &BlockRequest| (request.first_block - first_block) as usize * block_size;
+        match group[0].operation {
+            BlockOperation::Read => {
+                device.read_blocks(first_block, merged)?;
+                for request in group {
+                    let length = request.block_count as usize * block_size;
+                    let source = &merged[offset(request)..offset(request) + length];
+                    unsafe { core::ptr::copy_nonoverlapping(source.as_ptr(), request.buffer, length) };
+                }
+                Ok(())
+            },
+            BlockOperation::Write => {
+                for request in group {
+                    let length = request.block_count as usize * block_size;
+                    let destination = &mut merged[offset(request)..offset(request) + length];
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(request.buffer, destination.as_mut_ptr(), length)
+                    };
+                }
+                device.write_blocks(first_block, merged)
+            }
+        }
+    }
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A device made of fixed-size blocks which are read and written whole, such as a disk.
+pub trait BlockDevice: Sync {
+    /// A short name to identify the device by, e.g. in log messages.
+    fn name(&self) -> &str;
+
+    /// The size of a block in bytes, which every transfer is a multiple of. At most `MAX_BLOCK_SIZE`.
+    fn block_size(&self) -> u32;
+
+    fn block_count(&self) -> u64;
+
+    /// Reads `buffer.len() / block_size()` blocks starting from `first_block` into `buffer`.
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer.len() / block_size()` blocks starting from `first_block`.
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure every write so far has reached persistent storage, rather than some cache of the device.
+    fn flush(&self) -> Result<(), BlockError>;
+
+    /// The queue requests submitted with `submit()` wait in.
+    fn request_queue(&self) -> &RequestQueue;
+
+    /// Queues a request to be carried out later, at the latest when `run_queue()` is called. Drivers of
+    /// devices which can work on requests by themselves override this along with `run_queue()`.
+    fn submit(&self, request: BlockRequest) -> Result<(), BlockError> {
+        self.request_queue().push(self, request)
+    }
+
+    /// Carries out every submitted request, returning once they have all completed.
+    fn run_queue(&self) {
+        self.request_queue().run(self);
+    }
+
+    #[inline]
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+/// Checks that a request of `buffer_length` bytes starting from `first_block` fits within the device and is
+/// made of whole blocks. Drivers call this before doing anything else with a request.
+pub fn validate_request(
+    device: &(impl BlockDevice + ?Sized),
+    first_block: u64,
+    buffer_length: usize
+) -> Result<(), BlockError> {
+    let block_size = device.block_size() as usize;
+    if buffer_length % block_size != 0 {
+        return Err(BlockError::InvalidBufferSize);
+    }
+    let block_count = (buffer_length / block_size) as u64;
+    match first_block.checked_add(block_count) {
+        Some(end) if end <= device.block_count() => Ok(()),
+        _ => Err(BlockError::OutOfRange)
+    }
+}
+
+/// Whether two references point to the same device, as devices are told apart by their address.
+#[inline]
+pub fn same_device(a: &dyn BlockDevice, b: &dyn BlockDevice) -> bool {
+    core::ptr::addr_eq(a as *const dyn BlockDevice, b as *const dyn BlockDevice)
+}
+
+static DEVICES: Mutex<ArrayVec<&'static dyn BlockDevice, MAX_BLOCK_DEVICES>> =
+    Mutex::new(ArrayVec::new_const());
+
+/// Makes a block device known to the rest of the kernel. Called by storage drivers once a device is ready.
+/// Fails with `TooManyDevices` once the table is full, which leaves the device unused.
+pub fn register_device(device: &'static dyn BlockDevice) -> Result<(), BlockError> {
+    // The buffer cache has no room for larger blocks, and no real device has them anyway
+    if device.block_size() as usize > MAX_BLOCK_SIZE {
+        return Ok(());
+    }
+    without_interrupts(|| {
+        DEVICES
+            .lock()
+            .try_push(device)
+            .map_err(|_| BlockError::TooManyDevices)
+    })
+}
+
+pub fn devices() -> ArrayVec<&'static dyn BlockDevice, MAX_BLOCK_DEVICES> {
+    without_interrupts(|| DEVICES.lock().clone())
+}
+
+pub fn find_device(name: &str) -> Option<&'static dyn BlockDevice> {
+    without_interrupts(|| DEVICES.lock().iter().find(|d| d.name() == name).copied())
+}
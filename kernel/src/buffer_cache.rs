@@ -0,0 +1,253 @@
+use arrayvec::ArrayVec;
+use spin::{Lazy, Mutex};
+
+use crate::block::{self, BlockDevice, BlockError, BlockOperation, BlockRequest, MAX_BLOCK_SIZE};
+use crate::interrupts_general::without_interrupts;
+use crate::memory::{allocate_frames, FRAME_SIZE};
+
+const CACHE_BUFFERS: usize = 256;
+
+/// A cached copy of a single block of a device.
+struct CacheBuffer {
+    device: &'static dyn BlockDevice,
+    block: u64,
+    /// Set if the buffer has been written to since it was last written back to the device
+    dirty: bool,
+    /// The value of the cache's clock when the buffer was last accessed
+    last_used: u64,
+    data: *mut u8
+}
+
+/// Keeps recently used blocks of every device in memory. Writes only go to the cached copy of a block, and
+/// reach the device when the buffer is evicted to make room for another block or when the device is synced.
+/// When every buffer is in use, the least recently used one is evicted.
+struct BufferCache {
+    buffers: ArrayVec<CacheBuffer, CACHE_BUFFERS>,
+    /// The memory of the buffers which haven't been used for any block yet
+    unused_memory: ArrayVec<*mut u8, CACHE_BUFFERS>,
+    /// Counts up with every access, so that the buffer with the smallest `last_used` is the least recently
+    /// used one
+    clock: u64
+}
+
+unsafe impl Send for BufferCache {}
+
+static CACHE: Lazy<Mutex<BufferCache>> = Lazy::new(|| {
+    let Some(frames) = allocate_frames((CACHE_BUFFERS * MAX_BLOCK_SIZE) as u64 / FRAME_SIZE)
+    else {
+        panic!("Not enough memory for the buffer cache");
+    };
+    let base = frames.as_ptr::<u8>();
+    let unused_memory = (0..CACHE_BUFFERS)
+        .rev()
+        .map(|i| unsafe { base.add(i * MAX_BLOCK_SIZE) })
+        .collect();
+    Mutex::new(BufferCache {
+        buffers: ArrayVec::new(),
+        unused_memory,
+        clock: 0
+    })
+});
+
+/// Where the completion handlers of a sync put the first error that occurred
+static SYNC_ERROR: Mutex<Option<BlockError>> = Mutex::new(None);
+
+/// Drivers which complete requests by themselves may call this from an interrupt handler.
+fn handle_sync_completion(result: Result<(), BlockError>, _context: u64) {
+    if let Err(e) = result {
+        without_interrupts(|| {
+            SYNC_ERROR.lock().get_or_insert(e);
+        });
+    }
+}
+
+impl BufferCache {
+    fn find(&self, device: &dyn BlockDevice, block: u64) -> Option<usize> {
+        self.buffers
+            .iter()
+            .position(|b| b.block == block && block::same_device(b.device, device))
+    }
+
+    /// Returns the index of the buffer holding `block`, reading the block into a buffer first if it isn't
+    /// cached. If `fill` is false, a newly assigned buffer is left with whatever it contained before, for
+    /// when the whole block is about to be overwritten anyway.
+    fn get(&mut self, device: &'static dyn BlockDevice, block: u64, fill: bool) -> Result<usize, BlockError> {
+        self.clock += 1;
+        if let Some(index) = self.find(device, block) {
+            self.buffers[index].last_used = self.clock;
+            return Ok(index);
+        }
+
+        let block_size = device.block_size() as usize;
+        let data = match self.unused_memory.pop() {
+            Some(data) => data,
+            None => {
+                let index = self.evict()?;
+                self.buffers.swap_remove(index).data
+            }
+        };
+        if fill {
+            let buffer = unsafe { core::slice::from_raw_parts_mut(data, block_size) };
+            if let Err(e) = device.read_blocks(block, buffer) {
+                self.unused_memory.push(data);
+                return Err(e);
+            }
+        }
+        self.buffers.push(CacheBuffer {
+            device,
+            block,
+            dirty: false,
+            last_used: self.clock,
+            data
+        });
+        Ok(self.buffers.len() - 1)
+    }
+
+    /// Picks the least recently used buffer to be reused, writing it back first if it's dirty.
+    fn evict(&mut self) -> Result<usize, BlockError> {
+        let (index, buffer) = self
+            .buffers
+            .iter_mut()
+            .enumerate()
+            .min_by_key(|(_, b)| b.last_used)
+            .ok_or(BlockError::OutOfMemory)?;
+        if buffer.dirty {
+            let block_size = buffer.device.block_size() as usize;
+            let data = unsafe { core::slice::from_raw_parts(buffer.data, block_size) };
+            buffer.device.write_blocks(buffer.block, data)?;
+            buffer.dirty = false;
+        }
+        Ok(index)
+    }
+
+    /// Writes every dirty buffer of `device` back, and flushes the device.
+    fn sync(&mut self, device: &'static dyn BlockDevice) -> Result<(), BlockError> {
+        without_interrupts(|| *SYNC_ERROR.lock() = None);
+        for buffer in self
+            .buffers
+            .iter()
+            .filter(|b| b.dirty && block::same_device(b.device, device))
+        {
+            device.submit(BlockRequest {
+                operation: BlockOperation::Write,
+                first_block: buffer.block,
+                block_count: 1,
+                buffer: buffer.data,
+                completion: handle_sync_completion,
+                context: 0
+            })?;
+        }
+        device.run_queue();
+        if let Some(e) = without_interrupts(|| SYNC_ERROR.lock().take()) {
+            return Err(e);
+        }
+        for buffer in self
+            .buffers
+            .iter_mut()
+            .filter(|b| block::same_device(b.device, device))
+        {
+            buffer.dirty = false;
+        }
+        device.flush()
+    }
+}
+
+/// Calls `f` with each block-sized piece of the byte range starting at `offset`: the block, the range within
+/// the block, and the range within the whole byte range.
+fn for_each_block(
+    block_size: usize,
+    offset: u64,
+    length: usize,
+    mut f: impl FnMut(u64, core::ops::Range<usize>, core::ops::Range<usize>) -> Result<(), BlockError>
+) -> Result<(), BlockError> {
+    let mut done = 0;
+    while done < length {
+        let position = offset + done as u64;
+        let block = position / block_size as u64;
+        let start_in_block = (position % block_size as u64) as usize;
+        let chunk_length = (block_size - start_in_block).min(length - done);
+        f(
+            block,
+            start_in_block..start_in_block + chunk_length,
+            done..done + chunk_length
+        )?;
+        done += chunk_length;
+    }
+    Ok(())
+}
+
+fn check_range(device: &dyn BlockDevice, offset: u64, length: usize) -> Result<(), BlockError> {
+    match offset.checked_add(length as u64) {
+        Some(end) if end <= device.size() => Ok(()),
+        _ => Err(BlockError::OutOfRange)
+    }
+}
+
+/// Reads `buffer.len()` bytes starting from the byte `offset` of a device through the cache. Neither needs
+/// to be aligned to blocks.
+pub fn read(device: &'static dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+    check_range(device, offset, buffer.len())?;
+    let mut cache = CACHE.lock();
+    for_each_block(
+        device.block_size() as usize,
+        offset,
+        buffer.len(),
+        |block, in_block, in_buffer| {
+            let index = cache.get(device, block, true)?;
+            let data = unsafe { core::slice::from_raw_parts(cache.buffers[index].data, in_block.end) };
+            buffer[in_buffer].copy_from_slice(&data[in_block]);
+            Ok(())
+        }
+    )
+}
+
+/// Writes `data` starting from the byte `offset` of a device. The data only reaches the device once the
+/// affected blocks are evicted from the cache or the device is synced.
+pub fn write(device: &'static dyn BlockDevice, offset: u64, data: &[u8]) -> Result<(), BlockError> {
+    check_range(device, offset, data.len())?;
+    let block_size = device.block_size() as usize;
+    let mut cache = CACHE.lock();
+    for_each_block(block_size, offset, data.len(), |block, in_block, in_data| {
+        let whole_block = in_block.len() == block_size;
+        let index = cache.get(device, block, !whole_block)?;
+        let buffer = &mut cache.buffers[index];
+        let cached = unsafe { core::slice::from_raw_parts_mut(buffer.data, in_block.end) };
+        cached[in_block].copy_from_slice(&data[in_data]);
+        buffer.dirty = true;
+        Ok(())
+    })
+}
+
+/// Writes every modified block of `device` back to it, and makes sure they've reached persistent storage.
+pub fn sync(device: &'static dyn BlockDevice) -> Result<(), BlockError> {
+    CACHE.lock().sync(device)
+}
+
+/// Syncs every device, returning the first error that occurred. Devices after a failing one are still
+/// synced.
+pub fn sync_all() -> Result<(), BlockError> {
+    let mut result = Ok(());
+    for device in block::devices() {
+        let device_result = sync(device);
+        if result.is_ok() {
+            result = device_result;
+        }
+    }
+    result
+}
+
+/// Drops every cached block of `device` without writing anything back, e.g. after the device has been
+/// written to without going through the cache.
+pub fn invalidate(device: &'static dyn BlockDevice) {
+    let mut cache = CACHE.lock();
+    let mut i = 0;
+    while i < cache.buffers.len() {
+        if block::same_device(cache.buffers[i].device, device) {
+            let buffer = cache.buffers.swap_remove(i);
+            cache.unused_memory.push(buffer.data);
+        }
+        else {
+            i += 1;
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::vfs::VfsError;
+use crate::{keyboard, print};
+
+const BACKSPACE: u8 = 0x08;
+/// Control-D, which ends the input like on a terminal
+const END_OF_TRANSMISSION: u8 = 0x04;
+
+/// The terminal's input, which is handed out a line at a time like in the canonical mode of Unix terminals.
+struct Input {
+    /// Lines which are finished but haven't been read yet
+    ready: VecDeque<u8>,
+    /// The line being typed, which can still be changed with backspace
+    line: Vec<u8>,
+    /// Set by control-D on an empty line, so that the next read returns 0 to signal the end of the input
+    end_of_input: bool
+}
+
+static INPUT: Mutex<Input> = Mutex::new(Input {
+    ready: VecDeque::new(),
+    line: Vec::new(),
+    end_of_input: false
+});
+
+impl Input {
+    fn handle_key(&mut self, event: keyboard::KeyEvent) {
+        let character = match event.to_ascii() {
+            Some(b'd') if event.modifiers.control() => END_OF_TRANSMISSION,
+            Some(character) => character,
+            None => return
+        };
+        match character {
+            BACKSPACE => {
+                // The text renderer can't take characters back, so only the line is changed
+                self.line.pop();
+            },
+            END_OF_TRANSMISSION => {
+                self.end_of_input = self.line.is_empty();
+                self.ready.extend(self.line.drain(..));
+            },
+            b'\n' => {
+                print!("\n");
+                self.line.push(b'\n');
+                self.ready.extend(self.line.drain(..));
+            },
+            _ => {
+                print!("{}", printable(character) as char);
+                self.line.push(character);
+            }
+        }
+    }
+}
+
+/// Replaces characters the text renderer has no glyphs for.
+#[inline]
+fn printable(character: u8) -> u8 {
+    match character {
+        b'\n' | b' '..=b'~' => character,
+        b'\t' => b' ',
+        _ => b'?'
+    }
+}
+
+/// Handles the key presses that have arrived, echoing them. This is done by readers, and by the idle loop
+/// so that typing shows up even when no one is reading.
+pub fn process_input() {
+    let mut input = INPUT.lock();
+    while let Some(event) = keyboard::pop_event() {
+        input.handle_key(event);
+    }
+}
+
+/// Waits until a line has been typed and reads as much of it as fits into `buffer`, echoing what's typed.
+/// Returns 0 once the end of the input has been typed, and fails if a signal arrives while waiting.
+pub fn read(buffer: &mut [u8]) -> Result<usize, VfsError> {
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+    let mut length = 0;
+    keyboard::EVENT_WAITERS
+        .wait_until_interruptible(|| {
+            let mut input = INPUT.lock();
+            while let Some(event) = keyboard::pop_event() {
+                input.handle_key(event);
+            }
+            if input.ready.is_empty() && !input.end_of_input {
+                return false;
+            }
+            length = buffer.len().min(input.ready.len());
+            for (byte, character) in buffer.iter_mut().zip(input.ready.drain(..length)) {
+                *byte = character;
+            }
+            if length == 0 {
+                input.end_of_input = false;
+            }
+            true
+        })
+        .map_err(|_| VfsError::Interrupted)?;
+    Ok(length)
+}
+
+/// Prints what's written, replacing what the text renderer can't show.
+pub fn write(data: &[u8]) {
+    let text: String = data.iter().map(|&byte| printable(byte) as char).collect();
+    print!("{}", text);
+}
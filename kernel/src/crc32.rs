@@ -0,0 +1,50 @@
+/// The reflected form of the IEEE 802.3 polynomial, as used by GPT, zlib and most others.
+const POLYNOMIAL: u32 = 0xedb88320;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut value = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = match value & 1 {
+                1 => (value >> 1) ^ POLYNOMIAL,
+                _ => value >> 1
+            };
+            bit += 1;
+        }
+        table[i] = value;
+        i += 1;
+    }
+    table
+};
+
+/// A CRC-32 computed over data that arrives in pieces.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32
+}
+
+impl Crc32 {
+    pub const fn new() -> Crc32 {
+        Crc32 { state: u32::MAX }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = TABLE[((self.state ^ byte as u32) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    #[inline]
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,143 @@
+use crate::graphics::{Color, Rgb};
+use crate::limine::LimineFramebuffer;
+
+const CURSOR_WIDTH: usize = 12;
+const CURSOR_HEIGHT: usize = 19;
+
+/// 'X' is the outline, 'O' the fill and '.' a transparent pixel.
+const CURSOR_SPRITE: [&[u8; CURSOR_WIDTH]; CURSOR_HEIGHT] = [
+    b"X...........",
+    b"XX..........",
+    b"XOX.........",
+    b"XOOX........",
+    b"XOOOX.......",
+    b"XOOOOX......",
+    b"XOOOOOX.....",
+    b"XOOOOOOX....",
+    b"XOOOOOOOX...",
+    b"XOOOOOOOOX..",
+    b"XOOOOOOOOOX.",
+    b"XOOOOOOXXXXX",
+    b"XOOOXOOX....",
+    b"XOOXXOOX....",
+    b"XOX..XOOX...",
+    b"XX...XOOX...",
+    b"X.....XOOX..",
+    b".......XOOX.",
+    b"........XX.."
+];
+
+/// A mouse cursor drawn directly onto the framebuffer. The pixels underneath the cursor are saved when it is
+/// drawn and written back when it is hidden, so the cursor can be moved around without damaging whatever
+/// was drawn on the screen. Anything drawing to the area under the cursor should hide it first, otherwise the
+/// old contents will be restored over the new ones once the cursor moves.
+pub struct Cursor {
+    x: i64,
+    y: i64,
+    saved_pixels: [Color; CURSOR_WIDTH * CURSOR_HEIGHT],
+    visible: bool
+}
+
+impl Cursor {
+    pub const fn new() -> Cursor {
+        Cursor {
+            x: 0,
+            y: 0,
+            saved_pixels: [Color::BLACK; CURSOR_WIDTH * CURSOR_HEIGHT],
+            visible: false
+        }
+    }
+
+    #[inline]
+    pub fn position(&self) -> (i64, i64) {
+        (self.x, self.y)
+    }
+
+    pub fn show(&mut self, framebuffer: &mut LimineFramebuffer) {
+        if self.visible {
+            return;
+        }
+        let outline = Color::new(framebuffer, Rgb { r: 0, g: 0, b: 0 });
+        let fill = Color::new(
+            framebuffer,
+            Rgb {
+                r: 0xff,
+                g: 0xff,
+                b: 0xff
+            }
+        );
+        for (sprite_y, row) in CURSOR_SPRITE.iter().enumerate() {
+            for (sprite_x, pixel) in row.iter().enumerate() {
+                let Some((x, y)) = self.screen_position(framebuffer, sprite_x, sprite_y)
+                else {
+                    continue;
+                };
+                let saved_pixel = &mut self.saved_pixels[sprite_y * CURSOR_WIDTH + sprite_x];
+                unsafe {
+                    *saved_pixel = framebuffer.get_pixel_color_unchecked(x, y);
+                    match pixel {
+                        b'X' => framebuffer.set_pixel_color_unchecked(x, y, outline),
+                        b'O' => framebuffer.set_pixel_color_unchecked(x, y, fill),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.visible = true;
+    }
+
+    /// Restores the pixels that were underneath the cursor.
+    pub fn hide(&mut self, framebuffer: &mut LimineFramebuffer) {
+        if !self.visible {
+            return;
+        }
+        for sprite_y in 0..CURSOR_HEIGHT {
+            for sprite_x in 0..CURSOR_WIDTH {
+                let Some((x, y)) = self.screen_position(framebuffer, sprite_x, sprite_y)
+                else {
+                    continue;
+                };
+                let saved_pixel = self.saved_pixels[sprite_y * CURSOR_WIDTH + sprite_x];
+                unsafe { framebuffer.set_pixel_color_unchecked(x, y, saved_pixel) };
+            }
+        }
+        self.visible = false;
+    }
+
+    /// Moves the cursor to the given position, clamped so that the tip of the cursor stays on the screen.
+    pub fn move_to(&mut self, framebuffer: &mut LimineFramebuffer, x: i64, y: i64) {
+        let was_visible = self.visible;
+        self.hide(framebuffer);
+        self.x = x.clamp(0, framebuffer.width as i64 - 1);
+        self.y = y.clamp(0, framebuffer.height as i64 - 1);
+        if was_visible {
+            self.show(framebuffer);
+        }
+    }
+
+    #[inline]
+    pub fn move_by(&mut self, framebuffer: &mut LimineFramebuffer, dx: i64, dy: i64) {
+        self.move_to(framebuffer, self.x + dx, self.y + dy);
+    }
+
+    #[inline]
+    fn screen_position(
+        &self,
+        framebuffer: &LimineFramebuffer,
+        sprite_x: usize,
+        sprite_y: usize
+    ) -> Option<(u64, u64)> {
+        let x = self.x as u64 + sprite_x as u64;
+        let y = self.y as u64 + sprite_y as u64;
+        if x >= framebuffer.width || y >= framebuffer.height {
+            return None;
+        }
+        Some((x, y))
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
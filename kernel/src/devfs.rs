@@ -0,0 +1,383 @@
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::ptr;
+
+use spin::{Lazy, Mutex};
+
+use crate::block::{self, BlockDevice};
+use crate::cpuid::{get_cpu_info, CPUBasicFeatureFlags};
+use crate::memory::{FRAME_SIZE, HHDM_OFFSET};
+use crate::serial::{self, SerialPort};
+use crate::vfs::{self, FileSystem, FileSystemType, FileType, Inode, Stat, VfsError};
+use crate::{buffer_cache, console, keyboard};
+
+const ROOT_INODE: u64 = 1;
+/// /dev/shm, where a ramfs is mounted for shared memory objects
+const SHM_NAME: &str = "shm";
+const SHM_INODE: u64 = 15;
+/// Inode numbers of the serial ports and block devices start here, followed by their index
+const FIRST_SERIAL_INODE: u64 = 16;
+const FIRST_BLOCK_INODE: u64 = 32;
+
+/// Every key event read from the keyboard device takes up this many bytes: the key's usage id, 1 if it was
+/// pressed and 0 if released, the modifier bits and a padding byte.
+const KEY_EVENT_SIZE: usize = 4;
+/// How many times to ask RDRAND for a number before falling back to the xorshift generator
+const RDRAND_RETRIES: u32 = 10;
+
+#[derive(Clone, Copy)]
+enum Device {
+    /// Reads nothing and discards what's written
+    Null,
+    /// Reads zeroes and discards what's written
+    Zero,
+    /// Reads random bytes and discards what's written
+    Random,
+    /// The memory of the framebuffer, which can also be mapped
+    Framebuffer,
+    /// Key events as they come in, `KEY_EVENT_SIZE` bytes each
+    Keyboard,
+    /// The screen and keyboard as a terminal, which is what programs read and write by default
+    Console,
+    Serial(&'static SerialPort),
+    Block(&'static dyn BlockDevice)
+}
+
+/// A device, along with its name in /dev and its inode number.
+struct DeviceNode {
+    name: &'static str,
+    inode: u64,
+    device: Device
+}
+
+/// Every device, in the order they're listed in. Serial ports and block devices are listed as they are when
+/// called, so those found after /dev was mounted show up too.
+fn devices() -> Vec<DeviceNode> {
+    let fixed = [
+        ("null", Device::Null),
+        ("zero", Device::Zero),
+        ("random", Device::Random),
+        ("fb0", Device::Framebuffer),
+        ("keyboard", Device::Keyboard),
+        ("console", Device::Console)
+    ];
+    let fixed = fixed
+        .into_iter()
+        .enumerate()
+        .map(|(i, (name, device))| DeviceNode {
+            name,
+            inode: ROOT_INODE + 1 + i as u64,
+            device
+        });
+    let serial_ports = serial::ports().iter().enumerate().map(|(i, port)| DeviceNode {
+        name: port.name(),
+        inode: FIRST_SERIAL_INODE + i as u64,
+        device: Device::Serial(port)
+    });
+    let block_devices = block::devices()
+        .into_iter()
+        .enumerate()
+        .map(|(i, device)| DeviceNode {
+            name: device.name(),
+            inode: FIRST_BLOCK_INODE + i as u64,
+            device: Device::Block(device)
+        });
+    fixed.chain(serial_ports).chain(block_devices).collect()
+}
+
+/// The virtual address and size of the framebuffer's memory.
+fn framebuffer_memory() -> (*mut u8, u64) {
+    let framebuffer = crate::FRAMEBUFFER.lock();
+    (
+        framebuffer.address as *mut u8,
+        framebuffer.pitch * framebuffer.height
+    )
+}
+
+static HAS_RDRAND: Lazy<bool> = Lazy::new(|| {
+    get_cpu_info()
+        .feature_flags
+        .contains(CPUBasicFeatureFlags::RDRAND)
+});
+
+static XORSHIFT_STATE: Mutex<u64> = Mutex::new(0);
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let success: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) success, options(nomem, nostack));
+        }
+        if success != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A random number from RDRAND if the CPU has it, and from a xorshift generator seeded with the time stamp
+/// counter otherwise, which is good enough for anything but cryptography.
+pub fn random_u64() -> u64 {
+    if *HAS_RDRAND {
+        if let Some(value) = rdrand() {
+            return value;
+        }
+    }
+    let mut state = XORSHIFT_STATE.lock();
+    if *state == 0 {
+        *state = unsafe { _rdtsc() } | 1;
+    }
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+impl DeviceNode {
+    fn file_type(&self) -> FileType {
+        match self.device {
+            Device::Block(_) => FileType::BlockDevice,
+            _ => FileType::CharacterDevice
+        }
+    }
+}
+
+impl Inode for DeviceNode {
+    /// Only root can get at the memory of devices, while the rest are for everyone.
+    fn stat(&self) -> Result<Stat, VfsError> {
+        let (mode, size, block_size) = match self.device {
+            Device::Framebuffer => (0o600, framebuffer_memory().1, FRAME_SIZE as u32),
+            Device::Block(device) => (0o600, device.size(), device.block_size()),
+            _ => (0o666, 0, FRAME_SIZE as u32)
+        };
+        Ok(Stat {
+            inode: self.inode,
+            device: 0,
+            file_type: self.file_type(),
+            mode,
+            link_count: 1,
+            uid: 0,
+            gid: 0,
+            size,
+            block_size,
+            blocks: 0
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        match self.device {
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buffer.fill(0);
+                Ok(buffer.len())
+            },
+            Device::Random => {
+                for chunk in buffer.chunks_mut(8) {
+                    let length = chunk.len();
+                    chunk.copy_from_slice(&random_u64().to_le_bytes()[..length]);
+                }
+                Ok(buffer.len())
+            },
+            Device::Framebuffer => {
+                let (address, size) = framebuffer_memory();
+                let length = buffer.len().min(size.saturating_sub(offset) as usize);
+                if length == 0 {
+                    return Ok(0);
+                }
+                unsafe {
+                    ptr::copy_nonoverlapping(address.add(offset as usize), buffer.as_mut_ptr(), length)
+                };
+                Ok(length)
+            },
+            // Only whole events are read, and reads return nothing rather than wait if there are none
+            Device::Keyboard => {
+                let mut length = 0;
+                for event in buffer.chunks_exact_mut(KEY_EVENT_SIZE) {
+                    let Some(key_event) = keyboard::pop_event()
+                    else {
+                        break;
+                    };
+                    event.copy_from_slice(&[
+                        key_event.key,
+                        key_event.pressed as u8,
+                        key_event.modifiers.bits(),
+                        0
+                    ]);
+                    length += KEY_EVENT_SIZE;
+                }
+                Ok(length)
+            },
+            Device::Console => console::read(buffer),
+            Device::Serial(port) => Ok(port.read(buffer)),
+            Device::Block(device) => {
+                let length = buffer.len().min(device.size().saturating_sub(offset) as usize);
+                buffer_cache::read(device, offset, &mut buffer[..length])
+                    .map_err(|_| VfsError::DeviceError)?;
+                Ok(length)
+            }
+        }
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        match self.device {
+            Device::Null | Device::Zero | Device::Random => Ok(data.len()),
+            Device::Framebuffer => {
+                // Holding the lock keeps the console from drawing over what's being written
+                let framebuffer = crate::FRAMEBUFFER.lock();
+                let size = framebuffer.pitch * framebuffer.height;
+                let length = data.len().min(size.saturating_sub(offset) as usize);
+                if length == 0 && !data.is_empty() {
+                    return Err(VfsError::NoSpace);
+                }
+                let address = framebuffer.address as *mut u8;
+                unsafe { ptr::copy_nonoverlapping(data.as_ptr(), address.add(offset as usize), length) };
+                Ok(length)
+            },
+            Device::Keyboard => Err(VfsError::NotSupported),
+            Device::Console => {
+                console::write(data);
+                Ok(data.len())
+            },
+            Device::Serial(port) => {
+                port.write(data);
+                Ok(data.len())
+            },
+            Device::Block(device) => {
+                let length = data.len().min(device.size().saturating_sub(offset) as usize);
+                if length == 0 && !data.is_empty() {
+                    return Err(VfsError::NoSpace);
+                }
+                buffer_cache::write(device, offset, &data[..length]).map_err(|_| VfsError::DeviceError)?;
+                Ok(length)
+            }
+        }
+    }
+
+    fn physical_page(&self, offset: u64) -> Result<u64, VfsError> {
+        let Device::Framebuffer = self.device
+        else {
+            return Err(VfsError::NotSupported);
+        };
+        let (address, size) = framebuffer_memory();
+        match offset < size.next_multiple_of(FRAME_SIZE) {
+            true => Ok(address as u64 - *HHDM_OFFSET + offset),
+            false => Err(VfsError::InvalidArgument)
+        }
+    }
+}
+
+/// The directory of every device, which can't be changed.
+struct DevfsRoot;
+
+impl Inode for DevfsRoot {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        Ok(Stat {
+            inode: ROOT_INODE,
+            device: 0,
+            file_type: FileType::Directory,
+            mode: 0o755,
+            link_count: 2,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            block_size: FRAME_SIZE as u32,
+            blocks: 0
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        if name == SHM_NAME {
+            return Ok(Arc::new(ShmDirectory));
+        }
+        let node = devices()
+            .into_iter()
+            .find(|node| node.name == name)
+            .ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(node))
+    }
+
+    fn read_directory(
+        &self,
+        start: usize,
+        f: &mut dyn FnMut(vfs::DirectoryEntry) -> bool
+    ) -> Result<(), VfsError> {
+        let shm = vfs::DirectoryEntry {
+            name: SHM_NAME.to_string(),
+            inode: SHM_INODE,
+            file_type: FileType::Directory
+        };
+        let entries = devices().into_iter().map(|node| vfs::DirectoryEntry {
+            name: node.name.to_string(),
+            inode: node.inode,
+            file_type: node.file_type()
+        });
+        for entry in entries.chain([shm]).skip(start) {
+            if !f(entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u16) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+}
+
+/// An empty directory, which is only there to have something mounted on it.
+struct ShmDirectory;
+
+impl Inode for ShmDirectory {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        Ok(Stat {
+            inode: SHM_INODE,
+            device: 0,
+            file_type: FileType::Directory,
+            mode: 0o1777,
+            link_count: 2,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            block_size: FRAME_SIZE as u32,
+            blocks: 0
+        })
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotFound)
+    }
+
+    fn read_directory(
+        &self,
+        _start: usize,
+        _f: &mut dyn FnMut(vfs::DirectoryEntry) -> bool
+    ) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u16) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+}
+
+struct Devfs;
+
+impl FileSystem for Devfs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevfsRoot)
+    }
+}
+
+fn mount(_device: Option<&'static dyn BlockDevice>, _options: &str) -> Result<Arc<dyn FileSystem>, VfsError> {
+    Ok(Arc::new(Devfs))
+}
+
+pub static DEVFS_FILE_SYSTEM: FileSystemType = FileSystemType { name: "devfs", mount };
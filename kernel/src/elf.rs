@@ -0,0 +1,421 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+
+use crate::devfs::random_u64;
+use crate::memory::FRAME_SIZE;
+use crate::paging::{PageFlags, PagingError};
+use crate::signal;
+use crate::user_memory::USER_SPACE_END;
+use crate::vfs::{FileType, Inode, OpenFile, OpenFlags, VfsError};
+use crate::virtual_memory::{Backing, ProcessMemory, Protection, Region};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+/// Position independent executables, which are loaded at `DYNAMIC_BASE`
+const TYPE_SHARED: u16 = 3;
+const MACHINE_X86_64: u16 = 62;
+
+const SEGMENT_LOAD: u32 = 1;
+const SEGMENT_INTERPRETER: u32 = 3;
+const SEGMENT_PROGRAM_HEADERS: u32 = 6;
+const SEGMENT_EXECUTABLE: u32 = 1 << 0;
+const SEGMENT_WRITABLE: u32 = 1 << 1;
+const SEGMENT_READABLE: u32 = 1 << 2;
+
+/// Where position independent executables are put, which is where non-PIE ones usually start as well
+const DYNAMIC_BASE: u64 = 0x40_0000;
+/// The user stack grows down from here, leaving a guard page at the top of user space
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - FRAME_SIZE;
+/// How big the stack region starts out, before it grows down on demand
+pub const USER_STACK_SIZE: u64 = 128 * 1024;
+/// Programs can't be loaded any higher than this, which keeps them clear of the stack
+const PROGRAM_END: u64 = 0x7000_0000_0000;
+/// How big the arguments and environment can be together, like the 128 KiB limit Linux used to have
+const MAX_ARGUMENTS_SIZE: usize = 64 * 1024;
+
+// Entries of the auxiliary vector, which tells the program about itself and the system
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_PLATFORM: u64 = 15;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+const PLATFORM: &str = "x86_64";
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ElfError {
+    /// The file isn't an ELF file at all
+    NotElf,
+    /// An ELF file for a different machine, or of a kind that can't be run, e.g. an object file
+    Unsupported,
+    /// The program has to be loaded by a dynamic linker
+    NeedsInterpreter,
+    /// A header or segment points outside of the file, or a segment would end up outside of user space
+    Malformed,
+    /// The arguments and environment don't fit on the stack
+    ArgumentsTooLong,
+    OutOfMemory,
+    FileSystemError(VfsError)
+}
+
+impl From<VfsError> for ElfError {
+    fn from(error: VfsError) -> Self {
+        ElfError::FileSystemError(error)
+    }
+}
+
+impl From<PagingError> for ElfError {
+    fn from(error: PagingError) -> Self {
+        match error {
+            PagingError::OutOfMemory => ElfError::OutOfMemory,
+            _ => ElfError::Malformed
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileHeader {
+    identification: [u8; 16],
+    file_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_name_index: u16
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    virtual_address: u64,
+    physical_address: u64,
+    file_size: u64,
+    memory_size: u64,
+    alignment: u64
+}
+
+/// A program ready to be run, with its segments and stack in an address space of its own. Most of the
+/// segments are only read from the file once their pages are touched.
+pub struct LoadedProgram {
+    pub memory: ProcessMemory,
+    pub entry: u64,
+    /// Points to the argument count, followed by the arguments, environment and auxiliary vector
+    pub stack_pointer: u64
+}
+
+/// Reads as much of the file at `offset` as fits into `buffer`, which is less only at the end of the file.
+fn read_at(file: &dyn Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, ElfError> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read_at(offset + read as u64, &mut buffer[read..])? {
+            0 => break,
+            length => read += length
+        }
+    }
+    Ok(read)
+}
+
+/// Reads a structure out of the file, which may be at any alignment.
+fn read_struct<T: Copy>(file: &dyn Inode, offset: u64) -> Result<T, ElfError> {
+    let mut bytes = vec![0; size_of::<T>()];
+    if read_at(file, offset, &mut bytes)? < bytes.len() {
+        return Err(ElfError::Malformed);
+    }
+    Ok(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Opens the file to load, returning its inode and size.
+fn open_file(path: &str) -> Result<(Arc<dyn Inode>, u64), ElfError> {
+    let file = OpenFile::open(path, OpenFlags::READ, 0)?;
+    let stat = file.stat()?;
+    if stat.file_type != FileType::Regular {
+        return Err(VfsError::NotSupported.into());
+    }
+    Ok((file.dentry().inode().clone(), stat.size))
+}
+
+/// Loads the static executable at `path` into a new address space, and sets up its stack with the arguments
+/// and environment the way the System V ABI describes.
+pub fn load(path: &str, arguments: &[String], environment: &[String]) -> Result<LoadedProgram, ElfError> {
+    let (file, file_size) = open_file(path)?;
+    let header: FileHeader = read_struct(&*file, 0).map_err(|_| ElfError::NotElf)?;
+    if header.identification[..4] != ELF_MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if header.identification[4] != CLASS_64
+        || header.identification[5] != DATA_LITTLE_ENDIAN
+        || header.identification[6] != VERSION_CURRENT
+        || header.machine != MACHINE_X86_64
+    {
+        return Err(ElfError::Unsupported);
+    }
+    let base = match header.file_type {
+        TYPE_EXECUTABLE => 0,
+        TYPE_SHARED => DYNAMIC_BASE,
+        _ => return Err(ElfError::Unsupported)
+    };
+    if header.program_header_size as usize != size_of::<ProgramHeader>() {
+        return Err(ElfError::Malformed);
+    }
+    let table_size = header.program_header_count as u64 * size_of::<ProgramHeader>() as u64;
+    if header.program_header_offset.checked_add(table_size).is_none() {
+        return Err(ElfError::Malformed);
+    }
+    let program_headers = (0..header.program_header_count as u64)
+        .map(|i| {
+            read_struct(
+                &*file,
+                header.program_header_offset + i * size_of::<ProgramHeader>() as u64
+            )
+        })
+        .collect::<Result<Vec<ProgramHeader>, ElfError>>()?;
+    if program_headers
+        .iter()
+        .any(|h| h.segment_type == SEGMENT_INTERPRETER)
+    {
+        return Err(ElfError::NeedsInterpreter);
+    }
+
+    let mut memory = ProcessMemory::new()?;
+    for segment in program_headers.iter().filter(|h| h.segment_type == SEGMENT_LOAD) {
+        load_segment(&mut memory, &file, file_size, segment, base)?;
+    }
+    let mut loaded_segments = program_headers.iter().filter(|h| h.segment_type == SEGMENT_LOAD);
+    // Whether `start..end` is inside a loaded segment, which were checked not to overflow when they were
+    // loaded
+    let is_loaded = |start: u64, end: u64| {
+        loaded_segments
+            .clone()
+            .any(|s| base + s.virtual_address <= start && end <= base + s.virtual_address + s.memory_size)
+    };
+    // The entry point has to be in the program, as a non-canonical one couldn't even be returned to
+    let entry = base.checked_add(header.entry).ok_or(ElfError::Malformed)?;
+    if !is_loaded(entry, entry.saturating_add(1)) {
+        return Err(ElfError::Malformed);
+    }
+    // Where the program headers are in memory, which the C library uses e.g. to find thread local storage
+    let program_headers_address = match program_headers
+        .iter()
+        .find(|h| h.segment_type == SEGMENT_PROGRAM_HEADERS)
+    {
+        // Has to be part of a loaded segment, as user space reads the program headers from there
+        Some(h) => {
+            let address = base.checked_add(h.virtual_address).ok_or(ElfError::Malformed)?;
+            let end = address.checked_add(table_size).ok_or(ElfError::Malformed)?;
+            if !is_loaded(address, end) {
+                return Err(ElfError::Malformed);
+            }
+            address
+        },
+        None => loaded_segments
+            .find(|h| (h.offset..h.offset + h.file_size).contains(&header.program_header_offset))
+            .map_or(0, |h| {
+                base + h.virtual_address + header.program_header_offset - h.offset
+            })
+    };
+
+    let auxiliary_vector = [
+        (AT_PHDR, program_headers_address),
+        (AT_PHENT, size_of::<ProgramHeader>() as u64),
+        (AT_PHNUM, program_headers.len() as u64),
+        (AT_PAGESZ, FRAME_SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_SECURE, 0)
+    ];
+    let stack_pointer = set_up_stack(&mut memory, path, arguments, environment, &auxiliary_vector)?;
+    signal::map_trampoline(&mut memory)?;
+    Ok(LoadedProgram {
+        memory,
+        entry,
+        stack_pointer
+    })
+}
+
+/// Adds a file-backed region for a `PT_LOAD` segment, whose pages are read from the file when they're first
+/// touched. The rest of the segment is zero-filled, which is where e.g. `.bss` is. A first page shared with
+/// the previous segment is loaded right away instead, and gets the permissions of both.
+fn load_segment(
+    memory: &mut ProcessMemory,
+    file: &Arc<dyn Inode>,
+    file_size: u64,
+    segment: &ProgramHeader,
+    base: u64
+) -> Result<(), ElfError> {
+    let start = base
+        .checked_add(segment.virtual_address)
+        .ok_or(ElfError::Malformed)?;
+    let end = start
+        .checked_add(segment.memory_size)
+        .ok_or(ElfError::Malformed)?;
+    let file_end = segment
+        .offset
+        .checked_add(segment.file_size)
+        .ok_or(ElfError::Malformed)?;
+    if segment.file_size > segment.memory_size
+        || file_end > file_size
+        || end > PROGRAM_END
+        || start < FRAME_SIZE
+        || start % FRAME_SIZE != segment.offset % FRAME_SIZE
+    {
+        return Err(ElfError::Malformed);
+    }
+    if segment.memory_size == 0 {
+        return Ok(());
+    }
+    let mut protection = Protection::empty();
+    if segment.flags & SEGMENT_READABLE != 0 {
+        protection |= Protection::READ;
+    }
+    if segment.flags & SEGMENT_WRITABLE != 0 {
+        protection |= Protection::WRITE;
+    }
+    if segment.flags & SEGMENT_EXECUTABLE != 0 {
+        protection |= Protection::EXECUTE;
+    }
+    let first_page = start - start % FRAME_SIZE;
+    // Where the part of the segment that's read from the file ends in memory
+    let file_part_end = start + segment.file_size;
+    let mut region_start = first_page;
+    if memory.region(first_page).is_some() {
+        memory.populate_range(first_page, first_page + 1)?;
+        let address_space = memory.address_space_mut();
+        let (_, existing_flags) = address_space.translate(first_page).ok_or(ElfError::Malformed)?;
+        let mut combined = existing_flags;
+        if protection.contains(Protection::WRITE) {
+            combined |= PageFlags::WRITABLE;
+        }
+        if protection.contains(Protection::EXECUTE) {
+            combined -= PageFlags::NO_EXECUTE;
+        }
+        address_space.set_flags(first_page, combined)?;
+        let length = file_part_end.min(first_page + FRAME_SIZE).saturating_sub(start);
+        let mut contents = vec![0; length as usize];
+        if read_at(&**file, segment.offset, &mut contents)? < contents.len() {
+            return Err(ElfError::Malformed);
+        }
+        address_space.write(start, &contents)?;
+        region_start += FRAME_SIZE;
+    }
+    let region_end = end.next_multiple_of(FRAME_SIZE);
+    if region_start < region_end {
+        memory.add_region(Region {
+            start: region_start,
+            end: region_end,
+            protection,
+            backing: Backing::File {
+                inode: file.clone(),
+                offset: segment.offset + region_start - start,
+                length: file_part_end.saturating_sub(region_start)
+            },
+            grows_down: false,
+            shared: false,
+            may_write: true
+        })?;
+    }
+    Ok(())
+}
+
+/// Maps the stack and puts the argument count, the pointers to the arguments and environment variables and
+/// the auxiliary vector at the bottom, with the strings they point to above them. Returns the stack pointer
+/// to start with, which is 16 byte aligned.
+fn set_up_stack(
+    memory: &mut ProcessMemory,
+    path: &str,
+    arguments: &[String],
+    environment: &[String],
+    auxiliary_vector: &[(u64, u64)]
+) -> Result<u64, ElfError> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    memory.add_region(Region {
+        start: stack_bottom,
+        end: USER_STACK_TOP,
+        protection: Protection::READ | Protection::WRITE,
+        backing: Backing::Anonymous,
+        grows_down: true,
+        shared: false,
+        may_write: true
+    })?;
+
+    // The strings go at the top, in the order they're added
+    let mut strings = Vec::new();
+    let mut add_string = |bytes: &[u8]| {
+        strings.extend_from_slice(bytes);
+        strings.push(0);
+        strings.len()
+    };
+    let argument_ends: Vec<usize> = arguments.iter().map(|a| add_string(a.as_bytes())).collect();
+    let environment_ends: Vec<usize> = environment.iter().map(|e| add_string(e.as_bytes())).collect();
+    let path_end = add_string(path.as_bytes());
+    let platform_end = add_string(PLATFORM.as_bytes());
+    let mut random = [0; 16];
+    random[..8].copy_from_slice(&random_u64().to_le_bytes());
+    random[8..].copy_from_slice(&random_u64().to_le_bytes());
+    strings.extend_from_slice(&random);
+    let random_end = strings.len();
+    if strings.len() > MAX_ARGUMENTS_SIZE {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+    let strings_start = (USER_STACK_TOP - strings.len() as u64) & !0xf;
+    // Turns the end of a string in `strings` into the address of its start
+    let address = |end: usize, length: usize| strings_start + (end - length) as u64;
+
+    let mut words = vec![arguments.len() as u64];
+    for (argument, &end) in arguments.iter().zip(&argument_ends) {
+        words.push(address(end, argument.len() + 1));
+    }
+    words.push(0);
+    for (variable, &end) in environment.iter().zip(&environment_ends) {
+        words.push(address(end, variable.len() + 1));
+    }
+    words.push(0);
+    let extra_entries = [
+        (AT_EXECFN, address(path_end, path.len() + 1)),
+        (AT_PLATFORM, address(platform_end, PLATFORM.len() + 1)),
+        (AT_RANDOM, address(random_end, random.len()))
+    ];
+    for &(key, value) in auxiliary_vector.iter().chain(&extra_entries) {
+        words.extend([key, value]);
+    }
+    words.extend([AT_NULL, 0]);
+
+    let stack_pointer = (strings_start - words.len() as u64 * 8) & !0xf;
+    if stack_pointer < stack_bottom {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+    let word_bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    memory.write(stack_pointer, &word_bytes)?;
+    memory.write(strings_start, &strings)?;
+    Ok(stack_pointer)
+}
@@ -0,0 +1,59 @@
+/// A fixed-capacity FIFO queue for passing input events from interrupt handlers to the rest of the kernel.
+/// When the queue is full the oldest event is dropped, since for input devices the most recent state is the
+/// most useful one.
+pub struct EventQueue<T: Copy, const N: usize> {
+    events: [Option<T>; N],
+    head: usize,
+    len: usize
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> EventQueue<T, N> {
+        EventQueue {
+            events: [None; N],
+            head: 0,
+            len: 0
+        }
+    }
+
+    pub fn push(&mut self, event: T) {
+        let tail = (self.head + self.len) % N;
+        self.events[tail] = Some(event);
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+        }
+        else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        event
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T: Copy, const N: usize> Default for EventQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
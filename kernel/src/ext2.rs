@@ -0,0 +1,1235 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+
+use spin::Mutex;
+
+use crate::block::BlockDevice;
+use crate::buffer_cache;
+use crate::vfs::{self, FileSystem, FileSystemType, FileSystemUsage, FileType, Inode, Stat, VfsError};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+/// Blocks larger than a page aren't supported, like on Linux, which also keeps record lengths within 16 bits
+const MAX_BLOCK_SIZE: u64 = 4096;
+const ROOT_INODE: u32 = 2;
+/// Revision 0 file systems have 128 byte inodes, the first 10 of which are reserved
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+
+/// Offsets of the free counts in the superblock, which are kept up to date while allocating
+const SUPERBLOCK_FREE_BLOCKS_OFFSET: u64 = 12;
+const SUPERBLOCK_FREE_INODES_OFFSET: u64 = 16;
+
+/// Directory entries have a file type byte instead of the high byte of the name length
+const INCOMPAT_FILETYPE: u32 = 0x2;
+/// Other incompatible features, such as compression or a journal needing recovery, mean the file system
+/// can't even be read
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+/// Only some groups have superblock backups, which doesn't matter as they're never written
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+/// Regular files can be larger than 4 GiB, with the high half of the size stored separately
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+/// File systems with other read-only compatible features are mounted read-only
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const GROUP_BLOCK_BITMAP_OFFSET: u64 = 0;
+const GROUP_INODE_BITMAP_OFFSET: u64 = 4;
+const GROUP_INODE_TABLE_OFFSET: u64 = 8;
+const GROUP_FREE_BLOCKS_OFFSET: u64 = 12;
+const GROUP_FREE_INODES_OFFSET: u64 = 14;
+const GROUP_DIRECTORIES_OFFSET: u64 = 16;
+
+const INODE_MODE_OFFSET: usize = 0;
+const INODE_UID_OFFSET: usize = 2;
+const INODE_SIZE_OFFSET: usize = 4;
+const INODE_GID_OFFSET: usize = 24;
+const INODE_LINK_COUNT_OFFSET: usize = 26;
+const INODE_SECTORS_OFFSET: usize = 28;
+const INODE_FLAGS_OFFSET: usize = 32;
+const INODE_BLOCKS_OFFSET: usize = 40;
+const INODE_EXTENDED_ATTRIBUTES_OFFSET: usize = 104;
+/// Only regular files use this for the size, directories have an ACL here on old file systems
+const INODE_SIZE_HIGH_OFFSET: usize = 108;
+const INODE_UID_HIGH_OFFSET: usize = 120;
+const INODE_GID_HIGH_OFFSET: usize = 122;
+
+/// Blocks 0 to 11 of a file are pointed to by the inode directly, followed by pointers to a single, double
+/// and triple indirect block
+const DIRECT_BLOCKS: usize = 12;
+const BLOCK_POINTERS: usize = 15;
+/// Symbolic links to paths shorter than this are stored in the block pointers rather than a block
+const FAST_SYMLINK_MAX_LENGTH: usize = BLOCK_POINTERS * 4;
+/// Set on directories with a hashed index, which isn't kept up to date, so it's cleared when they change
+const INODE_FLAG_INDEX: u32 = 0x1000;
+
+const MODE_TYPE_MASK: u16 = 0o170000;
+const MODE_FIFO: u16 = 0o010000;
+const MODE_CHARACTER_DEVICE: u16 = 0o020000;
+const MODE_DIRECTORY: u16 = 0o040000;
+const MODE_BLOCK_DEVICE: u16 = 0o060000;
+const MODE_REGULAR: u16 = 0o100000;
+const MODE_SYMLINK: u16 = 0o120000;
+const MODE_PERMISSIONS_MASK: u16 = 0o7777;
+
+/// The header of a directory record, which the name follows, padded to 4 bytes
+const RECORD_HEADER_SIZE: usize = 8;
+const RECORD_TYPE_REGULAR: u8 = 1;
+const RECORD_TYPE_DIRECTORY: u8 = 2;
+const RECORD_TYPE_CHARACTER_DEVICE: u8 = 3;
+const RECORD_TYPE_BLOCK_DEVICE: u8 = 4;
+const RECORD_TYPE_FIFO: u8 = 5;
+const RECORD_TYPE_SYMLINK: u8 = 7;
+
+#[inline]
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+#[inline]
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[inline]
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+#[inline]
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The length of a directory record holding a name of `name_length` bytes.
+#[inline]
+fn record_length(name_length: usize) -> usize {
+    (RECORD_HEADER_SIZE + name_length).next_multiple_of(4)
+}
+
+fn file_type(mode: u16) -> FileType {
+    match mode & MODE_TYPE_MASK {
+        MODE_DIRECTORY => FileType::Directory,
+        MODE_SYMLINK => FileType::Symlink,
+        MODE_CHARACTER_DEVICE => FileType::CharacterDevice,
+        MODE_BLOCK_DEVICE => FileType::BlockDevice,
+        MODE_FIFO => FileType::Fifo,
+        // Sockets have no type of their own in the VFS
+        _ => FileType::Regular
+    }
+}
+
+fn record_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Regular => RECORD_TYPE_REGULAR,
+        FileType::Directory => RECORD_TYPE_DIRECTORY,
+        FileType::Symlink => RECORD_TYPE_SYMLINK,
+        FileType::CharacterDevice => RECORD_TYPE_CHARACTER_DEVICE,
+        FileType::BlockDevice => RECORD_TYPE_BLOCK_DEVICE,
+        FileType::Fifo => RECORD_TYPE_FIFO
+    }
+}
+
+/// The fields of an on-disk inode which are used. The rest, such as the timestamps, are left as they are
+/// when it's written back.
+#[derive(Debug, Clone, Copy)]
+struct InodeData {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    link_count: u16,
+    /// How many 512 byte sectors the data and indirect blocks take up
+    sectors: u32,
+    flags: u32,
+    blocks: [u32; BLOCK_POINTERS],
+    /// The block holding the extended attributes, which may be shared with other inodes
+    extended_attribute_block: u32
+}
+
+impl InodeData {
+    fn new(mode: u16) -> InodeData {
+        InodeData {
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            link_count: 1,
+            sectors: 0,
+            flags: 0,
+            blocks: [0; BLOCK_POINTERS],
+            extended_attribute_block: 0
+        }
+    }
+
+    #[inline]
+    fn file_type(&self) -> FileType {
+        file_type(self.mode)
+    }
+
+    #[inline]
+    fn is_directory(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
+    }
+
+    #[inline]
+    fn is_regular(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_REGULAR
+    }
+}
+
+/// A record of a directory, which is unused if its inode is 0. Records take up the whole of every block of a
+/// directory, with the last one of a block extending to its end.
+struct Record<'a> {
+    /// Where the record is on the device
+    offset: u64,
+    inode: u32,
+    length: u16,
+    file_type: u8,
+    name: &'a [u8]
+}
+
+/// An ext2 file system as mounted in the VFS. There is at most one inode object for every inode in use, so
+/// that everyone sees the same size and blocks, and inodes are only freed once nobody has them open anymore.
+struct Ext2Volume {
+    device: &'static dyn BlockDevice,
+    block_size: u64,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    group_count: u32,
+    inode_size: u64,
+    /// The first inode which isn't reserved
+    first_inode: u32,
+    has_record_types: bool,
+    large_files: bool,
+    read_only: bool,
+    /// Held while allocating or freeing, so that the bitmaps and free counts change together
+    allocation: Mutex<()>,
+    inodes: Mutex<BTreeMap<u32, Weak<Ext2Inode>>>
+}
+
+impl Ext2Volume {
+    fn new(device: &'static dyn BlockDevice, read_only: bool) -> Result<Ext2Volume, VfsError> {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        buffer_cache::read(device, SUPERBLOCK_OFFSET, &mut superblock).map_err(|_| VfsError::DeviceError)?;
+        if read_u16(&superblock, 56) != MAGIC {
+            return Err(VfsError::Corrupted);
+        }
+        let log_block_size = read_u32(&superblock, 24);
+        if log_block_size > MAX_BLOCK_SIZE.trailing_zeros() - 10 {
+            return Err(VfsError::NotSupported);
+        }
+        let block_size = 1024 << log_block_size;
+        let revision = read_u32(&superblock, 76);
+        let (inode_size, first_inode, incompatible, read_only_compatible) = match revision {
+            0 => (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INODE, 0, 0),
+            _ => (
+                read_u16(&superblock, 88) as u64,
+                read_u32(&superblock, 84),
+                read_u32(&superblock, 96),
+                read_u32(&superblock, 100)
+            )
+        };
+        if incompatible & !SUPPORTED_INCOMPAT != 0 {
+            return Err(VfsError::NotSupported);
+        }
+        let read_only = read_only || read_only_compatible & !SUPPORTED_RO_COMPAT != 0;
+
+        let inodes_count = read_u32(&superblock, 0);
+        let blocks_count = read_u32(&superblock, 4);
+        let first_data_block = read_u32(&superblock, 20);
+        let blocks_per_group = read_u32(&superblock, 32);
+        let inodes_per_group = read_u32(&superblock, 40);
+        let bits_per_block = block_size as u32 * 8;
+        if !(1..=bits_per_block).contains(&blocks_per_group)
+            || !(1..=bits_per_block).contains(&inodes_per_group)
+            || !inode_size.is_power_of_two()
+            || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size)
+            || first_data_block >= blocks_count
+            || blocks_count as u64 * block_size > device.size()
+        {
+            return Err(VfsError::Corrupted);
+        }
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if inodes_count as u64 > group_count as u64 * inodes_per_group as u64 {
+            return Err(VfsError::Corrupted);
+        }
+        Ok(Ext2Volume {
+            device,
+            block_size,
+            blocks_count,
+            inodes_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            group_count,
+            inode_size,
+            first_inode,
+            has_record_types: incompatible & INCOMPAT_FILETYPE != 0,
+            large_files: read_only_compatible & RO_COMPAT_LARGE_FILE != 0,
+            read_only,
+            allocation: Mutex::new(()),
+            inodes: Mutex::new(BTreeMap::new())
+        })
+    }
+
+    fn inode(self: &Arc<Ext2Volume>, number: u32) -> Result<Arc<Ext2Inode>, VfsError> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&number).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        inodes.retain(|_, inode| inode.strong_count() > 0);
+        let inode = Arc::new(Ext2Inode {
+            volume: self.clone(),
+            number,
+            data: Mutex::new(self.load_inode(number)?)
+        });
+        inodes.insert(number, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    #[inline]
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        buffer_cache::read(self.device, offset, buffer).map_err(|_| VfsError::DeviceError)
+    }
+
+    #[inline]
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), VfsError> {
+        if self.read_only {
+            return Err(VfsError::ReadOnly);
+        }
+        buffer_cache::write(self.device, offset, data).map_err(|_| VfsError::DeviceError)
+    }
+
+    fn read_u16_at(&self, offset: u64) -> Result<u16, VfsError> {
+        let mut bytes = [0; 2];
+        self.read_bytes(offset, &mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_u32_at(&self, offset: u64) -> Result<u32, VfsError> {
+        let mut bytes = [0; 4];
+        self.read_bytes(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write_u16_at(&self, offset: u64, value: u16) -> Result<(), VfsError> {
+        self.write_bytes(offset, &value.to_le_bytes())
+    }
+
+    fn write_u32_at(&self, offset: u64, value: u32) -> Result<(), VfsError> {
+        self.write_bytes(offset, &value.to_le_bytes())
+    }
+
+    fn check_writable(&self) -> Result<(), VfsError> {
+        match self.read_only {
+            true => Err(VfsError::ReadOnly),
+            false => Ok(())
+        }
+    }
+
+    #[inline]
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size
+    }
+
+    #[inline]
+    fn sectors_per_block(&self) -> u32 {
+        (self.block_size / 512) as u32
+    }
+
+    /// How many block numbers fit in an indirect block.
+    #[inline]
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    /// The group descriptor table is in the block following the superblock.
+    fn group_descriptor_offset(&self, group: u32) -> u64 {
+        self.block_offset(self.first_data_block + 1) + group as u64 * GROUP_DESCRIPTOR_SIZE
+    }
+
+    /// How many blocks a group has, as the last one may be cut short.
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        (self.blocks_count - self.first_data_block - group * self.blocks_per_group).min(self.blocks_per_group)
+    }
+
+    #[inline]
+    fn inode_group(&self, number: u32) -> u32 {
+        (number - 1) / self.inodes_per_group
+    }
+
+    fn inode_offset(&self, number: u32) -> Result<u64, VfsError> {
+        if !(1..=self.inodes_count).contains(&number) {
+            return Err(VfsError::Corrupted);
+        }
+        let group = self.inode_group(number);
+        let table = self.read_u32_at(self.group_descriptor_offset(group) + GROUP_INODE_TABLE_OFFSET)?;
+        let index = (number - 1) % self.inodes_per_group;
+        Ok(self.block_offset(table) + index as u64 * self.inode_size)
+    }
+
+    fn load_inode(&self, number: u32) -> Result<InodeData, VfsError> {
+        let mut raw = [0; GOOD_OLD_INODE_SIZE as usize];
+        self.read_bytes(self.inode_offset(number)?, &mut raw)?;
+        let mode = read_u16(&raw, INODE_MODE_OFFSET);
+        let size_high = match mode & MODE_TYPE_MASK {
+            MODE_REGULAR => read_u32(&raw, INODE_SIZE_HIGH_OFFSET),
+            _ => 0
+        };
+        let mut blocks = [0; BLOCK_POINTERS];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = read_u32(&raw, INODE_BLOCKS_OFFSET + i * 4);
+        }
+        Ok(InodeData {
+            mode,
+            uid: read_u16(&raw, INODE_UID_OFFSET) as u32
+                | (read_u16(&raw, INODE_UID_HIGH_OFFSET) as u32) << 16,
+            gid: read_u16(&raw, INODE_GID_OFFSET) as u32
+                | (read_u16(&raw, INODE_GID_HIGH_OFFSET) as u32) << 16,
+            size: read_u32(&raw, INODE_SIZE_OFFSET) as u64 | (size_high as u64) << 32,
+            link_count: read_u16(&raw, INODE_LINK_COUNT_OFFSET),
+            sectors: read_u32(&raw, INODE_SECTORS_OFFSET),
+            flags: read_u32(&raw, INODE_FLAGS_OFFSET),
+            blocks,
+            extended_attribute_block: read_u32(&raw, INODE_EXTENDED_ATTRIBUTES_OFFSET)
+        })
+    }
+
+    fn store_inode(&self, number: u32, data: &InodeData) -> Result<(), VfsError> {
+        let offset = self.inode_offset(number)?;
+        let mut raw = [0; GOOD_OLD_INODE_SIZE as usize];
+        self.read_bytes(offset, &mut raw)?;
+        write_u16(&mut raw, INODE_MODE_OFFSET, data.mode);
+        write_u16(&mut raw, INODE_UID_OFFSET, data.uid as u16);
+        write_u16(&mut raw, INODE_UID_HIGH_OFFSET, (data.uid >> 16) as u16);
+        write_u16(&mut raw, INODE_GID_OFFSET, data.gid as u16);
+        write_u16(&mut raw, INODE_GID_HIGH_OFFSET, (data.gid >> 16) as u16);
+        write_u32(&mut raw, INODE_SIZE_OFFSET, data.size as u32);
+        if data.is_regular() {
+            write_u32(&mut raw, INODE_SIZE_HIGH_OFFSET, (data.size >> 32) as u32);
+        }
+        write_u16(&mut raw, INODE_LINK_COUNT_OFFSET, data.link_count);
+        write_u32(&mut raw, INODE_SECTORS_OFFSET, data.sectors);
+        write_u32(&mut raw, INODE_FLAGS_OFFSET, data.flags);
+        for (i, &block) in data.blocks.iter().enumerate() {
+            write_u32(&mut raw, INODE_BLOCKS_OFFSET + i * 4, block);
+        }
+        self.write_bytes(offset, &raw)
+    }
+
+    /// Adds `delta` to the free count at `group_field` of a group's descriptor and at `superblock_field` of
+    /// the superblock.
+    fn adjust_free_count(
+        &self,
+        group: u32,
+        group_field: u64,
+        superblock_field: u64,
+        delta: i32
+    ) -> Result<(), VfsError> {
+        let group_offset = self.group_descriptor_offset(group) + group_field;
+        let group_count = self.read_u16_at(group_offset)?;
+        self.write_u16_at(group_offset, group_count.wrapping_add_signed(delta as i16))?;
+        let superblock_offset = SUPERBLOCK_OFFSET + superblock_field;
+        let superblock_count = self.read_u32_at(superblock_offset)?;
+        self.write_u32_at(superblock_offset, superblock_count.wrapping_add_signed(delta))
+    }
+
+    /// Finds a clear bit among the first `bits` of the bitmap of a group, at or after `first_bit`, sets it
+    /// and returns its index.
+    fn set_free_bit(&self, bitmap_block: u32, first_bit: u32, bits: u32) -> Result<Option<u32>, VfsError> {
+        let mut bitmap = vec![0; (bits as usize).div_ceil(8)];
+        let offset = self.block_offset(bitmap_block);
+        self.read_bytes(offset, &mut bitmap)?;
+        let Some(bit) = (first_bit..bits).find(|&bit| bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0)
+        else {
+            return Ok(None);
+        };
+        let byte = bitmap[bit as usize / 8] | 1 << (bit % 8);
+        self.write_bytes(offset + bit as u64 / 8, &[byte])?;
+        Ok(Some(bit))
+    }
+
+    fn clear_bit(&self, bitmap_block: u32, bit: u32) -> Result<(), VfsError> {
+        let offset = self.block_offset(bitmap_block) + bit as u64 / 8;
+        let mut byte = [0];
+        self.read_bytes(offset, &mut byte)?;
+        if byte[0] & (1 << (bit % 8)) == 0 {
+            return Err(VfsError::Corrupted);
+        }
+        self.write_bytes(offset, &[byte[0] & !(1 << (bit % 8))])
+    }
+
+    /// Allocates a zeroed block, preferably in `goal_group` to keep the blocks of a file close to its inode.
+    fn allocate_block(&self, goal_group: u32) -> Result<u32, VfsError> {
+        let _allocation = self.allocation.lock();
+        for group in (goal_group..self.group_count).chain(0..goal_group) {
+            let descriptor = self.group_descriptor_offset(group);
+            if self.read_u16_at(descriptor + GROUP_FREE_BLOCKS_OFFSET)? == 0 {
+                continue;
+            }
+            let bitmap = self.read_u32_at(descriptor + GROUP_BLOCK_BITMAP_OFFSET)?;
+            let Some(bit) = self.set_free_bit(bitmap, 0, self.blocks_in_group(group))?
+            else {
+                continue;
+            };
+            self.adjust_free_count(group, GROUP_FREE_BLOCKS_OFFSET, SUPERBLOCK_FREE_BLOCKS_OFFSET, -1)?;
+            let block = self.first_data_block + group * self.blocks_per_group + bit;
+            self.write_bytes(self.block_offset(block), &vec![0; self.block_size as usize])?;
+            return Ok(block);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), VfsError> {
+        if !(self.first_data_block..self.blocks_count).contains(&block) {
+            return Err(VfsError::Corrupted);
+        }
+        let _allocation = self.allocation.lock();
+        let group = (block - self.first_data_block) / self.blocks_per_group;
+        let descriptor = self.group_descriptor_offset(group);
+        let bitmap = self.read_u32_at(descriptor + GROUP_BLOCK_BITMAP_OFFSET)?;
+        self.clear_bit(bitmap, (block - self.first_data_block) % self.blocks_per_group)?;
+        self.adjust_free_count(group, GROUP_FREE_BLOCKS_OFFSET, SUPERBLOCK_FREE_BLOCKS_OFFSET, 1)
+    }
+
+    /// Allocates an inode, preferably in `goal_group`, and clears it on the device.
+    fn allocate_inode(&self, goal_group: u32, directory: bool) -> Result<u32, VfsError> {
+        let _allocation = self.allocation.lock();
+        for group in (goal_group..self.group_count).chain(0..goal_group) {
+            let descriptor = self.group_descriptor_offset(group);
+            if self.read_u16_at(descriptor + GROUP_FREE_INODES_OFFSET)? == 0 {
+                continue;
+            }
+            // The reserved inodes are all in the first group
+            let first_bit = (self.first_inode - 1).saturating_sub(group * self.inodes_per_group);
+            let bits = (self.inodes_count - group * self.inodes_per_group).min(self.inodes_per_group);
+            let bitmap = self.read_u32_at(descriptor + GROUP_INODE_BITMAP_OFFSET)?;
+            let Some(bit) = self.set_free_bit(bitmap, first_bit, bits)?
+            else {
+                continue;
+            };
+            self.adjust_free_count(group, GROUP_FREE_INODES_OFFSET, SUPERBLOCK_FREE_INODES_OFFSET, -1)?;
+            if directory {
+                let directories = self.read_u16_at(descriptor + GROUP_DIRECTORIES_OFFSET)?;
+                self.write_u16_at(descriptor + GROUP_DIRECTORIES_OFFSET, directories.wrapping_add(1))?;
+            }
+            let number = group * self.inodes_per_group + bit + 1;
+            self.write_bytes(self.inode_offset(number)?, &vec![0; self.inode_size as usize])?;
+            return Ok(number);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_inode(&self, number: u32, directory: bool) -> Result<(), VfsError> {
+        let _allocation = self.allocation.lock();
+        let group = self.inode_group(number);
+        let descriptor = self.group_descriptor_offset(group);
+        let bitmap = self.read_u32_at(descriptor + GROUP_INODE_BITMAP_OFFSET)?;
+        self.clear_bit(bitmap, (number - 1) % self.inodes_per_group)?;
+        self.adjust_free_count(group, GROUP_FREE_INODES_OFFSET, SUPERBLOCK_FREE_INODES_OFFSET, 1)?;
+        if directory {
+            let directories = self.read_u16_at(descriptor + GROUP_DIRECTORIES_OFFSET)?;
+            self.write_u16_at(descriptor + GROUP_DIRECTORIES_OFFSET, directories.wrapping_sub(1))?;
+        }
+        Ok(())
+    }
+
+    /// The largest a file can be, limited by how many blocks the inode can point to and, without the large
+    /// file feature, by the 32 bit size field.
+    fn max_file_size(&self, data: &InodeData) -> u64 {
+        let pointers = self.pointers_per_block();
+        let blocks = DIRECT_BLOCKS as u64 + pointers + pointers.pow(2) + pointers.pow(3);
+        let max_size = blocks * self.block_size;
+        match data.is_regular() && self.large_files {
+            true => max_size,
+            false => max_size.min(u32::MAX as u64)
+        }
+    }
+
+    /// The block pointer of the inode which the `index`th block of a file is under, how deep the tree of
+    /// indirect blocks under it is, and the index of the block within that tree.
+    fn block_slot(&self, index: u64) -> Result<(usize, u32, u64), VfsError> {
+        let pointers = self.pointers_per_block();
+        let mut index = index;
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, 0, 0));
+        }
+        index -= DIRECT_BLOCKS as u64;
+        for depth in 1..=3 {
+            if index < pointers.pow(depth) {
+                return Ok((DIRECT_BLOCKS + depth as usize - 1, depth, index));
+            }
+            index -= pointers.pow(depth);
+        }
+        Err(VfsError::FileTooLarge)
+    }
+
+    /// The block holding the `index`th block of the file with inode `number`. Missing blocks, including
+    /// indirect ones on the way, are allocated if `allocate` is set, and are holes reading as zeroes
+    /// otherwise.
+    fn map_block(
+        &self,
+        number: u32,
+        data: &mut InodeData,
+        index: u64,
+        allocate: bool
+    ) -> Result<Option<u32>, VfsError> {
+        let (slot, depth, index) = self.block_slot(index)?;
+        let goal_group = self.inode_group(number);
+        let mut block = data.blocks[slot];
+        if block == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            block = self.allocate_block(goal_group)?;
+            data.blocks[slot] = block;
+            data.sectors += self.sectors_per_block();
+        }
+        let pointers = self.pointers_per_block();
+        for level in (0..depth).rev() {
+            if block >= self.blocks_count {
+                return Err(VfsError::Corrupted);
+            }
+            let pointer_offset = self.block_offset(block) + index / pointers.pow(level) % pointers * 4;
+            let mut next = self.read_u32_at(pointer_offset)?;
+            if next == 0 {
+                if !allocate {
+                    return Ok(None);
+                }
+                next = self.allocate_block(goal_group)?;
+                self.write_u32_at(pointer_offset, next)?;
+                data.sectors += self.sectors_per_block();
+            }
+            block = next;
+        }
+        match block < self.blocks_count {
+            true => Ok(Some(block)),
+            false => Err(VfsError::Corrupted)
+        }
+    }
+
+    /// Frees the blocks under `block`, which is a data block at depth 0 and an indirect block otherwise,
+    /// except for those holding the first `keep` blocks of data under it. Returns whether `block` itself was
+    /// freed.
+    fn free_tree(&self, data: &mut InodeData, block: u32, depth: u32, keep: u64) -> Result<bool, VfsError> {
+        if depth > 0 {
+            let pointers = self.pointers_per_block();
+            let per_pointer = pointers.pow(depth - 1);
+            // Pointers before the first one are to trees which are kept whole
+            for pointer in keep / per_pointer..pointers {
+                let pointer_offset = self.block_offset(block) + pointer * 4;
+                let child = self.read_u32_at(pointer_offset)?;
+                if child == 0 {
+                    continue;
+                }
+                let child_keep = keep.saturating_sub(pointer * per_pointer);
+                if self.free_tree(data, child, depth - 1, child_keep)? {
+                    self.write_u32_at(pointer_offset, 0)?;
+                }
+            }
+        }
+        if keep > 0 {
+            return Ok(false);
+        }
+        self.free_block(block)?;
+        data.sectors = data.sectors.saturating_sub(self.sectors_per_block());
+        Ok(true)
+    }
+
+    /// Frees every block of a file past the first `keep` ones.
+    fn free_blocks_past(&self, data: &mut InodeData, keep: u64) -> Result<(), VfsError> {
+        let pointers = self.pointers_per_block();
+        let mut first_index = 0;
+        for slot in 0..BLOCK_POINTERS {
+            let depth = slot.saturating_sub(DIRECT_BLOCKS - 1) as u32;
+            let capacity = pointers.pow(depth);
+            let block = data.blocks[slot];
+            if block != 0
+                && keep < first_index + capacity
+                && self.free_tree(data, block, depth, keep.saturating_sub(first_index))?
+            {
+                data.blocks[slot] = 0;
+            }
+            first_index += capacity;
+        }
+        Ok(())
+    }
+
+    fn read_file(
+        &self,
+        number: u32,
+        data: &InodeData,
+        offset: u64,
+        buffer: &mut [u8]
+    ) -> Result<usize, VfsError> {
+        if offset >= data.size {
+            return Ok(0);
+        }
+        let length = buffer.len().min((data.size - offset) as usize);
+        let mut data = *data;
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let in_block = (position % self.block_size) as usize;
+            let chunk = (self.block_size as usize - in_block).min(length - done);
+            match self.map_block(number, &mut data, position / self.block_size, false)? {
+                Some(block) => {
+                    let block_offset = self.block_offset(block) + in_block as u64;
+                    self.read_bytes(block_offset, &mut buffer[done..done + chunk])?;
+                },
+                None => buffer[done..done + chunk].fill(0)
+            }
+            done += chunk;
+        }
+        Ok(length)
+    }
+
+    /// Writes into a file, allocating blocks as needed, and stores the inode with its new size and blocks.
+    fn write_file(
+        &self,
+        number: u32,
+        data: &mut InodeData,
+        offset: u64,
+        bytes: &[u8]
+    ) -> Result<(), VfsError> {
+        let end = offset
+            .checked_add(bytes.len() as u64)
+            .filter(|&end| end <= self.max_file_size(data))
+            .ok_or(VfsError::FileTooLarge)?;
+        let mut result = Ok(());
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = offset + done as u64;
+            let in_block = (position % self.block_size) as usize;
+            let chunk = (self.block_size as usize - in_block).min(bytes.len() - done);
+            result = self
+                .map_block(number, data, position / self.block_size, true)
+                .and_then(|block| {
+                    let block_offset = self.block_offset(block.unwrap()) + in_block as u64;
+                    self.write_bytes(block_offset, &bytes[done..done + chunk])
+                });
+            if result.is_err() {
+                break;
+            }
+            done += chunk;
+        }
+        // Blocks allocated before a failure stay with the file
+        if result.is_ok() {
+            data.size = data.size.max(end);
+        }
+        self.store_inode(number, data)?;
+        result
+    }
+
+    fn truncate_file(&self, number: u32, data: &mut InodeData, size: u64) -> Result<(), VfsError> {
+        if size > self.max_file_size(data) {
+            return Err(VfsError::FileTooLarge);
+        }
+        if size < data.size {
+            self.free_blocks_past(data, size.div_ceil(self.block_size))?;
+            // The rest of the last block has to read as zeroes if the file grows again
+            let in_block = (size % self.block_size) as usize;
+            if in_block != 0 {
+                if let Some(block) = self.map_block(number, data, size / self.block_size, false)? {
+                    let zeroes = vec![0; self.block_size as usize - in_block];
+                    self.write_bytes(self.block_offset(block) + in_block as u64, &zeroes)?;
+                }
+            }
+        }
+        data.size = size;
+        self.store_inode(number, data)
+    }
+
+    /// Calls `f` with every record of a directory, including unused ones, along with the offset of the record
+    /// before it in the same block, until it returns a value.
+    fn find_record<T>(
+        &self,
+        number: u32,
+        directory: &InodeData,
+        mut f: impl FnMut(&Record, Option<u64>) -> Result<Option<T>, VfsError>
+    ) -> Result<Option<T>, VfsError> {
+        let mut data = *directory;
+        let mut buffer = vec![0; self.block_size as usize];
+        for index in 0..directory.size / self.block_size {
+            let block = self
+                .map_block(number, &mut data, index, false)?
+                .ok_or(VfsError::Corrupted)?;
+            let block_offset = self.block_offset(block);
+            self.read_bytes(block_offset, &mut buffer)?;
+            let mut position = 0;
+            let mut previous = None;
+            while position < buffer.len() {
+                if position + RECORD_HEADER_SIZE > buffer.len() {
+                    return Err(VfsError::Corrupted);
+                }
+                let length = read_u16(&buffer, position + 4);
+                let name_length = buffer[position + 6] as usize;
+                if (length as usize) < record_length(name_length)
+                    || length % 4 != 0
+                    || position + length as usize > buffer.len()
+                {
+                    return Err(VfsError::Corrupted);
+                }
+                let record = Record {
+                    offset: block_offset + position as u64,
+                    inode: read_u32(&buffer, position),
+                    length,
+                    file_type: match self.has_record_types {
+                        true => buffer[position + 7],
+                        false => 0
+                    },
+                    name: &buffer[position + RECORD_HEADER_SIZE..position + RECORD_HEADER_SIZE + name_length]
+                };
+                if let Some(value) = f(&record, previous)? {
+                    return Ok(Some(value));
+                }
+                previous = Some(record.offset);
+                position += length as usize;
+            }
+        }
+        Ok(None)
+    }
+
+    /// The inode number of an entry of a directory.
+    fn lookup(&self, number: u32, directory: &InodeData, name: &str) -> Result<Option<u32>, VfsError> {
+        self.find_record(number, directory, |record, _| {
+            Ok((record.inode != 0 && record.name == name.as_bytes()).then_some(record.inode))
+        })
+    }
+
+    fn is_directory_empty(&self, number: u32, directory: &InodeData) -> Result<bool, VfsError> {
+        let entry = self.find_record(number, directory, |record, _| {
+            Ok((record.inode != 0 && record.name != b"." && record.name != b"..").then_some(()))
+        })?;
+        Ok(entry.is_none())
+    }
+
+    fn write_record(
+        &self,
+        offset: u64,
+        inode: u32,
+        length: u16,
+        name: &str,
+        file_type: FileType
+    ) -> Result<(), VfsError> {
+        let mut record = [0; RECORD_HEADER_SIZE + vfs::MAX_NAME_LENGTH];
+        write_u32(&mut record, 0, inode);
+        write_u16(&mut record, 4, length);
+        record[6] = name.len() as u8;
+        if self.has_record_types {
+            record[7] = record_type(file_type);
+        }
+        record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+        self.write_bytes(offset, &record[..RECORD_HEADER_SIZE + name.len()])
+    }
+
+    /// Adds an entry to a directory, in the free space at the end of a record or in an unused record if there
+    /// is one large enough, and in a new block otherwise.
+    fn add_record(
+        &self,
+        number: u32,
+        directory: &mut InodeData,
+        name: &str,
+        inode: u32,
+        file_type: FileType
+    ) -> Result<(), VfsError> {
+        let needed = record_length(name.len()) as u16;
+        let space = self.find_record(number, directory, |record, _| {
+            if record.inode == 0 && record.length >= needed {
+                return Ok(Some((record.offset, record.length, None)));
+            }
+            let used = record_length(record.name.len()) as u16;
+            match record.inode != 0 && record.length - used >= needed {
+                true => Ok(Some((
+                    record.offset + used as u64,
+                    record.length - used,
+                    Some((record.offset, used))
+                ))),
+                false => Ok(None)
+            }
+        })?;
+        match space {
+            Some((offset, length, shortened)) => {
+                if let Some((record_offset, used)) = shortened {
+                    self.write_u16_at(record_offset + 4, used)?;
+                }
+                self.write_record(offset, inode, length, name, file_type)?;
+            },
+            None => {
+                let index = directory.size / self.block_size;
+                let block = self.map_block(number, directory, index, true)?.unwrap();
+                directory.size += self.block_size;
+                self.write_record(
+                    self.block_offset(block),
+                    inode,
+                    self.block_size as u16,
+                    name,
+                    file_type
+                )?;
+            }
+        }
+        directory.flags &= !INODE_FLAG_INDEX;
+        self.store_inode(number, directory)
+    }
+
+    /// Removes an entry from a directory, by merging its record into the one before it, or marking it as
+    /// unused if it's the first of its block.
+    fn remove_record(&self, number: u32, directory: &mut InodeData, name: &str) -> Result<(), VfsError> {
+        let found = self.find_record(number, directory, |record, previous| {
+            Ok((record.inode != 0 && record.name == name.as_bytes()).then_some((
+                record.offset,
+                record.length,
+                previous
+            )))
+        })?;
+        match found.ok_or(VfsError::NotFound)? {
+            (_, length, Some(previous)) => {
+                let previous_length = self.read_u16_at(previous + 4)?;
+                self.write_u16_at(previous + 4, previous_length + length)?;
+            },
+            (offset, _, None) => self.write_u32_at(offset, 0)?
+        }
+        directory.flags &= !INODE_FLAG_INDEX;
+        self.store_inode(number, directory)
+    }
+
+    /// Allocates and stores a new inode, along with the block holding "." and ".." for directories.
+    fn new_inode(&self, parent: u32, data: &mut InodeData) -> Result<u32, VfsError> {
+        let directory = data.is_directory();
+        let number = self.allocate_inode(self.inode_group(parent), directory)?;
+        if directory {
+            data.link_count = 2;
+            let result = self.map_block(number, data, 0, true).and_then(|block| {
+                let block_offset = self.block_offset(block.unwrap());
+                let dot_length = record_length(1) as u16;
+                self.write_record(block_offset, number, dot_length, ".", FileType::Directory)?;
+                let dot_dot_length = self.block_size as u16 - dot_length;
+                self.write_record(
+                    block_offset + dot_length as u64,
+                    parent,
+                    dot_dot_length,
+                    "..",
+                    FileType::Directory
+                )
+            });
+            if let Err(e) = result {
+                self.free_blocks_past(data, 0)?;
+                self.free_inode(number, true)?;
+                return Err(e);
+            }
+            data.size = self.block_size;
+        }
+        self.store_inode(number, data)?;
+        Ok(number)
+    }
+
+    /// Symbolic links to short paths have their path in the block pointers. Extended attributes take up a
+    /// block without being data.
+    fn is_fast_symlink(&self, data: &InodeData) -> bool {
+        let attribute_sectors = match data.extended_attribute_block {
+            0 => 0,
+            _ => self.sectors_per_block()
+        };
+        data.file_type() == FileType::Symlink && data.sectors == attribute_sectors
+    }
+
+    /// Frees the blocks and the inode of a file without links. Extended attribute blocks are left alone, as
+    /// they may be shared.
+    fn release(&self, number: u32, data: &mut InodeData) -> Result<(), VfsError> {
+        if !self.is_fast_symlink(data) {
+            self.free_blocks_past(data, 0)?;
+        }
+        data.size = 0;
+        self.store_inode(number, data)?;
+        self.free_inode(number, data.is_directory())
+    }
+}
+
+struct Ext2Inode {
+    volume: Arc<Ext2Volume>,
+    number: u32,
+    data: Mutex<InodeData>
+}
+
+impl Ext2Inode {
+    /// Creates a new inode in this directory and an entry for it named `name`.
+    fn create_entry(
+        &self,
+        name: &str,
+        mode: u16,
+        contents: impl FnOnce(&Ext2Inode, &mut InodeData) -> Result<(), VfsError>
+    ) -> Result<Arc<Ext2Inode>, VfsError> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+        if name.len() > vfs::MAX_NAME_LENGTH {
+            return Err(VfsError::NameTooLong);
+        }
+        let mut directory = self.data.lock();
+        if !directory.is_directory() {
+            return Err(VfsError::NotADirectory);
+        }
+        // The directory was removed while someone still had it
+        if directory.link_count == 0 {
+            return Err(VfsError::NotFound);
+        }
+        if volume.lookup(self.number, &directory, name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        let mut data = InodeData::new(mode);
+        let number = volume.new_inode(self.number, &mut data)?;
+        // The new inode is freed when it's dropped if something goes wrong from here on
+        let inode = volume.inode(number)?;
+        let mut data = inode.data.lock();
+        let result = contents(&inode, &mut data)
+            .and_then(|()| volume.add_record(self.number, &mut directory, name, number, data.file_type()));
+        if let Err(e) = result {
+            data.link_count = 0;
+            return Err(e);
+        }
+        if data.is_directory() {
+            directory.link_count += 1;
+            volume.store_inode(self.number, &directory)?;
+        }
+        drop(data);
+        Ok(inode)
+    }
+}
+
+impl Drop for Ext2Inode {
+    /// Files which were removed while still in use are only freed once nobody has them anymore.
+    fn drop(&mut self) {
+        let data = self.data.get_mut();
+        if data.link_count == 0 && !self.volume.read_only {
+            let _ = self.volume.release(self.number, data);
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        let data = self.data.lock();
+        Ok(Stat {
+            inode: self.number as u64,
+            device: 0,
+            file_type: data.file_type(),
+            mode: data.mode & MODE_PERMISSIONS_MASK,
+            link_count: data.link_count as u32,
+            uid: data.uid,
+            gid: data.gid,
+            size: data.size,
+            block_size: self.volume.block_size as u32,
+            blocks: data.sectors as u64
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let data = self.data.lock();
+        if !data.is_directory() {
+            return Err(VfsError::NotADirectory);
+        }
+        let number = self
+            .volume
+            .lookup(self.number, &data, name)?
+            .ok_or(VfsError::NotFound)?;
+        self.volume.inode(number).map(|inode| inode as Arc<dyn Inode>)
+    }
+
+    fn read_directory(
+        &self,
+        start: usize,
+        f: &mut dyn FnMut(vfs::DirectoryEntry) -> bool
+    ) -> Result<(), VfsError> {
+        let data = self.data.lock();
+        if !data.is_directory() {
+            return Err(VfsError::NotADirectory);
+        }
+        let volume = &self.volume;
+        let mut index = 0;
+        volume.find_record(self.number, &data, |record, _| {
+            if record.inode == 0 || record.name == b"." || record.name == b".." {
+                return Ok(None);
+            }
+            index += 1;
+            if index <= start {
+                return Ok(None);
+            }
+            let file_type = match record.file_type {
+                RECORD_TYPE_REGULAR => FileType::Regular,
+                RECORD_TYPE_DIRECTORY => FileType::Directory,
+                RECORD_TYPE_CHARACTER_DEVICE => FileType::CharacterDevice,
+                RECORD_TYPE_BLOCK_DEVICE => FileType::BlockDevice,
+                RECORD_TYPE_FIFO => FileType::Fifo,
+                RECORD_TYPE_SYMLINK => FileType::Symlink,
+                // Without types in the records, the type is only in the inode
+                _ => volume.load_inode(record.inode)?.file_type()
+            };
+            let entry = vfs::DirectoryEntry {
+                name: String::from_utf8_lossy(record.name).into_owned(),
+                inode: record.inode as u64,
+                file_type
+            };
+            Ok((!f(entry)).then_some(()))
+        })?;
+        Ok(())
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u16) -> Result<Arc<dyn Inode>, VfsError> {
+        let type_bits = match file_type {
+            FileType::Regular => MODE_REGULAR,
+            FileType::Directory => MODE_DIRECTORY,
+            FileType::Fifo => MODE_FIFO,
+            _ => return Err(VfsError::NotSupported)
+        };
+        let mode = type_bits | mode & MODE_PERMISSIONS_MASK;
+        self.create_entry(name, mode, |_, _| Ok(()))
+            .map(|inode| inode as Arc<dyn Inode>)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let inode = self.create_entry(name, MODE_SYMLINK | 0o777, |inode, data| {
+            if target.len() >= FAST_SYMLINK_MAX_LENGTH {
+                return inode.volume.write_file(inode.number, data, 0, target.as_bytes());
+            }
+            let mut bytes = [0; FAST_SYMLINK_MAX_LENGTH];
+            bytes[..target.len()].copy_from_slice(target.as_bytes());
+            for (i, block) in data.blocks.iter_mut().enumerate() {
+                *block = read_u32(&bytes, i * 4);
+            }
+            data.size = target.len() as u64;
+            inode.volume.store_inode(inode.number, data)
+        })?;
+        Ok(inode)
+    }
+
+    /// Directories have to be empty to be removed. Files which are still open keep their blocks until
+    /// they're closed.
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        volume.check_writable()?;
+        let mut directory = self.data.lock();
+        if !directory.is_directory() {
+            return Err(VfsError::NotADirectory);
+        }
+        let number = volume
+            .lookup(self.number, &directory, name)?
+            .ok_or(VfsError::NotFound)?;
+        let inode = volume.inode(number)?;
+        let mut data = inode.data.lock();
+        if data.is_directory() && !volume.is_directory_empty(number, &data)? {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+        volume.remove_record(self.number, &mut directory, name)?;
+        if data.is_directory() {
+            // Its ".." no longer links to the parent, and its "." goes away along with it
+            data.link_count = 0;
+            directory.link_count = directory.link_count.saturating_sub(1);
+            volume.store_inode(self.number, &directory)?;
+        }
+        else {
+            data.link_count = data.link_count.saturating_sub(1);
+        }
+        volume.store_inode(number, &data)
+    }
+
+    fn read_link(&self) -> Result<String, VfsError> {
+        let data = self.data.lock();
+        if data.file_type() != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+        let mut target = vec![0; data.size as usize];
+        if self.volume.is_fast_symlink(&data) {
+            let mut bytes = [0; FAST_SYMLINK_MAX_LENGTH];
+            for (i, &block) in data.blocks.iter().enumerate() {
+                write_u32(&mut bytes, i * 4, block);
+            }
+            let length = target.len().min(FAST_SYMLINK_MAX_LENGTH);
+            target[..length].copy_from_slice(&bytes[..length]);
+        }
+        else {
+            self.volume.read_file(self.number, &data, 0, &mut target)?;
+        }
+        String::from_utf8(target).map_err(|_| VfsError::Corrupted)
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let data = self.data.lock();
+        if data.is_directory() {
+            return Err(VfsError::IsADirectory);
+        }
+        self.volume.read_file(self.number, &data, offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, bytes: &[u8]) -> Result<usize, VfsError> {
+        self.volume.check_writable()?;
+        let mut data = self.data.lock();
+        if data.is_directory() {
+            return Err(VfsError::IsADirectory);
+        }
+        self.volume.write_file(self.number, &mut data, offset, bytes)?;
+        Ok(bytes.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        self.volume.check_writable()?;
+        let mut data = self.data.lock();
+        if data.is_directory() {
+            return Err(VfsError::IsADirectory);
+        }
+        self.volume.truncate_file(self.number, &mut data, size)
+    }
+}
+
+/// The root directory is kept loaded for as long as the file system is mounted.
+struct Ext2Mount {
+    volume: Arc<Ext2Volume>,
+    root: Arc<Ext2Inode>
+}
+
+impl FileSystem for Ext2Mount {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn usage(&self) -> Option<FileSystemUsage> {
+        let volume = &self.volume;
+        let free_blocks = volume
+            .read_u32_at(SUPERBLOCK_OFFSET + SUPERBLOCK_FREE_BLOCKS_OFFSET)
+            .ok()?;
+        Some(FileSystemUsage {
+            used_bytes: volume.blocks_count.saturating_sub(free_blocks) as u64 * volume.block_size,
+            total_bytes: Some(volume.blocks_count as u64 * volume.block_size)
+        })
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        buffer_cache::sync(self.volume.device).map_err(|_| VfsError::DeviceError)
+    }
+}
+
+/// Mounts the file system on `device`, read-only with the "ro" option.
+fn mount(device: Option<&'static dyn BlockDevice>, options: &str) -> Result<Arc<dyn FileSystem>, VfsError> {
+    let device = device.ok_or(VfsError::InvalidArgument)?;
+    let mut read_only = false;
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        match option {
+            "ro" => read_only = true,
+            "rw" => read_only = false,
+            _ => return Err(VfsError::InvalidArgument)
+        }
+    }
+    let volume = Arc::new(Ext2Volume::new(device, read_only)?);
+    let root = volume.inode(ROOT_INODE)?;
+    if !root.data.lock().is_directory() {
+        return Err(VfsError::Corrupted);
+    }
+    Ok(Arc::new(Ext2Mount { volume, root }))
+}
+
+pub static EXT2_FILE_SYSTEM: FileSystemType = FileSystemType { name: "ext2", mount };
@@ -0,0 +1,1421 @@
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::sync::{Arc, Weak};
+
+use arrayvec::{ArrayString, ArrayVec};
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::block::{BlockDevice, BlockError};
+use crate::buffer_cache;
+use crate::vfs::{self, FileSystem, FileSystemType, FileSystemUsage, FileType, Inode, Stat, VfsError};
+
+/// Names are limited to this many bytes of UTF-8. Long names which don't fit are shown by their short name.
+pub const MAX_NAME_LENGTH: usize = 255;
+/// Long names are at most 255 UTF-16 code units, spread over entries of 13 code units each
+const MAX_LONG_NAME_UNITS: usize = 255;
+const LONG_NAME_UNITS_PER_SLOT: usize = 13;
+const MAX_LONG_NAME_SLOTS: usize = MAX_LONG_NAME_UNITS.div_ceil(LONG_NAME_UNITS_PER_SLOT);
+/// The largest a file can be, as the size field is 32 bits
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+const SLOT_SIZE: u64 = 32;
+const BOOT_SIGNATURE_OFFSET: u64 = 510;
+const BOOT_SIGNATURE: u16 = 0xaa55;
+
+/// File systems with fewer clusters than these are FAT12 or FAT16, regardless of what the boot sector says
+const FAT12_MAX_CLUSTERS: u32 = 4084;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+/// The first cluster of the data region. Cluster numbers 0 and 1 are reserved.
+const FIRST_CLUSTER: u32 = 2;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FS_INFO_STRUCTURE_SIGNATURE: u32 = 0x61417272;
+const FS_INFO_STRUCTURE_SIGNATURE_OFFSET: u64 = 484;
+/// The free cluster count, followed by the next free cluster hint
+const FS_INFO_FREE_COUNT_OFFSET: u64 = 488;
+/// Written into the FSInfo fields when their value isn't known
+const FS_INFO_UNKNOWN: u32 = u32::MAX;
+
+/// Set in the extended flags of FAT32 file systems on which only one FAT, the active one, is in use
+const EXTENDED_FLAGS_MIRRORING_DISABLED: u16 = 1 << 7;
+
+const SLOT_END_OF_DIRECTORY: u8 = 0x00;
+const SLOT_DELETED: u8 = 0xe5;
+/// Stands for 0xe5 as the first character of a short name, which would otherwise mark the slot as deleted
+const SLOT_KANJI_E5: u8 = 0x05;
+const LONG_NAME_ATTRIBUTES: u8 = 0x0f;
+const LONG_NAME_LAST_SLOT: u8 = 0x40;
+const LONG_NAME_ORDER_MASK: u8 = 0x1f;
+/// Where the 13 code units of a long name slot are
+const LONG_NAME_UNIT_OFFSETS: [usize; LONG_NAME_UNITS_PER_SLOT] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Flags in the reserved byte of short entries, with which short names are shown in lowercase
+const SHORT_NAME_LOWERCASE_BASE: u8 = 0x08;
+const SHORT_NAME_LOWERCASE_EXTENSION: u8 = 0x10;
+const DOT_NAME: &[u8; 11] = b".          ";
+const DOT_DOT_NAME: &[u8; 11] = b"..         ";
+/// Characters allowed in short names apart from uppercase letters, digits and anything above 0x7f
+const SHORT_NAME_SPECIAL_CHARACTERS: &[u8] = b"$%'-_@~`!(){}^#&";
+/// Characters which aren't allowed in long names either, apart from control characters
+const INVALID_NAME_CHARACTERS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// There's no clock to get the time from yet, so every timestamp is midnight on 1980-01-01, the earliest date
+/// FAT can store. Dates are `(year - 1980) << 9 | month << 5 | day`.
+const TIMESTAMP_DATE: u16 = 1 << 5 | 1;
+const TIMESTAMP_TIME: u16 = 0;
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum FatError {
+    /// The device doesn't have a FAT file system on it
+    NotFat,
+    /// Something on the device doesn't make sense, e.g. a cluster chain pointing outside of the device
+    Corrupted,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidName,
+    /// There are no free clusters left
+    NoSpace,
+    /// The root directory of FAT12 and FAT16 file systems has a fixed number of entries, all of which are
+    /// used
+    DirectoryFull,
+    FileTooLarge,
+    DeviceError
+}
+
+impl From<BlockError> for FatError {
+    fn from(_: BlockError) -> Self {
+        FatError::DeviceError
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct FileAttributes: u8 {
+        const READ_ONLY = 1 << 0;
+        const HIDDEN = 1 << 1;
+        const SYSTEM = 1 << 2;
+        const VOLUME_ID = 1 << 3;
+        const DIRECTORY = 1 << 4;
+        const ARCHIVE = 1 << 5;
+    }
+}
+
+/// A file or directory, as found in its parent directory.
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    name: ArrayString<MAX_NAME_LENGTH>,
+    attributes: FileAttributes,
+    size: u32,
+    /// Zero for empty files, and for the root directory of FAT12 and FAT16 file systems
+    first_cluster: u32,
+    /// The first cluster of the directory the entry is in
+    parent_cluster: u32,
+    /// Where the short entry is on the device, or `None` for the root directory, which has no entry
+    offset: Option<u64>,
+    /// How many slots the entry takes up, including the ones holding its long name
+    slot_count: u8
+}
+
+impl DirectoryEntry {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn attributes(&self) -> FileAttributes {
+        self.attributes
+    }
+
+    #[inline]
+    pub fn is_directory(&self) -> bool {
+        self.attributes.contains(FileAttributes::DIRECTORY)
+    }
+
+    /// The size of the file in bytes. Always zero for directories.
+    #[inline]
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The first cluster of the file, which stays the same for as long as the file isn't empty and can
+    /// be used to tell files apart.
+    #[inline]
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    /// A number identifying the entry among the others on the file system, which is derived from where its
+    /// short entry is as that doesn't change. The root directory is 1.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.offset.map_or(1, |offset| offset / SLOT_SIZE + 2)
+    }
+}
+
+#[inline]
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+#[inline]
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The checksum of a short name, which the long name slots belonging to it hold.
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn is_valid_short_name_character(character: u8) -> bool {
+    character.is_ascii_uppercase()
+        || character.is_ascii_digit()
+        || character > 0x7f
+        || SHORT_NAME_SPECIAL_CHARACTERS.contains(&character)
+}
+
+/// Turns a short name as stored (space padded, without the dot) into a displayable name.
+fn format_short_name(short_name: &[u8; 11], lowercase_flags: u8) -> ArrayString<MAX_NAME_LENGTH> {
+    let mut name = ArrayString::new();
+    let push_part = |name: &mut ArrayString<MAX_NAME_LENGTH>, part: &[u8], lowercase: bool| {
+        for (i, &byte) in part.iter().enumerate() {
+            let byte = if i == 0 && byte == SLOT_KANJI_E5 { SLOT_DELETED } else { byte };
+            let character = match byte {
+                0..=0x7f if lowercase => byte.to_ascii_lowercase() as char,
+                0..=0x7f => byte as char,
+                // Which character it is depends on the code page, which isn't known
+                _ => char::REPLACEMENT_CHARACTER
+            };
+            name.push(character);
+        }
+    };
+    let base = short_name[..8].trim_ascii_end();
+    let extension = short_name[8..].trim_ascii_end();
+    push_part(&mut name, base, lowercase_flags & SHORT_NAME_LOWERCASE_BASE != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        push_part(
+            &mut name,
+            extension,
+            lowercase_flags & SHORT_NAME_LOWERCASE_EXTENSION != 0
+        );
+    }
+    name
+}
+
+/// The short name for `name` if it is a valid 8.3 name as is, in which case it doesn't need a long name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = match name.split_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (name, "")
+    };
+    let valid = |part: &str| {
+        part.bytes()
+            .all(|b| b.is_ascii() && is_valid_short_name_character(b))
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || !valid(base) || !valid(extension) {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short_name)
+}
+
+/// The short name for a file with a long name, made of the first characters of the long name along with a
+/// numeric tail such as "~1" to make it unique.
+fn generated_short_name(name: &str, tail: u32) -> [u8; 11] {
+    let to_short_character = |character: char| match character.to_ascii_uppercase() {
+        c if c.is_ascii() && is_valid_short_name_character(c as u8) => c as u8,
+        _ => b'_'
+    };
+    let (base, extension) = match name.trim_start_matches('.').rsplit_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (name.trim_start_matches('.'), "")
+    };
+    let mut short_name = [b' '; 11];
+    let mut tail_text = ArrayString::<8>::new();
+    let _ = core::fmt::write(&mut tail_text, format_args!("~{}", tail));
+    let base_length = 8 - tail_text.len();
+    let mut length = 0;
+    for character in base.chars().filter(|&c| c != ' ' && c != '.').take(base_length) {
+        short_name[length] = to_short_character(character);
+        length += 1;
+    }
+    short_name[length..length + tail_text.len()].copy_from_slice(tail_text.as_bytes());
+    for (i, character) in extension.chars().filter(|&c| c != ' ').take(3).enumerate() {
+        short_name[8 + i] = to_short_character(character);
+    }
+    short_name
+}
+
+fn validate_name(name: &str) -> Result<(), FatError> {
+    let invalid = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.len() > MAX_NAME_LENGTH
+        || name.encode_utf16().count() > MAX_LONG_NAME_UNITS
+        || name.ends_with(['.', ' '])
+        || name
+            .chars()
+            .any(|c| c.is_control() || INVALID_NAME_CHARACTERS.contains(&c));
+    match invalid {
+        true => Err(FatError::InvalidName),
+        false => Ok(())
+    }
+}
+
+/// Collects the long name slots preceding a short entry.
+struct LongNameState {
+    units: [u16; MAX_LONG_NAME_SLOTS * LONG_NAME_UNITS_PER_SLOT],
+    /// The number of slots, if the ones seen so far are a valid sequence
+    slot_count: Option<u8>,
+    /// The order number the next slot has to have
+    next_order: u8,
+    checksum: u8
+}
+
+impl LongNameState {
+    fn new() -> LongNameState {
+        LongNameState {
+            units: [0; MAX_LONG_NAME_SLOTS * LONG_NAME_UNITS_PER_SLOT],
+            slot_count: None,
+            next_order: 0,
+            checksum: 0
+        }
+    }
+
+    /// Slots come in reverse order, with the last part of the name first.
+    fn add(&mut self, slot: &[u8; SLOT_SIZE as usize]) {
+        let order = slot[0] & LONG_NAME_ORDER_MASK;
+        if slot[0] & LONG_NAME_LAST_SLOT != 0 {
+            self.slot_count = (1..=MAX_LONG_NAME_SLOTS as u8).contains(&order).then_some(order);
+            self.next_order = order;
+            self.checksum = slot[13];
+        }
+        if self.slot_count.is_none() || order != self.next_order || order == 0 || slot[13] != self.checksum {
+            self.slot_count = None;
+            return;
+        }
+        let start = (order as usize - 1) * LONG_NAME_UNITS_PER_SLOT;
+        for (i, &offset) in LONG_NAME_UNIT_OFFSETS.iter().enumerate() {
+            self.units[start + i] = read_u16(slot, offset);
+        }
+        self.next_order -= 1;
+    }
+
+    /// Returns the long name and the number of slots it takes up if the slots seen belong to the short
+    /// entry with `short_name`, and resets the state for the next entry.
+    fn take(&mut self, short_name: &[u8; 11]) -> Option<(ArrayString<MAX_NAME_LENGTH>, u8)> {
+        let slot_count = self.slot_count.take()?;
+        if self.next_order != 0 || self.checksum != short_name_checksum(short_name) {
+            return None;
+        }
+        let units = &self.units[..slot_count as usize * LONG_NAME_UNITS_PER_SLOT];
+        // The name ends with a zero unless it fills the last slot completely, followed by 0xffff padding
+        let length = units.iter().position(|&u| u == 0).unwrap_or(units.len());
+        let mut name = ArrayString::new();
+        for character in char::decode_utf16(units[..length].iter().copied()) {
+            name.try_push(character.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .ok()?;
+        }
+        Some((name, slot_count))
+    }
+}
+
+/// The state of cluster allocation, which is locked by every operation that modifies the file system.
+struct AllocationState {
+    /// The number of free clusters, if known
+    free_count: Option<u32>,
+    /// Where to start looking for a free cluster
+    next_free: u32
+}
+
+/// A FAT12, FAT16 or FAT32 file system on a block device. Everything goes through the buffer cache, so
+/// changes only reach the device once it's synced.
+pub struct FatFileSystem {
+    device: &'static dyn BlockDevice,
+    fat_type: FatType,
+    cluster_size: u64,
+    cluster_count: u32,
+    /// The byte offsets of the FATs, of which every one in use is kept the same
+    fat_offsets: ArrayVec<u64, 4>,
+    /// Where the root directory is on FAT12 and FAT16, which is outside of the data region
+    root_directory_offset: u64,
+    root_entry_count: u32,
+    /// The first cluster of the root directory on FAT32
+    root_cluster: u32,
+    data_offset: u64,
+    fs_info_offset: Option<u64>,
+    state: Mutex<AllocationState>
+}
+
+/// Goes through the slots of a directory, which is either a cluster chain or the fixed root directory of
+/// FAT12 and FAT16.
+struct SlotIterator<'a> {
+    file_system: &'a FatFileSystem,
+    /// Zero for the fixed root directory
+    cluster: u32,
+    /// The index of the next slot within the cluster or the fixed root directory
+    index: u64,
+    /// How many clusters have been gone through, to detect chains that loop
+    clusters_seen: u32
+}
+
+impl SlotIterator<'_> {
+    /// Returns the device offset of the next slot, or `None` at the end of the directory.
+    fn next(&mut self) -> Result<Option<u64>, FatError> {
+        let file_system = self.file_system;
+        if self.cluster == 0 {
+            if self.index >= file_system.root_entry_count as u64 {
+                return Ok(None);
+            }
+            self.index += 1;
+            return Ok(Some(
+                file_system.root_directory_offset + (self.index - 1) * SLOT_SIZE
+            ));
+        }
+        if !file_system.is_valid_cluster(self.cluster) {
+            return Err(FatError::Corrupted);
+        }
+        if self.index == file_system.cluster_size / SLOT_SIZE {
+            let Some(next) = file_system.next_cluster(self.cluster)?
+            else {
+                return Ok(None);
+            };
+            self.clusters_seen += 1;
+            if self.clusters_seen > file_system.cluster_count {
+                return Err(FatError::Corrupted);
+            }
+            self.cluster = next;
+            self.index = 0;
+        }
+        self.index += 1;
+        Ok(Some(
+            file_system.cluster_offset(self.cluster) + (self.index - 1) * SLOT_SIZE
+        ))
+    }
+}
+
+impl FatFileSystem {
+    /// Reads the boot sector of the file system on `device`, and the FSInfo structure on FAT32.
+    pub fn new(device: &'static dyn BlockDevice) -> Result<FatFileSystem, FatError> {
+        let mut boot_sector = [0; 90];
+        buffer_cache::read(device, 0, &mut boot_sector)?;
+        let mut signature = [0; 2];
+        buffer_cache::read(device, BOOT_SIGNATURE_OFFSET, &mut signature)?;
+        if u16::from_le_bytes(signature) != BOOT_SIGNATURE {
+            return Err(FatError::NotFat);
+        }
+
+        let bytes_per_sector = read_u16(&boot_sector, 11) as u64;
+        let sectors_per_cluster = boot_sector[13] as u64;
+        let reserved_sectors = read_u16(&boot_sector, 14) as u64;
+        let fat_count = boot_sector[16] as u64;
+        let root_entry_count = read_u16(&boot_sector, 17) as u32;
+        let total_sectors = match read_u16(&boot_sector, 19) {
+            0 => read_u32(&boot_sector, 32) as u64,
+            count => count as u64
+        };
+        let fat_sectors = match read_u16(&boot_sector, 22) {
+            0 => read_u32(&boot_sector, 36) as u64,
+            count => count as u64
+        };
+        if !(512..=4096).contains(&bytes_per_sector)
+            || !bytes_per_sector.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || !(1..=4).contains(&fat_count)
+            || fat_sectors == 0
+            || total_sectors * bytes_per_sector > device.size()
+        {
+            return Err(FatError::NotFat);
+        }
+
+        let root_directory_sectors = (root_entry_count as u64 * SLOT_SIZE).div_ceil(bytes_per_sector);
+        let first_data_sector = reserved_sectors + fat_count * fat_sectors + root_directory_sectors;
+        let data_sectors = total_sectors
+            .checked_sub(first_data_sector)
+            .ok_or(FatError::NotFat)?;
+        let cluster_count = (data_sectors / sectors_per_cluster) as u32;
+        let fat_type = match cluster_count {
+            0..=FAT12_MAX_CLUSTERS => FatType::Fat12,
+            _ if cluster_count <= FAT16_MAX_CLUSTERS => FatType::Fat16,
+            _ => FatType::Fat32
+        };
+        // The FAT has to have room for an entry for every cluster
+        let fat_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32
+        };
+        if (cluster_count as u64 + 2) * fat_bits > fat_sectors * bytes_per_sector * 8 {
+            return Err(FatError::Corrupted);
+        }
+
+        let fat_offset = |index: u64| (reserved_sectors + index * fat_sectors) * bytes_per_sector;
+        let mut fat_offsets: ArrayVec<u64, 4> = (0..fat_count).map(fat_offset).collect();
+        let mut root_cluster = 0;
+        let mut fs_info_offset = None;
+        if fat_type == FatType::Fat32 {
+            let extended_flags = read_u16(&boot_sector, 40);
+            if extended_flags & EXTENDED_FLAGS_MIRRORING_DISABLED != 0 {
+                let active = (extended_flags & 0xf) as u64;
+                if active >= fat_count {
+                    return Err(FatError::Corrupted);
+                }
+                fat_offsets = [fat_offset(active)].into_iter().collect();
+            }
+            root_cluster = read_u32(&boot_sector, 44);
+            let fs_info_sector = read_u16(&boot_sector, 48) as u64;
+            if fs_info_sector != 0 && fs_info_sector < reserved_sectors {
+                fs_info_offset = Some(fs_info_sector * bytes_per_sector);
+            }
+        }
+
+        let mut file_system = FatFileSystem {
+            device,
+            fat_type,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            cluster_count,
+            fat_offsets,
+            root_directory_offset: (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+            root_entry_count,
+            root_cluster,
+            data_offset: first_data_sector * bytes_per_sector,
+            fs_info_offset: None,
+            state: Mutex::new(AllocationState {
+                free_count: None,
+                next_free: FIRST_CLUSTER
+            })
+        };
+        if fat_type == FatType::Fat32 && !file_system.is_valid_cluster(root_cluster) {
+            return Err(FatError::Corrupted);
+        }
+        if let Some(offset) = fs_info_offset {
+            file_system.read_fs_info(offset)?;
+        }
+        Ok(file_system)
+    }
+
+    /// Takes the free cluster count and next free cluster hint from FSInfo, if it's valid.
+    fn read_fs_info(&mut self, offset: u64) -> Result<(), FatError> {
+        let mut lead_signature = [0; 4];
+        let mut fields = [0; 12];
+        self.read_bytes(offset, &mut lead_signature)?;
+        self.read_bytes(offset + FS_INFO_STRUCTURE_SIGNATURE_OFFSET, &mut fields)?;
+        if u32::from_le_bytes(lead_signature) != FS_INFO_LEAD_SIGNATURE
+            || read_u32(&fields, 0) != FS_INFO_STRUCTURE_SIGNATURE
+        {
+            return Ok(());
+        }
+        self.fs_info_offset = Some(offset);
+        let free_count = read_u32(&fields, 4);
+        let next_free = read_u32(&fields, 8);
+        let next_free_valid = self.is_valid_cluster(next_free);
+        let cluster_count = self.cluster_count;
+        let state = self.state.get_mut();
+        if free_count <= cluster_count {
+            state.free_count = Some(free_count);
+        }
+        if next_free_valid {
+            state.next_free = next_free;
+        }
+        Ok(())
+    }
+
+    fn write_fs_info(&self, state: &AllocationState) -> Result<(), FatError> {
+        let Some(offset) = self.fs_info_offset
+        else {
+            return Ok(());
+        };
+        let mut fields = [0; 8];
+        fields[..4].copy_from_slice(&state.free_count.unwrap_or(FS_INFO_UNKNOWN).to_le_bytes());
+        fields[4..].copy_from_slice(&state.next_free.to_le_bytes());
+        self.write_bytes(offset + FS_INFO_FREE_COUNT_OFFSET, &fields)
+    }
+
+    #[inline]
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    #[inline]
+    pub fn device(&self) -> &'static dyn BlockDevice {
+        self.device
+    }
+
+    /// The number of free clusters as recorded by the file system, if it keeps track of it.
+    pub fn free_space(&self) -> Option<u64> {
+        let free_count = self.state.lock().free_count?;
+        Some(free_count as u64 * self.cluster_size)
+    }
+
+    #[inline]
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FatError> {
+        buffer_cache::read(self.device, offset, buffer).map_err(FatError::from)
+    }
+
+    #[inline]
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), FatError> {
+        buffer_cache::write(self.device, offset, data).map_err(FatError::from)
+    }
+
+    #[inline]
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster)
+    }
+
+    #[inline]
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fffffff
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FatError> {
+        let fat_offset = self.fat_offsets[0];
+        Ok(match self.fat_type {
+            FatType::Fat12 => {
+                // Entries are 1.5 bytes, with odd clusters in the upper 12 bits of their two bytes
+                let mut bytes = [0; 2];
+                self.read_bytes(fat_offset + (cluster + cluster / 2) as u64, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes);
+                match cluster % 2 {
+                    0 => value as u32 & 0xfff,
+                    _ => value as u32 >> 4
+                }
+            },
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.read_bytes(fat_offset + cluster as u64 * 2, &mut bytes)?;
+                u16::from_le_bytes(bytes) as u32
+            },
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.read_bytes(fat_offset + cluster as u64 * 4, &mut bytes)?;
+                u32::from_le_bytes(bytes) & 0x0fffffff
+            }
+        })
+    }
+
+    /// Sets the entry of `cluster` in every FAT in use.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FatError> {
+        for &fat_offset in &self.fat_offsets {
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let offset = fat_offset + (cluster + cluster / 2) as u64;
+                    let mut bytes = [0; 2];
+                    self.read_bytes(offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let new = match cluster % 2 {
+                        0 => (old & 0xf000) | (value as u16 & 0xfff),
+                        _ => (old & 0x000f) | ((value as u16) << 4)
+                    };
+                    self.write_bytes(offset, &new.to_le_bytes())?;
+                },
+                FatType::Fat16 => {
+                    self.write_bytes(fat_offset + cluster as u64 * 2, &(value as u16).to_le_bytes())?;
+                },
+                FatType::Fat32 => {
+                    // The upper 4 bits are reserved and have to be preserved
+                    let offset = fat_offset + cluster as u64 * 4;
+                    let mut bytes = [0; 4];
+                    self.read_bytes(offset, &mut bytes)?;
+                    let new = (u32::from_le_bytes(bytes) & 0xf0000000) | (value & 0x0fffffff);
+                    self.write_bytes(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The cluster following `cluster` in its chain, or `None` if it's the last one.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let value = self.fat_entry(cluster)?;
+        // Anything from the bad cluster marker up marks the end of the chain
+        if value >= self.end_of_chain() - 8 {
+            return Ok(None);
+        }
+        match self.is_valid_cluster(value) {
+            true => Ok(Some(value)),
+            false => Err(FatError::Corrupted)
+        }
+    }
+
+    /// The cluster at position `index` in the chain starting with `first_cluster`.
+    fn nth_cluster(&self, first_cluster: u32, index: u64) -> Result<Option<u32>, FatError> {
+        if !self.is_valid_cluster(first_cluster) || index >= self.cluster_count as u64 {
+            return Err(FatError::Corrupted);
+        }
+        let mut cluster = first_cluster;
+        for _ in 0..index {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(None)
+            }
+        }
+        Ok(Some(cluster))
+    }
+
+    /// Finds a free cluster, marks it as the end of a chain and zeroes it. It's linked to `previous` if
+    /// given.
+    fn allocate_cluster(&self, state: &mut AllocationState, previous: Option<u32>) -> Result<u32, FatError> {
+        if state.free_count == Some(0) {
+            return Err(FatError::NoSpace);
+        }
+        let start = match self.is_valid_cluster(state.next_free) {
+            true => state.next_free,
+            false => FIRST_CLUSTER
+        };
+        let mut cluster = start;
+        loop {
+            if self.fat_entry(cluster)? == 0 {
+                break;
+            }
+            cluster += 1;
+            if cluster == FIRST_CLUSTER + self.cluster_count {
+                cluster = FIRST_CLUSTER;
+            }
+            if cluster == start {
+                state.free_count = Some(0);
+                return Err(FatError::NoSpace);
+            }
+        }
+        self.set_fat_entry(cluster, self.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        state.free_count = state.free_count.map(|count| count.saturating_sub(1));
+        state.next_free = cluster + 1;
+
+        let zeroes = [0; 512];
+        let offset = self.cluster_offset(cluster);
+        for chunk_offset in (0..self.cluster_size).step_by(zeroes.len()) {
+            self.write_bytes(offset + chunk_offset, &zeroes)?;
+        }
+        Ok(cluster)
+    }
+
+    /// Frees every cluster of the chain starting with `first_cluster`.
+    fn free_chain(&self, state: &mut AllocationState, first_cluster: u32) -> Result<(), FatError> {
+        let mut cluster = Some(first_cluster);
+        let mut freed = 0;
+        while let Some(current) = cluster {
+            if freed > self.cluster_count {
+                return Err(FatError::Corrupted);
+            }
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, 0)?;
+            freed += 1;
+        }
+        state.free_count = state
+            .free_count
+            .map(|count| (count + freed).min(self.cluster_count));
+        Ok(())
+    }
+
+    /// The root directory, which doesn't have an entry of its own.
+    pub fn root(&self) -> DirectoryEntry {
+        DirectoryEntry {
+            name: ArrayString::new(),
+            attributes: FileAttributes::DIRECTORY,
+            size: 0,
+            first_cluster: self.root_cluster,
+            parent_cluster: self.root_cluster,
+            offset: None,
+            slot_count: 0
+        }
+    }
+
+    fn slots(&self, directory_cluster: u32) -> SlotIterator {
+        SlotIterator {
+            file_system: self,
+            cluster: directory_cluster,
+            index: 0,
+            clusters_seen: 0
+        }
+    }
+
+    /// Calls `f` with every entry in `directory` apart from "." and "..", until it returns false.
+    pub fn read_directory(
+        &self,
+        directory: &DirectoryEntry,
+        mut f: impl FnMut(&DirectoryEntry) -> bool
+    ) -> Result<(), FatError> {
+        if !directory.is_directory() {
+            return Err(FatError::NotADirectory);
+        }
+        let mut slots = self.slots(directory.first_cluster);
+        let mut long_name = LongNameState::new();
+        let mut slot = [0; SLOT_SIZE as usize];
+        while let Some(offset) = slots.next()? {
+            self.read_bytes(offset, &mut slot)?;
+            match slot[0] {
+                SLOT_END_OF_DIRECTORY => break,
+                SLOT_DELETED => {
+                    long_name.slot_count = None;
+                    continue;
+                },
+                _ => {}
+            }
+            if slot[11] & 0x3f == LONG_NAME_ATTRIBUTES {
+                long_name.add(&slot);
+                continue;
+            }
+            let short_name: &[u8; 11] = slot[..11].try_into().unwrap();
+            let attributes = FileAttributes::from_bits_truncate(slot[11]);
+            let (name, slot_count) = match long_name.take(short_name) {
+                Some(long_name) => long_name,
+                None => (format_short_name(short_name, slot[12]), 1)
+            };
+            if attributes.contains(FileAttributes::VOLUME_ID)
+                || short_name == DOT_NAME
+                || short_name == DOT_DOT_NAME
+            {
+                continue;
+            }
+            let entry = DirectoryEntry {
+                name,
+                attributes,
+                size: read_u32(&slot, 28),
+                first_cluster: (read_u16(&slot, 20) as u32) << 16 | read_u16(&slot, 26) as u32,
+                parent_cluster: directory.first_cluster,
+                offset: Some(offset),
+                slot_count
+            };
+            if !f(&entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up an entry in `directory` by name, ignoring the case of ASCII letters like other FAT
+    /// implementations do.
+    pub fn lookup(&self, directory: &DirectoryEntry, name: &str) -> Result<DirectoryEntry, FatError> {
+        let mut found = None;
+        self.read_directory(directory, |entry| {
+            if entry.name.eq_ignore_ascii_case(name) {
+                found = Some(entry.clone());
+                return false;
+            }
+            true
+        })?;
+        found.ok_or(FatError::NotFound)
+    }
+
+    /// Looks up a path relative to the root directory, with components separated by slashes. "." and ".."
+    /// aren't supported.
+    pub fn open(&self, path: &str) -> Result<DirectoryEntry, FatError> {
+        let mut entry = self.root();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !entry.is_directory() {
+                return Err(FatError::NotADirectory);
+            }
+            entry = self.lookup(&entry, component)?;
+        }
+        Ok(entry)
+    }
+
+    /// Reads from a file starting at `offset`, returning how many bytes were read, which is less than
+    /// `buffer.len()` at the end of the file.
+    pub fn read(&self, file: &DirectoryEntry, offset: u64, buffer: &mut [u8]) -> Result<usize, FatError> {
+        if file.is_directory() {
+            return Err(FatError::IsADirectory);
+        }
+        if offset >= file.size as u64 {
+            return Ok(0);
+        }
+        let length = buffer.len().min((file.size as u64 - offset) as usize);
+        let mut cluster = self
+            .nth_cluster(file.first_cluster, offset / self.cluster_size)?
+            .ok_or(FatError::Corrupted)?;
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let in_cluster = position % self.cluster_size;
+            if done > 0 && in_cluster == 0 {
+                cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupted)?;
+            }
+            let chunk_length = ((self.cluster_size - in_cluster) as usize).min(length - done);
+            self.read_bytes(
+                self.cluster_offset(cluster) + in_cluster,
+                &mut buffer[done..done + chunk_length]
+            )?;
+            done += chunk_length;
+        }
+        Ok(length)
+    }
+
+    /// Writes `data` into a file starting at `offset`, allocating clusters as needed. A gap between the end
+    /// of the file and `offset` is filled with zeroes.
+    pub fn write(&self, file: &mut DirectoryEntry, offset: u64, data: &[u8]) -> Result<(), FatError> {
+        if file.is_directory() {
+            return Err(FatError::IsADirectory);
+        }
+        let end = offset + data.len() as u64;
+        if end > MAX_FILE_SIZE {
+            return Err(FatError::FileTooLarge);
+        }
+        let mut state = self.state.lock();
+        let result = self.write_locked(&mut state, file, offset, data);
+        self.write_fs_info(&state)?;
+        result
+    }
+
+    fn write_locked(
+        &self,
+        state: &mut AllocationState,
+        file: &mut DirectoryEntry,
+        offset: u64,
+        data: &[u8]
+    ) -> Result<(), FatError> {
+        let zeroes = [0; 512];
+        let mut position = (file.size as u64).min(offset);
+        let end = offset + data.len() as u64;
+        let mut cluster = None;
+        while position < end {
+            let cluster_index = position / self.cluster_size;
+            let in_cluster = position % self.cluster_size;
+            // Every cluster but the first one of the write follows the previous one
+            let current = match cluster {
+                Some(previous) if in_cluster == 0 => match self.next_cluster(previous)? {
+                    Some(next) => next,
+                    None => self.allocate_cluster(state, Some(previous))?
+                },
+                Some(current) => current,
+                None => self.cluster_for_write(state, file, cluster_index)?
+            };
+            cluster = Some(current);
+            let chunk_end = (position - in_cluster + self.cluster_size).min(end);
+            let device_offset = self.cluster_offset(current) + in_cluster;
+            if position < offset {
+                let length = (chunk_end.min(offset) - position).min(zeroes.len() as u64);
+                self.write_bytes(device_offset, &zeroes[..length as usize])?;
+                position += length;
+            }
+            else {
+                let data_range = (position - offset) as usize..(chunk_end - offset) as usize;
+                self.write_bytes(device_offset, &data[data_range])?;
+                position = chunk_end;
+            }
+            if position > file.size as u64 {
+                file.size = position as u32;
+            }
+        }
+        self.store_entry(file)
+    }
+
+    /// The cluster at position `index` in the file's chain, extending the chain up to it if necessary.
+    fn cluster_for_write(
+        &self,
+        state: &mut AllocationState,
+        file: &mut DirectoryEntry,
+        index: u64
+    ) -> Result<u32, FatError> {
+        if file.first_cluster == 0 {
+            file.first_cluster = self.allocate_cluster(state, None)?;
+        }
+        else if !self.is_valid_cluster(file.first_cluster) {
+            return Err(FatError::Corrupted);
+        }
+        let mut cluster = file.first_cluster;
+        for _ in 0..index {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => self.allocate_cluster(state, Some(cluster))?
+            };
+        }
+        Ok(cluster)
+    }
+
+    /// Changes the size of a file, freeing the clusters past the new end or filling the new part with
+    /// zeroes.
+    pub fn truncate(&self, file: &mut DirectoryEntry, size: u32) -> Result<(), FatError> {
+        if file.is_directory() {
+            return Err(FatError::IsADirectory);
+        }
+        let mut state = self.state.lock();
+        let result = self.truncate_locked(&mut state, file, size);
+        self.write_fs_info(&state)?;
+        result
+    }
+
+    fn truncate_locked(
+        &self,
+        state: &mut AllocationState,
+        file: &mut DirectoryEntry,
+        size: u32
+    ) -> Result<(), FatError> {
+        if size > file.size {
+            return self.write_locked(state, file, size as u64, &[]);
+        }
+        let kept_clusters = (size as u64).div_ceil(self.cluster_size);
+        if file.first_cluster != 0 {
+            if kept_clusters == 0 {
+                self.free_chain(state, file.first_cluster)?;
+                file.first_cluster = 0;
+            }
+            else {
+                let last = self
+                    .nth_cluster(file.first_cluster, kept_clusters - 1)?
+                    .ok_or(FatError::Corrupted)?;
+                if let Some(rest) = self.next_cluster(last)? {
+                    self.set_fat_entry(last, self.end_of_chain())?;
+                    self.free_chain(state, rest)?;
+                }
+            }
+        }
+        file.size = size;
+        self.store_entry(file)
+    }
+
+    /// Writes the size and first cluster of an entry back into its directory.
+    fn store_entry(&self, entry: &DirectoryEntry) -> Result<(), FatError> {
+        let Some(offset) = entry.offset
+        else {
+            return Ok(());
+        };
+        let mut slot = [0; SLOT_SIZE as usize];
+        self.read_bytes(offset, &mut slot)?;
+        slot[11] = entry.attributes.bits();
+        slot[20..22].copy_from_slice(&((entry.first_cluster >> 16) as u16).to_le_bytes());
+        slot[22..24].copy_from_slice(&TIMESTAMP_TIME.to_le_bytes());
+        slot[24..26].copy_from_slice(&TIMESTAMP_DATE.to_le_bytes());
+        slot[26..28].copy_from_slice(&(entry.first_cluster as u16).to_le_bytes());
+        slot[28..32].copy_from_slice(&entry.size.to_le_bytes());
+        self.write_bytes(offset, &slot)
+    }
+
+    /// Whether `directory` has an entry with the given short name, including deleted and dot entries, which
+    /// is what makes generated short names unique.
+    fn has_short_name(&self, directory: &DirectoryEntry, short_name: &[u8; 11]) -> Result<bool, FatError> {
+        let mut slots = self.slots(directory.first_cluster);
+        let mut slot = [0; SLOT_SIZE as usize];
+        while let Some(offset) = slots.next()? {
+            self.read_bytes(offset, &mut slot)?;
+            if slot[0] == SLOT_END_OF_DIRECTORY {
+                break;
+            }
+            if slot[11] & 0x3f != LONG_NAME_ATTRIBUTES && &slot[..11] == short_name {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Finds `count` consecutive free slots in a directory, growing the directory if there aren't enough.
+    fn find_free_slots(
+        &self,
+        state: &mut AllocationState,
+        directory: &DirectoryEntry,
+        count: usize
+    ) -> Result<ArrayVec<u64, { MAX_LONG_NAME_SLOTS + 1 }>, FatError> {
+        let mut run = ArrayVec::new();
+        let mut slots = self.slots(directory.first_cluster);
+        let mut first_byte = [0];
+        loop {
+            while let Some(offset) = slots.next()? {
+                self.read_bytes(offset, &mut first_byte)?;
+                match first_byte[0] {
+                    SLOT_END_OF_DIRECTORY | SLOT_DELETED => run.push(offset),
+                    _ => run.clear()
+                }
+                if run.len() == count {
+                    return Ok(run);
+                }
+            }
+            if slots.cluster == 0 {
+                return Err(FatError::DirectoryFull);
+            }
+            let new_cluster = self.allocate_cluster(state, Some(slots.cluster))?;
+            slots = SlotIterator {
+                file_system: self,
+                cluster: new_cluster,
+                index: 0,
+                clusters_seen: slots.clusters_seen + 1
+            };
+        }
+    }
+
+    /// Creates an empty file or directory in `directory`, with a long name unless `name` is a valid
+    /// uppercase 8.3 name.
+    pub fn create(
+        &self,
+        directory: &DirectoryEntry,
+        name: &str,
+        attributes: FileAttributes
+    ) -> Result<DirectoryEntry, FatError> {
+        validate_name(name)?;
+        if !directory.is_directory() {
+            return Err(FatError::NotADirectory);
+        }
+        match self.lookup(directory, name) {
+            Ok(_) => return Err(FatError::AlreadyExists),
+            Err(FatError::NotFound) => {},
+            Err(e) => return Err(e)
+        }
+        let mut state = self.state.lock();
+        let result = self.create_locked(&mut state, directory, name, attributes);
+        self.write_fs_info(&state)?;
+        result
+    }
+
+    fn create_locked(
+        &self,
+        state: &mut AllocationState,
+        directory: &DirectoryEntry,
+        name: &str,
+        attributes: FileAttributes
+    ) -> Result<DirectoryEntry, FatError> {
+        let (short_name, needs_long_name) = match exact_short_name(name) {
+            Some(short_name) if !self.has_short_name(directory, &short_name)? => (short_name, false),
+            _ => {
+                let mut tail = 1;
+                loop {
+                    let short_name = generated_short_name(name, tail);
+                    if !self.has_short_name(directory, &short_name)? {
+                        break (short_name, true);
+                    }
+                    tail += 1;
+                    if tail > 999999 {
+                        return Err(FatError::AlreadyExists);
+                    }
+                }
+            }
+        };
+        let units: ArrayVec<u16, MAX_LONG_NAME_UNITS> = match needs_long_name {
+            true => name.encode_utf16().collect(),
+            false => ArrayVec::new()
+        };
+        let long_name_slots = units.len().div_ceil(LONG_NAME_UNITS_PER_SLOT);
+        let slots = self.find_free_slots(state, directory, long_name_slots + 1)?;
+
+        let mut entry = DirectoryEntry {
+            name: ArrayString::from(name).map_err(|_| FatError::InvalidName)?,
+            attributes: attributes - FileAttributes::VOLUME_ID,
+            size: 0,
+            first_cluster: 0,
+            parent_cluster: directory.first_cluster,
+            offset: Some(slots[long_name_slots]),
+            slot_count: slots.len() as u8
+        };
+        if entry.is_directory() {
+            entry.first_cluster = self.allocate_cluster(state, None)?;
+            self.write_dot_entries(&entry)?;
+        }
+
+        // The long name slots come first, in reverse order
+        let checksum = short_name_checksum(&short_name);
+        for (i, &offset) in slots[..long_name_slots].iter().enumerate() {
+            let order = (long_name_slots - i) as u8;
+            let mut slot = [0; SLOT_SIZE as usize];
+            slot[0] = order;
+            if i == 0 {
+                slot[0] |= LONG_NAME_LAST_SLOT;
+            }
+            slot[11] = LONG_NAME_ATTRIBUTES;
+            slot[13] = checksum;
+            let start = (order as usize - 1) * LONG_NAME_UNITS_PER_SLOT;
+            for (j, &unit_offset) in LONG_NAME_UNIT_OFFSETS.iter().enumerate() {
+                // The name is terminated by a zero if it doesn't fill the slot, and padded with 0xffff
+                let unit = match (start + j).cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[start + j],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff
+                };
+                slot[unit_offset..unit_offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            self.write_bytes(offset, &slot)?;
+        }
+        let mut slot = [0; SLOT_SIZE as usize];
+        slot[..11].copy_from_slice(&short_name);
+        slot[14..16].copy_from_slice(&TIMESTAMP_TIME.to_le_bytes());
+        slot[16..18].copy_from_slice(&TIMESTAMP_DATE.to_le_bytes());
+        slot[18..20].copy_from_slice(&TIMESTAMP_DATE.to_le_bytes());
+        self.write_bytes(slots[long_name_slots], &slot)?;
+        self.store_entry(&entry)?;
+        Ok(entry)
+    }
+
+    /// Writes the "." and ".." entries of a new directory, pointing to the directory itself and its parent.
+    fn write_dot_entries(&self, directory: &DirectoryEntry) -> Result<(), FatError> {
+        // The root directory is referred to as cluster 0, even on FAT32
+        let parent_cluster = match directory.parent_cluster == self.root_cluster {
+            true => 0,
+            false => directory.parent_cluster
+        };
+        let offset = self.cluster_offset(directory.first_cluster);
+        for (i, (name, cluster)) in [
+            (DOT_NAME, directory.first_cluster),
+            (DOT_DOT_NAME, parent_cluster)
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut slot = [0; SLOT_SIZE as usize];
+            slot[..11].copy_from_slice(name);
+            slot[11] = FileAttributes::DIRECTORY.bits();
+            slot[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+            slot[24..26].copy_from_slice(&TIMESTAMP_DATE.to_le_bytes());
+            slot[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+            self.write_bytes(offset + i as u64 * SLOT_SIZE, &slot)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes a file or an empty directory, freeing its clusters.
+    pub fn remove(&self, entry: &DirectoryEntry) -> Result<(), FatError> {
+        let Some(offset) = entry.offset
+        else {
+            // The root directory can't be removed
+            return Err(FatError::DirectoryNotEmpty);
+        };
+        if entry.is_directory() {
+            let mut empty = true;
+            self.read_directory(entry, |_| {
+                empty = false;
+                false
+            })?;
+            if !empty {
+                return Err(FatError::DirectoryNotEmpty);
+            }
+        }
+        let mut state = self.state.lock();
+
+        // The long name slots are the ones right before the short entry, though not necessarily in the same
+        // cluster
+        let mut previous_slots = ArrayVec::<u64, { MAX_LONG_NAME_SLOTS + 1 }>::new();
+        let mut slots = self.slots(entry.parent_cluster);
+        loop {
+            let slot_offset = slots.next()?.ok_or(FatError::NotFound)?;
+            if previous_slots.is_full() {
+                previous_slots.remove(0);
+            }
+            previous_slots.push(slot_offset);
+            if slot_offset == offset {
+                break;
+            }
+        }
+        let first = previous_slots.len().saturating_sub(entry.slot_count as usize);
+        for &slot_offset in &previous_slots[first..] {
+            self.write_bytes(slot_offset, &[SLOT_DELETED])?;
+        }
+
+        let result = match entry.first_cluster {
+            0 => Ok(()),
+            first_cluster => self.free_chain(&mut state, first_cluster)
+        };
+        self.write_fs_info(&state)?;
+        result
+    }
+}
+
+impl From<FatError> for VfsError {
+    fn from(error: FatError) -> Self {
+        match error {
+            FatError::NotFat | FatError::Corrupted => VfsError::Corrupted,
+            FatError::NotFound => VfsError::NotFound,
+            FatError::NotADirectory => VfsError::NotADirectory,
+            FatError::IsADirectory => VfsError::IsADirectory,
+            FatError::AlreadyExists => VfsError::AlreadyExists,
+            FatError::DirectoryNotEmpty => VfsError::DirectoryNotEmpty,
+            FatError::InvalidName => VfsError::InvalidName,
+            FatError::NoSpace | FatError::DirectoryFull => VfsError::NoSpace,
+            FatError::FileTooLarge => VfsError::FileTooLarge,
+            FatError::DeviceError => VfsError::DeviceError
+        }
+    }
+}
+
+/// A FAT file system as mounted in the VFS. There is at most one inode for every entry, so that the size and
+/// first cluster of a file stay the same for everyone who has it open.
+struct FatVolume {
+    file_system: FatFileSystem,
+    inodes: Mutex<BTreeMap<u64, Weak<FatInode>>>
+}
+
+impl FatVolume {
+    fn inode(self: &Arc<FatVolume>, entry: DirectoryEntry) -> Arc<FatInode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&entry.id()).and_then(Weak::upgrade) {
+            return inode;
+        }
+        inodes.retain(|_, inode| inode.strong_count() > 0);
+        let id = entry.id();
+        let inode = Arc::new(FatInode {
+            volume: self.clone(),
+            entry: Mutex::new(entry)
+        });
+        inodes.insert(id, Arc::downgrade(&inode));
+        inode
+    }
+
+    fn is_in_use(&self, id: u64) -> bool {
+        self.inodes
+            .lock()
+            .get(&id)
+            .is_some_and(|inode| inode.strong_count() > 0)
+    }
+}
+
+/// FAT has no owners or permissions, so everything belongs to root and is writable unless it's read-only.
+struct FatInode {
+    volume: Arc<FatVolume>,
+    entry: Mutex<DirectoryEntry>
+}
+
+impl Inode for FatInode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        let entry = self.entry.lock();
+        let (file_type, mode) = match entry.is_directory() {
+            true => (FileType::Directory, 0o755),
+            false => (FileType::Regular, 0o644)
+        };
+        let mode = match entry.attributes.contains(FileAttributes::READ_ONLY) {
+            true => mode & 0o555,
+            false => mode
+        };
+        let cluster_size = self.volume.file_system.cluster_size;
+        Ok(Stat {
+            inode: entry.id(),
+            device: 0,
+            file_type,
+            mode,
+            link_count: 1,
+            uid: 0,
+            gid: 0,
+            size: entry.size as u64,
+            block_size: cluster_size as u32,
+            blocks: (entry.size as u64).div_ceil(cluster_size) * cluster_size / 512
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let entry = self.volume.file_system.lookup(&self.entry.lock(), name)?;
+        Ok(self.volume.inode(entry))
+    }
+
+    fn read_directory(
+        &self,
+        start: usize,
+        f: &mut dyn FnMut(vfs::DirectoryEntry) -> bool
+    ) -> Result<(), VfsError> {
+        let mut index = 0;
+        self.volume
+            .file_system
+            .read_directory(&self.entry.lock(), |entry| {
+                index += 1;
+                if index <= start {
+                    return true;
+                }
+                f(vfs::DirectoryEntry {
+                    name: entry.name().to_string(),
+                    inode: entry.id(),
+                    file_type: match entry.is_directory() {
+                        true => FileType::Directory,
+                        false => FileType::Regular
+                    }
+                })
+            })?;
+        Ok(())
+    }
+
+    fn create(&self, name: &str, file_type: FileType, _mode: u16) -> Result<Arc<dyn Inode>, VfsError> {
+        let attributes = match file_type {
+            FileType::Regular => FileAttributes::ARCHIVE,
+            FileType::Directory => FileAttributes::DIRECTORY,
+            _ => return Err(VfsError::NotSupported)
+        };
+        let entry = self
+            .volume
+            .file_system
+            .create(&self.entry.lock(), name, attributes)?;
+        Ok(self.volume.inode(entry))
+    }
+
+    /// Files which are still open can't be removed, as their clusters would be reused while they're being
+    /// read and written.
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        let file_system = &self.volume.file_system;
+        let entry = file_system.lookup(&self.entry.lock(), name)?;
+        if self.volume.is_in_use(entry.id()) {
+            return Err(VfsError::Busy);
+        }
+        file_system.remove(&entry)?;
+        Ok(())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(self.volume.file_system.read(&self.entry.lock(), offset, buffer)?)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        self.volume
+            .file_system
+            .write(&mut self.entry.lock(), offset, data)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        let size = u32::try_from(size).map_err(|_| VfsError::FileTooLarge)?;
+        self.volume.file_system.truncate(&mut self.entry.lock(), size)?;
+        Ok(())
+    }
+}
+
+/// The VFS wants the volume itself, which it can't get from a `&self` of the file system trait.
+struct FatMount(Arc<FatVolume>);
+
+impl FileSystem for FatMount {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.0.inode(self.0.file_system.root())
+    }
+
+    fn usage(&self) -> Option<FileSystemUsage> {
+        let file_system = &self.0.file_system;
+        let total_bytes = file_system.cluster_count as u64 * file_system.cluster_size;
+        Some(FileSystemUsage {
+            used_bytes: total_bytes - file_system.free_space()?,
+            total_bytes: Some(total_bytes)
+        })
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        buffer_cache::sync(self.0.file_system.device).map_err(|_| VfsError::DeviceError)
+    }
+}
+
+fn mount(device: Option<&'static dyn BlockDevice>, _options: &str) -> Result<Arc<dyn FileSystem>, VfsError> {
+    let device = device.ok_or(VfsError::InvalidArgument)?;
+    let file_system = FatFileSystem::new(device)?;
+    Ok(Arc::new(FatMount(Arc::new(FatVolume {
+        file_system,
+        inodes: Mutex::new(BTreeMap::new())
+    }))))
+}
+
+pub static FAT_FILE_SYSTEM: FileSystemType = FileSystemType { name: "fat", mount };
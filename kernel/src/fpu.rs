@@ -0,0 +1,93 @@
+use core::arch::asm;
+
+const CR0_MONITOR_COPROCESSOR: u64 = 1 << 1;
+const CR0_EMULATION: u64 = 1 << 2;
+/// Lets `fxsave` and `fxrstor` handle the SSE registers, and enables SSE instructions
+const CR4_OSFXSR: u64 = 1 << 9;
+/// Reports unmasked SSE floating point exceptions as #XM instead of #UD
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+
+/// The x87 control word after `fninit`, with every exception masked and 64 bit precision
+const DEFAULT_CONTROL_WORD: u16 = 0x037f;
+/// The MXCSR value after reset, with every SSE exception masked and rounding to nearest
+const DEFAULT_MXCSR: u32 = 0x1f80;
+const CONTROL_WORD_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+/// Where `fxsave` stores which MXCSR bits the CPU supports, or 0 for the default
+const MXCSR_MASK_OFFSET: usize = 28;
+/// The supported MXCSR bits when the CPU reports 0, which is all but denormals-are-zero
+const DEFAULT_MXCSR_MASK: u32 = 0xffbf;
+/// The size of the `fxsave` area
+pub const FPU_STATE_SIZE: usize = 512;
+
+/// Enables the x87 FPU and SSE, which the kernel doesn't use itself but user programs expect.
+pub fn init() {
+    unsafe {
+        let mut cr0: u64;
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        cr0 = cr0 & !CR0_EMULATION | CR0_MONITOR_COPROCESSOR;
+        asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+        let mut cr4: u64;
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+        asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+    }
+}
+
+/// The x87 and SSE registers of a thread, in the format of `fxsave`, which needs 16 byte alignment.
+#[repr(C, align(16))]
+pub struct FpuState([u8; FPU_STATE_SIZE]);
+
+impl FpuState {
+    /// The state a thread starts with, which has every floating point exception masked.
+    pub fn new() -> FpuState {
+        let mut state = FpuState([0; FPU_STATE_SIZE]);
+        state.0[CONTROL_WORD_OFFSET..CONTROL_WORD_OFFSET + 2]
+            .copy_from_slice(&DEFAULT_CONTROL_WORD.to_le_bytes());
+        state.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        state
+    }
+
+    /// A state user mode handed in, e.g. to return from a signal handler with. MXCSR bits the CPU doesn't
+    /// support are cleared, as `fxrstor` raises a #GP for them.
+    pub fn from_bytes(bytes: [u8; FPU_STATE_SIZE]) -> FpuState {
+        let mut current = FpuState::new();
+        current.save();
+        let mask = match current.read_u32(MXCSR_MASK_OFFSET) {
+            0 => DEFAULT_MXCSR_MASK,
+            mask => mask
+        };
+        let mut state = FpuState(bytes);
+        let mxcsr = state.read_u32(MXCSR_OFFSET) & mask;
+        state.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&mxcsr.to_le_bytes());
+        state
+    }
+
+    /// The `fxsave` image, e.g. for handing it to user mode.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; FPU_STATE_SIZE] {
+        &self.0
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.0[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Stores the CPU's x87 and SSE registers.
+    #[inline]
+    pub fn save(&mut self) {
+        unsafe { asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags)) };
+    }
+
+    /// Loads the x87 and SSE registers into the CPU.
+    #[inline]
+    pub fn restore(&self) {
+        unsafe { asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, preserves_flags)) };
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,158 @@
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::{self, null_mut};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::memory::allocate_frames;
+use crate::msr::write_msr;
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+/// The user segments come data first, as SYSRET loads SS from the selector 8 bytes above the kernel code
+/// segment and CS from the one 16 bytes above it. The low two bits are the requested privilege level.
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+const TSS_SELECTOR: u16 = 0x28;
+
+// Flat segments, where only the access byte and the long mode bit of the code segments matter
+const KERNEL_CODE_DESCRIPTOR: u64 = 0x00af9a000000ffff;
+const KERNEL_DATA_DESCRIPTOR: u64 = 0x00cf92000000ffff;
+const USER_DATA_DESCRIPTOR: u64 = 0x00cff2000000ffff;
+const USER_CODE_DESCRIPTOR: u64 = 0x00affa000000ffff;
+/// Present, type "available 64-bit TSS"
+const TSS_DESCRIPTOR_ACCESS: u64 = 0x89;
+
+const MSR_GS_BASE: u32 = 0xc0000101;
+const MSR_KERNEL_GS_BASE: u32 = 0xc0000102;
+
+/// The size of the stack the CPU switches to when user mode is interrupted or makes a system call
+const KERNEL_STACK_FRAMES: u64 = 16;
+
+/// Offsets of the fields of `CpuLocal`, for assembly that reaches them through GS
+pub const CPU_LOCAL_KERNEL_STACK: usize = 0;
+pub const CPU_LOCAL_USER_STACK: usize = 8;
+
+/// In long mode the TSS holds no task state anymore, just the stacks to switch to when entering the kernel.
+#[repr(C, packed(4))]
+struct TaskStateSegment {
+    reserved0: u32,
+    /// The stacks for entering privilege levels 0 to 2, of which only the first is used
+    privilege_stacks: [u64; 3],
+    reserved1: u64,
+    interrupt_stacks: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    /// Pointing past the end of the TSS means there is no I/O permission bitmap, so user mode can't access
+    /// any I/O ports
+    io_map_base: u16
+}
+
+/// Per-CPU data, which the system call entry code finds through the GS base after a `swapgs`.
+#[repr(C)]
+struct CpuLocal {
+    /// The top of the stack to run the kernel on when user mode makes a system call
+    kernel_stack: u64,
+    /// Where the entry code keeps the user stack pointer until it's on the kernel stack
+    user_stack: u64
+}
+
+/// The GDT has to stay in writable memory, as the CPU marks the TSS descriptor busy when it's loaded.
+#[repr(C, align(16))]
+struct CpuTables {
+    gdt: [u64; 7],
+    tss: TaskStateSegment,
+    local: CpuLocal
+}
+
+#[repr(C, packed)]
+struct Gdtr {
+    limit: u16,
+    address: u64
+}
+
+static TABLES: AtomicPtr<CpuTables> = AtomicPtr::new(null_mut());
+
+/// Replaces the GDT Limine set up with one that has user segments and a TSS, and points the kernel GS base
+/// at the per-CPU data. This has to happen before the IDT is created, as its gates use the code segment
+/// that's loaded at the time.
+pub fn init() {
+    let Some(stack) = allocate_frames(KERNEL_STACK_FRAMES)
+    else {
+        panic!("No memory for the kernel stack");
+    };
+    let kernel_stack = stack.virtual_address() + stack.size();
+    let tables = Box::leak(Box::new(CpuTables {
+        gdt: [
+            0,
+            KERNEL_CODE_DESCRIPTOR,
+            KERNEL_DATA_DESCRIPTOR,
+            USER_DATA_DESCRIPTOR,
+            USER_CODE_DESCRIPTOR,
+            0,
+            0
+        ],
+        tss: TaskStateSegment {
+            reserved0: 0,
+            privilege_stacks: [kernel_stack, 0, 0],
+            reserved1: 0,
+            interrupt_stacks: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            io_map_base: size_of::<TaskStateSegment>() as u16
+        },
+        local: CpuLocal {
+            kernel_stack,
+            user_stack: 0
+        }
+    }));
+    let tss_address = ptr::addr_of!(tables.tss) as u64;
+    let tss_limit = size_of::<TaskStateSegment>() as u64 - 1;
+    tables.gdt[5] = (tss_limit & 0xffff)
+        | (tss_address & 0xff_ffff) << 16
+        | TSS_DESCRIPTOR_ACCESS << 40
+        | (tss_limit >> 16 & 0xf) << 48
+        | (tss_address >> 24 & 0xff) << 56;
+    tables.gdt[6] = tss_address >> 32;
+
+    let gdtr = Gdtr {
+        limit: size_of::<[u64; 7]>() as u16 - 1,
+        address: tables.gdt.as_ptr() as u64
+    };
+    unsafe {
+        // CS can only be changed by a far jump, call or return
+        asm!(
+            "lgdt [{gdtr}]",
+            "push {code}",
+            "lea {scratch}, [rip + 2f]",
+            "push {scratch}",
+            "retfq",
+            "2:",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov ss, {data:x}",
+            "xor {scratch:e}, {scratch:e}",
+            "mov fs, {scratch:x}",
+            "mov gs, {scratch:x}",
+            "ltr {tss:x}",
+            gdtr = in(reg) &gdtr,
+            code = in(reg) KERNEL_CODE_SELECTOR as u64,
+            data = in(reg) KERNEL_DATA_SELECTOR as u64,
+            tss = in(reg) TSS_SELECTOR as u64,
+            scratch = out(reg) _
+        );
+    }
+    // The kernel runs with the user's GS base, and only swaps in the per-CPU data for a moment on entry
+    write_msr(MSR_GS_BASE, 0);
+    write_msr(MSR_KERNEL_GS_BASE, ptr::addr_of!(tables.local) as u64);
+    TABLES.store(tables, Ordering::Release);
+}
+
+/// Sets the stack the CPU switches to when user mode is interrupted or makes a system call.
+pub fn set_kernel_stack(top: u64) {
+    let tables = TABLES.load(Ordering::Acquire);
+    unsafe {
+        (ptr::addr_of_mut!((*tables).tss.privilege_stacks) as *mut u64).write_unaligned(top);
+        (*tables).local.kernel_stack = top;
+    }
+}
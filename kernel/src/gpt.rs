@@ -0,0 +1,375 @@
+use core::fmt::{self, Display, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arrayvec::ArrayString;
+use spin::Once;
+
+use crate::block::{self, BlockDevice, BlockError, RequestQueue};
+use crate::buffer_cache;
+use crate::crc32::Crc32;
+
+const MAX_PARTITIONS: usize = 64;
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+/// The header is 92 bytes, followed by reserved space up to the end of its block
+const HEADER_SIZE: usize = 92;
+const HEADER_CRC32_OFFSET: usize = 16;
+/// Entries may be larger than this, but anything after the name is reserved
+const ENTRY_SIZE: usize = 128;
+/// The name is 36 UTF-16 code units
+const ENTRY_NAME_LENGTH: usize = 36;
+/// The MBR in the first 512 bytes of the disk, which holds four partition records and ends with a signature
+const MBR_SIZE: usize = 512;
+const MBR_RECORDS_OFFSET: usize = 446;
+const MBR_RECORD_SIZE: usize = 16;
+const MBR_RECORD_TYPE_OFFSET: usize = 4;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// The MBR partition type that covers the disk on GPT disks, so that tools which only know MBR leave it be
+const PROTECTIVE_MBR_TYPE: u8 = 0xee;
+
+/// A GUID, stored the way GPT does: the first three fields little endian, and the last two as bytes.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const ZERO: Guid = Guid([0; 16]);
+
+    /// Builds a GUID from the fields of its usual text form, e.g. C12A7328-F81F-11D2-BA4B-00A0C93EC93B is
+    /// `Guid::new(0xc12a7328, 0xf81f, 0x11d2, 0xba4b, 0x00a0c93ec93b)`.
+    pub const fn new(a: u32, b: u16, c: u16, d: u16, e: u64) -> Guid {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        let d = d.to_be_bytes();
+        let e = e.to_be_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], e[2], e[3], e[4], e[5], e[6], e[7]
+        ])
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The partition type of the EFI system partition, which the bootloader and kernel are on
+pub const EFI_SYSTEM_PARTITION: Guid = Guid::new(0xc12a7328, 0xf81f, 0x11d2, 0xba4b, 0x00a0c93ec93b);
+/// The partition type Linux uses for its file systems, such as ext2
+pub const LINUX_FILE_SYSTEM: Guid = Guid::new(0x0fc63daf, 0x8483, 0x4772, 0x8e79, 0x3d69d8477de4);
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum GptError {
+    /// There's no protective MBR, or neither the primary nor the backup header has the GPT signature
+    NoPartitionTable,
+    /// A header has the signature, but its fields make no sense
+    InvalidHeader,
+    ChecksumMismatch,
+    TooManyPartitions,
+    /// Reading the device failed
+    DeviceError
+}
+
+impl From<BlockError> for GptError {
+    fn from(_: BlockError) -> Self {
+        GptError::DeviceError
+    }
+}
+
+/// The fields of a GPT header that are needed to find the partition entries.
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    first_usable_block: u64,
+    last_usable_block: u64,
+    /// Where the partition entries start on the disk, in bytes
+    entries_offset: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc32: u32
+}
+
+#[inline]
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[inline]
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads and checks the header in `block`, including the checksum of the partition entries it points to.
+fn read_header(device: &'static dyn BlockDevice, block: u64) -> Result<Header, GptError> {
+    let block_size = device.block_size() as u64;
+    let mut data = [0; HEADER_SIZE];
+    buffer_cache::read(device, block * block_size, &mut data)?;
+    if &data[0..8] != SIGNATURE {
+        return Err(GptError::NoPartitionTable);
+    }
+    let header_size = read_u32(&data, 12) as usize;
+    if header_size < HEADER_SIZE || header_size as u64 > block_size {
+        return Err(GptError::InvalidHeader);
+    }
+    // The checksum covers the whole header as given by its size, with the checksum field itself zeroed
+    let expected_crc32 = read_u32(&data, HEADER_CRC32_OFFSET);
+    data[HEADER_CRC32_OFFSET..HEADER_CRC32_OFFSET + 4].fill(0);
+    let mut crc = Crc32::new();
+    crc.update(&data);
+    let mut remaining = header_size - HEADER_SIZE;
+    let mut offset = block * block_size + HEADER_SIZE as u64;
+    let mut chunk = [0; 64];
+    while remaining > 0 {
+        let length = remaining.min(chunk.len());
+        buffer_cache::read(device, offset, &mut chunk[..length])?;
+        crc.update(&chunk[..length]);
+        remaining -= length;
+        offset += length as u64;
+    }
+    if crc.finish() != expected_crc32 {
+        return Err(GptError::ChecksumMismatch);
+    }
+
+    // Everything past the checksum comes from the disk, so it's checked before any offsets are worked out
+    let entries_block = read_u64(&data, 72);
+    let entry_count = read_u32(&data, 80);
+    let entry_size = read_u32(&data, 84);
+    let entries_size = entry_count as u64 * entry_size as u64;
+    let entries_offset = entries_block
+        .checked_mul(block_size)
+        .ok_or(GptError::InvalidHeader)?;
+    let entries_end = entries_offset
+        .checked_add(entries_size)
+        .ok_or(GptError::InvalidHeader)?;
+    let header = Header {
+        first_usable_block: read_u64(&data, 40),
+        last_usable_block: read_u64(&data, 48),
+        entries_offset,
+        entry_count,
+        entry_size,
+        entries_crc32: read_u32(&data, 88)
+    };
+    if read_u64(&data, 24) != block
+        || entries_block >= device.block_count()
+        || header.entry_size < ENTRY_SIZE as u32
+        || !header.entry_size.is_power_of_two()
+        || header.first_usable_block > header.last_usable_block
+        || header.last_usable_block >= device.block_count()
+        || entries_end > device.size()
+    {
+        return Err(GptError::InvalidHeader);
+    }
+
+    let mut crc = Crc32::new();
+    let mut entry = [0; ENTRY_SIZE];
+    for i in 0..header.entry_count as u64 {
+        // Within the entries, which were checked to end on the device
+        let entry_offset = header.entries_offset + i * header.entry_size as u64;
+        buffer_cache::read(device, entry_offset, &mut entry)?;
+        crc.update(&entry);
+        // Whatever comes after the part of the entry this knows about is checksummed too
+        let mut remaining = header.entry_size as usize - ENTRY_SIZE;
+        let mut offset = entry_offset + ENTRY_SIZE as u64;
+        while remaining > 0 {
+            let length = remaining.min(chunk.len());
+            buffer_cache::read(device, offset, &mut chunk[..length])?;
+            crc.update(&chunk[..length]);
+            remaining -= length;
+            offset += length as u64;
+        }
+    }
+    if crc.finish() != header.entries_crc32 {
+        return Err(GptError::ChecksumMismatch);
+    }
+    Ok(header)
+}
+
+/// A partition of a disk, which is a block device itself. The buffer cache tells the partition and its disk
+/// apart, so the same blocks mustn't be written through both.
+pub struct Partition {
+    name: ArrayString<16>,
+    disk: &'static dyn BlockDevice,
+    first_block: u64,
+    block_count: u64,
+    type_guid: Guid,
+    unique_guid: Guid,
+    /// The partition's own name, as opposed to the name of the block device
+    label: ArrayString<{ ENTRY_NAME_LENGTH * 3 }>,
+    request_queue: RequestQueue
+}
+
+impl Partition {
+    #[inline]
+    pub fn type_guid(&self) -> Guid {
+        self.type_guid
+    }
+
+    #[inline]
+    pub fn unique_guid(&self) -> Guid {
+        self.unique_guid
+    }
+
+    #[inline]
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    #[inline]
+    pub fn is_efi_system_partition(&self) -> bool {
+        self.type_guid == EFI_SYSTEM_PARTITION
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> u32 {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        self.disk.read_blocks(self.first_block + first_block, buffer)
+    }
+
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        self.disk.write_blocks(self.first_block + first_block, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+
+    fn request_queue(&self) -> &RequestQueue {
+        &self.request_queue
+    }
+}
+
+static PARTITIONS: [Once<Partition>; MAX_PARTITIONS] = [const { Once::new() }; MAX_PARTITIONS];
+static PARTITION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Every partition found so far.
+pub fn partitions() -> impl Iterator<Item = &'static Partition> {
+    PARTITIONS.iter().map_while(|p| p.get())
+}
+
+fn is_partition(device: &dyn BlockDevice) -> bool {
+    partitions().any(|p| block::same_device(p, device))
+}
+
+/// Decodes the UTF-16LE name of a partition entry, replacing anything that isn't valid UTF-16.
+fn decode_label(entry: &[u8]) -> ArrayString<{ ENTRY_NAME_LENGTH * 3 }> {
+    let units = entry[56..56 + ENTRY_NAME_LENGTH * 2]
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0);
+    let mut label = ArrayString::new();
+    for character in char::decode_utf16(units) {
+        // Every code unit decodes to at most three bytes, so this always fits
+        let _ = label.try_push(character.unwrap_or(char::REPLACEMENT_CHARACTER));
+    }
+    label
+}
+
+/// Checks that the first block has an MBR with a protective partition record, as GPT disks do.
+fn has_protective_mbr(disk: &'static dyn BlockDevice) -> Result<bool, GptError> {
+    let mut mbr = [0; MBR_SIZE];
+    buffer_cache::read(disk, 0, &mut mbr)?;
+    if mbr[MBR_SIZE - 2..] != MBR_SIGNATURE {
+        return Ok(false);
+    }
+    Ok(mbr[MBR_RECORDS_OFFSET..MBR_SIZE - 2]
+        .chunks_exact(MBR_RECORD_SIZE)
+        .any(|record| record[MBR_RECORD_TYPE_OFFSET] == PROTECTIVE_MBR_TYPE))
+}
+
+/// Reads the partition table of a disk and registers a block device for every partition in it, named after
+/// the disk with the partition's number appended (e.g. "ahci0p1"). The backup header at the end of the disk
+/// is used if the primary one is damaged. Returns how many partitions there are.
+pub fn scan(disk: &'static dyn BlockDevice) -> Result<usize, GptError> {
+    if disk.block_count() < 2 || !has_protective_mbr(disk)? {
+        return Err(GptError::NoPartitionTable);
+    }
+    let header = match read_header(disk, 1) {
+        Ok(header) => header,
+        Err(GptError::DeviceError) => return Err(GptError::DeviceError),
+        // Without a valid primary header, the alternate LBA field of it can't be trusted either
+        Err(primary_error) => match read_header(disk, disk.block_count() - 1) {
+            Ok(header) => header,
+            Err(GptError::NoPartitionTable) => return Err(primary_error),
+            Err(e) => return Err(e)
+        }
+    };
+
+    let mut count = 0;
+    let mut entry = [0; ENTRY_SIZE];
+    for i in 0..header.entry_count as u64 {
+        // Within the entries, which `read_header()` checked to end on the disk
+        let offset = header.entries_offset + i * header.entry_size as u64;
+        buffer_cache::read(disk, offset, &mut entry)?;
+        let type_guid = Guid(entry[0..16].try_into().unwrap());
+        if type_guid == Guid::ZERO {
+            continue;
+        }
+        let first_block = read_u64(&entry, 32);
+        let last_block = read_u64(&entry, 40);
+        if first_block < header.first_usable_block
+            || last_block > header.last_usable_block
+            || first_block > last_block
+        {
+            continue;
+        }
+
+        let index = PARTITION_COUNT.fetch_add(1, Ordering::Relaxed);
+        let slot = PARTITIONS.get(index).ok_or(GptError::TooManyPartitions)?;
+        let mut name = ArrayString::new();
+        if write!(name, "{}p{}", disk.name(), i + 1).is_err() {
+            name.clear();
+            let _ = write!(name, "part{}", index);
+        }
+        let partition = slot.call_once(|| Partition {
+            name,
+            disk,
+            first_block,
+            block_count: last_block - first_block + 1,
+            type_guid,
+            unique_guid: Guid(entry[16..32].try_into().unwrap()),
+            label: decode_label(&entry),
+            request_queue: RequestQueue::new()
+        });
+        block::register_device(partition).map_err(|_| GptError::TooManyPartitions)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Scans every registered block device which isn't a partition itself and doesn't have its partitions
+/// registered yet. Devices without a GPT are left alone.
+pub fn scan_all() {
+    for device in block::devices() {
+        if is_partition(device) || partitions().any(|p| block::same_device(p.disk, device)) {
+            continue;
+        }
+        let _ = scan(device);
+    }
+}
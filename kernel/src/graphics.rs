@@ -18,6 +18,9 @@ pub enum ColorError {
 }
 
 impl Color {
+    /// All color components zero, which is black regardless of the framebuffer's pixel format.
+    pub const BLACK: Color = Color(0);
+
     #[inline]
     pub fn new(framebuffer: &LimineFramebuffer, color_values: Rgb) -> Color {
         Self::new_closest(framebuffer, color_values)
@@ -99,6 +102,23 @@ impl LimineFramebuffer {
         self._set_pixel_color(x, y, color);
     }
 
+    /// Reads back the color of a pixel.
+    ///
+    /// # Safety
+    /// `x` and `y` must be within the bounds of the framebuffer.
+    #[inline]
+    pub unsafe fn get_pixel_color_unchecked(&self, x: u64, y: u64) -> Color {
+        let bytes_per_pixel = (self.bpp / 8) as u64;
+        let mut color = Color(0);
+        let color_p = (&mut color as *mut Color) as *mut u8;
+        let pixel_base_ptr: *const u8 =
+            (self.address as *const u8).add((self.get_pixel_offset(x, y) * bytes_per_pixel) as usize);
+        for i in 0..bytes_per_pixel {
+            *color_p.offset(i as isize) = pixel_base_ptr.offset(i as isize).read_volatile();
+        }
+        color
+    }
+
     #[inline]
     fn _set_pixel_color(&self, x: u64, y: u64, color: Color) {
         let bytes_per_pixel = (self.bpp / 8) as u64;
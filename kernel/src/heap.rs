@@ -0,0 +1,121 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, null_mut};
+
+use spin::Mutex;
+
+use crate::interrupts_general::without_interrupts;
+use crate::memory::{
+    allocate_frames, allocate_frames_aligned, free_frames, PhysicalFrames, FRAME_SIZE, HHDM_OFFSET
+};
+
+/// Allocations of up to 2 KiB are rounded up to one of these sizes and carved out of frames split into
+/// objects of that size. Larger ones get frames of their own.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Free objects of a size class are linked through their first bytes.
+struct FreeObject {
+    next: *mut FreeObject
+}
+
+/// The free objects of every size class. Frames split into objects are never given back to the frame
+/// allocator, as objects of the same size tend to be needed again.
+struct Heap {
+    free_lists: [*mut FreeObject; SIZE_CLASSES.len()],
+    /// How many bytes are allocated, counting whole size classes and frames
+    allocated_bytes: usize
+}
+
+unsafe impl Send for Heap {}
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap {
+    free_lists: [null_mut(); SIZE_CLASSES.len()],
+    allocated_bytes: 0
+});
+
+impl Heap {
+    fn allocate(&mut self, class: usize) -> *mut u8 {
+        if self.free_lists[class].is_null() {
+            let Some(frame) = allocate_frames(1)
+            else {
+                return null_mut();
+            };
+            // Objects are aligned to their size, as the frame is aligned to more than any size class
+            let base = frame.as_ptr::<u8>();
+            let size = SIZE_CLASSES[class];
+            for offset in (0..FRAME_SIZE as usize).step_by(size).rev() {
+                self.free(class, unsafe { base.add(offset) });
+            }
+        }
+        let object = self.free_lists[class];
+        self.free_lists[class] = unsafe { (*object).next };
+        object as *mut u8
+    }
+
+    fn free(&mut self, class: usize, object: *mut u8) {
+        let object = object as *mut FreeObject;
+        let next = self.free_lists[class];
+        unsafe { ptr::write(object, FreeObject { next }) };
+        self.free_lists[class] = object;
+    }
+}
+
+/// The size class an allocation comes from, or `None` if it gets frames of its own.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class_size| class_size >= size)
+}
+
+#[inline]
+fn frame_count(layout: Layout) -> u64 {
+    (layout.size() as u64).div_ceil(FRAME_SIZE)
+}
+
+/// The allocator behind `alloc`, which everything is allocated from through the higher half direct map.
+struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = size_class(layout) {
+            return without_interrupts(|| {
+                let mut heap = HEAP.lock();
+                let object = heap.allocate(class);
+                if !object.is_null() {
+                    heap.allocated_bytes += SIZE_CLASSES[class];
+                }
+                object
+            });
+        }
+        let alignment = (layout.align() as u64).div_ceil(FRAME_SIZE);
+        let Some(frames) = allocate_frames_aligned(frame_count(layout), alignment)
+        else {
+            return null_mut();
+        };
+        without_interrupts(|| HEAP.lock().allocated_bytes += frames.size() as usize);
+        frames.as_ptr()
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        if let Some(class) = size_class(layout) {
+            without_interrupts(|| {
+                let mut heap = HEAP.lock();
+                heap.free(class, pointer);
+                heap.allocated_bytes -= SIZE_CLASSES[class];
+            });
+            return;
+        }
+        let frames = PhysicalFrames {
+            physical_address: pointer as u64 - *HHDM_OFFSET,
+            count: frame_count(layout)
+        };
+        without_interrupts(|| HEAP.lock().allocated_bytes -= frames.size() as usize);
+        free_frames(frames);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+/// How many bytes of the heap are in use, e.g. for memory usage statistics.
+pub fn allocated_bytes() -> usize {
+    without_interrupts(|| HEAP.lock().allocated_bytes)
+}
@@ -0,0 +1,569 @@
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use crate::interrupts_general::without_interrupts;
+use crate::keyboard::{self, KeyEvent, KeyModifiers, KEY_LEFT_CONTROL};
+use crate::mouse::{self, MouseButtons, MouseEvent, ABSOLUTE_POSITION_MAX};
+use crate::usb::{
+    EndpointDescriptor, EndpointTransferType, InterfaceInfo, SetupPacket, UsbDevice, UsbDriver, UsbError,
+    UsbInterfaceId, DESCRIPTOR_TYPE_HID, REQUEST_GET_DESCRIPTOR, REQUEST_RECIPIENT_INTERFACE,
+    REQUEST_TYPE_CLASS, REQUEST_TYPE_DEVICE_TO_HOST, REQUEST_TYPE_STANDARD
+};
+
+const MAX_KEYBOARDS: usize = 4;
+const MAX_POINTERS: usize = 4;
+const MAX_REPORT_DESCRIPTOR_SIZE: usize = 1024;
+
+const CLASS_HID: u8 = 0x03;
+const SUBCLASS_BOOT: u8 = 0x01;
+const PROTOCOL_KEYBOARD: u8 = 0x01;
+const PROTOCOL_MOUSE: u8 = 0x02;
+
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+const REQUEST_SET_IDLE: u8 = 0x0a;
+const REQUEST_SET_PROTOCOL: u8 = 0x0b;
+const PROTOCOL_BOOT: u16 = 0;
+const PROTOCOL_REPORT: u16 = 1;
+
+const BOOT_KEYBOARD_REPORT_SIZE: usize = 8;
+/// Reported in every key slot when more keys are held down than the report has room for
+const KEY_ERROR_ROLL_OVER: u8 = 0x01;
+
+const USAGE_PAGE_GENERIC_DESKTOP: u16 = 0x01;
+const USAGE_PAGE_BUTTON: u16 = 0x09;
+const USAGE_X: u16 = 0x30;
+const USAGE_Y: u16 = 0x31;
+const USAGE_WHEEL: u16 = 0x38;
+
+const ITEM_TYPE_MAIN: u8 = 0;
+const ITEM_TYPE_GLOBAL: u8 = 1;
+const ITEM_TYPE_LOCAL: u8 = 2;
+const ITEM_LONG: u8 = 0xfe;
+
+const MAIN_INPUT: u8 = 0x8;
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_LOGICAL_MINIMUM: u8 = 0x1;
+const GLOBAL_LOGICAL_MAXIMUM: u8 = 0x2;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const LOCAL_USAGE: u8 = 0x0;
+const LOCAL_USAGE_MINIMUM: u8 = 0x1;
+const LOCAL_USAGE_MAXIMUM: u8 = 0x2;
+
+const INPUT_CONSTANT: u32 = 1 << 0;
+const INPUT_RELATIVE: u32 = 1 << 2;
+
+/// Where a value is in an input report, and how to interpret it.
+#[derive(Debug, Clone, Copy)]
+struct ReportField {
+    bit_offset: u32,
+    bit_size: u32,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    relative: bool
+}
+
+impl ReportField {
+    /// Extracts the value of the field from a report (without the report id), or `None` if the report is
+    /// too short to contain it.
+    fn extract(&self, report: &[u8]) -> Option<i32> {
+        if self.bit_size == 0 || self.bit_size > 32 {
+            return None;
+        }
+        let mut value: u32 = 0;
+        for i in 0..self.bit_size {
+            let bit = self.bit_offset + i;
+            let byte = *report.get((bit / 8) as usize)?;
+            value |= (((byte >> (bit % 8)) & 1) as u32) << i;
+        }
+        // Fields are only signed if they can hold negative values
+        if self.logical_minimum < 0 && self.bit_size < 32 && value & (1 << (self.bit_size - 1)) != 0 {
+            value |= !0 << self.bit_size;
+        }
+        Some(value as i32)
+    }
+}
+
+/// The fields of a pointing device's input report which are of interest, as found in its report
+/// descriptor.
+#[derive(Debug, Clone, Copy)]
+struct PointerLayout {
+    /// The id every report with these fields starts with, if the device uses report ids
+    report_id: Option<u8>,
+    x: ReportField,
+    y: ReportField,
+    wheel: Option<ReportField>,
+    buttons: [Option<ReportField>; 5]
+}
+
+/// The fixed report format of boot protocol mice
+const BOOT_MOUSE_LAYOUT: PointerLayout = {
+    const fn axis(bit_offset: u32) -> ReportField {
+        ReportField {
+            bit_offset,
+            bit_size: 8,
+            logical_minimum: -127,
+            logical_maximum: 127,
+            relative: true
+        }
+    }
+    const fn button(bit_offset: u32) -> Option<ReportField> {
+        Some(ReportField {
+            bit_offset,
+            bit_size: 1,
+            logical_minimum: 0,
+            logical_maximum: 1,
+            relative: false
+        })
+    }
+    PointerLayout {
+        report_id: None,
+        x: axis(8),
+        y: axis(16),
+        wheel: None,
+        buttons: [button(0), button(1), button(2), None, None]
+    }
+};
+
+/// Keeps track of the state a report descriptor parser needs. Only what's needed to find the pointer
+/// fields is supported, which excludes e.g. the push and pop items.
+struct ReportDescriptorParser {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    report_size: u32,
+    report_count: u32,
+    report_id: Option<u8>,
+    /// Usages of the next main item, with their usage page
+    usages: ArrayVec<(u16, u16), 16>,
+    usage_minimum: Option<(u16, u16)>,
+    usage_maximum: Option<u16>,
+    /// The bit offset of the next input field within the report of each report id
+    bit_offsets: ArrayVec<(Option<u8>, u32), 16>
+}
+
+impl ReportDescriptorParser {
+    fn new() -> ReportDescriptorParser {
+        ReportDescriptorParser {
+            usage_page: 0,
+            logical_minimum: 0,
+            logical_maximum: 0,
+            report_size: 0,
+            report_count: 0,
+            report_id: None,
+            usages: ArrayVec::new(),
+            usage_minimum: None,
+            usage_maximum: None,
+            bit_offsets: ArrayVec::new()
+        }
+    }
+
+    fn clear_local_items(&mut self) {
+        self.usages.clear();
+        self.usage_minimum = None;
+        self.usage_maximum = None;
+    }
+
+    /// A 32-bit usage includes its usage page in the high half, shorter ones use the current usage page.
+    fn usage(&self, data: u32, size: usize) -> (u16, u16) {
+        match size {
+            4 => ((data >> 16) as u16, data as u16),
+            _ => (self.usage_page, data as u16)
+        }
+    }
+
+    /// The usage of the `index`th field of the current main item. Fields past the listed usages get the last
+    /// one.
+    fn field_usage(&self, index: u32) -> Option<(u16, u16)> {
+        if let (Some((page, minimum)), Some(maximum)) = (self.usage_minimum, self.usage_maximum) {
+            let usage = minimum as u32 + index;
+            return Some((page, usage.min(maximum as u32) as u16));
+        }
+        self.usages.get(index as usize).or(self.usages.last()).copied()
+    }
+
+    /// Takes the bit offset for an input item of `bit_count` bits in the current report. Returns `None` if
+    /// the descriptor has more reports than are kept track of.
+    fn take_bit_offset(&mut self, bit_count: u32) -> Option<u32> {
+        let report_id = self.report_id;
+        let index = match self.bit_offsets.iter().position(|(id, _)| *id == report_id) {
+            Some(index) => index,
+            None => {
+                self.bit_offsets.try_push((report_id, 0)).ok()?;
+                self.bit_offsets.len() - 1
+            }
+        };
+        let offset = self.bit_offsets[index].1;
+        self.bit_offsets[index].1 += bit_count;
+        Some(offset)
+    }
+}
+
+fn sign_extend(data: u32, size: usize) -> i32 {
+    match size {
+        1 => data as u8 as i8 as i32,
+        2 => data as u16 as i16 as i32,
+        _ => data as i32
+    }
+}
+
+/// Finds the X and Y axes of a pointing device, along with its wheel and buttons, in its report descriptor.
+/// Only fields in the same report as the X axis are used.
+fn parse_pointer_layout(descriptor: &[u8]) -> Option<PointerLayout> {
+    let mut parser = ReportDescriptorParser::new();
+    let mut x = None;
+    let mut y = None;
+    let mut wheel = None;
+    // Fields are collected along with the report id they belong to, and filtered at the end
+    let mut buttons = [None; 5];
+    let mut offset = 0;
+    while offset < descriptor.len() {
+        let prefix = descriptor[offset];
+        if prefix == ITEM_LONG {
+            let data_size = *descriptor.get(offset + 1)? as usize;
+            offset += 3 + data_size;
+            continue;
+        }
+        let size = match prefix & 0b11 {
+            3 => 4,
+            size => size as usize
+        };
+        let item_type = (prefix >> 2) & 0b11;
+        let tag = prefix >> 4;
+        let bytes = descriptor.get(offset + 1..offset + 1 + size)?;
+        let data = bytes
+            .iter()
+            .enumerate()
+            .fold(0, |data, (i, byte)| data | ((*byte as u32) << (8 * i)));
+        offset += 1 + size;
+
+        match (item_type, tag) {
+            (ITEM_TYPE_GLOBAL, GLOBAL_USAGE_PAGE) => parser.usage_page = data as u16,
+            (ITEM_TYPE_GLOBAL, GLOBAL_LOGICAL_MINIMUM) => parser.logical_minimum = sign_extend(data, size),
+            (ITEM_TYPE_GLOBAL, GLOBAL_LOGICAL_MAXIMUM) => {
+                // The maximum is only signed if the minimum is negative
+                parser.logical_maximum = match parser.logical_minimum < 0 {
+                    true => sign_extend(data, size),
+                    false => data as i32
+                };
+            },
+            (ITEM_TYPE_GLOBAL, GLOBAL_REPORT_SIZE) => parser.report_size = data,
+            (ITEM_TYPE_GLOBAL, GLOBAL_REPORT_ID) => parser.report_id = Some(data as u8),
+            (ITEM_TYPE_GLOBAL, GLOBAL_REPORT_COUNT) => parser.report_count = data,
+            (ITEM_TYPE_LOCAL, LOCAL_USAGE) => {
+                let _ = parser.usages.try_push(parser.usage(data, size));
+            },
+            (ITEM_TYPE_LOCAL, LOCAL_USAGE_MINIMUM) => parser.usage_minimum = Some(parser.usage(data, size)),
+            (ITEM_TYPE_LOCAL, LOCAL_USAGE_MAXIMUM) => parser.usage_maximum = Some(parser.usage(data, size).1),
+            (ITEM_TYPE_MAIN, MAIN_INPUT) => {
+                let report_size = parser.report_size;
+                let report_count = parser.report_count;
+                let first_bit = parser.take_bit_offset(report_size * report_count)?;
+                if data & INPUT_CONSTANT == 0 {
+                    for i in 0..report_count {
+                        let Some(usage) = parser.field_usage(i)
+                        else {
+                            break;
+                        };
+                        let field = ReportField {
+                            bit_offset: first_bit + i * report_size,
+                            bit_size: report_size,
+                            logical_minimum: parser.logical_minimum,
+                            logical_maximum: parser.logical_maximum,
+                            relative: data & INPUT_RELATIVE != 0
+                        };
+                        let field = (parser.report_id, field);
+                        match usage {
+                            (USAGE_PAGE_GENERIC_DESKTOP, USAGE_X) if x.is_none() => x = Some(field),
+                            (USAGE_PAGE_GENERIC_DESKTOP, USAGE_Y) if y.is_none() => y = Some(field),
+                            (USAGE_PAGE_GENERIC_DESKTOP, USAGE_WHEEL) if wheel.is_none() => {
+                                wheel = Some(field)
+                            },
+                            (USAGE_PAGE_BUTTON, button @ 1..=5) if buttons[button as usize - 1].is_none() => {
+                                buttons[button as usize - 1] = Some(field);
+                            },
+                            _ => {}
+                        }
+                    }
+                }
+                parser.clear_local_items();
+            },
+            // Local items only apply to the next main item, be it an input, output, feature or collection
+            (ITEM_TYPE_MAIN, _) => parser.clear_local_items(),
+            _ => {}
+        }
+    }
+
+    let (report_id, x) = x?;
+    let (y_report_id, y) = y?;
+    if y_report_id != report_id {
+        return None;
+    }
+    let in_report =
+        |field: Option<(Option<u8>, ReportField)>| field.filter(|(id, _)| *id == report_id).map(|(_, f)| f);
+    Some(PointerLayout {
+        report_id,
+        x,
+        y,
+        wheel: in_report(wheel),
+        buttons: buttons.map(in_report)
+    })
+}
+
+fn class_request(device: UsbDevice, interface_number: u8, request: u8, value: u16) -> Result<(), UsbError> {
+    let setup = SetupPacket {
+        request_type: REQUEST_TYPE_CLASS | REQUEST_RECIPIENT_INTERFACE,
+        request,
+        value,
+        index: interface_number as u16,
+        length: 0
+    };
+    device.control_transfer(setup, &mut [])?;
+    Ok(())
+}
+
+fn interrupt_in_endpoint(interface: &InterfaceInfo) -> Result<EndpointDescriptor, UsbError> {
+    interface
+        .endpoints
+        .iter()
+        .find(|e| e.transfer_type() == EndpointTransferType::Interrupt && e.is_in())
+        .copied()
+        .ok_or(UsbError::InvalidDescriptor)
+}
+
+/// Reads the report descriptor, the length of which is given in the HID descriptor among the class
+/// descriptors of the interface. Returns the number of bytes read.
+fn read_report_descriptor(
+    device: UsbDevice,
+    interface: &InterfaceInfo,
+    buffer: &mut [u8; MAX_REPORT_DESCRIPTOR_SIZE]
+) -> Result<usize, UsbError> {
+    // The HID descriptor is followed by (type, length) pairs of the descriptors the device has
+    let hid_descriptor = interface.class_descriptors;
+    if hid_descriptor.len() < 9 || hid_descriptor[1] != DESCRIPTOR_TYPE_HID {
+        return Err(UsbError::InvalidDescriptor);
+    }
+    let descriptor_count = hid_descriptor[5] as usize;
+    let length = (0..descriptor_count)
+        .map(|i| 6 + 3 * i)
+        .filter(|offset| offset + 3 <= hid_descriptor.len())
+        .find(|offset| hid_descriptor[*offset] == DESCRIPTOR_TYPE_REPORT)
+        .map(|offset| u16::from_le_bytes([hid_descriptor[offset + 1], hid_descriptor[offset + 2]]))
+        .ok_or(UsbError::InvalidDescriptor)?;
+    let length = (length as usize).min(MAX_REPORT_DESCRIPTOR_SIZE);
+    let setup = SetupPacket {
+        request_type: REQUEST_TYPE_DEVICE_TO_HOST | REQUEST_TYPE_STANDARD | REQUEST_RECIPIENT_INTERFACE,
+        request: REQUEST_GET_DESCRIPTOR,
+        value: (DESCRIPTOR_TYPE_REPORT as u16) << 8,
+        index: interface.descriptor.interface_number as u16,
+        length: length as u16
+    };
+    device.control_transfer(setup, &mut buffer[..length])
+}
+
+/// The previous report of every keyboard, which the next one is compared against to find out which keys
+/// were pressed and released
+static KEYBOARDS: Mutex<ArrayVec<[u8; BOOT_KEYBOARD_REPORT_SIZE], MAX_KEYBOARDS>> =
+    Mutex::new(ArrayVec::new_const());
+
+/// Boot protocol keyboard reports consist of the modifier bits, a reserved byte and the usage ids of up to
+/// six keys being held down.
+fn handle_keyboard_report(report: &[u8], keyboard_index: u64) {
+    let Ok(report) = <[u8; BOOT_KEYBOARD_REPORT_SIZE]>::try_from(&report[..report.len().min(8)])
+    else {
+        return;
+    };
+    if report[2..].iter().all(|key| *key == KEY_ERROR_ROLL_OVER) {
+        return;
+    }
+    // Always called with interrupts disabled, either from the interrupt handler or while the controller is
+    // polled with its lock held
+    let mut keyboards = KEYBOARDS.lock();
+    let Some(previous) = keyboards.get_mut(keyboard_index as usize)
+    else {
+        return;
+    };
+    let modifiers = KeyModifiers::from_bits_retain(report[0]);
+    let changed_modifiers = previous[0] ^ report[0];
+    for bit in 0..8 {
+        if changed_modifiers & (1 << bit) != 0 {
+            keyboard::push_event(KeyEvent {
+                key: KEY_LEFT_CONTROL + bit,
+                pressed: report[0] & (1 << bit) != 0,
+                modifiers
+            });
+        }
+    }
+    let keys = |report: &[u8; BOOT_KEYBOARD_REPORT_SIZE]| {
+        report[2..]
+            .iter()
+            .copied()
+            .filter(|key| *key > KEY_ERROR_ROLL_OVER)
+            .collect::<ArrayVec<u8, 6>>()
+    };
+    let (old_keys, new_keys) = (keys(previous), keys(&report));
+    for key in old_keys.iter().filter(|key| !new_keys.contains(key)) {
+        keyboard::push_event(KeyEvent {
+            key: *key,
+            pressed: false,
+            modifiers
+        });
+    }
+    for key in new_keys.iter().filter(|key| !old_keys.contains(key)) {
+        keyboard::push_event(KeyEvent {
+            key: *key,
+            pressed: true,
+            modifiers
+        });
+    }
+    *previous = report;
+}
+
+fn probe_keyboard(device: UsbDevice, interface: &InterfaceInfo) -> Result<(), UsbError> {
+    let endpoint = interrupt_in_endpoint(interface)?;
+    let interface_number = interface.descriptor.interface_number;
+    class_request(device, interface_number, REQUEST_SET_PROTOCOL, PROTOCOL_BOOT)?;
+    // Only report when something changes. Not every keyboard supports this, but they should all at least
+    // not send more often than their default idle rate.
+    let _ = class_request(device, interface_number, REQUEST_SET_IDLE, 0);
+    device.configure_endpoint(&endpoint)?;
+
+    let index = without_interrupts(|| {
+        let mut keyboards = KEYBOARDS.lock();
+        keyboards
+            .try_push([0; BOOT_KEYBOARD_REPORT_SIZE])
+            .map(|_| keyboards.len() - 1)
+            .map_err(|_| UsbError::OutOfMemory)
+    })?;
+    let length = (endpoint.max_packet_size() as usize).max(BOOT_KEYBOARD_REPORT_SIZE);
+    device.start_interrupt_transfers(
+        endpoint.endpoint_address,
+        length,
+        handle_keyboard_report,
+        index as u64
+    )
+}
+
+static POINTERS: Mutex<ArrayVec<PointerLayout, MAX_POINTERS>> = Mutex::new(ArrayVec::new_const());
+
+/// Scales an absolute axis value to `0..=ABSOLUTE_POSITION_MAX`.
+fn scale_absolute(field: &ReportField, value: i32) -> u16 {
+    let range = (field.logical_maximum as i64 - field.logical_minimum as i64).max(1);
+    let value = (value as i64 - field.logical_minimum as i64).clamp(0, range);
+    (value * ABSOLUTE_POSITION_MAX as i64 / range) as u16
+}
+
+fn handle_pointer_report(report: &[u8], pointer_index: u64) {
+    let Some(layout) = without_interrupts(|| POINTERS.lock().get(pointer_index as usize).copied())
+    else {
+        return;
+    };
+    let report = match layout.report_id {
+        Some(id) if report.first() != Some(&id) => return,
+        Some(_) => &report[1..],
+        None => report
+    };
+    let (Some(x), Some(y)) = (layout.x.extract(report), layout.y.extract(report))
+    else {
+        return;
+    };
+    let mut buttons = MouseButtons::empty();
+    for (i, field) in layout.buttons.iter().enumerate() {
+        if field
+            .and_then(|f| f.extract(report))
+            .is_some_and(|pressed| pressed != 0)
+        {
+            buttons |= MouseButtons::from_bits_truncate(1 << i);
+        }
+    }
+    let dz = layout
+        .wheel
+        .and_then(|f| f.extract(report))
+        .unwrap_or(0)
+        .clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+    // HID devices report downwards movement as positive, unlike PS/2 mice. The wheel is reported the same
+    // way, with positive values being away from the user.
+    let event = match layout.x.relative {
+        true => MouseEvent {
+            dx: x.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            dy: (-y).clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            dz: dz.saturating_neg(),
+            buttons,
+            absolute_position: None
+        },
+        false => MouseEvent {
+            dx: 0,
+            dy: 0,
+            dz: dz.saturating_neg(),
+            buttons,
+            absolute_position: Some((scale_absolute(&layout.x, x), scale_absolute(&layout.y, y)))
+        }
+    };
+    mouse::push_event(event);
+}
+
+/// Handles mice, which support the boot protocol, as well as tablets and other pointing devices, which only
+/// have their own report format. The report protocol is used whenever the report descriptor can be made
+/// sense of, as it's the only way to get at the scroll wheel and absolute positions.
+fn probe_pointer(device: UsbDevice, interface: &InterfaceInfo) -> Result<(), UsbError> {
+    let endpoint = interrupt_in_endpoint(interface)?;
+    let interface_number = interface.descriptor.interface_number;
+    let is_boot_mouse = interface.descriptor.interface_subclass == SUBCLASS_BOOT
+        && interface.descriptor.interface_protocol == PROTOCOL_MOUSE;
+
+    let mut report_descriptor = [0; MAX_REPORT_DESCRIPTOR_SIZE];
+    let layout = read_report_descriptor(device, interface, &mut report_descriptor)
+        .ok()
+        .and_then(|length| parse_pointer_layout(&report_descriptor[..length]));
+    let layout = match layout {
+        Some(layout) => {
+            if is_boot_mouse {
+                // Devices start out in the report protocol, but the firmware may have switched them to the
+                // boot protocol
+                class_request(device, interface_number, REQUEST_SET_PROTOCOL, PROTOCOL_REPORT)?;
+            }
+            layout
+        },
+        None if is_boot_mouse => {
+            class_request(device, interface_number, REQUEST_SET_PROTOCOL, PROTOCOL_BOOT)?;
+            BOOT_MOUSE_LAYOUT
+        },
+        None => return Err(UsbError::Unsupported)
+    };
+    let _ = class_request(device, interface_number, REQUEST_SET_IDLE, 0);
+    device.configure_endpoint(&endpoint)?;
+
+    let index = without_interrupts(|| {
+        let mut pointers = POINTERS.lock();
+        pointers
+            .try_push(layout)
+            .map(|_| pointers.len() - 1)
+            .map_err(|_| UsbError::OutOfMemory)
+    })?;
+    device.start_interrupt_transfers(
+        endpoint.endpoint_address,
+        endpoint.max_packet_size() as usize,
+        handle_pointer_report,
+        index as u64
+    )
+}
+
+pub static USB_KEYBOARD_DRIVER: UsbDriver = UsbDriver {
+    name: "usb-keyboard",
+    ids: &[UsbInterfaceId::class(
+        CLASS_HID,
+        Some(SUBCLASS_BOOT),
+        Some(PROTOCOL_KEYBOARD)
+    )],
+    probe: probe_keyboard
+};
+
+/// Matches every HID interface, and only accepts those whose reports have an X and a Y axis. Should be
+/// registered after the keyboard driver.
+pub static USB_POINTER_DRIVER: UsbDriver = UsbDriver {
+    name: "usb-pointer",
+    ids: &[UsbInterfaceId::class(CLASS_HID, None, None)],
+    probe: probe_pointer
+};
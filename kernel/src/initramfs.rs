@@ -0,0 +1,239 @@
+use alloc::format;
+use alloc::string::String;
+use core::{slice, str};
+
+use crate::vfs::{self, OpenFile, OpenFlags, VfsError};
+
+/// Limine module with this command line (MODULE_CMDLINE in limine.cfg) is the initramfs
+const MODULE_CMDLINE: &[u8] = b"initramfs";
+
+const USTAR_BLOCK_SIZE: usize = 512;
+const USTAR_MAGIC: &[u8] = b"ustar";
+const USTAR_TYPE_REGULAR: u8 = b'0';
+/// Old tar implementations mark regular files with a zero byte rather than '0'
+const USTAR_TYPE_REGULAR_OLD: u8 = 0;
+const USTAR_TYPE_SYMLINK: u8 = b'2';
+const USTAR_TYPE_DIRECTORY: u8 = b'5';
+
+const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+/// Like newc, but with a checksum of the data, which isn't checked
+const CPIO_NEWC_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER_NAME: &str = "TRAILER!!!";
+/// The file type bits of a cpio mode
+const CPIO_MODE_TYPE_MASK: u32 = 0o170000;
+const CPIO_MODE_DIRECTORY: u32 = 0o040000;
+const CPIO_MODE_REGULAR: u32 = 0o100000;
+const CPIO_MODE_SYMLINK: u32 = 0o120000;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum InitramfsError {
+    /// Limine didn't load a module with the initramfs command line
+    NotFound,
+    /// The archive is neither a ustar nor a cpio newc archive
+    UnknownFormat,
+    /// A header is malformed, or the archive ends in the middle of an entry
+    Corrupted,
+    /// Creating one of the files failed
+    FileSystemError(VfsError)
+}
+
+impl From<VfsError> for InitramfsError {
+    fn from(error: VfsError) -> Self {
+        InitramfsError::FileSystemError(error)
+    }
+}
+
+/// What an archive entry is, and what's unpacked of it. Other kinds of entries are skipped.
+enum EntryKind<'a> {
+    Directory,
+    File(&'a [u8]),
+    /// A symbolic link to the path given
+    Symlink(&'a str)
+}
+
+/// Parses an octal or hexadecimal number in ASCII, which may be padded with spaces and zero bytes.
+fn parse_number(field: &[u8], radix: u32) -> Result<u32, InitramfsError> {
+    let text = str::from_utf8(field).map_err(|_| InitramfsError::Corrupted)?;
+    let text = text.trim_matches(|c| c == ' ' || c == '\0');
+    match text {
+        "" => Ok(0),
+        _ => u32::from_str_radix(text, radix).map_err(|_| InitramfsError::Corrupted)
+    }
+}
+
+/// The part of a field before the first zero byte.
+fn until_nul(field: &[u8]) -> &[u8] {
+    let length = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..length]
+}
+
+/// Creates a directory unless it already exists.
+fn create_directory(path: &str, mode: u16) -> Result<(), VfsError> {
+    match vfs::create_directory(path, mode) {
+        Ok(()) | Err(VfsError::AlreadyExists) => Ok(()),
+        Err(e) => Err(e)
+    }
+}
+
+/// Creates every missing directory on the way to `path`, as archives don't have to have entries for them.
+fn create_parent_directories(path: &str) -> Result<(), VfsError> {
+    let end = path.rfind('/').unwrap_or(0);
+    for (i, _) in path[..end].match_indices('/').skip(1) {
+        create_directory(&path[..i], 0o755)?;
+    }
+    if end > 0 {
+        create_directory(&path[..end], 0o755)?;
+    }
+    Ok(())
+}
+
+/// Creates an entry of the archive under `destination`, along with any missing parent directories.
+fn unpack_entry(destination: &str, name: &str, mode: u16, kind: EntryKind) -> Result<(), InitramfsError> {
+    let name = name.trim_start_matches("./").trim_matches('/');
+    if name.is_empty() || name == "." {
+        return Ok(());
+    }
+    let path = format!("{}/{}", destination.trim_end_matches('/'), name);
+    create_parent_directories(&path)?;
+    match kind {
+        EntryKind::Directory => create_directory(&path, mode)?,
+        EntryKind::File(data) => {
+            let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+            let file = OpenFile::open(&path, flags, mode)?;
+            file.write(data)?;
+        },
+        EntryKind::Symlink(target) => vfs::symlink(target, &path)?
+    }
+    Ok(())
+}
+
+/// Unpacks a POSIX ustar archive, which is a sequence of 512 byte headers each followed by the entry's data
+/// padded to 512 bytes, and ends with blocks of zeroes.
+fn unpack_ustar(archive: &[u8], destination: &str) -> Result<usize, InitramfsError> {
+    let mut offset = 0;
+    let mut count = 0;
+    while let Some(header) = archive.get(offset..offset + USTAR_BLOCK_SIZE) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if &header[257..262] != USTAR_MAGIC {
+            return Err(InitramfsError::Corrupted);
+        }
+        let size = parse_number(&header[124..136], 8)? as usize;
+        let data_offset = offset + USTAR_BLOCK_SIZE;
+        let data = archive
+            .get(data_offset..data_offset + size)
+            .ok_or(InitramfsError::Corrupted)?;
+        offset = data_offset + size.next_multiple_of(USTAR_BLOCK_SIZE);
+
+        // Names longer than 100 bytes are split between the name and a prefix
+        let name = until_nul(&header[0..100]);
+        let prefix = until_nul(&header[345..500]);
+        let name = str::from_utf8(name).map_err(|_| InitramfsError::Corrupted)?;
+        let prefix = str::from_utf8(prefix).map_err(|_| InitramfsError::Corrupted)?;
+        let name = match prefix {
+            "" => String::from(name),
+            _ => format!("{}/{}", prefix, name)
+        };
+        let mode = parse_number(&header[100..108], 8)? as u16;
+        let kind = match header[156] {
+            USTAR_TYPE_REGULAR | USTAR_TYPE_REGULAR_OLD => EntryKind::File(data),
+            USTAR_TYPE_DIRECTORY => EntryKind::Directory,
+            USTAR_TYPE_SYMLINK => {
+                let target = until_nul(&header[157..257]);
+                EntryKind::Symlink(str::from_utf8(target).map_err(|_| InitramfsError::Corrupted)?)
+            },
+            _ => continue
+        };
+        unpack_entry(destination, &name, mode, kind)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Unpacks a cpio archive in the "new ASCII" format, which is a sequence of 110 byte headers each followed
+/// by the entry's name and data, both padded to 4 bytes, and ends with an entry named "TRAILER!!!".
+fn unpack_cpio(archive: &[u8], destination: &str) -> Result<usize, InitramfsError> {
+    let mut offset = 0;
+    let mut count = 0;
+    loop {
+        let header = archive
+            .get(offset..offset + CPIO_HEADER_SIZE)
+            .ok_or(InitramfsError::Corrupted)?;
+        if &header[..6] != CPIO_NEWC_MAGIC && &header[..6] != CPIO_NEWC_CRC_MAGIC {
+            return Err(InitramfsError::Corrupted);
+        }
+        // Every field is 8 hexadecimal digits following the magic
+        let field = |index: usize| parse_number(&header[6 + index * 8..14 + index * 8], 16);
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_offset = offset + CPIO_HEADER_SIZE;
+        let name = archive
+            .get(name_offset..name_offset + name_size)
+            .ok_or(InitramfsError::Corrupted)?;
+        let name = str::from_utf8(until_nul(name)).map_err(|_| InitramfsError::Corrupted)?;
+        if name == CPIO_TRAILER_NAME {
+            return Ok(count);
+        }
+        let data_offset = (name_offset + name_size).next_multiple_of(4);
+        let data = archive
+            .get(data_offset..data_offset + size)
+            .ok_or(InitramfsError::Corrupted)?;
+        offset = (data_offset + size).next_multiple_of(4);
+
+        let kind = match mode & CPIO_MODE_TYPE_MASK {
+            CPIO_MODE_REGULAR => EntryKind::File(data),
+            CPIO_MODE_DIRECTORY => EntryKind::Directory,
+            // The target of a symbolic link is its data
+            CPIO_MODE_SYMLINK => {
+                EntryKind::Symlink(str::from_utf8(data).map_err(|_| InitramfsError::Corrupted)?)
+            },
+            _ => continue
+        };
+        unpack_entry(destination, name, mode as u16, kind)?;
+        count += 1;
+    }
+}
+
+/// Unpacks a ustar or cpio newc archive into the directory `destination`, returning how many files and
+/// directories and symbolic links were created.
+pub fn unpack(archive: &[u8], destination: &str) -> Result<usize, InitramfsError> {
+    if archive.starts_with(CPIO_NEWC_MAGIC) || archive.starts_with(CPIO_NEWC_CRC_MAGIC) {
+        unpack_cpio(archive, destination)
+    }
+    else if archive.get(257..262) == Some(USTAR_MAGIC) {
+        unpack_ustar(archive, destination)
+    }
+    else {
+        Err(InitramfsError::UnknownFormat)
+    }
+}
+
+/// Compares a null-terminated string given by Limine with `expected`.
+fn c_string_equals(string: *const u8, expected: &[u8]) -> bool {
+    if string.is_null() {
+        return false;
+    }
+    let length = (0..).take_while(|&i| unsafe { *string.add(i) } != 0).count();
+    unsafe { slice::from_raw_parts(string, length) == expected }
+}
+
+/// The contents of the initramfs module loaded by Limine, which stays in memory for as long as the kernel
+/// runs.
+pub fn module() -> Result<&'static [u8], InitramfsError> {
+    let response = crate::LIMINE_MODULE_REQUEST.response;
+    if response.is_null() {
+        return Err(InitramfsError::NotFound);
+    }
+    let response = unsafe { &*response };
+    let modules = unsafe { slice::from_raw_parts(response.modules, response.module_count as usize) };
+    let module = modules
+        .iter()
+        .map(|&module| unsafe { &*module })
+        .find(|module| c_string_equals(module.cmdline, MODULE_CMDLINE))
+        .ok_or(InitramfsError::NotFound)?;
+    Ok(unsafe { slice::from_raw_parts(module.address, module.size as usize) })
+}
@@ -1,32 +1,222 @@
-use core::arch::asm;
+use core::arch::{asm, global_asm};
+use core::mem::offset_of;
 use core::panic;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use spin::Lazy;
+use spin::{Lazy, Mutex};
 
-use crate::interrupts_general::{Idt, InterruptStackFrame, SegmentSelectorErrorCode};
-use crate::println;
+use crate::interrupts_general::{
+    enable_interrupts, without_interrupts, Idt, InterruptStackFrame, SegmentSelectorErrorCode
+};
+use crate::pic::{self, PIC_1_OFFSET};
+use crate::signal::{
+    self, SignalInfo, BUS_ADRALN, FPE_INTDIV, ILL_ILLOPN, SEGV_ACCERR, SEGV_MAPERR, SIGBUS, SIGFPE, SIGILL,
+    SIGSEGV, SIGTRAP, SI_KERNEL, TRAP_BRKPT, TRAP_TRACE
+};
+use crate::syscall::UserContext;
+use crate::virtual_memory::Access;
+use crate::{apic, mouse, pit, println, process, ps2, scheduler};
+
+/// Vectors `DYNAMIC_VECTORS_START..=DYNAMIC_VECTORS_END` are handed out at runtime by `allocate_vector()`,
+/// e.g. for MSIs. The vectors below them are used by CPU exceptions and the 8259 PIC, and the one above them
+/// is the local APIC's spurious interrupt vector.
+pub const DYNAMIC_VECTORS_START: u8 = 0x30;
+pub const DYNAMIC_VECTORS_END: u8 = 0xef;
+const DIVIDE_ERROR_VECTOR: u8 = 0;
+const DEBUG_VECTOR: u8 = 1;
+const BREAKPOINT_VECTOR: u8 = 3;
+const INVALID_OPCODE_VECTOR: u8 = 6;
+const STACK_SEGMENT_VECTOR: u8 = 12;
+const GENERAL_PROTECTION_VECTOR: u8 = 13;
+const PAGE_FAULT_VECTOR: u8 = 14;
+const X87_FLOATING_POINT_VECTOR: u8 = 16;
+const ALIGNMENT_CHECK_VECTOR: u8 = 17;
+const SIMD_FLOATING_POINT_VECTOR: u8 = 19;
+const TIMER_VECTOR: u8 = PIC_1_OFFSET + pit::IRQ;
+/// Page fault error code bits
+const PAGE_FAULT_PRESENT: u64 = 1 << 0;
+const PAGE_FAULT_WRITE: u64 = 1 << 1;
+const PAGE_FAULT_INSTRUCTION_FETCH: u64 = 1 << 4;
+/// Set in DR6 when a debug exception comes from single-stepping with the trap flag. It's sticky, so DR6 is
+/// cleared after every debug exception.
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+/// What DR6 reads as without any debug exception conditions, as its reserved bits read as set
+const DR6_CLEAR: u64 = 0xffff0ff0;
+
+/// A handler for a dynamically allocated vector, called with the vector and the context value it was
+/// registered with.
+pub type DynamicInterruptHandler = fn(vector: u8, context: u64);
+
+struct VectorAllocator {
+    allocated: [u64; 4]
+}
+
+impl VectorAllocator {
+    #[inline]
+    fn is_allocated(&self, vector: u8) -> bool {
+        self.allocated[vector as usize / 64] & (1 << (vector % 64)) != 0
+    }
+
+    #[inline]
+    fn set_allocated(&mut self, vector: u8, allocated: bool) {
+        match allocated {
+            true => self.allocated[vector as usize / 64] |= 1 << (vector % 64),
+            false => self.allocated[vector as usize / 64] &= !(1 << (vector % 64))
+        }
+    }
+}
+
+/// How many times each vector has been raised, for statistics
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+static VECTOR_ALLOCATOR: Mutex<VectorAllocator> = Mutex::new(VectorAllocator { allocated: [0; 4] });
+static DYNAMIC_HANDLERS: Mutex<[Option<(DynamicInterruptHandler, u64)>; 256]> = Mutex::new([None; 256]);
 
 static IDT: Lazy<Idt> = Lazy::new(|| {
     let mut idt = Idt::new();
-    idt.breakpoint.set_to_handler(breakpoint_interrupt);
     idt.double_fault.set_to_handler(double_fault_interrupt);
-    idt.general_protection
-        .set_to_handler(general_protection_interrupt);
     idt.segment_not_present
         .set_to_handler(segment_not_present_interrupt);
-    idt.page_fault.set_to_handler(page_fault);
+    unsafe {
+        idt.divide_by_zero
+            .set_to_entry_stub(trap_divide_error as *const ());
+        // User mode raises debug exceptions with `int1` or by single-stepping with the trap flag, and
+        // breakpoints with `int3`, which all become SIGTRAP
+        idt.debug.set_to_entry_stub(trap_debug as *const ());
+        idt.breakpoint
+            .set_to_user_callable_entry_stub(trap_breakpoint as *const ());
+        idt.invalid_opcode
+            .set_to_entry_stub(trap_invalid_opcode as *const ());
+        idt.stack.set_to_entry_stub(trap_stack_segment as *const ());
+        idt.general_protection
+            .set_to_entry_stub(trap_general_protection as *const ());
+        idt.page_fault.set_to_entry_stub(trap_page_fault as *const ());
+        idt.floating_point_exception_pending
+            .set_to_entry_stub(trap_x87_floating_point as *const ());
+        idt.alignment_check
+            .set_to_entry_stub(trap_alignment_check as *const ());
+        idt.simd_floating_point
+            .set_to_entry_stub(trap_simd_floating_point as *const ());
+        idt[TIMER_VECTOR as usize].set_to_entry_stub(trap_timer as *const ());
+    }
+    idt[(PIC_1_OFFSET + ps2::AUX_PORT_IRQ) as usize].set_to_handler(ps2_mouse_interrupt);
+    let dynamic_handler_rows: [[extern "x86-interrupt" fn(InterruptStackFrame); 16]; 12] = [
+        dynamic_handler_row!(0x3),
+        dynamic_handler_row!(0x4),
+        dynamic_handler_row!(0x5),
+        dynamic_handler_row!(0x6),
+        dynamic_handler_row!(0x7),
+        dynamic_handler_row!(0x8),
+        dynamic_handler_row!(0x9),
+        dynamic_handler_row!(0xa),
+        dynamic_handler_row!(0xb),
+        dynamic_handler_row!(0xc),
+        dynamic_handler_row!(0xd),
+        dynamic_handler_row!(0xe)
+    ];
+    for (i, handler) in dynamic_handler_rows.iter().flatten().enumerate() {
+        idt[DYNAMIC_VECTORS_START as usize + i].set_to_handler(*handler);
+    }
+    idt[apic::SPURIOUS_INTERRUPT_VECTOR as usize].set_to_handler(spurious_interrupt);
     idt
 });
 
+/// Expands to the dynamic vector handlers for the 16 vectors starting at `$high * 16`.
+macro_rules! dynamic_handler_row {
+    ($high:literal) => {
+        [
+            dynamic_handler::<{ $high * 16 }>,
+            dynamic_handler::<{ $high * 16 + 1 }>,
+            dynamic_handler::<{ $high * 16 + 2 }>,
+            dynamic_handler::<{ $high * 16 + 3 }>,
+            dynamic_handler::<{ $high * 16 + 4 }>,
+            dynamic_handler::<{ $high * 16 + 5 }>,
+            dynamic_handler::<{ $high * 16 + 6 }>,
+            dynamic_handler::<{ $high * 16 + 7 }>,
+            dynamic_handler::<{ $high * 16 + 8 }>,
+            dynamic_handler::<{ $high * 16 + 9 }>,
+            dynamic_handler::<{ $high * 16 + 10 }>,
+            dynamic_handler::<{ $high * 16 + 11 }>,
+            dynamic_handler::<{ $high * 16 + 12 }>,
+            dynamic_handler::<{ $high * 16 + 13 }>,
+            dynamic_handler::<{ $high * 16 + 14 }>,
+            dynamic_handler::<{ $high * 16 + 15 }>
+        ]
+    };
+}
+use dynamic_handler_row;
+
 pub fn load_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_interrupt(stack_frame: InterruptStackFrame) {
-    println!(
-        "EXCEPTION: Breakpoint exception occurred! Stack frame: \n{:#?}",
-        stack_frame
-    );
+#[inline]
+fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How many times an interrupt or exception has arrived on `vector` since boot.
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Reserves a single free vector from the dynamic range.
+pub fn allocate_vector() -> Option<u8> {
+    allocate_vectors(1)
+}
+
+/// Reserves `count` consecutive free vectors from the dynamic range and returns the first one. `count` must
+/// be a power of two, and the first vector is aligned to it, as multi-message MSI requires that.
+pub fn allocate_vectors(count: u8) -> Option<u8> {
+    if count == 0 || !count.is_power_of_two() {
+        return None;
+    }
+    without_interrupts(|| {
+        let mut allocator = VECTOR_ALLOCATOR.lock();
+        let mut first = DYNAMIC_VECTORS_START.next_multiple_of(count);
+        while first as u16 + count as u16 - 1 <= DYNAMIC_VECTORS_END as u16 {
+            if (first..first + count).all(|v| !allocator.is_allocated(v)) {
+                for vector in first..first + count {
+                    allocator.set_allocated(vector, true);
+                }
+                return Some(first);
+            }
+            first += count;
+        }
+        None
+    })
+}
+
+/// Returns a vector to the allocator, unregistering its handler.
+pub fn free_vector(vector: u8) {
+    without_interrupts(|| {
+        DYNAMIC_HANDLERS.lock()[vector as usize] = None;
+        VECTOR_ALLOCATOR.lock().set_allocated(vector, false);
+    });
+}
+
+/// Sets the function called when an interrupt arrives on `vector`, which should have been allocated with
+/// `allocate_vector()`. The end of interrupt is signaled to the local APIC after the handler returns.
+pub fn register_handler(vector: u8, handler: DynamicInterruptHandler, context: u64) {
+    if !(DYNAMIC_VECTORS_START..=DYNAMIC_VECTORS_END).contains(&vector) {
+        panic!("Vector {:#x} is not in the dynamically allocated range", vector);
+    }
+    without_interrupts(|| DYNAMIC_HANDLERS.lock()[vector as usize] = Some((handler, context)));
+}
+
+extern "x86-interrupt" fn dynamic_handler<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    count_interrupt(VECTOR);
+    // Copied out of the table, so that the handler is free to register or free vectors itself
+    let handler = DYNAMIC_HANDLERS.lock()[VECTOR as usize];
+    if let Some((handler, context)) = handler {
+        handler(VECTOR, context);
+    }
+    apic::send_end_of_interrupt();
+}
+
+/// Spurious interrupts don't get an end of interrupt, as they aren't actually in service.
+extern "x86-interrupt" fn spurious_interrupt(_stack_frame: InterruptStackFrame) {
+    count_interrupt(apic::SPURIOUS_INTERRUPT_VECTOR);
 }
 
 extern "x86-interrupt" fn double_fault_interrupt(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
@@ -36,22 +226,6 @@ extern "x86-interrupt" fn double_fault_interrupt(stack_frame: InterruptStackFram
     );
 }
 
-extern "x86-interrupt" fn general_protection_interrupt(stack_frame: InterruptStackFrame, error_code: u64) {
-    match error_code {
-        0 => panic!(
-            "EXCEPTION: General protection occurred! Stack frame: \n{:#?}\nError not related to a segment \
-             descriptor access.",
-            stack_frame,
-        ),
-        _ => panic!(
-            "EXCEPTION: General protection occurred! Stack frame: \n{:#?}\nError related to a segment \
-             descriptor access. Segment descriptor in question: {:?}",
-            stack_frame,
-            SegmentSelectorErrorCode(error_code as u16)
-        )
-    }
-}
-
 extern "x86-interrupt" fn segment_not_present_interrupt(stack_frame: InterruptStackFrame, error_code: u64) {
     panic!(
         "EXCEPTION: Segment not present occurred! Stack frame: \n{:#?}\nError code: {:?}",
@@ -60,17 +234,294 @@ extern "x86-interrupt" fn segment_not_present_interrupt(stack_frame: InterruptSt
     );
 }
 
-extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame, error_code: u64) {
-    let reg_cr2: u64;
+// The exceptions user mode can cause and the timer interrupt, which switches threads, enter through stubs
+// that save every register as a `UserContext`, so that signals can be delivered before returning to user
+// mode, by changing the context. The stubs of vectors without an error code push R15 themselves, the others
+// swap it with the error code the CPU pushed. Each stub passes its vector in R14 to the common part, which
+// returns to user mode the same way system calls do.
+global_asm!(
+    "trap_common:",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rbx",
+    "push rax",
+    "cld",
+    "mov rdi, rsp",
+    "mov rsi, r15",
+    "mov rdx, r14",
+    "call {handler}",
+    "test byte ptr [rsp + {cs_offset}], 3",
+    "jnz return_to_user_mode_from_stack",
+    "pop rax",
+    "pop rbx",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "iretq",
+    ".global trap_divide_error",
+    "trap_divide_error:",
+    "push r15",
+    "xor r15d, r15d",
+    "push r14",
+    "mov r14d, {divide_error}",
+    "jmp trap_common",
+    ".global trap_debug",
+    "trap_debug:",
+    "push r15",
+    "xor r15d, r15d",
+    "push r14",
+    "mov r14d, {debug}",
+    "jmp trap_common",
+    ".global trap_breakpoint",
+    "trap_breakpoint:",
+    "push r15",
+    "xor r15d, r15d",
+    "push r14",
+    "mov r14d, {breakpoint}",
+    "jmp trap_common",
+    ".global trap_invalid_opcode",
+    "trap_invalid_opcode:",
+    "push r15",
+    "xor r15d, r15d",
+    "push r14",
+    "mov r14d, {invalid_opcode}",
+    "jmp trap_common",
+    ".global trap_stack_segment",
+    "trap_stack_segment:",
+    "xchg r15, [rsp]",
+    "push r14",
+    "mov r14d, {stack_segment}",
+    "jmp trap_common",
+    ".global trap_general_protection",
+    "trap_general_protection:",
+    "xchg r15, [rsp]",
+    "push r14",
+    "mov r14d, {general_protection}",
+    "jmp trap_common",
+    ".global trap_page_fault",
+    "trap_page_fault:",
+    "xchg r15, [rsp]",
+    "push r14",
+    "mov r14d, {page_fault}",
+    "jmp trap_common",
+    ".global trap_x87_floating_point",
+    "trap_x87_floating_point:",
+    "push r15",
+    "xor r15d, r15d",
+    "push r14",
+    "mov r14d, {x87_floating_point}",
+    "jmp trap_common",
+    ".global trap_alignment_check",
+    "trap_alignment_check:",
+    "xchg r15, [rsp]",
+    "push r14",
+    "mov r14d, {alignment_check}",
+    "jmp trap_common",
+    ".global trap_simd_floating_point",
+    "trap_simd_floating_point:",
+    "push r15",
+    "xor r15d, r15d",
+    "push r14",
+    "mov r14d, {simd_floating_point}",
+    "jmp trap_common",
+    ".global trap_timer",
+    "trap_timer:",
+    "push r15",
+    "xor r15d, r15d",
+    "push r14",
+    "mov r14d, {timer}",
+    "jmp trap_common",
+    handler = sym handle_trap,
+    cs_offset = const offset_of!(UserContext, cs),
+    divide_error = const DIVIDE_ERROR_VECTOR,
+    debug = const DEBUG_VECTOR,
+    breakpoint = const BREAKPOINT_VECTOR,
+    invalid_opcode = const INVALID_OPCODE_VECTOR,
+    stack_segment = const STACK_SEGMENT_VECTOR,
+    general_protection = const GENERAL_PROTECTION_VECTOR,
+    page_fault = const PAGE_FAULT_VECTOR,
+    x87_floating_point = const X87_FLOATING_POINT_VECTOR,
+    alignment_check = const ALIGNMENT_CHECK_VECTOR,
+    simd_floating_point = const SIMD_FLOATING_POINT_VECTOR,
+    timer = const TIMER_VECTOR
+);
+
+extern "C" {
+    fn trap_divide_error();
+    fn trap_debug();
+    fn trap_breakpoint();
+    fn trap_invalid_opcode();
+    fn trap_stack_segment();
+    fn trap_general_protection();
+    fn trap_page_fault();
+    fn trap_x87_floating_point();
+    fn trap_alignment_check();
+    fn trap_simd_floating_point();
+    fn trap_timer();
+}
+
+/// Handles what enters through the trap stubs, with the interrupted registers in `context`. Exceptions in
+/// user mode become signals for the process, which are delivered along with any other pending ones before it
+/// returns to user mode, while exceptions in the kernel are bugs.
+extern "C" fn handle_trap(context: &mut UserContext, error_code: u64, vector: u64) {
+    let vector = vector as u8;
+    count_interrupt(vector);
+    let user_mode = context.cs & 0b11 == 3;
+    if vector == TIMER_VECTOR {
+        pit::tick();
+        pic::send_end_of_interrupt(pit::IRQ);
+        // The kernel isn't preemptible, so only threads running user code are switched away from
+        if !user_mode {
+            return;
+        }
+        scheduler::preempt();
+    }
+    else {
+        let fault_address = match vector {
+            PAGE_FAULT_VECTOR => read_cr2(),
+            DEBUG_VECTOR => read_and_clear_dr6(),
+            _ => 0
+        };
+        // Breakpoints the kernel hits are only reported
+        if !user_mode && vector == BREAKPOINT_VECTOR {
+            println!(
+                "EXCEPTION: Breakpoint exception occurred! Registers: \n{:#x?}",
+                context
+            );
+            return;
+        }
+        if !user_mode {
+            kernel_exception(context, error_code, vector, fault_address);
+        }
+        // Resolving a page fault may read from a file, which takes interrupts. The kernel isn't preempted, so
+        // the handler itself can't be switched away from.
+        enable_interrupts();
+        user_exception(context, error_code, vector, fault_address);
+    }
+    enable_interrupts();
+    signal::deliver_pending(context, None);
+}
+
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags)) };
+    cr2
+}
+
+fn read_and_clear_dr6() -> u64 {
+    let dr6: u64;
     unsafe {
-        asm!(
-            "mov {cr2_contents}, CR2",
-            cr2_contents = out(reg) reg_cr2
-        );
+        asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack, preserves_flags));
+        asm!("mov dr6, {}", in(reg) DR6_CLEAR, options(nomem, nostack, preserves_flags));
     }
-    panic!(
-        "EXCEPTION: Page fault occurred! Stack frame:\n{:#?}\nError code: {:b}\nAdress of memory access \
-         that generated the page fault: {:X}\n",
-        stack_frame, error_code, reg_cr2
-    );
+    dr6
+}
+
+/// Turns an exception the current process caused into a signal, unless it's a page fault that just needs
+/// the page to be mapped in. `fault_address` is CR2 for page faults and DR6 for debug exceptions.
+fn user_exception(context: &UserContext, error_code: u64, vector: u8, fault_address: u64) {
+    let (signal, info) = match vector {
+        PAGE_FAULT_VECTOR => {
+            let access = if error_code & PAGE_FAULT_INSTRUCTION_FETCH != 0 {
+                Access::Execute
+            }
+            else if error_code & PAGE_FAULT_WRITE != 0 {
+                Access::Write
+            }
+            else {
+                Access::Read
+            };
+            if process::resolve_page_fault(fault_address, access) {
+                return;
+            }
+            let code = match error_code & PAGE_FAULT_PRESENT {
+                0 => SEGV_MAPERR,
+                _ => SEGV_ACCERR
+            };
+            (SIGSEGV, SignalInfo::fault(code, fault_address))
+        },
+        DIVIDE_ERROR_VECTOR => (SIGFPE, SignalInfo::fault(FPE_INTDIV, context.rip)),
+        DEBUG_VECTOR => {
+            let code = match fault_address & DR6_SINGLE_STEP {
+                0 => TRAP_BRKPT,
+                _ => TRAP_TRACE
+            };
+            (SIGTRAP, SignalInfo::fault(code, context.rip))
+        },
+        BREAKPOINT_VECTOR => (SIGTRAP, SignalInfo::fault(SI_KERNEL, 0)),
+        X87_FLOATING_POINT_VECTOR | SIMD_FLOATING_POINT_VECTOR => {
+            (SIGFPE, SignalInfo::fault(SI_KERNEL, context.rip))
+        },
+        INVALID_OPCODE_VECTOR => (SIGILL, SignalInfo::fault(ILL_ILLOPN, context.rip)),
+        ALIGNMENT_CHECK_VECTOR => (SIGBUS, SignalInfo::fault(BUS_ADRALN, 0)),
+        // General protection and stack segment faults, e.g. from non-canonical addresses or privileged
+        // instructions
+        _ => (SIGSEGV, SignalInfo::fault(SI_KERNEL, 0))
+    };
+    signal::force(signal, info);
+}
+
+fn kernel_exception(context: &UserContext, error_code: u64, vector: u8, fault_address: u64) -> ! {
+    match vector {
+        PAGE_FAULT_VECTOR => panic!(
+            "EXCEPTION: Page fault occurred! Registers:\n{:#x?}\nError code: {:b}\nAdress of memory access \
+             that generated the page fault: {:X}\n",
+            context, error_code, fault_address
+        ),
+        GENERAL_PROTECTION_VECTOR if error_code == 0 => panic!(
+            "EXCEPTION: General protection occurred! Registers: \n{:#x?}\nError not related to a segment \
+             descriptor access.",
+            context
+        ),
+        GENERAL_PROTECTION_VECTOR => panic!(
+            "EXCEPTION: General protection occurred! Registers: \n{:#x?}\nError related to a segment \
+             descriptor access. Segment descriptor in question: {:?}",
+            context,
+            SegmentSelectorErrorCode(error_code as u16)
+        ),
+        _ => panic!(
+            "EXCEPTION: {} occurred! Registers: \n{:#x?}\nError code: {:#x}",
+            exception_name(vector),
+            context,
+            error_code
+        )
+    }
+}
+
+fn exception_name(vector: u8) -> &'static str {
+    match vector {
+        DIVIDE_ERROR_VECTOR => "Divide error",
+        DEBUG_VECTOR => "Debug exception",
+        INVALID_OPCODE_VECTOR => "Invalid opcode",
+        STACK_SEGMENT_VECTOR => "Stack segment fault",
+        X87_FLOATING_POINT_VECTOR => "x87 floating point exception",
+        ALIGNMENT_CHECK_VECTOR => "Alignment check",
+        SIMD_FLOATING_POINT_VECTOR => "SIMD floating point exception",
+        _ => "Unknown exception"
+    }
+}
+
+extern "x86-interrupt" fn ps2_mouse_interrupt(_stack_frame: InterruptStackFrame) {
+    count_interrupt(PIC_1_OFFSET + ps2::AUX_PORT_IRQ);
+    mouse::handle_byte(ps2::read_data_unchecked());
+    pic::send_end_of_interrupt(ps2::AUX_PORT_IRQ);
 }
@@ -2,6 +2,67 @@ use core::arch::asm;
 use core::fmt::Debug;
 use core::ops::{Index, IndexMut};
 
+#[inline]
+pub fn enable_interrupts() {
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+}
+
+#[inline]
+pub fn disable_interrupts() {
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+}
+
+/// Stops the CPU until the next interrupt arrives.
+#[inline]
+pub fn halt() {
+    unsafe {
+        asm!("hlt", options(nomem, nostack));
+    }
+}
+
+/// Enables interrupts and stops the CPU until the next interrupt arrives. As `sti` only takes effect after
+/// the following instruction, no interrupt can slip in between the two, so this is how to wait for an
+/// interrupt after checking with interrupts disabled that it hasn't arrived already.
+#[inline]
+pub fn enable_interrupts_and_halt() {
+    unsafe {
+        asm!("sti", "hlt", options(nomem, nostack));
+    }
+}
+
+#[inline]
+pub fn are_interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!(
+            "pushfq",
+            "pop {rflags}",
+            rflags = out(reg) rflags,
+            options(nomem, preserves_flags)
+        );
+    }
+    rflags & (1 << 9) != 0
+}
+
+/// Runs `f` with interrupts disabled, restoring the previous interrupt state afterwards. Data shared with
+/// interrupt handlers must only be locked inside this, because otherwise an interrupt arriving while the lock
+/// is held would deadlock.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let were_enabled = are_interrupts_enabled();
+    if were_enabled {
+        disable_interrupts();
+    }
+    let result = f();
+    if were_enabled {
+        enable_interrupts();
+    }
+    result
+}
+
 #[repr(C, align(16))]
 pub struct InterruptDescriptor {
     handler_address_0_15: u16,
@@ -41,7 +102,18 @@ impl InterruptDescriptor {
         id
     }
 
-    pub fn new(address: *const ()) -> InterruptDescriptor {
+    /// A descriptor that only the CPU and the kernel can raise, as `int` from user mode causes a #GP instead.
+    pub fn kernel_only(address: *const ()) -> InterruptDescriptor {
+        let options = InterruptDescriptorFlags::Present as u16
+            | InterruptDescriptorFlags::TypeInterruptGate as u16
+            | InterruptDescriptorFlags::dpl(0)
+            | InterruptDescriptorFlags::ist(0);
+        InterruptDescriptor::with_options(address as usize, options)
+    }
+
+    /// A descriptor that user mode can also raise with `int`, which only suits vectors meant for it, such as
+    /// the breakpoint of `int3`.
+    pub fn user_callable(address: *const ()) -> InterruptDescriptor {
         let options = InterruptDescriptorFlags::Present as u16
             | InterruptDescriptorFlags::TypeInterruptGate as u16
             | InterruptDescriptorFlags::dpl(3)
@@ -84,7 +156,26 @@ impl InterruptDescriptorFlags {
 pub struct InterruptHandler(InterruptDescriptor);
 impl InterruptHandler {
     pub fn set_to_handler(&mut self, handler_addr: extern "x86-interrupt" fn(InterruptStackFrame)) {
-        self.0 = InterruptDescriptor::new(handler_addr as *const ());
+        self.0 = InterruptDescriptor::kernel_only(handler_addr as *const ());
+    }
+
+    /// Points the vector at an assembly entry stub rather than an `x86-interrupt` function, e.g. one that
+    /// saves every register for delivering signals. User mode can't raise the vector with `int`.
+    ///
+    /// # Safety
+    ///
+    /// `address` has to be code that handles the interrupt stack frame and returns with `iretq`.
+    pub unsafe fn set_to_entry_stub(&mut self, address: *const ()) {
+        self.0 = InterruptDescriptor::kernel_only(address);
+    }
+
+    /// Like `set_to_entry_stub()`, but user mode can raise the vector with `int` as well.
+    ///
+    /// # Safety
+    ///
+    /// `address` has to be code that handles the interrupt stack frame and returns with `iretq`.
+    pub unsafe fn set_to_user_callable_entry_stub(&mut self, address: *const ()) {
+        self.0 = InterruptDescriptor::user_callable(address);
     }
 
     pub const fn empty() -> InterruptHandler {
@@ -95,7 +186,18 @@ impl InterruptHandler {
 pub struct InterruptHandlerWithErrorCode(InterruptDescriptor);
 impl InterruptHandlerWithErrorCode {
     pub fn set_to_handler(&mut self, handler_addr: extern "x86-interrupt" fn(InterruptStackFrame, u64)) {
-        self.0 = InterruptDescriptor::new(handler_addr as *const ());
+        self.0 = InterruptDescriptor::kernel_only(handler_addr as *const ());
+    }
+
+    /// Points the vector at an assembly entry stub rather than an `x86-interrupt` function, e.g. one that
+    /// saves every register for delivering signals. User mode can't raise the vector with `int`.
+    ///
+    /// # Safety
+    ///
+    /// `address` has to be code that handles the interrupt stack frame, including the error code the CPU
+    /// pushes, and returns with `iretq`.
+    pub unsafe fn set_to_entry_stub(&mut self, address: *const ()) {
+        self.0 = InterruptDescriptor::kernel_only(address);
     }
 
     pub const fn empty() -> InterruptHandlerWithErrorCode {
@@ -106,7 +208,7 @@ impl InterruptHandlerWithErrorCode {
 pub struct AbortInterruptHandlerWithErrorCode(InterruptDescriptor);
 impl AbortInterruptHandlerWithErrorCode {
     pub fn set_to_handler(&mut self, handler_addr: extern "x86-interrupt" fn(InterruptStackFrame, u64) -> !) {
-        self.0 = InterruptDescriptor::new(handler_addr as *const ());
+        self.0 = InterruptDescriptor::kernel_only(handler_addr as *const ());
     }
 
     pub const fn empty() -> AbortInterruptHandlerWithErrorCode {
@@ -231,11 +333,24 @@ struct Idtr {
 #[repr(C)]
 #[derive(Debug)]
 pub struct InterruptStackFrame {
-    return_ss: SegmentSelector,
-    return_stack_pointer: u64,
-    rflags: u64,
+    return_address: u64,
     return_cs: SegmentSelector,
-    return_address: u64
+    rflags: u64,
+    return_stack_pointer: u64,
+    return_ss: SegmentSelector
+}
+
+impl InterruptStackFrame {
+    /// Whether the interrupt arrived while the CPU was running user code.
+    #[inline]
+    pub fn is_user_mode(&self) -> bool {
+        self.return_cs.0 & 0b11 == 3
+    }
+
+    #[inline]
+    pub fn return_address(&self) -> u64 {
+        self.return_address
+    }
 }
 
 #[repr(transparent)]
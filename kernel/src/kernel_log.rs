@@ -0,0 +1,49 @@
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::interrupts_general::without_interrupts;
+
+/// How many bytes of the kernel's output are kept, dropping the oldest first
+const LOG_SIZE: usize = 64 * 1024;
+
+/// A ring buffer of everything printed.
+struct KernelLog {
+    buffer: [u8; LOG_SIZE],
+    /// Where the oldest byte is
+    start: usize,
+    length: usize
+}
+
+static LOG: Mutex<KernelLog> = Mutex::new(KernelLog {
+    buffer: [0; LOG_SIZE],
+    start: 0,
+    length: 0
+});
+
+/// Called with everything that is printed.
+pub fn append(text: &str) {
+    without_interrupts(|| {
+        let mut log = LOG.lock();
+        for &byte in text.as_bytes() {
+            let end = (log.start + log.length) % LOG_SIZE;
+            log.buffer[end] = byte;
+            if log.length == LOG_SIZE {
+                log.start = (log.start + 1) % LOG_SIZE;
+            }
+            else {
+                log.length += 1;
+            }
+        }
+    });
+}
+
+/// A copy of the log, oldest output first. Once the log has wrapped around, the first character may be cut
+/// in the middle.
+pub fn contents() -> Vec<u8> {
+    without_interrupts(|| {
+        let log = LOG.lock();
+        let (first, second) = log.buffer.split_at(log.start);
+        second.iter().chain(first).take(log.length).copied().collect()
+    })
+}
@@ -0,0 +1,144 @@
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::event_queue::EventQueue;
+use crate::interrupts_general::without_interrupts;
+use crate::scheduler::WaitQueue;
+
+const EVENT_QUEUE_CAPACITY: usize = 256;
+
+/// Keys are identified by their usage id on the USB HID keyboard/keypad usage page, which every keyboard
+/// driver translates its own key codes into. These are the ones that need special handling.
+pub const KEY_ENTER: u8 = 0x28;
+pub const KEY_ESCAPE: u8 = 0x29;
+pub const KEY_BACKSPACE: u8 = 0x2a;
+pub const KEY_TAB: u8 = 0x2b;
+pub const KEY_CAPS_LOCK: u8 = 0x39;
+pub const KEY_RIGHT_ARROW: u8 = 0x4f;
+pub const KEY_LEFT_ARROW: u8 = 0x50;
+pub const KEY_DOWN_ARROW: u8 = 0x51;
+pub const KEY_UP_ARROW: u8 = 0x52;
+/// The modifier keys are the usage ids from this one to `KEY_RIGHT_GUI`, in the same order as the bits of
+/// `KeyModifiers`
+pub const KEY_LEFT_CONTROL: u8 = 0xe0;
+pub const KEY_RIGHT_GUI: u8 = 0xe7;
+
+bitflags! {
+    /// The bits are laid out like the modifier byte of a USB HID boot protocol keyboard report.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct KeyModifiers: u8 {
+        const LEFT_CONTROL = 1 << 0;
+        const LEFT_SHIFT = 1 << 1;
+        const LEFT_ALT = 1 << 2;
+        const LEFT_GUI = 1 << 3;
+        const RIGHT_CONTROL = 1 << 4;
+        const RIGHT_SHIFT = 1 << 5;
+        const RIGHT_ALT = 1 << 6;
+        const RIGHT_GUI = 1 << 7;
+    }
+}
+
+impl KeyModifiers {
+    #[inline]
+    pub fn shift(&self) -> bool {
+        self.intersects(KeyModifiers::LEFT_SHIFT | KeyModifiers::RIGHT_SHIFT)
+    }
+
+    #[inline]
+    pub fn control(&self) -> bool {
+        self.intersects(KeyModifiers::LEFT_CONTROL | KeyModifiers::RIGHT_CONTROL)
+    }
+}
+
+/// A key being pressed or released, along with the modifiers held down at the time (including the key itself
+/// if it is a modifier).
+#[derive(Debug, Clone, Copy)]
+pub struct KeyEvent {
+    pub key: u8,
+    pub pressed: bool,
+    pub modifiers: KeyModifiers
+}
+
+/// The characters of the keys from A (usage id 0x04) to slash (0x38) on a US layout, without and with shift
+const US_LAYOUT: [(u8, u8); 0x35] = [
+    (b'a', b'A'),
+    (b'b', b'B'),
+    (b'c', b'C'),
+    (b'd', b'D'),
+    (b'e', b'E'),
+    (b'f', b'F'),
+    (b'g', b'G'),
+    (b'h', b'H'),
+    (b'i', b'I'),
+    (b'j', b'J'),
+    (b'k', b'K'),
+    (b'l', b'L'),
+    (b'm', b'M'),
+    (b'n', b'N'),
+    (b'o', b'O'),
+    (b'p', b'P'),
+    (b'q', b'Q'),
+    (b'r', b'R'),
+    (b's', b'S'),
+    (b't', b'T'),
+    (b'u', b'U'),
+    (b'v', b'V'),
+    (b'w', b'W'),
+    (b'x', b'X'),
+    (b'y', b'Y'),
+    (b'z', b'Z'),
+    (b'1', b'!'),
+    (b'2', b'@'),
+    (b'3', b'#'),
+    (b'4', b'$'),
+    (b'5', b'%'),
+    (b'6', b'^'),
+    (b'7', b'&'),
+    (b'8', b'*'),
+    (b'9', b'('),
+    (b'0', b')'),
+    (b'\n', b'\n'),
+    (0x1b, 0x1b),
+    (0x08, 0x08),
+    (b'\t', b'\t'),
+    (b' ', b' '),
+    (b'-', b'_'),
+    (b'=', b'+'),
+    (b'[', b'{'),
+    (b']', b'}'),
+    (b'\\', b'|'),
+    // Non-US # and ~, which is where US keyboards have the backslash key
+    (b'\\', b'|'),
+    (b';', b':'),
+    (b'\'', b'"'),
+    (b'`', b'~'),
+    (b',', b'<'),
+    (b'.', b'>'),
+    (b'/', b'?')
+];
+
+impl KeyEvent {
+    /// The ASCII character the key produces on a US layout, if any. Only key presses produce characters.
+    pub fn to_ascii(&self) -> Option<u8> {
+        if !self.pressed {
+            return None;
+        }
+        let (normal, shifted) = *US_LAYOUT.get((self.key as usize).checked_sub(0x04)?)?;
+        Some(if self.modifiers.shift() { shifted } else { normal })
+    }
+}
+
+static EVENTS: Mutex<EventQueue<KeyEvent, EVENT_QUEUE_CAPACITY>> = Mutex::new(EventQueue::new());
+
+/// Threads waiting for key presses
+pub static EVENT_WAITERS: WaitQueue = WaitQueue::new();
+
+/// Called by keyboard drivers, usually from an interrupt handler.
+pub fn push_event(event: KeyEvent) {
+    without_interrupts(|| EVENTS.lock().push(event));
+    EVENT_WAITERS.wake_all();
+}
+
+pub fn pop_event() -> Option<KeyEvent> {
+    without_interrupts(|| EVENTS.lock().pop())
+}
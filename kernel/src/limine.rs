@@ -30,6 +30,54 @@ macro_rules! LIMINE_STACK_SIZE_REQUEST_ID {
     };
 }
 
+#[macro_export]
+macro_rules! LIMINE_HHDM_REQUEST_ID {
+    () => {
+        [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0x48dcf1cb8ad2b852,
+            0x63984e959a98244b
+        ]
+    };
+}
+
+#[macro_export]
+macro_rules! LIMINE_RSDP_REQUEST_ID {
+    () => {
+        [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0xc5e77b6b397e7b43,
+            0x27637845accdcf3c
+        ]
+    };
+}
+
+#[macro_export]
+macro_rules! LIMINE_MEMMAP_REQUEST_ID {
+    () => {
+        [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0x67cf3d9d378a806f,
+            0xe304acdfc50c3c62
+        ]
+    };
+}
+
+#[macro_export]
+macro_rules! LIMINE_MODULE_REQUEST_ID {
+    () => {
+        [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0x3e7e279702be32af,
+            0xca1c4f3bd1280cee
+        ]
+    };
+}
+
 #[repr(C)]
 pub struct LimineFramebufferRequest {
     pub id: [u64; 4],
@@ -105,3 +153,126 @@ pub struct LimineStackSizeResponse {
 }
 
 unsafe impl Sync for LimineStackSizeRequest {}
+
+#[repr(C)]
+pub struct LimineHhdmRequest {
+    pub id: [u64; 4],
+    pub revision: u64,
+    pub response: *const LimineHhdmResponse
+}
+
+#[repr(C)]
+pub struct LimineHhdmResponse {
+    pub revision: u64,
+    /// The virtual address offset of the higher half direct map, where all of physical memory is mapped
+    pub offset: u64
+}
+
+unsafe impl Sync for LimineHhdmRequest {}
+
+#[repr(C)]
+pub struct LimineRsdpRequest {
+    pub id: [u64; 4],
+    pub revision: u64,
+    pub response: *const LimineRsdpResponse
+}
+
+#[repr(C)]
+pub struct LimineRsdpResponse {
+    pub revision: u64,
+    /// Virtual (higher half direct map) address of the ACPI RSDP structure
+    pub address: *const ()
+}
+
+unsafe impl Sync for LimineRsdpRequest {}
+
+#[repr(C)]
+pub struct LimineMemmapRequest {
+    pub id: [u64; 4],
+    pub revision: u64,
+    pub response: *const LimineMemmapResponse
+}
+
+#[repr(C)]
+pub struct LimineMemmapResponse {
+    pub revision: u64,
+    pub entry_count: u64,
+    pub entries: *const *const LimineMemmapEntry
+}
+
+// Not an enum, since newer Limine versions might report types this kernel doesn't know about
+pub const LIMINE_MEMMAP_USABLE: u64 = 0;
+pub const LIMINE_MEMMAP_RESERVED: u64 = 1;
+pub const LIMINE_MEMMAP_ACPI_RECLAIMABLE: u64 = 2;
+pub const LIMINE_MEMMAP_ACPI_NVS: u64 = 3;
+pub const LIMINE_MEMMAP_BAD_MEMORY: u64 = 4;
+pub const LIMINE_MEMMAP_BOOTLOADER_RECLAIMABLE: u64 = 5;
+pub const LIMINE_MEMMAP_KERNEL_AND_MODULES: u64 = 6;
+pub const LIMINE_MEMMAP_FRAMEBUFFER: u64 = 7;
+
+#[repr(C)]
+pub struct LimineMemmapEntry {
+    pub base: u64,
+    pub length: u64,
+    pub entry_type: u64
+}
+
+unsafe impl Sync for LimineMemmapRequest {}
+
+#[repr(C)]
+pub struct LimineModuleRequest {
+    pub id: [u64; 4],
+    pub revision: u64,
+    pub response: *const LimineModuleResponse,
+
+    /* Request revision 1 */
+    pub internal_module_count: u64,
+    pub internal_modules: *const *const LimineInternalModule
+}
+
+/// A module the kernel asks for itself, rather than one listed in limine.cfg
+#[repr(C)]
+pub struct LimineInternalModule {
+    pub path: *const u8,
+    pub cmdline: *const u8,
+    pub flags: u64
+}
+
+#[repr(C)]
+pub struct LimineModuleResponse {
+    pub revision: u64,
+    pub module_count: u64,
+    pub modules: *const *const LimineFile
+}
+
+#[repr(C)]
+pub struct LimineUuid {
+    pub a: u32,
+    pub b: u16,
+    pub c: u16,
+    pub d: [u8; 8]
+}
+
+/// A file loaded by Limine, such as a module
+#[repr(C)]
+pub struct LimineFile {
+    pub revision: u64,
+    /// Virtual (higher half direct map) address of the contents of the file
+    pub address: *const u8,
+    pub size: u64,
+    /// Null-terminated path of the file on the boot medium
+    pub path: *const u8,
+    /// Null-terminated string given to the file with MODULE_CMDLINE in limine.cfg
+    pub cmdline: *const u8,
+    pub media_type: u32,
+    pub unused: u32,
+    pub tftp_ip: u32,
+    pub tftp_port: u32,
+    pub partition_index: u32,
+    pub mbr_disk_id: u32,
+    pub gpt_disk_uuid: LimineUuid,
+    pub gpt_part_uuid: LimineUuid,
+    pub part_uuid: LimineUuid
+}
+
+unsafe impl Sync for LimineModuleRequest {}
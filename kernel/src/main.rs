@@ -2,24 +2,78 @@
 #![no_main]
 #![feature(abi_x86_interrupt, generic_const_exprs)]
 
+extern crate alloc;
+
+pub mod acpi;
+pub mod ahci;
+pub mod apic;
+pub mod block;
+pub mod buffer_cache;
+pub mod console;
 pub mod cpuid;
+pub mod crc32;
+pub mod cursor;
+pub mod devfs;
+pub mod elf;
+pub mod event_queue;
+pub mod ext2;
+pub mod fat;
+pub mod fpu;
+pub mod gdt;
+pub mod gpt;
 pub mod graphics;
+pub mod heap;
+pub mod hid;
+pub mod initramfs;
 pub mod interrupts;
 pub mod interrupts_general;
+pub mod kernel_log;
+pub mod keyboard;
 pub mod limine;
+pub mod memory;
+pub mod mouse;
+pub mod msi;
 pub mod msr;
 pub mod mtrr;
+pub mod nvme;
+pub mod paging;
+pub mod pci;
+pub mod pic;
+pub mod pipe;
+pub mod pit;
+pub mod port_io;
+pub mod process;
+pub mod procfs;
+pub mod ps2;
+pub mod ramdisk;
+pub mod ramfs;
+pub mod scheduler;
+pub mod serial;
+pub mod signal;
+pub mod syscall;
 pub mod text_rendering;
+pub mod usb;
+pub mod usb_storage;
+pub mod user_memory;
+pub mod vfs;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtual_memory;
+pub mod xhci;
 
+use alloc::string::String;
 use core::panic::PanicInfo;
-use core::ptr::{self, null, null_mut};
+use core::ptr::{null, null_mut};
 
-use cpuid::is_cpuid_supported;
-use limine::{LimineFramebuffer, LimineFramebufferRequest, LimineStackSizeRequest};
-use msr::read_msr_only_low_order_32bits;
+use cursor::Cursor;
+use interrupts_general::enable_interrupts;
+use limine::{
+    LimineFramebuffer, LimineFramebufferRequest, LimineHhdmRequest, LimineMemmapRequest, LimineModuleRequest,
+    LimineRsdpRequest, LimineStackSizeRequest
+};
 use spin::{Lazy, Mutex};
 
-use crate::cpuid::{get_cpu_info, CPUBasicFeatureFlags};
+use crate::block::BlockDevice;
 
 LIMINE_BASE_REVISION! { 1 }
 
@@ -38,6 +92,36 @@ static LIMINE_STACK_SIZE_REQUEST: LimineStackSizeRequest = LimineStackSizeReques
     stack_size: 1024 * 512 // 512 KiB
 };
 
+#[used]
+static LIMINE_HHDM_REQUEST: LimineHhdmRequest = LimineHhdmRequest {
+    id: LIMINE_HHDM_REQUEST_ID!(),
+    revision: 0,
+    response: null()
+};
+
+#[used]
+static LIMINE_MEMMAP_REQUEST: LimineMemmapRequest = LimineMemmapRequest {
+    id: LIMINE_MEMMAP_REQUEST_ID!(),
+    revision: 0,
+    response: null()
+};
+
+#[used]
+static LIMINE_RSDP_REQUEST: LimineRsdpRequest = LimineRsdpRequest {
+    id: LIMINE_RSDP_REQUEST_ID!(),
+    revision: 0,
+    response: null()
+};
+
+#[used]
+static LIMINE_MODULE_REQUEST: LimineModuleRequest = LimineModuleRequest {
+    id: LIMINE_MODULE_REQUEST_ID!(),
+    revision: 0,
+    response: null(),
+    internal_module_count: 0,
+    internal_modules: null()
+};
+
 static FRAMEBUFFER: Lazy<Mutex<&'static mut LimineFramebuffer>> = Lazy::new(|| {
     if LIMINE_FB_REQUEST.response.is_null() {
         // ERROR
@@ -58,33 +142,202 @@ static FRAMEBUFFER: Lazy<Mutex<&'static mut LimineFramebuffer>> = Lazy::new(|| {
 
 #[no_mangle]
 extern "C" fn _start() -> ! {
+    gdt::init();
     interrupts::load_idt();
-    println!("CPUID support: {}", is_cpuid_supported());
-    if is_cpuid_supported() {
-        let ci = get_cpu_info();
-        println!("Physical address bit width: {}", ci.physical_adress_bit_width);
-        // println!("{:x}_{:x}", ci.family_id, ci.model);
-        // mtrr::print_mtrr_memory_mappings(ci);
-        let apic_supported = ci.feature_flags.contains(CPUBasicFeatureFlags::APIC);
-        println!("Feature flag APIC: {}", apic_supported);
-        if apic_supported {
-            let apic_base_msr = read_msr_only_low_order_32bits(0x1b);
-            let is_bsp = (apic_base_msr >> 8) & 0b1;
-            let enabled = (apic_base_msr >> 11) & 0b1;
-            let base_addr = apic_base_msr & 0xfffff000;
-            println!("Is BSP: {}", is_bsp);
-            println!("Is enabled: {}", enabled);
-            println!("Base adress: {:X}", base_addr);
-            let lapic_version_reg = unsafe { ptr::read(0xfee00030 as *const u32) };
-            if lapic_version_reg & 0xf0 == 0x0 {
-                println!("82489DX Discrete APIC");
+    paging::init();
+    syscall::init();
+    scheduler::init();
+    serial::init();
+    for port in serial::ports() {
+        println!("Serial port {}", port.name());
+    }
+
+    let pci_devices = pci::init();
+    println!("PCI devices: {}", pci_devices.len());
+    for device in pci_devices {
+        println!(
+            "{}: {:04x}:{:04x}, class {:02x}.{:02x}.{:02x}",
+            device.address, device.vendor_id, device.device_id, device.class, device.subclass, device.prog_if
+        );
+    }
+    usb::register_driver(&usb_storage::USB_STORAGE_DRIVER);
+    usb::register_driver(&hid::USB_KEYBOARD_DRIVER);
+    usb::register_driver(&hid::USB_POINTER_DRIVER);
+    pci::register_driver(&xhci::XHCI_DRIVER);
+    pci::register_driver(&ahci::AHCI_DRIVER);
+    pci::register_driver(&nvme::NVME_DRIVER);
+    pci::register_driver(&virtio_blk::VIRTIO_BLOCK_DRIVER);
+    pci::probe_drivers();
+    gpt::scan_all();
+    for device in block::devices() {
+        println!(
+            "Block device {}: {} blocks of {} bytes",
+            device.name(),
+            device.block_count(),
+            device.block_size()
+        );
+    }
+    for partition in gpt::partitions() {
+        println!(
+            "Partition {} \"{}\": type {}, id {}{}",
+            partition.name(),
+            partition.label(),
+            partition.type_guid(),
+            partition.unique_guid(),
+            if partition.is_efi_system_partition() {
+                " (EFI system partition)"
             }
             else {
-                println!("Integrated APIC");
+                ""
+            }
+        );
+    }
+    vfs::register_file_system(&fat::FAT_FILE_SYSTEM);
+    vfs::register_file_system(&ext2::EXT2_FILE_SYSTEM);
+    vfs::register_file_system(&ramfs::RAMFS_FILE_SYSTEM);
+    vfs::register_file_system(&devfs::DEVFS_FILE_SYSTEM);
+    vfs::register_file_system(&procfs::PROCFS_FILE_SYSTEM);
+    mount_root();
+    list_root_directory();
+
+    pic::init();
+    apic::init();
+    match mouse::init() {
+        Ok(()) => pic::unmask_irq(ps2::AUX_PORT_IRQ),
+        Err(e) => println!("PS/2 mouse initialization failed: {:?}", e)
+    }
+    pit::init();
+    pic::unmask_irq(pit::IRQ);
+    enable_interrupts();
+    start_init();
+
+    let mut cursor = Cursor::new();
+    {
+        let mut framebuffer = FRAMEBUFFER.lock();
+        let (center_x, center_y) = (framebuffer.width as i64 / 2, framebuffer.height as i64 / 2);
+        cursor.move_to(*framebuffer, center_x, center_y);
+        cursor.show(*framebuffer);
+    }
+    loop {
+        while let Some(event) = mouse::pop_event() {
+            let mut framebuffer = FRAMEBUFFER.lock();
+            match event.absolute_position {
+                Some((x, y)) => {
+                    let max = mouse::ABSOLUTE_POSITION_MAX as i64;
+                    let x = x as i64 * (framebuffer.width as i64 - 1) / max;
+                    let y = y as i64 * (framebuffer.height as i64 - 1) / max;
+                    cursor.move_to(*framebuffer, x, y);
+                },
+                // Mice report upwards movement as positive, while the y coordinate grows downwards on screen
+                None => cursor.move_by(*framebuffer, event.dx as i64, -event.dy as i64)
             }
         }
+        console::process_input();
+        scheduler::idle();
     }
-    loop {}
+}
+
+/// Scratch space for everyone, which is capped so that filling it up doesn't take all of memory
+const TMP_OPTIONS: &str = "size=16m";
+/// Shared memory objects, which are capped like /tmp but with room for a few screen sized buffers
+const SHM_OPTIONS: &str = "size=64m";
+
+/// Mounts a ramfs as the root, fills it with the contents of the initramfs, and mounts a ramfs on /tmp, the
+/// devices on /dev with a ramfs for shared memory on /dev/shm, kernel information on /proc and the EFI system
+/// partition on /boot. The first Linux partition is mounted on /mnt if it has an ext2 file system.
+fn mount_root() {
+    if let Err(e) = vfs::mount(None, "/", "ramfs", "") {
+        panic!("Mounting the root file system failed: {:?}", e);
+    }
+    match initramfs::module().and_then(|archive| initramfs::unpack(archive, "/")) {
+        Ok(count) => println!("Unpacked {} files from the initramfs", count),
+        Err(e) => println!("Unpacking the initramfs failed: {:?}", e)
+    }
+    if let Err(e) = mount_on_directory(None, "/tmp", "ramfs", TMP_OPTIONS) {
+        println!("Mounting /tmp failed: {:?}", e);
+    }
+    if let Err(e) = mount_on_directory(None, "/dev", "devfs", "") {
+        println!("Mounting /dev failed: {:?}", e);
+    }
+    // Devfs has the directory for it, as nothing can be created there
+    else if let Err(e) = vfs::mount(None, "/dev/shm", "ramfs", SHM_OPTIONS) {
+        println!("Mounting /dev/shm failed: {:?}", e);
+    }
+    if let Err(e) = mount_on_directory(None, "/proc", "procfs", "") {
+        println!("Mounting /proc failed: {:?}", e);
+    }
+    if let Some(esp) = gpt::partitions().find(|p| p.is_efi_system_partition()) {
+        if let Err(e) = mount_on_directory(Some(esp.name()), "/boot", "fat", "") {
+            println!("Mounting {} on /boot failed: {:?}", esp.name(), e);
+        }
+    }
+    if let Some(partition) = gpt::partitions().find(|p| p.type_guid() == gpt::LINUX_FILE_SYSTEM) {
+        if let Err(e) = mount_on_directory(Some(partition.name()), "/mnt", "ext2", "") {
+            println!("Mounting {} on /mnt failed: {:?}", partition.name(), e);
+        }
+    }
+    for mount in vfs::mounts() {
+        print!(
+            "{} on {} type {}",
+            mount.source, mount.path, mount.file_system_type
+        );
+        match mount.usage {
+            Some(vfs::FileSystemUsage {
+                used_bytes,
+                total_bytes: Some(total_bytes)
+            }) => println!(", {} of {} KiB used", used_bytes / 1024, total_bytes / 1024),
+            Some(usage) => println!(", {} KiB used", usage.used_bytes / 1024),
+            None => println!()
+        }
+    }
+}
+
+/// Mounts a file system on `path`, creating the directory first if it doesn't exist.
+fn mount_on_directory(
+    source: Option<&str>,
+    path: &str,
+    file_system_type: &str,
+    options: &str
+) -> Result<(), vfs::VfsError> {
+    match vfs::create_directory(path, 0o755) {
+        Ok(()) | Err(vfs::VfsError::AlreadyExists) => vfs::mount(source, path, file_system_type, options),
+        Err(e) => Err(e)
+    }
+}
+
+/// The first program, which is run once the kernel is set up if the root file system has it
+const INIT_PATH: &str = "/bin/init";
+const INIT_ENVIRONMENT: [&str; 2] = ["PATH=/bin", "HOME=/"];
+
+/// Starts init as the first process, which runs alongside the kernel's idle loop.
+fn start_init() {
+    if vfs::stat(INIT_PATH).is_err() {
+        return;
+    }
+    let environment = INIT_ENVIRONMENT.map(String::from);
+    if let Err(e) = process::start_init(INIT_PATH, &environment) {
+        println!("Starting {} failed: {:?}", INIT_PATH, e);
+    }
+}
+
+/// Lists what's in the root directory through a file descriptor, the way a process would.
+fn list_root_directory() {
+    let mut files = vfs::FileDescriptorTable::new();
+    let fd = match files.open("/", vfs::OpenFlags::READ | vfs::OpenFlags::DIRECTORY, 0) {
+        Ok(fd) => fd,
+        Err(e) => {
+            println!("Opening / failed: {:?}", e);
+            return;
+        }
+    };
+    println!("Contents of /:");
+    while let Ok(Some(entry)) = files.read_directory(fd) {
+        match vfs::stat(&alloc::format!("/{}", entry.name)) {
+            Ok(stat) => println!("    {} ({:?}, {} bytes)", entry.name, stat.file_type, stat.size),
+            Err(_) => println!("    {}", entry.name)
+        }
+    }
+    let _ = files.close(fd);
 }
 
 pub fn sleep(loop_iters: u64) {
@@ -0,0 +1,256 @@
+use alloc::collections::BTreeMap;
+use core::ptr;
+
+use spin::{Lazy, Mutex};
+
+use crate::interrupts_general::without_interrupts;
+use crate::limine::{LimineMemmapEntry, LIMINE_MEMMAP_USABLE};
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Everything below this physical address is mapped in the higher half direct map, whether it's RAM or not.
+pub const HHDM_MINIMUM_MAPPED_LIMIT: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Limine maps all of physical memory (and for base revision 1, at least the first 4 GiB regardless of the
+/// memory map, which covers the MMIO ranges of most devices) at this offset in the higher half.
+pub static HHDM_OFFSET: Lazy<u64> = Lazy::new(|| {
+    let response = crate::LIMINE_HHDM_REQUEST.response;
+    if response.is_null() {
+        panic!("Limine did not respond to the HHDM request");
+    }
+    unsafe { (*response).offset }
+});
+
+#[inline]
+pub fn physical_to_virtual(physical_address: u64) -> u64 {
+    physical_address + *HHDM_OFFSET
+}
+
+#[inline]
+pub fn physical_to_virtual_ptr<T>(physical_address: u64) -> *mut T {
+    physical_to_virtual(physical_address) as *mut T
+}
+
+/// Returns the virtual address of a range of memory mapped I/O, or `None` if the range is above the part of
+/// physical memory guaranteed to be mapped. There's no way to map anything else yet, as the kernel still
+/// runs on the page tables Limine set up.
+pub fn mmio_to_virtual(physical_address: u64, size: u64) -> Option<u64> {
+    if physical_address.checked_add(size)? > HHDM_MINIMUM_MAPPED_LIMIT {
+        return None;
+    }
+    Some(physical_to_virtual(physical_address))
+}
+
+/// A physically contiguous run of page frames, which is also accessible through the higher half direct map.
+#[derive(Debug)]
+pub struct PhysicalFrames {
+    pub physical_address: u64,
+    pub count: u64
+}
+
+impl PhysicalFrames {
+    #[inline]
+    pub fn virtual_address(&self) -> u64 {
+        physical_to_virtual(self.physical_address)
+    }
+
+    #[inline]
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virtual_address() as *mut T
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.count * FRAME_SIZE
+    }
+}
+
+/// Keeps track of which frames of physical memory are free with a bitmap, where a set bit means the frame is
+/// in use. The bitmap covers physical memory up to the end of the highest usable memory map entry, and
+/// lives in the first usable entry large enough to hold it.
+struct FrameAllocator {
+    bitmap: *mut u64,
+    frame_count: u64,
+    /// Where the search for free frames starts from, so that allocations don't have to scan over the
+    /// (usually fully allocated) start of the bitmap every time
+    next_free_hint: u64,
+    free_frame_count: u64
+}
+
+unsafe impl Send for FrameAllocator {}
+
+impl FrameAllocator {
+    fn new(entries: &[&LimineMemmapEntry]) -> FrameAllocator {
+        let usable = || entries.iter().filter(|e| e.entry_type == LIMINE_MEMMAP_USABLE);
+        let end_address = usable().map(|e| e.base + e.length).max().unwrap_or(0);
+        let frame_count = end_address / FRAME_SIZE;
+        let bitmap_size = frame_count.div_ceil(64) * 8;
+        let Some(bitmap_entry) = usable().find(|e| e.length >= bitmap_size)
+        else {
+            panic!("No usable memory region large enough for the frame allocator bitmap");
+        };
+        let bitmap: *mut u64 = physical_to_virtual_ptr(bitmap_entry.base);
+        unsafe { ptr::write_bytes(bitmap as *mut u8, 0xff, bitmap_size as usize) };
+
+        let mut allocator = FrameAllocator {
+            bitmap,
+            frame_count,
+            next_free_hint: 0,
+            free_frame_count: 0
+        };
+        for entry in usable() {
+            // Usable entries are guaranteed to be page aligned
+            let first_frame = entry.base / FRAME_SIZE;
+            for frame in first_frame..first_frame + entry.length / FRAME_SIZE {
+                allocator.set_used(frame, false);
+            }
+        }
+        let bitmap_first_frame = bitmap_entry.base / FRAME_SIZE;
+        for frame in bitmap_first_frame..bitmap_first_frame + bitmap_size.div_ceil(FRAME_SIZE) {
+            allocator.set_used(frame, true);
+        }
+        allocator
+    }
+
+    #[inline]
+    fn is_used(&self, frame: u64) -> bool {
+        unsafe { *self.bitmap.add((frame / 64) as usize) & (1 << (frame % 64)) != 0 }
+    }
+
+    #[inline]
+    fn set_used(&mut self, frame: u64, used: bool) {
+        if self.is_used(frame) == used {
+            return;
+        }
+        let word = unsafe { &mut *self.bitmap.add((frame / 64) as usize) };
+        if used {
+            *word |= 1 << (frame % 64);
+            self.free_frame_count -= 1;
+        }
+        else {
+            *word &= !(1 << (frame % 64));
+            self.free_frame_count += 1;
+        }
+    }
+
+    /// Finds `count` contiguous free frames, the first of which is aligned to `alignment` frames.
+    fn allocate(&mut self, count: u64, alignment: u64) -> Option<u64> {
+        if count == 0 || count > self.free_frame_count {
+            return None;
+        }
+        // Scan from the hint first, then from the start in case something got freed below it
+        for start in [self.next_free_hint, 0] {
+            let mut first = start.next_multiple_of(alignment);
+            while first + count <= self.frame_count {
+                match (first..first + count).find(|f| self.is_used(*f)) {
+                    Some(used_frame) => first = (used_frame + 1).next_multiple_of(alignment),
+                    None => {
+                        for frame in first..first + count {
+                            self.set_used(frame, true);
+                        }
+                        self.next_free_hint = first + count;
+                        return Some(first);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, first_frame: u64, count: u64) {
+        for frame in first_frame..first_frame + count {
+            if !self.is_used(frame) {
+                panic!("Frame {:#x} freed twice", frame * FRAME_SIZE);
+            }
+            self.set_used(frame, false);
+        }
+        self.next_free_hint = self.next_free_hint.min(first_frame);
+    }
+}
+
+/// The memory map Limine passed to the kernel, which stays valid as long as bootloader reclaimable memory
+/// isn't reused.
+pub fn memory_map() -> &'static [&'static LimineMemmapEntry] {
+    let response = crate::LIMINE_MEMMAP_REQUEST.response;
+    if response.is_null() {
+        panic!("Limine did not respond to the memory map request");
+    }
+    unsafe {
+        core::slice::from_raw_parts(
+            (*response).entries as *const &LimineMemmapEntry,
+            (*response).entry_count as usize
+        )
+    }
+}
+
+static FRAME_ALLOCATOR: Lazy<Mutex<FrameAllocator>> =
+    Lazy::new(|| Mutex::new(FrameAllocator::new(memory_map())));
+
+/// Allocates `count` physically contiguous, zeroed frames.
+#[inline]
+pub fn allocate_frames(count: u64) -> Option<PhysicalFrames> {
+    allocate_frames_aligned(count, 1)
+}
+
+/// Like `allocate_frames()`, but the physical address of the first frame is a multiple of `alignment`
+/// frames. Some devices need buffers which don't cross e.g. 64 KiB boundaries.
+pub fn allocate_frames_aligned(count: u64, alignment: u64) -> Option<PhysicalFrames> {
+    let first_frame = without_interrupts(|| FRAME_ALLOCATOR.lock().allocate(count, alignment.max(1)))?;
+    let frames = PhysicalFrames {
+        physical_address: first_frame * FRAME_SIZE,
+        count
+    };
+    unsafe { ptr::write_bytes(frames.as_ptr::<u8>(), 0, frames.size() as usize) };
+    Some(frames)
+}
+
+pub fn free_frames(frames: PhysicalFrames) {
+    without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .free(frames.physical_address / FRAME_SIZE, frames.count)
+    });
+}
+
+/// How many owners besides the first the frames mapped into several address spaces have, e.g. after a fork
+/// until one of the processes writes to them. Frames not in here have a single owner.
+static FRAME_SHARES: Mutex<BTreeMap<u64, u32>> = Mutex::new(BTreeMap::new());
+
+/// Adds an owner to a frame, which is then only freed once every owner has called `release_frame()`.
+pub fn share_frame(physical_address: u64) {
+    without_interrupts(|| *FRAME_SHARES.lock().entry(physical_address).or_insert(0) += 1);
+}
+
+/// Whether a frame has more than one owner.
+pub fn is_frame_shared(physical_address: u64) -> bool {
+    without_interrupts(|| FRAME_SHARES.lock().contains_key(&physical_address))
+}
+
+/// Drops an owner of a frame, and frees it if that was the last one.
+pub fn release_frame(physical_address: u64) {
+    let was_shared = without_interrupts(|| {
+        let mut shares = FRAME_SHARES.lock();
+        match shares.get_mut(&physical_address) {
+            Some(1) => {
+                shares.remove(&physical_address);
+                true
+            },
+            Some(count) => {
+                *count -= 1;
+                true
+            },
+            None => false
+        }
+    });
+    if !was_shared {
+        free_frames(PhysicalFrames {
+            physical_address,
+            count: 1
+        });
+    }
+}
+
+/// The number of free frames, e.g. for memory usage statistics.
+pub fn free_frame_count() -> u64 {
+    without_interrupts(|| FRAME_ALLOCATOR.lock().free_frame_count)
+}
@@ -0,0 +1,199 @@
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::event_queue::EventQueue;
+use crate::interrupts_general::without_interrupts;
+use crate::ps2::{self, Ps2Error};
+
+const COMMAND_SET_SAMPLE_RATE: u8 = 0xf3;
+const COMMAND_GET_DEVICE_ID: u8 = 0xf2;
+const COMMAND_ENABLE_DATA_REPORTING: u8 = 0xf4;
+const COMMAND_SET_DEFAULTS: u8 = 0xf6;
+const COMMAND_RESET: u8 = 0xff;
+
+const SELF_TEST_PASSED: u8 = 0xaa;
+
+/// Device ids reported by the mouse. The extended ones are only reported after the corresponding "magic"
+/// sequence of sample rates has been sent to the mouse.
+const DEVICE_ID_STANDARD: u8 = 0x00;
+const DEVICE_ID_SCROLL_WHEEL: u8 = 0x03;
+const DEVICE_ID_FIVE_BUTTONS: u8 = 0x04;
+
+const PACKET_Y_OVERFLOW: u8 = 0b10000000;
+const PACKET_X_OVERFLOW: u8 = 0b1000000;
+const PACKET_Y_SIGN: u8 = 0b100000;
+const PACKET_X_SIGN: u8 = 0b10000;
+/// Bit 3 of the first byte of every packet is always set. Checking it is the only way to notice that the
+/// packet stream has gotten out of sync.
+const PACKET_ALWAYS_ONE: u8 = 0b1000;
+
+const EVENT_QUEUE_CAPACITY: usize = 256;
+
+/// Absolute positions are scaled so that this is the right or bottom edge of the screen.
+pub const ABSOLUTE_POSITION_MAX: u16 = u16::MAX;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct MouseButtons: u8 {
+        const LEFT = 1 << 0;
+        const RIGHT = 1 << 1;
+        const MIDDLE = 1 << 2;
+        const FOURTH = 1 << 3;
+        const FIFTH = 1 << 4;
+    }
+}
+
+/// A single report from a mouse. Movement is relative to the previous event, with positive `dy` meaning
+/// upwards movement and positive `dz` meaning the scroll wheel was turned towards the user. Devices such as
+/// tablets report where on the screen the pointer is instead, with `(0, 0)` being the top left corner and
+/// `ABSOLUTE_POSITION_MAX` the opposite edges.
+#[derive(Debug, Clone, Copy)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub dz: i8,
+    pub buttons: MouseButtons,
+    pub absolute_position: Option<(u16, u16)>
+}
+
+#[derive(Clone, Copy)]
+pub struct PacketParser {
+    packet: [u8; 4],
+    received_bytes: usize,
+    packet_size: usize,
+    device_id: u8
+}
+
+impl PacketParser {
+    pub const fn new(device_id: u8) -> PacketParser {
+        PacketParser {
+            packet: [0; 4],
+            received_bytes: 0,
+            packet_size: match device_id {
+                DEVICE_ID_SCROLL_WHEEL | DEVICE_ID_FIVE_BUTTONS => 4,
+                _ => 3
+            },
+            device_id
+        }
+    }
+
+    /// Feeds a single byte received from the mouse into the parser. Returns the event once a whole packet
+    /// has been received, and `None` otherwise or if the packet was invalid.
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.received_bytes == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            // Out of sync, so skip bytes until one that could be the start of a packet
+            return None;
+        }
+        self.packet[self.received_bytes] = byte;
+        self.received_bytes += 1;
+        if self.received_bytes < self.packet_size {
+            return None;
+        }
+        self.received_bytes = 0;
+        self.parse_packet()
+    }
+
+    fn parse_packet(&self) -> Option<MouseEvent> {
+        let flags = self.packet[0];
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+            return None;
+        }
+        // The movement values are 9-bit two's complement numbers, with the sign bit in the first byte
+        let mut dx = self.packet[1] as i16;
+        if flags & PACKET_X_SIGN != 0 {
+            dx -= 0x100;
+        }
+        let mut dy = self.packet[2] as i16;
+        if flags & PACKET_Y_SIGN != 0 {
+            dy -= 0x100;
+        }
+        let mut buttons = MouseButtons::from_bits_truncate(flags & 0b111);
+        let mut dz = 0;
+        match self.device_id {
+            DEVICE_ID_SCROLL_WHEEL => dz = self.packet[3] as i8,
+            DEVICE_ID_FIVE_BUTTONS => {
+                // Only the low 4 bits are the scroll wheel movement, as a 4-bit two's complement number
+                dz = ((self.packet[3] << 4) as i8) >> 4;
+                if self.packet[3] & 0b10000 != 0 {
+                    buttons |= MouseButtons::FOURTH;
+                }
+                if self.packet[3] & 0b100000 != 0 {
+                    buttons |= MouseButtons::FIFTH;
+                }
+            },
+            _ => {}
+        }
+        Some(MouseEvent {
+            dx,
+            dy,
+            dz,
+            buttons,
+            absolute_position: None
+        })
+    }
+}
+
+struct Mouse {
+    parser: PacketParser,
+    events: EventQueue<MouseEvent, EVENT_QUEUE_CAPACITY>
+}
+
+static MOUSE: Mutex<Mouse> = Mutex::new(Mouse {
+    parser: PacketParser::new(DEVICE_ID_STANDARD),
+    events: EventQueue::new()
+});
+
+/// Resets the mouse on the auxiliary PS/2 port, enables the scroll wheel and extra buttons if the mouse
+/// supports them, and enables data reporting. Must be called with interrupts disabled, as the responses of
+/// the mouse are read by polling.
+pub fn init() -> Result<(), Ps2Error> {
+    ps2::enable_aux_port()?;
+    ps2::send_to_aux_device(COMMAND_RESET)?;
+    if ps2::read_data()? != SELF_TEST_PASSED {
+        return Err(Ps2Error::NoAcknowledge);
+    }
+    // Device id sent after the self-test result, which is always the standard id after a reset
+    ps2::read_data()?;
+    ps2::send_to_aux_device(COMMAND_SET_DEFAULTS)?;
+
+    let mut device_id = enable_extension(&[200, 100, 80])?;
+    if device_id == DEVICE_ID_SCROLL_WHEEL {
+        device_id = enable_extension(&[200, 200, 80])?;
+    }
+    ps2::send_to_aux_device(COMMAND_ENABLE_DATA_REPORTING)?;
+
+    without_interrupts(|| {
+        let mut mouse = MOUSE.lock();
+        mouse.parser = PacketParser::new(device_id);
+        mouse.events.clear();
+    });
+    Ok(())
+}
+
+/// Sends a sequence of sample rates, which mice with extensions use as a signal to enable them, and returns
+/// the device id the mouse reports afterwards.
+fn enable_extension(sample_rates: &[u8]) -> Result<u8, Ps2Error> {
+    for rate in sample_rates {
+        ps2::send_to_aux_device(COMMAND_SET_SAMPLE_RATE)?;
+        ps2::send_to_aux_device(*rate)?;
+    }
+    ps2::send_to_aux_device(COMMAND_GET_DEVICE_ID)?;
+    ps2::read_data()
+}
+
+/// Called from the IRQ 12 handler with the byte the controller received from the mouse.
+pub fn handle_byte(byte: u8) {
+    let mut mouse = MOUSE.lock();
+    if let Some(event) = mouse.parser.add_byte(byte) {
+        mouse.events.push(event);
+    }
+}
+
+/// For mice other than the PS/2 one, such as USB mice, to feed their events into the same queue.
+pub fn push_event(event: MouseEvent) {
+    without_interrupts(|| MOUSE.lock().events.push(event));
+}
+
+pub fn pop_event() -> Option<MouseEvent> {
+    without_interrupts(|| MOUSE.lock().events.pop())
+}
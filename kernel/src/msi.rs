@@ -0,0 +1,323 @@
+use core::ptr;
+
+use crate::interrupts::{
+    allocate_vector, allocate_vectors, free_vector, register_handler, DynamicInterruptHandler
+};
+use crate::memory::mmio_to_virtual;
+use crate::pci::{Bar, MsiCapability, MsixCapability, PciCommand, PciDevice};
+
+/// MSIs are memory writes into this physical address range, which the local APICs pick up.
+const MESSAGE_ADDRESS_BASE: u64 = 0xfee00000;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE_SHIFT: u16 = 4;
+
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_VECTOR_CONTROL_MASKED: u32 = 1 << 0;
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum MsiError {
+    NotSupported,
+    NoFreeVectors,
+    TooManyVectors,
+    /// The BAR holding the MSI-X table doesn't exist or isn't a memory BAR
+    InvalidTableBar,
+    /// The BAR holding the MSI-X table is outside of the memory the kernel can access
+    TableNotMapped,
+    InvalidTableIndex
+}
+
+/// Only xAPIC ids fit into the message address. Destinations that would need the x2APIC extended
+/// destination id aren't supported.
+#[inline]
+fn message_address(destination_apic_id: u32) -> u64 {
+    MESSAGE_ADDRESS_BASE | ((destination_apic_id as u64 & 0xff) << 12)
+}
+
+/// Fixed delivery mode, edge triggered, which is all zero bits apart from the vector.
+#[inline]
+fn message_data(vector: u8) -> u32 {
+    vector as u32
+}
+
+/// Turns off the legacy INTx pin of the device, which MSI and MSI-X replace.
+fn disable_legacy_interrupts(device: &PciDevice) {
+    device.set_command(device.command() | PciCommand::INTERRUPT_DISABLE);
+}
+
+/// The MSI configuration of a device. Every message is delivered to the same CPU, with message `n` using
+/// vector `first_vector + n`.
+pub struct Msi {
+    device: &'static PciDevice,
+    capability: MsiCapability,
+    first_vector: u8,
+    vector_count: u8
+}
+
+impl Msi {
+    /// Allocates `vector_count` vectors (rounded up to a power of two, as MSI requires) with `handler`
+    /// registered for each of them, points the device's messages at the local APIC with the id
+    /// `destination_apic_id` and enables MSI.
+    pub fn enable(
+        device: &'static PciDevice,
+        vector_count: u8,
+        destination_apic_id: u32,
+        handler: DynamicInterruptHandler,
+        context: u64
+    ) -> Result<Msi, MsiError> {
+        let capability = device.msi_capability().ok_or(MsiError::NotSupported)?;
+        let vector_count = vector_count
+            .max(1)
+            .checked_next_power_of_two()
+            .ok_or(MsiError::TooManyVectors)?;
+        if vector_count > capability.max_vectors {
+            return Err(MsiError::TooManyVectors);
+        }
+        let first_vector = allocate_vectors(vector_count).ok_or(MsiError::NoFreeVectors)?;
+        for vector in first_vector..first_vector + vector_count {
+            register_handler(vector, handler, context);
+        }
+
+        let address = device.address;
+        let offset = capability.offset;
+        let message_address = message_address(destination_apic_id);
+        address.write_config_u32(offset + 4, message_address as u32);
+        let data_offset = if capability.is_64_bit {
+            address.write_config_u32(offset + 8, (message_address >> 32) as u32);
+            offset + 0xc
+        }
+        else {
+            offset + 8
+        };
+        address.write_config_u16(data_offset, message_data(first_vector) as u16);
+
+        let msi = Msi {
+            device,
+            capability,
+            first_vector,
+            vector_count
+        };
+        for message in 0..vector_count {
+            msi.unmask(message);
+        }
+        disable_legacy_interrupts(device);
+        let mut control = address.read_config_u16(offset + 2);
+        control &= !(0b111 << MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE_SHIFT);
+        control |= (vector_count.trailing_zeros() as u16) << MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE_SHIFT;
+        control |= MSI_CONTROL_ENABLE;
+        address.write_config_u16(offset + 2, control);
+        Ok(msi)
+    }
+
+    #[inline]
+    pub fn first_vector(&self) -> u8 {
+        self.first_vector
+    }
+
+    #[inline]
+    pub fn vector_count(&self) -> u8 {
+        self.vector_count
+    }
+
+    fn mask_bits_offset(&self) -> Option<u16> {
+        if !self.capability.per_vector_masking {
+            return None;
+        }
+        Some(match self.capability.is_64_bit {
+            true => self.capability.offset + 0x10,
+            false => self.capability.offset + 0xc
+        })
+    }
+
+    /// Masks a single message. Does nothing if the device doesn't support per-vector masking.
+    pub fn mask(&self, message: u8) {
+        if let Some(offset) = self.mask_bits_offset() {
+            let mask = self.device.address.read_config_u32(offset);
+            self.device
+                .address
+                .write_config_u32(offset, mask | (1 << message));
+        }
+    }
+
+    pub fn unmask(&self, message: u8) {
+        if let Some(offset) = self.mask_bits_offset() {
+            let mask = self.device.address.read_config_u32(offset);
+            self.device
+                .address
+                .write_config_u32(offset, mask & !(1 << message));
+        }
+    }
+
+    /// Disables MSI on the device and frees the vectors.
+    pub fn disable(self) {
+        let control_offset = self.capability.offset + 2;
+        let control = self.device.address.read_config_u16(control_offset);
+        self.device
+            .address
+            .write_config_u16(control_offset, control & !MSI_CONTROL_ENABLE);
+        for vector in self.first_vector..self.first_vector + self.vector_count {
+            free_vector(vector);
+        }
+    }
+}
+
+#[repr(C)]
+struct MsixTableEntry {
+    message_address_low: u32,
+    message_address_high: u32,
+    message_data: u32,
+    vector_control: u32
+}
+
+/// The MSI-X table of a device, where each entry can be pointed at any vector on any CPU and masked
+/// individually. Entries start out masked and without a vector.
+pub struct MsixTable {
+    device: &'static PciDevice,
+    capability: MsixCapability,
+    table: *mut MsixTableEntry,
+    vectors: [Option<u8>; 64]
+}
+
+unsafe impl Send for MsixTable {}
+
+impl MsixTable {
+    /// Maps the table, masks every entry and enables MSI-X. The device can't send any interrupts until an
+    /// entry is configured with `set_handler()`.
+    pub fn enable(device: &'static PciDevice) -> Result<MsixTable, MsiError> {
+        let capability = device.msix_capability().ok_or(MsiError::NotSupported)?;
+        let table_bar = device
+            .bars
+            .get(capability.table_bar as usize)
+            .copied()
+            .flatten()
+            .ok_or(MsiError::InvalidTableBar)?;
+        let Bar::Memory { address, size, .. } = table_bar
+        else {
+            return Err(MsiError::InvalidTableBar);
+        };
+        let table_size_bytes = capability.table_size as u64 * size_of::<MsixTableEntry>() as u64;
+        if capability.table_offset as u64 + table_size_bytes > size {
+            return Err(MsiError::InvalidTableBar);
+        }
+        let table = mmio_to_virtual(address + capability.table_offset as u64, table_size_bytes)
+            .ok_or(MsiError::TableNotMapped)? as *mut MsixTableEntry;
+        device.enable_memory_and_bus_mastering();
+
+        let msix = MsixTable {
+            device,
+            capability,
+            table,
+            vectors: [None; 64]
+        };
+        // The function mask keeps the device quiet while the individual entries are being masked
+        msix.set_control(MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK);
+        for index in 0..capability.table_size {
+            msix.mask(index);
+        }
+        disable_legacy_interrupts(device);
+        msix.set_control(MSIX_CONTROL_ENABLE);
+        Ok(msix)
+    }
+
+    fn set_control(&self, control_bits: u16) {
+        let offset = self.capability.offset + 2;
+        let control = self.device.address.read_config_u16(offset);
+        let control = (control & !(MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK)) | control_bits;
+        self.device.address.write_config_u16(offset, control);
+    }
+
+    #[inline]
+    pub fn table_size(&self) -> u16 {
+        self.capability.table_size
+    }
+
+    #[inline]
+    fn entry(&self, index: u16) -> *mut MsixTableEntry {
+        unsafe { self.table.add(index as usize) }
+    }
+
+    /// Points an entry at `vector` on the CPU with the local APIC id `destination_apic_id`. The entry is left
+    /// masked.
+    pub fn configure_entry(&self, index: u16, vector: u8, destination_apic_id: u32) -> Result<(), MsiError> {
+        if index >= self.capability.table_size {
+            return Err(MsiError::InvalidTableIndex);
+        }
+        self.mask(index);
+        let entry = self.entry(index);
+        let message_address = message_address(destination_apic_id);
+        unsafe {
+            ptr::write_volatile(
+                ptr::addr_of_mut!((*entry).message_address_low),
+                message_address as u32
+            );
+            ptr::write_volatile(
+                ptr::addr_of_mut!((*entry).message_address_high),
+                (message_address >> 32) as u32
+            );
+            ptr::write_volatile(ptr::addr_of_mut!((*entry).message_data), message_data(vector));
+        }
+        Ok(())
+    }
+
+    /// Allocates a vector for an entry, registers `handler` for it, targets it at the given CPU and unmasks
+    /// the entry. Returns the allocated vector.
+    pub fn set_handler(
+        &mut self,
+        index: u16,
+        destination_apic_id: u32,
+        handler: DynamicInterruptHandler,
+        context: u64
+    ) -> Result<u8, MsiError> {
+        if index >= self.capability.table_size || index as usize >= self.vectors.len() {
+            return Err(MsiError::InvalidTableIndex);
+        }
+        if let Some(old_vector) = self.vectors[index as usize].take() {
+            self.mask(index);
+            free_vector(old_vector);
+        }
+        let vector = allocate_vector().ok_or(MsiError::NoFreeVectors)?;
+        register_handler(vector, handler, context);
+        self.configure_entry(index, vector, destination_apic_id)?;
+        self.vectors[index as usize] = Some(vector);
+        self.unmask(index);
+        Ok(vector)
+    }
+
+    pub fn mask(&self, index: u16) {
+        let control = unsafe { ptr::addr_of_mut!((*self.entry(index)).vector_control) };
+        unsafe { ptr::write_volatile(control, ptr::read_volatile(control) | MSIX_VECTOR_CONTROL_MASKED) };
+    }
+
+    pub fn unmask(&self, index: u16) {
+        let control = unsafe { ptr::addr_of_mut!((*self.entry(index)).vector_control) };
+        unsafe { ptr::write_volatile(control, ptr::read_volatile(control) & !MSIX_VECTOR_CONTROL_MASKED) };
+    }
+
+    /// Whether the entry has a message waiting to be sent once it's unmasked.
+    pub fn is_pending(&self, index: u16) -> bool {
+        let Some(Some(Bar::Memory { address, .. })) = self
+            .device
+            .bars
+            .get(self.capability.pending_bit_array_bar as usize)
+        else {
+            return false;
+        };
+        let qword_address =
+            address + self.capability.pending_bit_array_offset as u64 + (index as u64 / 64) * 8;
+        let Some(qword) = mmio_to_virtual(qword_address, 8)
+        else {
+            return false;
+        };
+        unsafe { ptr::read_volatile(qword as *const u64) & (1 << (index % 64)) != 0 }
+    }
+
+    /// Disables MSI-X on the device and frees every vector allocated through `set_handler()`.
+    pub fn disable(self) {
+        self.set_control(MSIX_CONTROL_FUNCTION_MASK);
+        for vector in self.vectors.iter().flatten() {
+            free_vector(*vector);
+        }
+    }
+}
@@ -25,3 +25,14 @@ pub fn read_msr_only_low_order_32bits(address: u32) -> u32 {
     }
     msr_value_low
 }
+
+pub fn write_msr(address: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") address,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32
+        )
+    }
+}
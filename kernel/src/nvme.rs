@@ -0,0 +1,637 @@
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use arrayvec::{ArrayString, ArrayVec};
+use spin::{Mutex, Once};
+
+use crate::acpi;
+use crate::apic::local_apic_id;
+use crate::block::{self, BlockDevice, BlockError, BlockOperation, RequestQueue, MAX_BLOCK_SIZE};
+use crate::interrupts_general::{
+    are_interrupts_enabled, disable_interrupts, enable_interrupts, enable_interrupts_and_halt
+};
+use crate::memory::{allocate_frames, mmio_to_virtual, PhysicalFrames, FRAME_SIZE};
+use crate::msi::MsixTable;
+use crate::pci::{Bar, PciDevice, PciDeviceId, PciDriver, ProbeError};
+
+const MAX_CONTROLLERS: usize = 4;
+const MAX_NAMESPACES: usize = 16;
+/// One I/O queue pair is created per CPU, up to this many.
+const MAX_IO_QUEUES: usize = 16;
+/// Every queue has at most this many entries. Only one command is in progress per queue at a time, so
+/// there's no point in more.
+const QUEUE_ENTRIES: u16 = 64;
+/// Transfers are bounced through this many frames of DMA memory per I/O queue, and split into commands of at
+/// most this size.
+const TRANSFER_BUFFER_FRAMES: u64 = 32;
+/// How many times the registers or a completion queue are polled before an operation is considered to have
+/// timed out. When waiting for an interrupt, every iteration halts instead of spinning.
+const POLL_ITERATIONS: u32 = 10000000;
+
+const REG_CAP: u64 = 0x00;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1c;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+const DOORBELLS_OFFSET: u64 = 0x1000;
+
+const CAP_MAX_QUEUE_ENTRIES_MASK: u64 = 0xffff;
+const CAP_DOORBELL_STRIDE_SHIFT: u64 = 32;
+const CAP_NVM_COMMAND_SET: u64 = 1 << 37;
+const CAP_MIN_PAGE_SIZE_SHIFT: u64 = 48;
+const CC_ENABLE: u32 = 1 << 0;
+/// Submission queue entries are 2^6 bytes and completion queue entries 2^4 bytes
+const CC_QUEUE_ENTRY_SIZES: u32 = (6 << 16) | (4 << 20);
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL_STATUS: u32 = 1 << 1;
+
+const ADMIN_OPCODE_CREATE_IO_SUBMISSION_QUEUE: u8 = 0x01;
+const ADMIN_OPCODE_CREATE_IO_COMPLETION_QUEUE: u8 = 0x05;
+const ADMIN_OPCODE_IDENTIFY: u8 = 0x06;
+const ADMIN_OPCODE_SET_FEATURES: u8 = 0x09;
+const IO_OPCODE_FLUSH: u8 = 0x00;
+const IO_OPCODE_WRITE: u8 = 0x01;
+const IO_OPCODE_READ: u8 = 0x02;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+const QUEUE_PHYSICALLY_CONTIGUOUS: u32 = 1 << 0;
+const COMPLETION_QUEUE_INTERRUPTS_ENABLED: u32 = 1 << 1;
+
+/// Byte offsets into the identify controller data structure
+const IDENTIFY_CONTROLLER_MAX_TRANSFER_SIZE: usize = 77;
+const IDENTIFY_CONTROLLER_VOLATILE_WRITE_CACHE: usize = 525;
+/// Byte offsets into the identify namespace data structure
+const IDENTIFY_NAMESPACE_SIZE: usize = 0;
+const IDENTIFY_NAMESPACE_FORMATTED_LBA_SIZE: usize = 26;
+const IDENTIFY_NAMESPACE_LBA_FORMATS: usize = 128;
+
+const STATUS_PHASE: u16 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct SubmissionEntry {
+    opcode: u8,
+    flags: u8,
+    command_id: u16,
+    namespace_id: u32,
+    reserved: u64,
+    metadata_pointer: u64,
+    /// The physical region page entries pointing at the data. The second one points at a PRP list if the
+    /// data spans more than two pages.
+    prp: [u64; 2],
+    command_dwords: [u32; 6]
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CompletionEntry {
+    result: u32,
+    reserved: u32,
+    submission_queue_head: u16,
+    submission_queue_id: u16,
+    command_id: u16,
+    /// The status field, with the phase tag in the lowest bit
+    status: u16
+}
+
+#[inline]
+fn read_u32(address: u64) -> u32 {
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
+
+#[inline]
+fn write_u32(address: u64, value: u32) {
+    unsafe { ptr::write_volatile(address as *mut u32, value) }
+}
+
+/// 64-bit registers are accessed as two dwords, low half first, which works whether or not the controller
+/// supports 64-bit accesses.
+#[inline]
+fn read_u64(address: u64) -> u64 {
+    read_u32(address) as u64 | (read_u32(address + 4) as u64) << 32
+}
+
+#[inline]
+fn write_u64(address: u64, value: u64) {
+    write_u32(address, value as u32);
+    write_u32(address + 4, (value >> 32) as u32);
+}
+
+fn poll_until(mut condition: impl FnMut() -> bool) -> Result<(), BlockError> {
+    for _ in 0..POLL_ITERATIONS {
+        if condition() {
+            return Ok(());
+        }
+    }
+    Err(BlockError::Timeout)
+}
+
+/// A submission queue along with the completion queue its commands complete on, and the memory for the
+/// data of its commands. Only one command is in progress at a time.
+struct QueuePair {
+    id: u16,
+    submission_queue: PhysicalFrames,
+    completion_queue: PhysicalFrames,
+    submission_tail: u16,
+    completion_head: u16,
+    /// The phase tag completion entries written in the current pass through the completion queue have
+    phase: bool,
+    next_command_id: u16,
+    /// Data is bounced through this buffer, whose pages are listed in `prp_list`
+    buffer: PhysicalFrames,
+    prp_list: PhysicalFrames,
+    /// The local APIC id of the CPU this queue's interrupts go to, if it has interrupts
+    interrupt_apic_id: Option<u32>
+}
+
+unsafe impl Send for QueuePair {}
+
+impl QueuePair {
+    fn new(id: u16, interrupt_apic_id: Option<u32>) -> Result<QueuePair, BlockError> {
+        let allocate = |count| allocate_frames(count).ok_or(BlockError::OutOfMemory);
+        Ok(QueuePair {
+            id,
+            submission_queue: allocate(1)?,
+            completion_queue: allocate(1)?,
+            submission_tail: 0,
+            completion_head: 0,
+            phase: true,
+            next_command_id: 0,
+            buffer: allocate(TRANSFER_BUFFER_FRAMES)?,
+            prp_list: allocate(1)?,
+            interrupt_apic_id
+        })
+    }
+
+    /// Points the first PRP entry at the start of the buffer, and the second one at either its second page
+    /// or a list of every page after the first one.
+    fn data_pointers(&self, length: usize) -> [u64; 2] {
+        let pages = (length as u64).div_ceil(FRAME_SIZE).max(1);
+        let first = self.buffer.physical_address;
+        let second = match pages {
+            1 => 0,
+            2 => first + FRAME_SIZE,
+            _ => {
+                let list = self.prp_list.as_ptr::<u64>();
+                for page in 1..pages {
+                    unsafe { ptr::write_volatile(list.add(page as usize - 1), first + page * FRAME_SIZE) };
+                }
+                self.prp_list.physical_address
+            }
+        };
+        [first, second]
+    }
+
+    /// Submits a command and waits for it to complete, returning the command specific result.
+    fn run_command(
+        &mut self,
+        controller: &NvmeController,
+        mut entry: SubmissionEntry
+    ) -> Result<u32, BlockError> {
+        entry.command_id = self.next_command_id;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+        unsafe {
+            ptr::write_volatile(
+                self.submission_queue
+                    .as_ptr::<SubmissionEntry>()
+                    .add(self.submission_tail as usize),
+                entry
+            )
+        };
+        self.submission_tail = (self.submission_tail + 1) % QUEUE_ENTRIES;
+        // The entry has to be in memory before the controller is told about it
+        fence(Ordering::SeqCst);
+        write_u32(controller.doorbell(self.id, false), self.submission_tail as u32);
+
+        let completion = self.wait_for_completion(controller)?;
+        self.completion_head = (self.completion_head + 1) % QUEUE_ENTRIES;
+        if self.completion_head == 0 {
+            self.phase = !self.phase;
+        }
+        write_u32(controller.doorbell(self.id, true), self.completion_head as u32);
+        if completion.command_id != entry.command_id || completion.status >> 1 != 0 {
+            return Err(BlockError::DeviceError);
+        }
+        Ok(completion.result)
+    }
+
+    fn next_completion(&self) -> Option<CompletionEntry> {
+        let entry = unsafe {
+            ptr::read_volatile(
+                self.completion_queue
+                    .as_ptr::<CompletionEntry>()
+                    .add(self.completion_head as usize)
+            )
+        };
+        ((entry.status & STATUS_PHASE != 0) == self.phase).then_some(entry)
+    }
+
+    /// Waits for the next completion entry. If the queue's interrupts go to the current CPU, the CPU is
+    /// halted in between checks, which it is woken up from by the interrupt.
+    fn wait_for_completion(&self, controller: &NvmeController) -> Result<CompletionEntry, BlockError> {
+        let halt = self.interrupt_apic_id == Some(local_apic_id()) && are_interrupts_enabled();
+        for _ in 0..POLL_ITERATIONS {
+            if !halt {
+                if let Some(entry) = self.next_completion() {
+                    return Ok(entry);
+                }
+                continue;
+            }
+            // Checking with interrupts disabled makes sure the interrupt can't arrive before halting
+            disable_interrupts();
+            if let Some(entry) = self.next_completion() {
+                enable_interrupts();
+                return Ok(entry);
+            }
+            enable_interrupts_and_halt();
+        }
+        if controller.read_u32(REG_CSTS) & CSTS_FATAL_STATUS != 0 {
+            return Err(BlockError::DeviceError);
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Runs a command transferring `length` bytes from or into the queue's buffer.
+    fn run_data_command(
+        &mut self,
+        controller: &NvmeController,
+        mut entry: SubmissionEntry,
+        length: usize
+    ) -> Result<u32, BlockError> {
+        entry.prp = self.data_pointers(length);
+        self.run_command(controller, entry)
+    }
+}
+
+/// An I/O queue pair along with the CPU it belongs to.
+struct IoQueue {
+    apic_id: u32,
+    queue: Mutex<QueuePair>
+}
+
+struct NvmeController {
+    registers: u64,
+    doorbell_stride: u64,
+    admin_queue: Mutex<QueuePair>,
+    io_queues: ArrayVec<IoQueue, MAX_IO_QUEUES>,
+    /// The largest transfer a single command can do
+    max_transfer_size: usize,
+    volatile_write_cache: bool
+}
+
+impl NvmeController {
+    #[inline]
+    fn read_u32(&self, register: u64) -> u32 {
+        read_u32(self.registers + register)
+    }
+
+    #[inline]
+    fn doorbell(&self, queue_id: u16, completion: bool) -> u64 {
+        let index = queue_id as u64 * 2 + completion as u64;
+        self.registers + DOORBELLS_OFFSET + index * self.doorbell_stride
+    }
+
+    /// The I/O queue pair of the current CPU, or one shared with other CPUs if there weren't enough queues.
+    fn io_queue(&self) -> &Mutex<QueuePair> {
+        let apic_id = local_apic_id();
+        let io_queue = self
+            .io_queues
+            .iter()
+            .find(|q| q.apic_id == apic_id)
+            .unwrap_or(&self.io_queues[apic_id as usize % self.io_queues.len()]);
+        &io_queue.queue
+    }
+
+    fn identify(&self, structure: u32, namespace_id: u32) -> Result<[u8; FRAME_SIZE as usize], BlockError> {
+        let mut admin_queue = self.admin_queue.lock();
+        let mut entry = SubmissionEntry {
+            opcode: ADMIN_OPCODE_IDENTIFY,
+            namespace_id,
+            ..Default::default()
+        };
+        entry.command_dwords[0] = structure;
+        admin_queue.run_data_command(self, entry, FRAME_SIZE as usize)?;
+        let mut data = [0; FRAME_SIZE as usize];
+        unsafe { ptr::copy_nonoverlapping(admin_queue.buffer.as_ptr::<u8>(), data.as_mut_ptr(), data.len()) };
+        Ok(data)
+    }
+
+    /// Asks for `count` I/O queue pairs, returning how many the controller has allocated.
+    fn request_io_queues(&self, count: u16) -> Result<u16, BlockError> {
+        let mut entry = SubmissionEntry {
+            opcode: ADMIN_OPCODE_SET_FEATURES,
+            ..Default::default()
+        };
+        entry.command_dwords[0] = FEATURE_NUMBER_OF_QUEUES;
+        // Both counts are zero-based
+        entry.command_dwords[1] = (count as u32 - 1) << 16 | (count as u32 - 1);
+        let result = self.admin_queue.lock().run_command(self, entry)?;
+        let submission_queues = (result & 0xffff) as u16 + 1;
+        let completion_queues = (result >> 16) as u16 + 1;
+        Ok(count.min(submission_queues).min(completion_queues))
+    }
+
+    /// Creates the completion queue and then the submission queue of an I/O queue pair, with the completion
+    /// queue's interrupts going through the MSI-X entry with the same index as the queue, if any.
+    fn create_io_queue(&self, queue: &QueuePair) -> Result<(), BlockError> {
+        let mut admin_queue = self.admin_queue.lock();
+        let size_and_id = (QUEUE_ENTRIES as u32 - 1) << 16 | queue.id as u32;
+        let mut completion_flags = QUEUE_PHYSICALLY_CONTIGUOUS;
+        if queue.interrupt_apic_id.is_some() {
+            completion_flags |= COMPLETION_QUEUE_INTERRUPTS_ENABLED | (queue.id as u32) << 16;
+        }
+        let mut entry = SubmissionEntry {
+            opcode: ADMIN_OPCODE_CREATE_IO_COMPLETION_QUEUE,
+            prp: [queue.completion_queue.physical_address, 0],
+            ..Default::default()
+        };
+        entry.command_dwords[0] = size_and_id;
+        entry.command_dwords[1] = completion_flags;
+        admin_queue.run_command(self, entry)?;
+
+        let mut entry = SubmissionEntry {
+            opcode: ADMIN_OPCODE_CREATE_IO_SUBMISSION_QUEUE,
+            prp: [queue.submission_queue.physical_address, 0],
+            ..Default::default()
+        };
+        entry.command_dwords[0] = size_and_id;
+        entry.command_dwords[1] = (queue.id as u32) << 16 | QUEUE_PHYSICALLY_CONTIGUOUS;
+        admin_queue.run_command(self, entry)?;
+        Ok(())
+    }
+}
+
+/// A namespace of an NVMe controller, which is what holds the blocks.
+pub struct NvmeNamespace {
+    name: ArrayString<16>,
+    controller: &'static NvmeController,
+    id: u32,
+    block_size: u32,
+    block_count: u64,
+    request_queue: RequestQueue
+}
+
+impl NvmeNamespace {
+    /// Reads or writes `length` bytes at `buffer` starting from `first_block`, in commands of at most the
+    /// controller's maximum transfer size.
+    fn transfer(
+        &self,
+        operation: BlockOperation,
+        first_block: u64,
+        buffer: *mut u8,
+        length: usize
+    ) -> Result<(), BlockError> {
+        let block_size = self.block_size as usize;
+        let mut queue = self.controller.io_queue().lock();
+        let mut done = 0;
+        while done < length {
+            let chunk_length = (length - done).min(self.controller.max_transfer_size);
+            let block = first_block + (done / block_size) as u64;
+            let opcode = match operation {
+                BlockOperation::Read => IO_OPCODE_READ,
+                BlockOperation::Write => IO_OPCODE_WRITE
+            };
+            let mut entry = SubmissionEntry {
+                opcode,
+                namespace_id: self.id,
+                ..Default::default()
+            };
+            entry.command_dwords[0] = block as u32;
+            entry.command_dwords[1] = (block >> 32) as u32;
+            // Zero-based
+            entry.command_dwords[2] = (chunk_length / block_size - 1) as u32;
+            let buffer_chunk = unsafe { buffer.add(done) };
+            if operation == BlockOperation::Write {
+                unsafe { ptr::copy_nonoverlapping(buffer_chunk, queue.buffer.as_ptr(), chunk_length) };
+            }
+            queue.run_data_command(self.controller, entry, chunk_length)?;
+            if operation == BlockOperation::Read {
+                unsafe { ptr::copy_nonoverlapping(queue.buffer.as_ptr(), buffer_chunk, chunk_length) };
+            }
+            done += chunk_length;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        self.transfer(
+            BlockOperation::Read,
+            first_block,
+            buffer.as_mut_ptr(),
+            buffer.len()
+        )
+    }
+
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        // Only read from, despite the pointer being mutable
+        self.transfer(
+            BlockOperation::Write,
+            first_block,
+            buffer.as_ptr() as *mut u8,
+            buffer.len()
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // Without a volatile write cache, everything is persistent as soon as it's written
+        if !self.controller.volatile_write_cache {
+            return Ok(());
+        }
+        let entry = SubmissionEntry {
+            opcode: IO_OPCODE_FLUSH,
+            namespace_id: self.id,
+            ..Default::default()
+        };
+        self.controller
+            .io_queue()
+            .lock()
+            .run_command(self.controller, entry)?;
+        Ok(())
+    }
+
+    fn request_queue(&self) -> &RequestQueue {
+        &self.request_queue
+    }
+}
+
+static CONTROLLERS: [Once<NvmeController>; MAX_CONTROLLERS] = [const { Once::new() }; MAX_CONTROLLERS];
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NAMESPACES: [Once<NvmeNamespace>; MAX_NAMESPACES] = [const { Once::new() }; MAX_NAMESPACES];
+static NAMESPACE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Does nothing, as the interrupt only wakes up whoever is waiting for a completion, which checks the
+/// completion queue by themselves.
+fn handle_interrupt(_vector: u8, _context: u64) {}
+
+/// Resets the controller and sets up its admin queue, leaving it enabled.
+fn enable(registers: u64, admin_queue: &QueuePair) -> Result<(), BlockError> {
+    let configuration = registers + REG_CC;
+    write_u32(configuration, read_u32(configuration) & !CC_ENABLE);
+    poll_until(|| read_u32(registers + REG_CSTS) & CSTS_READY == 0)?;
+    let size = (QUEUE_ENTRIES as u32 - 1) << 16 | (QUEUE_ENTRIES as u32 - 1);
+    write_u32(registers + REG_AQA, size);
+    write_u64(registers + REG_ASQ, admin_queue.submission_queue.physical_address);
+    write_u64(registers + REG_ACQ, admin_queue.completion_queue.physical_address);
+    // The NVM command set and a page size of 4 KiB are both zero
+    write_u32(configuration, CC_QUEUE_ENTRY_SIZES | CC_ENABLE);
+    poll_until(|| read_u32(registers + REG_CSTS) & (CSTS_READY | CSTS_FATAL_STATUS) != 0)?;
+    if read_u32(registers + REG_CSTS) & CSTS_FATAL_STATUS != 0 {
+        return Err(BlockError::DeviceError);
+    }
+    Ok(())
+}
+
+fn probe_namespace(controller: &'static NvmeController, id: u32) -> Result<(), BlockError> {
+    let data = controller.identify(IDENTIFY_NAMESPACE, id)?;
+    let read_u64 = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    let block_count = read_u64(IDENTIFY_NAMESPACE_SIZE);
+    let format = (data[IDENTIFY_NAMESPACE_FORMATTED_LBA_SIZE] & 0xf) as usize;
+    // Each format is the metadata size, the LBA data size as a power of two and the relative performance
+    let block_size_shift = data[IDENTIFY_NAMESPACE_LBA_FORMATS + format * 4 + 2];
+    let block_size = 1u32.checked_shl(block_size_shift as u32).unwrap_or(0);
+    if block_count == 0 || block_size < 512 || block_size as usize > MAX_BLOCK_SIZE {
+        return Err(BlockError::NoMedium);
+    }
+    let index = NAMESPACE_COUNT.fetch_add(1, Ordering::Relaxed);
+    let slot = NAMESPACES.get(index).ok_or(BlockError::OutOfMemory)?;
+    let mut name = ArrayString::new();
+    let _ = write!(name, "nvme{}", index);
+    let namespace = slot.call_once(|| NvmeNamespace {
+        name,
+        controller,
+        id,
+        block_size,
+        block_count,
+        request_queue: RequestQueue::new()
+    });
+    block::register_device(namespace)
+}
+
+/// Creates an I/O queue pair per CPU, as far as the controller and its MSI-X table allow. Queue `n` has its
+/// interrupts go to the `n`th CPU through MSI-X entry `n`. Entry 0 would be the admin queue's, which is left
+/// masked as admin commands are only ever polled for.
+fn create_io_queues(
+    controller: &mut NvmeController,
+    mut interrupts: Option<&mut MsixTable>
+) -> Result<(), BlockError> {
+    let apic_ids = acpi::processor_apic_ids()
+        .ok()
+        .filter(|ids| !ids.is_empty())
+        .unwrap_or_else(|| [local_apic_id()].into_iter().collect());
+    let mut count = apic_ids.len().min(MAX_IO_QUEUES) as u16;
+    if let Some(interrupts) = &interrupts {
+        count = count.min(interrupts.table_size().saturating_sub(1)).max(1);
+    }
+    let count = controller.request_io_queues(count)?;
+    for (i, &apic_id) in apic_ids.iter().take(count as usize).enumerate() {
+        let id = i as u16 + 1;
+        let interrupt_apic_id = match interrupts.as_deref_mut() {
+            Some(table) => table
+                .set_handler(id, apic_id, handle_interrupt, 0)
+                .ok()
+                .map(|_| apic_id),
+            None => None
+        };
+        let queue = QueuePair::new(id, interrupt_apic_id)?;
+        controller.create_io_queue(&queue)?;
+        controller.io_queues.push(IoQueue {
+            apic_id,
+            queue: Mutex::new(queue)
+        });
+    }
+    Ok(())
+}
+
+fn initialize(pci_device: &'static PciDevice) -> Result<NvmeController, BlockError> {
+    let Some(Bar::Memory { address, size, .. }) = pci_device.bars[0]
+    else {
+        return Err(BlockError::DeviceError);
+    };
+    let registers = mmio_to_virtual(address, size).ok_or(BlockError::DeviceError)?;
+    pci_device.enable_memory_and_bus_mastering();
+    let capabilities = read_u64(registers + REG_CAP);
+    let min_page_size = FRAME_SIZE << ((capabilities >> CAP_MIN_PAGE_SIZE_SHIFT) & 0xf);
+    let max_queue_entries = (capabilities & CAP_MAX_QUEUE_ENTRIES_MASK) + 1;
+    if capabilities & CAP_NVM_COMMAND_SET == 0
+        || min_page_size != FRAME_SIZE
+        || max_queue_entries < QUEUE_ENTRIES as u64
+    {
+        return Err(BlockError::DeviceError);
+    }
+    let admin_queue = QueuePair::new(0, None)?;
+    enable(registers, &admin_queue)?;
+
+    let mut controller = NvmeController {
+        registers,
+        doorbell_stride: 4 << ((capabilities >> CAP_DOORBELL_STRIDE_SHIFT) & 0xf),
+        admin_queue: Mutex::new(admin_queue),
+        io_queues: ArrayVec::new(),
+        max_transfer_size: (TRANSFER_BUFFER_FRAMES * FRAME_SIZE) as usize,
+        volatile_write_cache: false
+    };
+    let data = controller.identify(IDENTIFY_CONTROLLER, 0)?;
+    // In units of the minimum page size as a power of two, with zero meaning no limit
+    let max_transfer_size_shift = data[IDENTIFY_CONTROLLER_MAX_TRANSFER_SIZE];
+    if max_transfer_size_shift != 0 {
+        let limit = (min_page_size as usize).checked_shl(max_transfer_size_shift as u32);
+        controller.max_transfer_size = controller.max_transfer_size.min(limit.unwrap_or(usize::MAX));
+    }
+    controller.volatile_write_cache = data[IDENTIFY_CONTROLLER_VOLATILE_WRITE_CACHE] & 1 != 0;
+
+    // Commands are waited for by polling if MSI-X isn't available. The table doesn't need to be kept around,
+    // as MSI-X is never disabled again.
+    let mut interrupts = MsixTable::enable(pci_device).ok();
+    create_io_queues(&mut controller, interrupts.as_mut())?;
+    Ok(controller)
+}
+
+/// Initializes the controller and registers a block device for every active namespace. Namespaces attached
+/// later on aren't noticed.
+fn probe(pci_device: &'static PciDevice) -> Result<(), ProbeError> {
+    let index = CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed);
+    let slot = CONTROLLERS.get(index).ok_or(ProbeError::Unsupported)?;
+    let controller = initialize(pci_device).map_err(|_| ProbeError::InitializationFailed)?;
+    let controller = slot.call_once(|| controller);
+    // A list of up to 1024 namespace ids, ending with a zero
+    let Ok(data) = controller.identify(IDENTIFY_ACTIVE_NAMESPACES, 0)
+    else {
+        return Ok(());
+    };
+    for id in data
+        .chunks_exact(4)
+        .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+    {
+        if id == 0 {
+            break;
+        }
+        let _ = probe_namespace(controller, id);
+    }
+    Ok(())
+}
+
+pub static NVME_DRIVER: PciDriver = PciDriver {
+    name: "nvme",
+    ids: &[PciDeviceId::class(0x01, 0x08, Some(0x02))],
+    probe
+};
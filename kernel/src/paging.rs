@@ -0,0 +1,425 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+
+use bitflags::bitflags;
+use spin::Once;
+
+use crate::memory::{
+    allocate_frames, free_frames, is_frame_shared, physical_to_virtual_ptr, release_frame, share_frame,
+    PhysicalFrames, FRAME_SIZE
+};
+use crate::msr::{read_msr, write_msr};
+
+const ENTRY_COUNT: usize = 512;
+/// The PML4 entries from here on map the higher half, which belongs to the kernel and is the same in every
+/// address space
+const KERNEL_PML4_START: usize = 256;
+/// The bits of a page table entry holding the physical address it points to
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const MSR_EFER: u32 = 0xc0000080;
+const EFER_NXE: u64 = 1 << 11;
+/// CPUID leaf 0x80000001 reports the no-execute bit in EDX bit 20
+const CPUID_EXTENDED_FEATURES: u32 = 0x80000001;
+const CPUID_NX: u32 = 1 << 20;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct PageFlags: u64 {
+        const PRESENT = 1 << 0;
+        const WRITABLE = 1 << 1;
+        /// Accessible from user mode
+        const USER = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const NO_CACHE = 1 << 4;
+        const ACCESSED = 1 << 5;
+        const DIRTY = 1 << 6;
+        const HUGE = 1 << 7;
+        const GLOBAL = 1 << 8;
+        /// Ignored by the CPU. The frame belongs to something other than the address space, e.g. a device,
+        /// so it isn't freed along with the address space.
+        const NOT_OWNED = 1 << 9;
+        /// Ignored by the CPU. The frame is shared with other address spaces, so the page is mapped read-only
+        /// and gets a copy of its own once it's written to, see `AddressSpace::copy_on_write()`.
+        const COPY_ON_WRITE = 1 << 10;
+        /// Only takes effect if the CPU supports it, see `set_no_execute()`
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum PagingError {
+    OutOfMemory,
+    AlreadyMapped,
+    NotMapped,
+    /// The address isn't page aligned, or not in the lower half
+    InvalidAddress
+}
+
+/// The page tables the kernel runs on outside of user programs, which are the ones Limine set up.
+static KERNEL_PML4: Once<u64> = Once::new();
+static NO_EXECUTE_SUPPORTED: Once<bool> = Once::new();
+
+/// Remembers the kernel's page tables and turns on no-execute pages if the CPU has them. This has to be
+/// called before any address space is created.
+pub fn init() {
+    KERNEL_PML4.call_once(current_pml4);
+    NO_EXECUTE_SUPPORTED.call_once(|| {
+        let extended_features = unsafe { __cpuid(CPUID_EXTENDED_FEATURES) };
+        if extended_features.edx & CPUID_NX == 0 {
+            return false;
+        }
+        write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_NXE);
+        true
+    });
+}
+
+/// Adds or removes `NO_EXECUTE`, which is left out on CPUs that don't support it, as it's a reserved bit for
+/// them.
+pub fn set_no_execute(flags: PageFlags, no_execute: bool) -> PageFlags {
+    let no_execute = no_execute && NO_EXECUTE_SUPPORTED.get() == Some(&true);
+    match no_execute {
+        true => flags | PageFlags::NO_EXECUTE,
+        false => flags - PageFlags::NO_EXECUTE
+    }
+}
+
+/// The physical address of the PML4 that's in use.
+fn current_pml4() -> u64 {
+    let cr3: u64;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+    cr3 & ADDRESS_MASK
+}
+
+/// Loads the page tables at `pml4`, as returned by `AddressSpace::page_tables()`, unless they're in use
+/// already. This is for switching between threads, which can't hold on to the address space itself.
+pub fn activate_page_tables(pml4: u64) {
+    if current_pml4() != pml4 {
+        unsafe { asm!("mov cr3, {}", in(reg) pml4, options(nostack, preserves_flags)) };
+    }
+}
+
+/// Switches to the kernel's own page tables, e.g. before the address space in use goes away.
+pub fn activate_kernel_address_space() {
+    let pml4 = *KERNEL_PML4.get().expect("Paging not initialized");
+    if current_pml4() != pml4 {
+        unsafe { asm!("mov cr3, {}", in(reg) pml4, options(nostack, preserves_flags)) };
+    }
+}
+
+#[inline]
+fn invalidate_page(virtual_address: u64) {
+    unsafe { asm!("invlpg [{}]", in(reg) virtual_address, options(nostack, preserves_flags)) };
+}
+
+/// The index into the page table at `level` (4 for the PML4 down to 1 for page tables) for an address.
+#[inline]
+fn table_index(virtual_address: u64, level: u32) -> usize {
+    (virtual_address >> (12 + 9 * (level - 1)) & 0x1ff) as usize
+}
+
+#[inline]
+fn table(physical_address: u64) -> *mut u64 {
+    physical_to_virtual_ptr(physical_address)
+}
+
+/// Returns the page table entry for a page in the lower half of the address space with the PML4 at `pml4`,
+/// creating the page tables on the way if `create` is set.
+fn page_table_entry(pml4: u64, virtual_address: u64, create: bool) -> Result<*mut u64, PagingError> {
+    let mut table_address = pml4;
+    for level in (2..=4).rev() {
+        let entry = unsafe { table(table_address).add(table_index(virtual_address, level)) };
+        let value = unsafe { *entry };
+        if value & PageFlags::PRESENT.bits() == 0 {
+            if !create {
+                return Err(PagingError::NotMapped);
+            }
+            let frames = allocate_frames(1).ok_or(PagingError::OutOfMemory)?;
+            // Whether a page is writable or accessible by user mode is decided by its own entry alone
+            let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER;
+            unsafe { *entry = frames.physical_address | flags.bits() };
+            table_address = frames.physical_address;
+        }
+        else {
+            table_address = value & ADDRESS_MASK;
+        }
+    }
+    Ok(unsafe { table(table_address).add(table_index(virtual_address, 1)) })
+}
+
+#[inline]
+fn check_user_page(virtual_address: u64) -> Result<(), PagingError> {
+    let in_lower_half = table_index(virtual_address, 4) < KERNEL_PML4_START && virtual_address >> 48 == 0;
+    match virtual_address % FRAME_SIZE == 0 && in_lower_half {
+        true => Ok(()),
+        false => Err(PagingError::InvalidAddress)
+    }
+}
+
+/// Looks up where a page of the address space in use is mapped, returning the physical address and flags.
+pub fn translate_current(virtual_address: u64) -> Option<(u64, PageFlags)> {
+    translate_in(current_pml4(), virtual_address)
+}
+
+fn translate_in(pml4: u64, virtual_address: u64) -> Option<(u64, PageFlags)> {
+    let page = virtual_address - virtual_address % FRAME_SIZE;
+    check_user_page(page).ok()?;
+    let entry = unsafe { *page_table_entry(pml4, page, false).ok()? };
+    if entry & PageFlags::PRESENT.bits() == 0 {
+        return None;
+    }
+    Some((
+        (entry & ADDRESS_MASK) | (virtual_address % FRAME_SIZE),
+        PageFlags::from_bits_truncate(entry & !ADDRESS_MASK)
+    ))
+}
+
+/// A set of page tables, where the lower half is for a user program and the higher half is shared with the
+/// kernel. Frames mapped into the lower half belong to the address space and are freed with it, unless
+/// they're mapped with `NOT_OWNED`.
+pub struct AddressSpace {
+    pml4: u64
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in the lower half. The kernel's PML4 entries are copied,
+    /// so the kernel mustn't map anything into parts of the higher half that had no page tables at boot.
+    pub fn new() -> Result<AddressSpace, PagingError> {
+        let kernel_pml4 = *KERNEL_PML4.get().expect("Paging not initialized");
+        let frames = allocate_frames(1).ok_or(PagingError::OutOfMemory)?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                table(kernel_pml4).add(KERNEL_PML4_START),
+                table(frames.physical_address).add(KERNEL_PML4_START),
+                ENTRY_COUNT - KERNEL_PML4_START
+            )
+        };
+        Ok(AddressSpace {
+            pml4: frames.physical_address
+        })
+    }
+
+    /// The physical address of the PML4, for `activate_page_tables()`.
+    #[inline]
+    pub fn page_tables(&self) -> u64 {
+        self.pml4
+    }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        current_pml4() == self.pml4
+    }
+
+    /// Switches to the address space by loading CR3, which also flushes the TLB.
+    pub fn activate(&self) {
+        unsafe { asm!("mov cr3, {}", in(reg) self.pml4, options(nostack, preserves_flags)) };
+    }
+
+    /// Maps the page at `virtual_address` to the frame at `physical_address`. `PRESENT` is added to the
+    /// flags.
+    pub fn map(
+        &mut self,
+        virtual_address: u64,
+        physical_address: u64,
+        flags: PageFlags
+    ) -> Result<(), PagingError> {
+        check_user_page(virtual_address)?;
+        let entry = page_table_entry(self.pml4, virtual_address, true)?;
+        unsafe {
+            if *entry & PageFlags::PRESENT.bits() != 0 {
+                return Err(PagingError::AlreadyMapped);
+            }
+            *entry = physical_address & ADDRESS_MASK | (flags | PageFlags::PRESENT).bits();
+        }
+        Ok(())
+    }
+
+    /// Maps a newly allocated, zeroed frame at `virtual_address`, returning its physical address.
+    pub fn map_new(&mut self, virtual_address: u64, flags: PageFlags) -> Result<u64, PagingError> {
+        let frames = allocate_frames(1).ok_or(PagingError::OutOfMemory)?;
+        let physical_address = frames.physical_address;
+        if let Err(e) = self.map(virtual_address, physical_address, flags - PageFlags::NOT_OWNED) {
+            free_frames(frames);
+            return Err(e);
+        }
+        Ok(physical_address)
+    }
+
+    /// Removes the mapping of a page, returning the frame it was mapped to along with its flags. The frame
+    /// isn't freed, that's up to the caller.
+    pub fn unmap(&mut self, virtual_address: u64) -> Result<(u64, PageFlags), PagingError> {
+        check_user_page(virtual_address)?;
+        let entry = page_table_entry(self.pml4, virtual_address, false)?;
+        let value = unsafe { ptr::replace(entry, 0) };
+        if value & PageFlags::PRESENT.bits() == 0 {
+            return Err(PagingError::NotMapped);
+        }
+        if self.is_active() {
+            invalidate_page(virtual_address);
+        }
+        Ok((
+            value & ADDRESS_MASK,
+            PageFlags::from_bits_truncate(value & !ADDRESS_MASK)
+        ))
+    }
+
+    /// Changes the flags of a mapped page, keeping the frame.
+    pub fn set_flags(&mut self, virtual_address: u64, flags: PageFlags) -> Result<(), PagingError> {
+        check_user_page(virtual_address)?;
+        let entry = page_table_entry(self.pml4, virtual_address, false)?;
+        unsafe {
+            if *entry & PageFlags::PRESENT.bits() == 0 {
+                return Err(PagingError::NotMapped);
+            }
+            *entry = *entry & ADDRESS_MASK | (flags | PageFlags::PRESENT).bits();
+        }
+        if self.is_active() {
+            invalidate_page(virtual_address);
+        }
+        Ok(())
+    }
+
+    /// Returns the physical address `virtual_address` is mapped to, and the flags of its page.
+    pub fn translate(&self, virtual_address: u64) -> Option<(u64, PageFlags)> {
+        translate_in(self.pml4, virtual_address)
+    }
+
+    /// Creates a copy of the address space, as for `fork()`. The frames aren't copied but shared, with the
+    /// writable pages of both address spaces turned into read-only `COPY_ON_WRITE` pages, which are only
+    /// copied once they're written to. Frames that aren't owned are shared as they are.
+    pub fn fork(&mut self) -> Result<AddressSpace, PagingError> {
+        let copy = AddressSpace::new()?;
+        fork_table(self.pml4, copy.pml4, 4, KERNEL_PML4_START)?;
+        if self.is_active() {
+            // Flushes the TLB, which still has the pages that became read-only as writable
+            self.activate();
+        }
+        Ok(copy)
+    }
+
+    /// Gives a `COPY_ON_WRITE` page a frame of its own and makes it writable again, copying the frame unless
+    /// this address space is its last owner. Returns false if the page isn't a copy-on-write page.
+    pub fn copy_on_write(&mut self, virtual_address: u64) -> Result<bool, PagingError> {
+        let page = virtual_address - virtual_address % FRAME_SIZE;
+        check_user_page(page)?;
+        let entry = page_table_entry(self.pml4, page, false)?;
+        let value = unsafe { *entry };
+        if value & PageFlags::PRESENT.bits() == 0 || value & PageFlags::COPY_ON_WRITE.bits() == 0 {
+            return Ok(false);
+        }
+        let shared_frame = value & ADDRESS_MASK;
+        let mut frame = shared_frame;
+        if is_frame_shared(shared_frame) {
+            frame = allocate_frames(1)
+                .ok_or(PagingError::OutOfMemory)?
+                .physical_address;
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    physical_to_virtual_ptr::<u8>(shared_frame),
+                    physical_to_virtual_ptr(frame),
+                    FRAME_SIZE as usize
+                )
+            };
+            release_frame(shared_frame);
+        }
+        let flags = (PageFlags::from_bits_truncate(value & !ADDRESS_MASK) - PageFlags::COPY_ON_WRITE)
+            | PageFlags::WRITABLE;
+        unsafe { *entry = frame | flags.bits() };
+        if self.is_active() {
+            invalidate_page(page);
+        }
+        Ok(true)
+    }
+
+    /// Copies `data` into the pages mapped at `virtual_address` through the higher half direct map, so that
+    /// it works whether or not the address space is active, and regardless of the pages' flags.
+    pub fn write(&self, virtual_address: u64, data: &[u8]) -> Result<(), PagingError> {
+        let mut written = 0;
+        while written < data.len() {
+            let address = virtual_address + written as u64;
+            let (physical_address, _) = self.translate(address).ok_or(PagingError::NotMapped)?;
+            let length = (data.len() - written).min((FRAME_SIZE - address % FRAME_SIZE) as usize);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    physical_to_virtual_ptr(physical_address),
+                    length
+                )
+            };
+            written += length;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the page tables of the lower half at `level` and below from `source` into `target`, sharing the
+/// frames they map as described for `AddressSpace::fork()`.
+fn fork_table(source: u64, target: u64, level: u32, entries: usize) -> Result<(), PagingError> {
+    for index in 0..entries {
+        let entry = unsafe { table(source).add(index) };
+        let value = unsafe { *entry };
+        if value & PageFlags::PRESENT.bits() == 0 {
+            continue;
+        }
+        if level > 1 {
+            let frames = allocate_frames(1).ok_or(PagingError::OutOfMemory)?;
+            unsafe { *table(target).add(index) = frames.physical_address | (value & !ADDRESS_MASK) };
+            fork_table(
+                value & ADDRESS_MASK,
+                frames.physical_address,
+                level - 1,
+                ENTRY_COUNT
+            )?;
+            continue;
+        }
+        let mut value = value;
+        if value & PageFlags::NOT_OWNED.bits() == 0 {
+            share_frame(value & ADDRESS_MASK);
+            if value & PageFlags::WRITABLE.bits() != 0 {
+                value = value & !PageFlags::WRITABLE.bits() | PageFlags::COPY_ON_WRITE.bits();
+                unsafe { *entry = value };
+            }
+        }
+        unsafe { *table(target).add(index) = value };
+    }
+    Ok(())
+}
+
+/// Frees the page tables of the lower half at `level` and below, along with the frames they map.
+fn free_table(physical_address: u64, level: u32, entries: usize) {
+    for index in 0..entries {
+        let value = unsafe { *table(physical_address).add(index) };
+        if value & PageFlags::PRESENT.bits() == 0 {
+            continue;
+        }
+        if level > 1 {
+            free_table(value & ADDRESS_MASK, level - 1, ENTRY_COUNT);
+        }
+        else if value & PageFlags::NOT_OWNED.bits() == 0 {
+            release_frame(value & ADDRESS_MASK);
+        }
+    }
+    if level < 4 {
+        free_frame(physical_address);
+    }
+}
+
+#[inline]
+fn free_frame(physical_address: u64) {
+    free_frames(PhysicalFrames {
+        physical_address,
+        count: 1
+    });
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel_address_space();
+        }
+        free_table(self.pml4, 4, KERNEL_PML4_START);
+        free_frame(self.pml4);
+    }
+}
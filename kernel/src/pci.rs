@@ -0,0 +1,618 @@
+use core::fmt::{self, Display};
+use core::ptr;
+
+use arrayvec::ArrayVec;
+use bitflags::bitflags;
+use spin::{Mutex, Once};
+
+use crate::acpi;
+use crate::interrupts_general::without_interrupts;
+use crate::memory::physical_to_virtual;
+use crate::port_io::{read_port_u32, write_port_u16, write_port_u32, write_port_u8};
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+
+pub const MAX_PCI_DEVICES: usize = 128;
+const MAX_ECAM_REGIONS: usize = 8;
+const MAX_DRIVERS: usize = 32;
+
+pub const REG_VENDOR_ID: u16 = 0x00;
+pub const REG_DEVICE_ID: u16 = 0x02;
+pub const REG_COMMAND: u16 = 0x04;
+pub const REG_STATUS: u16 = 0x06;
+pub const REG_REVISION_ID: u16 = 0x08;
+pub const REG_PROG_IF: u16 = 0x09;
+pub const REG_SUBCLASS: u16 = 0x0a;
+pub const REG_CLASS: u16 = 0x0b;
+pub const REG_HEADER_TYPE: u16 = 0x0e;
+pub const REG_BAR_0: u16 = 0x10;
+pub const REG_CAPABILITIES_POINTER: u16 = 0x34;
+pub const REG_INTERRUPT_LINE: u16 = 0x3c;
+pub const REG_INTERRUPT_PIN: u16 = 0x3d;
+
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_TYPE_GENERAL_DEVICE: u8 = 0x00;
+const HEADER_TYPE_PCI_TO_PCI_BRIDGE: u8 = 0x01;
+
+const BAR_IO_SPACE: u32 = 0b1;
+const BAR_MEMORY_TYPE_64_BIT: u32 = 0b100;
+const BAR_MEMORY_PREFETCHABLE: u32 = 0b1000;
+
+pub const CAPABILITY_ID_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_ID_MSI: u8 = 0x05;
+pub const CAPABILITY_ID_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAPABILITY_ID_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_ID_MSI_X: u8 = 0x11;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct PciCommand: u16 {
+        const IO_SPACE = 1 << 0;
+        const MEMORY_SPACE = 1 << 1;
+        const BUS_MASTER = 1 << 2;
+        const INTERRUPT_DISABLE = 1 << 10;
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// The memory mapped configuration space of the buses `start_bus..=end_bus` of one segment group. Each
+/// function gets 4 KiB of it.
+struct EcamRegion {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8
+}
+
+enum ConfigAccessMechanism {
+    Ecam(ArrayVec<EcamRegion, MAX_ECAM_REGIONS>),
+    /// The legacy mechanism through ports 0xcf8 and 0xcfc, which only reaches the first 256 bytes of the
+    /// configuration space of segment group 0.
+    PortIo
+}
+
+static CONFIG_ACCESS: Once<ConfigAccessMechanism> = Once::new();
+/// Accessing configuration space through ports takes two separate port accesses, which must not be
+/// interleaved with those of another access.
+static PORT_IO_LOCK: Mutex<()> = Mutex::new(());
+
+fn config_access() -> &'static ConfigAccessMechanism {
+    CONFIG_ACCESS.call_once(|| {
+        let Ok(entries) = acpi::mcfg_entries()
+        else {
+            return ConfigAccessMechanism::PortIo;
+        };
+        let mut regions = ArrayVec::new();
+        for entry in entries.iter().take(MAX_ECAM_REGIONS) {
+            regions.push(EcamRegion {
+                base_address: physical_to_virtual(entry.base_address),
+                segment: entry.segment_group,
+                start_bus: entry.start_bus,
+                end_bus: entry.end_bus
+            });
+        }
+        if regions.is_empty() {
+            ConfigAccessMechanism::PortIo
+        }
+        else {
+            ConfigAccessMechanism::Ecam(regions)
+        }
+    })
+}
+
+impl PciAddress {
+    #[inline]
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress {
+            segment,
+            bus,
+            device,
+            function
+        }
+    }
+
+    fn ecam_address(&self, regions: &[EcamRegion], offset: u16) -> Option<u64> {
+        let region = regions
+            .iter()
+            .find(|r| r.segment == self.segment && (r.start_bus..=r.end_bus).contains(&self.bus))?;
+        Some(
+            region.base_address
+                + (((self.bus - region.start_bus) as u64) << 20)
+                + ((self.device as u64) << 15)
+                + ((self.function as u64) << 12)
+                + (offset & 0xfff) as u64
+        )
+    }
+
+    #[inline]
+    fn port_io_address(&self, offset: u16) -> u32 {
+        0x80000000
+            | ((self.bus as u32) << 16)
+            | ((self.device as u32) << 11)
+            | ((self.function as u32) << 8)
+            | (offset as u32 & 0xfc)
+    }
+
+    /// Reads a dword from the configuration space. Offsets which are out of reach of the access mechanism in
+    /// use read as all ones, just like registers of devices which don't exist.
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        let offset = offset & !0b11;
+        match config_access() {
+            ConfigAccessMechanism::Ecam(regions) => match self.ecam_address(regions, offset) {
+                Some(address) => unsafe { ptr::read_volatile(address as *const u32) },
+                None => u32::MAX
+            },
+            ConfigAccessMechanism::PortIo => {
+                if self.segment != 0 || offset >= 256 {
+                    return u32::MAX;
+                }
+                without_interrupts(|| {
+                    let _guard = PORT_IO_LOCK.lock();
+                    write_port_u32(CONFIG_ADDRESS_PORT, self.port_io_address(offset));
+                    read_port_u32(CONFIG_DATA_PORT)
+                })
+            }
+        }
+    }
+
+    #[inline]
+    pub fn read_config_u16(&self, offset: u16) -> u16 {
+        (self.read_config_u32(offset) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    #[inline]
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        (self.read_config_u32(offset) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    pub fn write_config_u32(&self, offset: u16, value: u32) {
+        self.write_config(offset & !0b11, value, write_port_u32);
+    }
+
+    /// Writes just the given word, so that neighbouring registers with write-one-to-clear bits, like the
+    /// status register next to the command register, aren't affected.
+    pub fn write_config_u16(&self, offset: u16, value: u16) {
+        self.write_config(offset & !0b1, value, write_port_u16);
+    }
+
+    pub fn write_config_u8(&self, offset: u16, value: u8) {
+        self.write_config(offset, value, write_port_u8);
+    }
+
+    fn write_config<T>(&self, offset: u16, value: T, write_port: fn(u16, T)) {
+        match config_access() {
+            ConfigAccessMechanism::Ecam(regions) => {
+                if let Some(address) = self.ecam_address(regions, offset) {
+                    unsafe { ptr::write_volatile(address as *mut T, value) };
+                }
+            },
+            ConfigAccessMechanism::PortIo => {
+                if self.segment != 0 || offset >= 256 {
+                    return;
+                }
+                without_interrupts(|| {
+                    let _guard = PORT_IO_LOCK.lock();
+                    write_port_u32(CONFIG_ADDRESS_PORT, self.port_io_address(offset));
+                    write_port(CONFIG_DATA_PORT + (offset & 0b11), value);
+                });
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64_bit: bool
+    },
+    Io {
+        port: u16,
+        size: u32
+    }
+}
+
+impl Bar {
+    #[inline]
+    pub fn memory_address(&self) -> Option<u64> {
+        match self {
+            Bar::Memory { address, .. } => Some(*address),
+            Bar::Io { .. } => None
+        }
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        match self {
+            Bar::Memory { size, .. } => *size,
+            Bar::Io { size, .. } => *size as u64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u16
+}
+
+pub struct CapabilityIterator {
+    address: PciAddress,
+    next_offset: u8,
+    /// Guards against malformed capability lists which loop back on themselves. There's room for at most 48
+    /// capabilities in the 192 bytes of configuration space after the header.
+    remaining: u8
+}
+
+impl Iterator for CapabilityIterator {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The bottom two bits of the pointers are reserved
+        let offset = self.next_offset & !0b11;
+        if offset == 0 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.address.read_config_u16(offset as u16);
+        self.next_offset = (header >> 8) as u8;
+        Some(Capability {
+            id: header as u8,
+            offset: offset as u16
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsiCapability {
+    pub offset: u16,
+    pub is_64_bit: bool,
+    pub per_vector_masking: bool,
+    /// The number of vectors the function can use, which is always a power of two
+    pub max_vectors: u8
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsixCapability {
+    pub offset: u16,
+    pub table_size: u16,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    pub pending_bit_array_bar: u8,
+    pub pending_bit_array_offset: u32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision_id: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6]
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> Option<PciDevice> {
+        let vendor_id = address.read_config_u16(REG_VENDOR_ID);
+        if vendor_id == 0xffff {
+            return None;
+        }
+        let header_type = address.read_config_u8(REG_HEADER_TYPE) & !HEADER_TYPE_MULTI_FUNCTION;
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: address.read_config_u16(REG_DEVICE_ID),
+            class: address.read_config_u8(REG_CLASS),
+            subclass: address.read_config_u8(REG_SUBCLASS),
+            prog_if: address.read_config_u8(REG_PROG_IF),
+            revision_id: address.read_config_u8(REG_REVISION_ID),
+            header_type,
+            interrupt_line: address.read_config_u8(REG_INTERRUPT_LINE),
+            interrupt_pin: address.read_config_u8(REG_INTERRUPT_PIN),
+            bars: [None; 6]
+        };
+        let bar_count = match header_type {
+            HEADER_TYPE_GENERAL_DEVICE => 6,
+            HEADER_TYPE_PCI_TO_PCI_BRIDGE => 2,
+            _ => 0
+        };
+        device.probe_bars(bar_count);
+        Some(device)
+    }
+
+    /// Reads the BARs and determines their sizes by writing all ones into them and seeing which bits stick.
+    /// Decoding is disabled in the meantime, as otherwise the device would briefly respond at whatever
+    /// address the all-ones value happens to form.
+    fn probe_bars(&mut self, bar_count: usize) {
+        let command = self.command();
+        self.set_command(command - (PciCommand::IO_SPACE | PciCommand::MEMORY_SPACE));
+        let mut index = 0;
+        while index < bar_count {
+            let register = REG_BAR_0 + index as u16 * 4;
+            let value = self.address.read_config_u32(register);
+            self.address.write_config_u32(register, u32::MAX);
+            let size_mask = self.address.read_config_u32(register);
+            self.address.write_config_u32(register, value);
+
+            if value & BAR_IO_SPACE != 0 {
+                let size = !(size_mask & !0b11) & 0xffff;
+                if size_mask != 0 {
+                    self.bars[index] = Some(Bar::Io {
+                        port: (value & !0b11) as u16,
+                        size: size + 1
+                    });
+                }
+                index += 1;
+            }
+            else if value & BAR_MEMORY_TYPE_64_BIT != 0 && index + 1 < bar_count {
+                let upper_register = register + 4;
+                let upper_value = self.address.read_config_u32(upper_register);
+                self.address.write_config_u32(upper_register, u32::MAX);
+                let upper_size_mask = self.address.read_config_u32(upper_register);
+                self.address.write_config_u32(upper_register, upper_value);
+
+                let size_mask = ((upper_size_mask as u64) << 32) | (size_mask & !0b1111) as u64;
+                if size_mask != 0 {
+                    self.bars[index] = Some(Bar::Memory {
+                        address: ((upper_value as u64) << 32) | (value & !0b1111) as u64,
+                        size: !size_mask + 1,
+                        prefetchable: value & BAR_MEMORY_PREFETCHABLE != 0,
+                        is_64_bit: true
+                    });
+                }
+                // The upper half occupies the next BAR slot
+                index += 2;
+            }
+            else {
+                let size_mask = size_mask & !0b1111;
+                if size_mask != 0 {
+                    self.bars[index] = Some(Bar::Memory {
+                        address: (value & !0b1111) as u64,
+                        size: (!size_mask).wrapping_add(1) as u64,
+                        prefetchable: value & BAR_MEMORY_PREFETCHABLE != 0,
+                        is_64_bit: false
+                    });
+                }
+                index += 1;
+            }
+        }
+        self.set_command(command);
+    }
+
+    #[inline]
+    pub fn command(&self) -> PciCommand {
+        PciCommand::from_bits_retain(self.address.read_config_u16(REG_COMMAND))
+    }
+
+    #[inline]
+    pub fn set_command(&self, command: PciCommand) {
+        self.address.write_config_u16(REG_COMMAND, command.bits());
+    }
+
+    /// Enables the decoding of the device's memory BARs and lets it perform DMA.
+    pub fn enable_memory_and_bus_mastering(&self) {
+        self.set_command(self.command() | PciCommand::MEMORY_SPACE | PciCommand::BUS_MASTER);
+    }
+
+    pub fn capabilities(&self) -> CapabilityIterator {
+        let has_capabilities = self.address.read_config_u16(REG_STATUS) & STATUS_CAPABILITIES_LIST != 0;
+        CapabilityIterator {
+            address: self.address,
+            next_offset: match has_capabilities {
+                true => self.address.read_config_u8(REG_CAPABILITIES_POINTER),
+                false => 0
+            },
+            remaining: 48
+        }
+    }
+
+    #[inline]
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities().find(|c| c.id == id).map(|c| c.offset)
+    }
+
+    pub fn msi_capability(&self) -> Option<MsiCapability> {
+        let offset = self.find_capability(CAPABILITY_ID_MSI)?;
+        let message_control = self.address.read_config_u16(offset + 2);
+        Some(MsiCapability {
+            offset,
+            is_64_bit: message_control & (1 << 7) != 0,
+            per_vector_masking: message_control & (1 << 8) != 0,
+            max_vectors: 1 << ((message_control >> 1) & 0b111)
+        })
+    }
+
+    pub fn msix_capability(&self) -> Option<MsixCapability> {
+        let offset = self.find_capability(CAPABILITY_ID_MSI_X)?;
+        let message_control = self.address.read_config_u16(offset + 2);
+        let table = self.address.read_config_u32(offset + 4);
+        let pending_bit_array = self.address.read_config_u32(offset + 8);
+        Some(MsixCapability {
+            offset,
+            table_size: (message_control & 0x7ff) + 1,
+            table_bar: (table & 0b111) as u8,
+            table_offset: table & !0b111,
+            pending_bit_array_bar: (pending_bit_array & 0b111) as u8,
+            pending_bit_array_offset: pending_bit_array & !0b111
+        })
+    }
+}
+
+static DEVICES: Once<ArrayVec<PciDevice, MAX_PCI_DEVICES>> = Once::new();
+
+/// Enumerates every function on every bus reachable through the configuration access mechanism, which is
+/// ECAM if the firmware provided an MCFG table and the legacy port I/O mechanism otherwise. Only done once,
+/// later calls return the devices found the first time.
+pub fn init() -> &'static [PciDevice] {
+    DEVICES.call_once(|| {
+        let mut devices = ArrayVec::new();
+        match config_access() {
+            ConfigAccessMechanism::Ecam(regions) => {
+                for region in regions {
+                    for bus in region.start_bus..=region.end_bus {
+                        enumerate_bus(region.segment, bus, &mut devices);
+                    }
+                }
+            },
+            ConfigAccessMechanism::PortIo => {
+                for bus in 0..=255 {
+                    enumerate_bus(0, bus, &mut devices);
+                }
+            },
+        }
+        devices
+    })
+}
+
+fn enumerate_bus(segment: u16, bus: u8, devices: &mut ArrayVec<PciDevice, MAX_PCI_DEVICES>) {
+    for device in 0..32 {
+        let address = PciAddress::new(segment, bus, device, 0);
+        let Some(function_0) = PciDevice::read(address)
+        else {
+            continue;
+        };
+        let is_multi_function = address.read_config_u8(REG_HEADER_TYPE) & HEADER_TYPE_MULTI_FUNCTION != 0;
+        if devices.try_push(function_0).is_err() {
+            return;
+        }
+        if !is_multi_function {
+            continue;
+        }
+        for function in 1..8 {
+            if let Some(device) = PciDevice::read(PciAddress::new(segment, bus, device, function)) {
+                if devices.try_push(device).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the devices found by `init()`, or nothing if it hasn't been called yet.
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map(|d| d.as_slice()).unwrap_or(&[])
+}
+
+/// Describes which devices a driver supports. Fields that are `None` match anything.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDeviceId {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>
+}
+
+impl PciDeviceId {
+    pub const fn device(vendor_id: u16, device_id: u16) -> PciDeviceId {
+        PciDeviceId {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8, prog_if: Option<u8>) -> PciDeviceId {
+        PciDeviceId {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.map_or(true, |v| v == device.vendor_id)
+            && self.device_id.map_or(true, |d| d == device.device_id)
+            && self.class.map_or(true, |c| c == device.class)
+            && self.subclass.map_or(true, |s| s == device.subclass)
+            && self.prog_if.map_or(true, |p| p == device.prog_if)
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ProbeError {
+    /// The driver matched the device, but doesn't support it after a closer look
+    Unsupported,
+    InitializationFailed
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub ids: &'static [PciDeviceId],
+    pub probe: fn(&'static PciDevice) -> Result<(), ProbeError>
+}
+
+static DRIVERS: Mutex<ArrayVec<&'static PciDriver, MAX_DRIVERS>> = Mutex::new(ArrayVec::new_const());
+/// The driver bound to each device, indexed the same way as `devices()`.
+static BOUND_DRIVERS: Mutex<[Option<&'static PciDriver>; MAX_PCI_DEVICES]> =
+    Mutex::new([None; MAX_PCI_DEVICES]);
+
+/// Adds a driver to the registry. If the devices have already been enumerated, the driver gets to probe the
+/// ones which don't have a driver yet straight away.
+pub fn register_driver(driver: &'static PciDriver) {
+    if DRIVERS.lock().try_push(driver).is_err() {
+        panic!("Too many PCI drivers registered");
+    }
+    if DEVICES.is_completed() {
+        probe_drivers();
+    }
+}
+
+/// Binds every device without a driver to the first registered driver that matches it and whose probe
+/// function accepts it.
+pub fn probe_drivers() {
+    // Copied so that the lock isn't held while the probe functions run, as they might well register drivers
+    // themselves
+    let drivers = DRIVERS.lock().clone();
+    for (index, device) in devices().iter().enumerate() {
+        if BOUND_DRIVERS.lock()[index].is_some() {
+            continue;
+        }
+        for driver in drivers.iter() {
+            if !driver.ids.iter().any(|id| id.matches(device)) {
+                continue;
+            }
+            if (driver.probe)(device).is_ok() {
+                BOUND_DRIVERS.lock()[index] = Some(driver);
+                break;
+            }
+        }
+    }
+}
+
+pub fn bound_driver(address: PciAddress) -> Option<&'static PciDriver> {
+    let index = devices().iter().position(|d| d.address == address)?;
+    BOUND_DRIVERS.lock()[index]
+}
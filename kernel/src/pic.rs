@@ -0,0 +1,76 @@
+use crate::port_io::{io_wait, read_port_u8, write_port_u8};
+
+const PIC_1_COMMAND_PORT: u16 = 0x20;
+const PIC_1_DATA_PORT: u16 = 0x21;
+const PIC_2_COMMAND_PORT: u16 = 0xa0;
+const PIC_2_DATA_PORT: u16 = 0xa1;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4_PRESENT: u8 = 0x01;
+const ICW4_8086_MODE: u8 = 0x01;
+const COMMAND_END_OF_INTERRUPT: u8 = 0x20;
+
+/// The interrupt vector IRQ 0 is mapped to. IRQs 0-15 occupy the 16 vectors starting from here, since by
+/// default the PIC would deliver them on top of the CPU exception vectors.
+pub const PIC_1_OFFSET: u8 = 0x20;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// The IRQ line the secondary PIC is chained to on the primary one.
+const CASCADE_IRQ: u8 = 2;
+
+/// Reinitializes both 8259 PICs so that IRQs are delivered on vectors `PIC_1_OFFSET..PIC_1_OFFSET + 16`, and
+/// masks every IRQ line except the cascade line. Individual lines are enabled with `unmask_irq()`.
+pub fn init() {
+    write_port_u8(PIC_1_COMMAND_PORT, ICW1_INIT | ICW1_ICW4_PRESENT);
+    io_wait();
+    write_port_u8(PIC_2_COMMAND_PORT, ICW1_INIT | ICW1_ICW4_PRESENT);
+    io_wait();
+    write_port_u8(PIC_1_DATA_PORT, PIC_1_OFFSET);
+    io_wait();
+    write_port_u8(PIC_2_DATA_PORT, PIC_2_OFFSET);
+    io_wait();
+    // Tell the primary PIC which line the secondary one is connected to (as a bit mask), and the secondary
+    // PIC its cascade identity (as a number)
+    write_port_u8(PIC_1_DATA_PORT, 1 << CASCADE_IRQ);
+    io_wait();
+    write_port_u8(PIC_2_DATA_PORT, CASCADE_IRQ);
+    io_wait();
+    write_port_u8(PIC_1_DATA_PORT, ICW4_8086_MODE);
+    io_wait();
+    write_port_u8(PIC_2_DATA_PORT, ICW4_8086_MODE);
+    io_wait();
+
+    write_port_u8(PIC_1_DATA_PORT, !(1 << CASCADE_IRQ));
+    write_port_u8(PIC_2_DATA_PORT, 0xff);
+}
+
+pub fn unmask_irq(irq: u8) {
+    let (port, line) = irq_port_and_line(irq);
+    let mask = read_port_u8(port);
+    write_port_u8(port, mask & !(1 << line));
+}
+
+pub fn mask_irq(irq: u8) {
+    let (port, line) = irq_port_and_line(irq);
+    let mask = read_port_u8(port);
+    write_port_u8(port, mask | (1 << line));
+}
+
+/// Must be called at the end of every IRQ handler, otherwise the PIC won't deliver any more interrupts of
+/// the same or lower priority.
+pub fn send_end_of_interrupt(irq: u8) {
+    if irq >= 8 {
+        write_port_u8(PIC_2_COMMAND_PORT, COMMAND_END_OF_INTERRUPT);
+    }
+    write_port_u8(PIC_1_COMMAND_PORT, COMMAND_END_OF_INTERRUPT);
+}
+
+#[inline]
+fn irq_port_and_line(irq: u8) -> (u16, u8) {
+    if irq < 8 {
+        (PIC_1_DATA_PORT, irq)
+    }
+    else {
+        (PIC_2_DATA_PORT, irq - 8)
+    }
+}
@@ -0,0 +1,275 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::scheduler::WaitQueue;
+use crate::vfs::{Dentry, FileType, Inode, OpenFile, OpenFlags, Stat, VfsError};
+
+/// How much a pipe holds before writers have to wait, which is the default on Linux
+const PIPE_CAPACITY: usize = 64 * 1024;
+/// Writes up to this size go into the pipe in one piece, so that they're never interleaved with others
+const PIPE_ATOMIC_SIZE: usize = 4096;
+
+/// Inode numbers of pipes made by `pipe()`, which aren't on any file system
+static NEXT_PIPE_ID: AtomicU64 = AtomicU64::new(1);
+/// The pipes of the FIFOs that are open, by the mount and inode number of the FIFO, so that everyone who
+/// opens a FIFO gets the same pipe
+static FIFOS: Mutex<BTreeMap<(u64, u64), Weak<Pipe>>> = Mutex::new(BTreeMap::new());
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+    /// How often each end has been opened, so that opening a FIFO can wait for the other end to be opened
+    /// even if it's closed again right away
+    reader_opens: u64,
+    writer_opens: u64
+}
+
+/// A buffer that what's written to one end can be read from at the other, in order. Readers wait for data
+/// and writers for room, while the other end is open.
+pub struct Pipe {
+    id: u64,
+    /// The FIFO the pipe belongs to, if any
+    fifo: Option<(u64, u64)>,
+    state: Mutex<PipeState>,
+    /// Where readers wait for data or for the last writer to go away
+    readable: WaitQueue,
+    /// Where writers wait for room or for the last reader to go away
+    writable: WaitQueue
+}
+
+impl Pipe {
+    fn new(id: u64, fifo: Option<(u64, u64)>) -> Arc<Pipe> {
+        Arc::new(Pipe {
+            id,
+            fifo,
+            state: Mutex::new(PipeState {
+                buffer: VecDeque::new(),
+                readers: 0,
+                writers: 0,
+                reader_opens: 0,
+                writer_opens: 0
+            }),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new()
+        })
+    }
+
+    /// Reads what's in the pipe, waiting for something to be written first unless `non_blocking` is set.
+    /// Returns 0 once the pipe is empty and every writer is gone.
+    fn read(&self, buffer: &mut [u8], non_blocking: bool) -> Result<usize, VfsError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let mut result = Ok(0);
+        self.readable
+            .wait_until_interruptible(|| {
+                let mut state = self.state.lock();
+                if state.buffer.is_empty() {
+                    result = match (state.writers, non_blocking) {
+                        (0, _) => Ok(0),
+                        (_, true) => Err(VfsError::WouldBlock),
+                        (_, false) => return false
+                    };
+                    return true;
+                }
+                let length = buffer.len().min(state.buffer.len());
+                for (byte, value) in buffer.iter_mut().zip(state.buffer.drain(..length)) {
+                    *byte = value;
+                }
+                result = Ok(length);
+                true
+            })
+            .map_err(|_| VfsError::Interrupted)?;
+        self.writable.wake_all();
+        result
+    }
+
+    /// Writes everything, waiting for room as readers make it unless `non_blocking` is set. Fails with
+    /// `BrokenPipe` if there are no readers, and returns how much was written if that or a signal stops it
+    /// halfway.
+    fn write(&self, data: &[u8], non_blocking: bool) -> Result<usize, VfsError> {
+        let atomic = data.len() <= PIPE_ATOMIC_SIZE;
+        let mut written = 0;
+        let mut result = Ok(());
+        let waited = self.writable.wait_until_interruptible(|| {
+            let mut state = self.state.lock();
+            if state.readers == 0 {
+                result = Err(VfsError::BrokenPipe);
+                return true;
+            }
+            let room = PIPE_CAPACITY - state.buffer.len();
+            let left = data.len() - written;
+            if room == 0 || (atomic && room < left) {
+                if non_blocking {
+                    result = Err(VfsError::WouldBlock);
+                    return true;
+                }
+                return false;
+            }
+            let length = room.min(left);
+            state.buffer.extend(&data[written..written + length]);
+            written += length;
+            self.readable.wake_all();
+            written == data.len()
+        });
+        match (waited, result) {
+            _ if written > 0 => Ok(written),
+            (Err(_), _) => Err(VfsError::Interrupted),
+            (_, Err(e)) => Err(e),
+            (Ok(()), Ok(())) => Ok(0)
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        if let Some(key) = self.fifo {
+            let mut fifos = FIFOS.lock();
+            // The FIFO may have been opened again in the meantime, with a new pipe
+            if fifos.get(&key).is_some_and(|pipe| pipe.strong_count() == 0) {
+                fifos.remove(&key);
+            }
+        }
+    }
+}
+
+impl Inode for Pipe {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        Ok(Stat {
+            inode: self.id,
+            device: 0,
+            file_type: FileType::Fifo,
+            mode: 0o600,
+            link_count: 1,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            block_size: PIPE_ATOMIC_SIZE as u32,
+            blocks: 0
+        })
+    }
+}
+
+/// What an open file holds of a pipe: the read end, the write end, or both for a FIFO opened for reading
+/// and writing. The pipe counts its readers and writers through these, so that reads see the end of the
+/// file once the last writer is gone, and writes fail once the last reader is.
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    reads: bool,
+    writes: bool
+}
+
+impl PipeEnd {
+    fn new(pipe: Arc<Pipe>, reads: bool, writes: bool) -> PipeEnd {
+        {
+            let mut state = pipe.state.lock();
+            if reads {
+                state.readers += 1;
+                state.reader_opens += 1;
+            }
+            if writes {
+                state.writers += 1;
+                state.writer_opens += 1;
+            }
+        }
+        // Wakes whoever is opening the FIFO's other end
+        pipe.readable.wake_all();
+        pipe.writable.wake_all();
+        PipeEnd { pipe, reads, writes }
+    }
+
+    pub fn read(&self, buffer: &mut [u8], non_blocking: bool) -> Result<usize, VfsError> {
+        self.pipe.read(buffer, non_blocking)
+    }
+
+    pub fn write(&self, data: &[u8], non_blocking: bool) -> Result<usize, VfsError> {
+        self.pipe.write(data, non_blocking)
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        {
+            let mut state = self.pipe.state.lock();
+            if self.reads {
+                state.readers -= 1;
+            }
+            if self.writes {
+                state.writers -= 1;
+            }
+        }
+        self.pipe.readable.wake_all();
+        self.pipe.writable.wake_all();
+    }
+}
+
+/// Creates a pipe, returning open files for its read end and its write end, which are named like on Linux.
+/// `flags` may add `NON_BLOCK`.
+pub fn create(flags: OpenFlags) -> Result<(Arc<OpenFile>, Arc<OpenFile>), VfsError> {
+    let pipe = Pipe::new(NEXT_PIPE_ID.fetch_add(1, Ordering::Relaxed), None);
+    let name = format!("pipe:[{}]", pipe.id);
+    let reader = OpenFile::anonymous(
+        &name,
+        pipe.clone(),
+        flags | OpenFlags::READ,
+        Some(PipeEnd::new(pipe.clone(), true, false))
+    )?;
+    let writer = OpenFile::anonymous(
+        &name,
+        pipe.clone(),
+        flags | OpenFlags::WRITE,
+        Some(PipeEnd::new(pipe, false, true))
+    )?;
+    Ok((reader, writer))
+}
+
+/// Opens the pipe of the FIFO at `dentry`. Like on Linux, opening only one end waits until the other end is
+/// opened too, unless it's opened with `NON_BLOCK`, which fails right away for a write end without readers.
+pub fn open_fifo(dentry: &Dentry, flags: OpenFlags) -> Result<PipeEnd, VfsError> {
+    let stat = dentry.stat()?;
+    let key = (stat.device, stat.inode);
+    let pipe = {
+        let mut fifos = FIFOS.lock();
+        match fifos.get(&key).and_then(Weak::upgrade) {
+            Some(pipe) => pipe,
+            None => {
+                let pipe = Pipe::new(stat.inode, Some(key));
+                fifos.insert(key, Arc::downgrade(&pipe));
+                pipe
+            }
+        }
+    };
+    let reads = flags.contains(OpenFlags::READ);
+    let writes = flags.contains(OpenFlags::WRITE);
+    let non_blocking = flags.contains(OpenFlags::NON_BLOCK);
+    let (readers, reader_opens, writer_opens) = {
+        let state = pipe.state.lock();
+        (state.readers, state.reader_opens, state.writer_opens)
+    };
+    if writes && !reads && non_blocking && readers == 0 {
+        return Err(VfsError::NoReaders);
+    }
+    let end = PipeEnd::new(pipe.clone(), reads, writes);
+    if reads == writes || non_blocking {
+        return Ok(end);
+    }
+    let (queue, opens) = match reads {
+        true => (&pipe.readable, writer_opens),
+        false => (&pipe.writable, reader_opens)
+    };
+    queue
+        .wait_until_interruptible(|| {
+            let state = pipe.state.lock();
+            match reads {
+                true => state.writers > 0 || state.writer_opens != opens,
+                false => state.readers > 0 || state.reader_opens != opens
+            }
+        })
+        .map_err(|_| VfsError::Interrupted)?;
+    Ok(end)
+}
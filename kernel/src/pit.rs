@@ -0,0 +1,37 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::port_io::write_port_u8;
+
+const CHANNEL_0_DATA_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+/// Channel 0, low byte then high byte of the reload value, mode 2 (rate generator), binary counting
+const COMMAND_CHANNEL_0_RATE_GENERATOR: u8 = 0x34;
+/// The frequency the PIT's counters are driven with
+const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// The IRQ line of channel 0
+pub const IRQ: u8 = 0;
+/// How many timer interrupts arrive per second
+pub const TICKS_PER_SECOND: u32 = 100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs channel 0 to raise IRQ 0 `TICKS_PER_SECOND` times a second. The IRQ still has to be unmasked.
+pub fn init() {
+    let divisor = (BASE_FREQUENCY + TICKS_PER_SECOND / 2) / TICKS_PER_SECOND;
+    write_port_u8(COMMAND_PORT, COMMAND_CHANNEL_0_RATE_GENERATOR);
+    write_port_u8(CHANNEL_0_DATA_PORT, divisor as u8);
+    write_port_u8(CHANNEL_0_DATA_PORT, (divisor >> 8) as u8);
+}
+
+/// Called by the timer interrupt handler.
+#[inline]
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of timer interrupts since `init()`.
+#[inline]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
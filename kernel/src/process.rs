@@ -0,0 +1,412 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::elf::{self, ElfError};
+use crate::fpu::FpuState;
+use crate::scheduler::{self, WaitQueue};
+use crate::signal::{self, Signals, CLD_EXITED, CLD_KILLED};
+use crate::syscall::{return_to_user_mode, Errno, UserContext};
+use crate::user_memory::{read_user_path, read_user_string_array, write_user};
+use crate::vfs::{FileDescriptorTable, OpenFlags, VfsError};
+use crate::virtual_memory::{Access, ProcessMemory};
+use crate::{paging, println};
+
+/// The process ID of init, which adopts the children of processes that exit before them
+pub const INIT_PID: u64 = 1;
+/// What the standard input, output and error of init are connected to
+const CONSOLE_PATH: &str = "/dev/console";
+
+/// Makes `wait4()` return 0 instead of waiting if no child has exited yet
+const WNOHANG: u64 = 1;
+/// Makes `wait4()` report children that stopped
+const WUNTRACED: u64 = 2;
+/// Makes `wait4()` report stopped children that were continued
+const WCONTINUED: u64 = 8;
+
+/// A running program: an address space, the files it has open, and the threads running in it. Processes
+/// form a tree, where each parent collects the exit status of its children with `wait4()`.
+pub struct Process {
+    id: u64,
+    /// The file name of the program, for listing processes
+    name: Mutex<String>,
+    parent: Mutex<Weak<Process>>,
+    /// Children are kept after they exit until their parent has collected their exit status
+    children: Mutex<Vec<Arc<Process>>>,
+    /// `None` once the process has exited
+    memory: Mutex<Option<ProcessMemory>>,
+    /// The physical address of the page tables in `memory`, or 0, so that the scheduler can switch to them
+    /// without taking the lock
+    page_tables: AtomicU64,
+    files: Mutex<FileDescriptorTable>,
+    /// The status `wait4()` reports, which is set once the process has exited
+    exit_status: Mutex<Option<u32>>,
+    /// Where the process waits for its children to exit, stop or continue
+    child_exited: WaitQueue,
+    signals: Mutex<Signals>,
+    /// Where the process waits while it's stopped
+    resumed: WaitQueue
+}
+
+/// Every process that's still around, by ID
+static PROCESSES: Mutex<BTreeMap<u64, Weak<Process>>> = Mutex::new(BTreeMap::new());
+static NEXT_PID: AtomicU64 = AtomicU64::new(INIT_PID);
+
+impl Process {
+    fn new(
+        name: String,
+        parent: Weak<Process>,
+        memory: ProcessMemory,
+        files: FileDescriptorTable,
+        signals: Signals
+    ) -> Arc<Process> {
+        let process = Arc::new(Process {
+            id: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            name: Mutex::new(name),
+            parent: Mutex::new(parent),
+            children: Mutex::new(Vec::new()),
+            page_tables: AtomicU64::new(memory.address_space().page_tables()),
+            memory: Mutex::new(Some(memory)),
+            files: Mutex::new(files),
+            exit_status: Mutex::new(None),
+            child_exited: WaitQueue::new(),
+            signals: Mutex::new(signals),
+            resumed: WaitQueue::new()
+        });
+        PROCESSES.lock().insert(process.id, Arc::downgrade(&process));
+        process
+    }
+
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    /// The ID of the parent, or 0 if the process has none, like init.
+    pub fn parent_id(&self) -> u64 {
+        self.parent.lock().upgrade().map_or(0, |parent| parent.id)
+    }
+
+    /// The page tables to switch to for running the process, or `None` once it has exited.
+    #[inline]
+    pub fn page_tables(&self) -> Option<u64> {
+        match self.page_tables.load(Ordering::Relaxed) {
+            0 => None,
+            page_tables => Some(page_tables)
+        }
+    }
+
+    /// The file descriptors of the process.
+    pub fn files(&self) -> MutexGuard<FileDescriptorTable> {
+        self.files.lock()
+    }
+
+    /// The memory of the process, which is `None` once it has exited.
+    pub fn memory(&self) -> MutexGuard<Option<ProcessMemory>> {
+        self.memory.lock()
+    }
+
+    /// The wait status the process exited with, or `None` if it's still running.
+    pub fn exit_status(&self) -> Option<u32> {
+        *self.exit_status.lock()
+    }
+
+    /// The signal actions of the process, and the signals that are pending or blocked.
+    pub fn signals(&self) -> MutexGuard<Signals> {
+        self.signals.lock()
+    }
+
+    /// Where the process waits for SIGCONT while it's stopped.
+    #[inline]
+    pub fn resumed(&self) -> &WaitQueue {
+        &self.resumed
+    }
+
+    /// Tells the parent that the process exited, stopped or continued, with one of the `CLD_` codes and the
+    /// exit code or signal as `status`.
+    pub fn notify_parent(&self, code: i32, status: u32) {
+        let parent = self.parent.lock().upgrade();
+        if let Some(parent) = parent {
+            signal::send_child_status(&parent, self.id, code, status);
+            parent.child_exited.wake_all();
+        }
+    }
+
+    /// Replaces the memory, which has to be done while running in the process, as the new address space is
+    /// activated right away.
+    fn set_memory(&self, memory: ProcessMemory) {
+        let address_space = memory.address_space();
+        address_space.activate();
+        self.page_tables
+            .store(address_space.page_tables(), Ordering::Relaxed);
+        let old = self.memory.lock().replace(memory);
+        drop(old);
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        PROCESSES.lock().remove(&self.id);
+    }
+}
+
+/// The process the current thread belongs to. System calls always run in one.
+pub fn current() -> Arc<Process> {
+    scheduler::current_thread()
+        .process()
+        .cloned()
+        .expect("Not running in a process")
+}
+
+/// Looks up a process by ID.
+pub fn find(id: u64) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&id).and_then(Weak::upgrade)
+}
+
+/// Every process, ordered by ID.
+pub fn processes() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().filter_map(Weak::upgrade).collect()
+}
+
+/// The last component of a path, which processes are named after.
+fn program_name(path: &str) -> String {
+    String::from(path.rsplit('/').next().unwrap_or(path))
+}
+
+/// Opens the console as file descriptors 0, 1 and 2.
+fn standard_files() -> Result<FileDescriptorTable, VfsError> {
+    let mut files = FileDescriptorTable::new();
+    files.open(CONSOLE_PATH, OpenFlags::READ, 0)?;
+    files.open(CONSOLE_PATH, OpenFlags::WRITE, 0)?;
+    files.open(CONSOLE_PATH, OpenFlags::WRITE, 0)?;
+    Ok(files)
+}
+
+/// Starts a thread in the process that enters user mode with `context` and `fpu_state`.
+fn start_thread(process: &Arc<Process>, context: UserContext, fpu_state: FpuState) -> Result<(), Errno> {
+    let name = process.name();
+    scheduler::spawn(&name, Some(process.clone()), fpu_state, move || {
+        return_to_user_mode(&context)
+    })
+    .ok_or(Errno::OutOfMemory)?;
+    Ok(())
+}
+
+/// Loads the program at `path` as init, the first process, with the console as its standard input and
+/// output. It starts running once the scheduler gets to it.
+pub fn start_init(path: &str, environment: &[String]) -> Result<(), ElfError> {
+    let program = elf::load(path, &[String::from(path)], environment)?;
+    let process = Process::new(
+        program_name(path),
+        Weak::new(),
+        program.memory,
+        standard_files()?,
+        Signals::new()
+    );
+    let context = UserContext::new(program.entry, program.stack_pointer);
+    start_thread(&process, context, FpuState::new()).map_err(|_| ElfError::OutOfMemory)
+}
+
+/// Handles a page fault the current process caused in user mode, or one the kernel would cause accessing its
+/// memory on its behalf. Returns false if the access isn't allowed, rather than just not set up yet.
+pub fn resolve_page_fault(address: u64, access: Access) -> bool {
+    let Some(process) = scheduler::current_thread().process().cloned()
+    else {
+        return false;
+    };
+    let mut memory = process.memory.lock();
+    memory
+        .as_mut()
+        .is_some_and(|memory| memory.handle_page_fault(address, access))
+}
+
+/// Ends the current process with a wait status as `wait4()` reports it, i.e. either the exit code shifted
+/// left by 8 bits, or the number of the signal that killed it.
+pub fn exit(wait_status: u32) -> ! {
+    // Nothing may be left on the stack, as the thread never returns
+    exit_process(&current(), wait_status);
+    scheduler::exit_current_thread();
+}
+
+fn exit_process(process: &Arc<Process>, wait_status: u32) {
+    *process.files.lock() = FileDescriptorTable::new();
+    paging::activate_kernel_address_space();
+    process.page_tables.store(0, Ordering::Relaxed);
+    let memory = process.memory.lock().take();
+    drop(memory);
+
+    // The children are adopted by init, or left without a parent if it's init itself that exits
+    let children = mem::take(&mut *process.children.lock());
+    match find(INIT_PID).filter(|init| !Arc::ptr_eq(init, process)) {
+        Some(init) => {
+            for child in &children {
+                *child.parent.lock() = Arc::downgrade(&init);
+            }
+            // Children that already exited are reaped right away if init would have reaped them
+            let reaps = init.signals().reaps_children();
+            init.children.lock().extend(
+                children
+                    .into_iter()
+                    .filter(|child| !reaps || child.exit_status().is_none())
+            );
+            init.child_exited.wake_all();
+        },
+        None => {
+            for child in &children {
+                *child.parent.lock() = Weak::new();
+            }
+        },
+    }
+
+    // A parent that reaps its children right away has its `wait4()` see the child gone rather than exited
+    let parent = process.parent.lock().upgrade();
+    if let Some(parent) = parent.filter(|parent| parent.signals().reaps_children()) {
+        parent
+            .children
+            .lock()
+            .retain(|child| !Arc::ptr_eq(child, process));
+    }
+    *process.exit_status.lock() = Some(wait_status);
+    match wait_status & 0x7f {
+        0 => process.notify_parent(CLD_EXITED, wait_status >> 8),
+        signal => process.notify_parent(CLD_KILLED, signal)
+    }
+    if process.id == INIT_PID {
+        match wait_status & 0x7f {
+            0 => println!("init exited with status {}", wait_status >> 8),
+            signal => println!("init was killed by signal {}", signal)
+        }
+    }
+}
+
+/// Ends the process. Like on Linux, only the low 8 bits of the status are kept. The process only has a
+/// single thread, so `exit` and `exit_group` are the same.
+pub fn sys_exit(_context: &mut UserContext, [status, ..]: [u64; 6]) -> Result<u64, Errno> {
+    exit((status as u32 & 0xff) << 8);
+}
+
+pub fn sys_getpid(_context: &mut UserContext, _arguments: [u64; 6]) -> Result<u64, Errno> {
+    Ok(current().id)
+}
+
+pub fn sys_getppid(_context: &mut UserContext, _arguments: [u64; 6]) -> Result<u64, Errno> {
+    Ok(current().parent_id())
+}
+
+pub fn sys_sched_yield(_context: &mut UserContext, _arguments: [u64; 6]) -> Result<u64, Errno> {
+    scheduler::yield_now();
+    Ok(0)
+}
+
+/// Creates a child process with a copy-on-write copy of the address space and a copy of the file
+/// descriptors, which continues from the same point but gets 0 as the result. The parent gets the child's
+/// ID. `vfork()` is the same, as the parent doesn't need to wait with copy-on-write.
+pub fn sys_fork(context: &mut UserContext, _arguments: [u64; 6]) -> Result<u64, Errno> {
+    let parent = current();
+    let memory = parent
+        .memory
+        .lock()
+        .as_mut()
+        .expect("Running process has no memory")
+        .fork()?;
+    let files = parent.files.lock().clone();
+    let signals = parent.signals().fork();
+    let child = Process::new(parent.name(), Arc::downgrade(&parent), memory, files, signals);
+    parent.children.lock().push(child.clone());
+    let mut child_context = context.clone();
+    child_context.rax = 0;
+    // The registers are still the parent's user mode ones, as the kernel doesn't use them
+    let mut fpu_state = FpuState::new();
+    fpu_state.save();
+    if let Err(e) = start_thread(&child, child_context, fpu_state) {
+        parent
+            .children
+            .lock()
+            .retain(|process| !Arc::ptr_eq(process, &child));
+        return Err(e);
+    }
+    Ok(child.id)
+}
+
+/// Replaces the program of the process with the one at `path`, keeping the open files. On success, the
+/// system call returns into the new program.
+pub fn sys_execve(
+    context: &mut UserContext,
+    [path, arguments, environment, ..]: [u64; 6]
+) -> Result<u64, Errno> {
+    let path = read_user_path(path)?;
+    let arguments = read_user_string_array(arguments)?;
+    let environment = read_user_string_array(environment)?;
+    let program = elf::load(&path, &arguments, &environment)?;
+    let process = current();
+    process.set_memory(program.memory);
+    *process.name.lock() = program_name(&path);
+    process.signals().reset_handlers();
+    process.files().close_on_exec();
+    *context = UserContext::new(program.entry, program.stack_pointer);
+    Ok(0)
+}
+
+/// Waits for a child to exit, or the child with the ID `pid` if it's positive, and returns its ID after
+/// storing its wait status. With `WUNTRACED` and `WCONTINUED`, children that stopped or continued are
+/// reported as well. There are no process groups, so 0 and negative IDs wait for any child.
+pub fn sys_wait4(
+    _context: &mut UserContext,
+    [pid, status_address, options, ..]: [u64; 6]
+) -> Result<u64, Errno> {
+    if options & !(WNOHANG | WUNTRACED | WCONTINUED) != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let process = current();
+    let pid = pid as i64;
+    let matches = |child: &Arc<Process>| pid <= 0 || child.id == pid as u64;
+    let mut result = Err(Errno::NoChild);
+    process.child_exited.wait_until_interruptible(|| {
+        let mut children = process.children.lock();
+        if !children.iter().any(matches) {
+            result = Err(Errno::NoChild);
+            return true;
+        }
+        if let Some(index) = children
+            .iter()
+            .position(|child| matches(child) && child.exit_status().is_some())
+        {
+            let child = children.remove(index);
+            result = Ok(Some((child.id, child.exit_status().unwrap_or(0))));
+            return true;
+        }
+        let state_change = children.iter().filter(|child| matches(child)).find_map(|child| {
+            let status = child
+                .signals()
+                .take_state_change(options & WUNTRACED != 0, options & WCONTINUED != 0)?;
+            Some((child.id, status))
+        });
+        if state_change.is_some() {
+            result = Ok(state_change);
+            return true;
+        }
+        if options & WNOHANG != 0 {
+            result = Ok(None);
+            return true;
+        }
+        false
+    })?;
+    match result? {
+        Some((id, status)) => {
+            if status_address != 0 {
+                write_user(status_address, status)?;
+            }
+            Ok(id)
+        },
+        None => Ok(0)
+    }
+}
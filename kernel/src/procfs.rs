@@ -0,0 +1,246 @@
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+
+use crate::block::BlockDevice;
+use crate::cpuid::{get_cpu_info, is_cpuid_supported, CPUBasicFeatureFlags};
+use crate::limine::{
+    LIMINE_MEMMAP_ACPI_NVS, LIMINE_MEMMAP_ACPI_RECLAIMABLE, LIMINE_MEMMAP_BAD_MEMORY,
+    LIMINE_MEMMAP_BOOTLOADER_RECLAIMABLE, LIMINE_MEMMAP_FRAMEBUFFER, LIMINE_MEMMAP_KERNEL_AND_MODULES,
+    LIMINE_MEMMAP_RESERVED, LIMINE_MEMMAP_USABLE
+};
+use crate::memory::{self, FRAME_SIZE};
+use crate::scheduler::{self, ThreadState};
+use crate::vfs::{self, FileSystem, FileSystemType, FileType, Inode, Stat, VfsError};
+use crate::{apic, interrupts, kernel_log, mtrr};
+
+const ROOT_INODE: u64 = 1;
+
+/// Writes out the contents of a file, which are generated anew every time it's read.
+type Generator = fn(&mut String) -> fmt::Result;
+
+const FILES: [(&str, Generator); 6] = [
+    ("cpuinfo", write_cpu_info),
+    ("interrupts", write_interrupt_counts),
+    ("log", write_kernel_log),
+    ("memmap", write_memory_map),
+    ("mtrr", write_mtrrs),
+    ("threads", write_threads)
+];
+
+fn write_cpu_info(out: &mut String) -> fmt::Result {
+    if !is_cpuid_supported() {
+        return writeln!(out, "CPUID not supported");
+    }
+    let ci = get_cpu_info();
+    writeln!(out, "vendor: {}", ci.vendor_id_str)?;
+    writeln!(out, "family: {:#x}", ci.family_id)?;
+    writeln!(out, "model: {:#x}", ci.model)?;
+    writeln!(out, "stepping: {}", ci.stepping_id)?;
+    writeln!(out, "processor type: {:?}", ci.processor_type)?;
+    writeln!(
+        out,
+        "highest basic function: {:#x}",
+        ci.highest_supported_basic_function
+    )?;
+    writeln!(
+        out,
+        "highest extended function: {:#x}",
+        ci.highest_supported_extended_function
+    )?;
+    writeln!(out, "physical address bits: {}", ci.physical_adress_bit_width)?;
+    write!(out, "flags:")?;
+    for (name, _) in ci.feature_flags.iter_names() {
+        write!(out, " {}", name.to_lowercase())?;
+    }
+    writeln!(out)?;
+    if !ci.feature_flags.contains(CPUBasicFeatureFlags::APIC) {
+        return Ok(());
+    }
+    writeln!(out, "local APIC id: {}", apic::local_apic_id())?;
+    let version = apic::version();
+    let kind = match version < 0x10 {
+        true => "82489DX discrete",
+        false => "integrated"
+    };
+    writeln!(out, "local APIC version: {:#x} ({})", version, kind)?;
+    writeln!(out, "local APIC base address: {:#x}", apic::base_address())?;
+    writeln!(out, "x2APIC mode: {}", apic::is_x2apic())?;
+    writeln!(out, "bootstrap processor: {}", apic::is_bootstrap_processor())
+}
+
+/// Lists the vectors which have been raised at least once.
+fn write_interrupt_counts(out: &mut String) -> fmt::Result {
+    for vector in 0..=u8::MAX {
+        let count = interrupts::interrupt_count(vector);
+        if count > 0 {
+            writeln!(out, "{:#04x}: {}", vector, count)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_kernel_log(out: &mut String) -> fmt::Result {
+    out.push_str(&String::from_utf8_lossy(&kernel_log::contents()));
+    Ok(())
+}
+
+fn write_memory_map(out: &mut String) -> fmt::Result {
+    for entry in memory::memory_map() {
+        let entry_type = match entry.entry_type {
+            LIMINE_MEMMAP_USABLE => "usable",
+            LIMINE_MEMMAP_RESERVED => "reserved",
+            LIMINE_MEMMAP_ACPI_RECLAIMABLE => "ACPI reclaimable",
+            LIMINE_MEMMAP_ACPI_NVS => "ACPI NVS",
+            LIMINE_MEMMAP_BAD_MEMORY => "bad memory",
+            LIMINE_MEMMAP_BOOTLOADER_RECLAIMABLE => "bootloader reclaimable",
+            LIMINE_MEMMAP_KERNEL_AND_MODULES => "kernel and modules",
+            LIMINE_MEMMAP_FRAMEBUFFER => "framebuffer",
+            _ => "unknown"
+        };
+        writeln!(
+            out,
+            "{:#018x}-{:#018x} {}",
+            entry.base,
+            entry.base + entry.length - 1,
+            entry_type
+        )?;
+    }
+    writeln!(
+        out,
+        "free: {} KiB",
+        memory::free_frame_count() * FRAME_SIZE / 1024
+    )
+}
+
+fn write_mtrrs(out: &mut String) -> fmt::Result {
+    match is_cpuid_supported() {
+        true => mtrr::write_mtrr_memory_mappings(out, &get_cpu_info()),
+        false => writeln!(out, "MTRRs not supported")
+    }
+}
+
+/// Lists every thread with the process it belongs to, where kernel threads have a process ID of 0.
+fn write_threads(out: &mut String) -> fmt::Result {
+    writeln!(out, "ID    PID   STATE   NAME")?;
+    for thread in scheduler::threads() {
+        let state = match thread.state() {
+            ThreadState::Running => "running",
+            ThreadState::Ready => "ready",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Exited => "exited"
+        };
+        let process_id = thread.process().map_or(0, |process| process.id());
+        writeln!(
+            out,
+            "{:<5} {:<5} {:<7} {}",
+            thread.id(),
+            process_id,
+            state,
+            thread.name()
+        )?;
+    }
+    Ok(())
+}
+
+struct ProcFile {
+    index: usize
+}
+
+impl Inode for ProcFile {
+    /// The size is 0, as it isn't known before the contents are generated, like on Linux.
+    fn stat(&self) -> Result<Stat, VfsError> {
+        Ok(Stat {
+            inode: ROOT_INODE + 1 + self.index as u64,
+            device: 0,
+            file_type: FileType::Regular,
+            mode: 0o444,
+            link_count: 1,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            block_size: FRAME_SIZE as u32,
+            blocks: 0
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let mut contents = String::new();
+        (FILES[self.index].1)(&mut contents).map_err(|_| VfsError::InvalidArgument)?;
+        let contents = contents.as_bytes();
+        let start = contents.len().min(offset as usize);
+        let length = buffer.len().min(contents.len() - start);
+        buffer[..length].copy_from_slice(&contents[start..start + length]);
+        Ok(length)
+    }
+}
+
+struct ProcfsRoot;
+
+impl Inode for ProcfsRoot {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        Ok(Stat {
+            inode: ROOT_INODE,
+            device: 0,
+            file_type: FileType::Directory,
+            mode: 0o555,
+            link_count: 2,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            block_size: FRAME_SIZE as u32,
+            blocks: 0
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let index = FILES
+            .iter()
+            .position(|&(file_name, _)| file_name == name)
+            .ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(ProcFile { index }))
+    }
+
+    fn read_directory(
+        &self,
+        start: usize,
+        f: &mut dyn FnMut(vfs::DirectoryEntry) -> bool
+    ) -> Result<(), VfsError> {
+        for (index, &(name, _)) in FILES.iter().enumerate().skip(start) {
+            let entry = vfs::DirectoryEntry {
+                name: name.to_string(),
+                inode: ROOT_INODE + 1 + index as u64,
+                file_type: FileType::Regular
+            };
+            if !f(entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u16) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+}
+
+struct Procfs;
+
+impl FileSystem for Procfs {
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcfsRoot)
+    }
+}
+
+fn mount(_device: Option<&'static dyn BlockDevice>, _options: &str) -> Result<Arc<dyn FileSystem>, VfsError> {
+    Ok(Arc::new(Procfs))
+}
+
+pub static PROCFS_FILE_SYSTEM: FileSystemType = FileSystemType {
+    name: "procfs",
+    mount
+};
@@ -0,0 +1,142 @@
+use crate::port_io::{read_port_u8, write_port_u8};
+
+const DATA_PORT: u16 = 0x60;
+/// Reading this port returns the status register, writing to it sends a command to the controller.
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_BUFFER_FULL: u8 = 0b1;
+const STATUS_INPUT_BUFFER_FULL: u8 = 0b10;
+
+const COMMAND_READ_CONFIG_BYTE: u8 = 0x20;
+const COMMAND_WRITE_CONFIG_BYTE: u8 = 0x60;
+const COMMAND_DISABLE_AUX_PORT: u8 = 0xa7;
+const COMMAND_ENABLE_AUX_PORT: u8 = 0xa8;
+const COMMAND_DISABLE_FIRST_PORT: u8 = 0xad;
+const COMMAND_ENABLE_FIRST_PORT: u8 = 0xae;
+const COMMAND_WRITE_TO_AUX_PORT: u8 = 0xd4;
+
+const CONFIG_FIRST_PORT_INTERRUPT: u8 = 0b1;
+const CONFIG_AUX_PORT_INTERRUPT: u8 = 0b10;
+const CONFIG_AUX_PORT_CLOCK_DISABLED: u8 = 0b100000;
+
+pub const DEVICE_ACKNOWLEDGE: u8 = 0xfa;
+pub const DEVICE_RESEND: u8 = 0xfe;
+
+/// How many times the status register is polled before a controller operation is considered to have timed
+/// out. There's no timer to measure real time with yet, so this is just a number large enough for the
+/// controllers this has been run on (QEMU).
+const POLL_ITERATIONS: u32 = 100000;
+
+/// The IRQ lines the i8042 raises for data received from the first (keyboard) and auxiliary (mouse) ports.
+pub const FIRST_PORT_IRQ: u8 = 1;
+pub const AUX_PORT_IRQ: u8 = 12;
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Ps2Error {
+    Timeout,
+    NoAcknowledge
+}
+
+#[inline]
+pub fn read_status() -> u8 {
+    read_port_u8(STATUS_COMMAND_PORT)
+}
+
+/// Reads the data port without checking whether it actually contains any data. Meant for interrupt handlers,
+/// which only get called once the controller has received a byte.
+#[inline]
+pub fn read_data_unchecked() -> u8 {
+    read_port_u8(DATA_PORT)
+}
+
+fn wait_for_input_buffer_empty() -> Result<(), Ps2Error> {
+    for _ in 0..POLL_ITERATIONS {
+        if read_status() & STATUS_INPUT_BUFFER_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn wait_for_output_buffer_full() -> Result<(), Ps2Error> {
+    for _ in 0..POLL_ITERATIONS {
+        if read_status() & STATUS_OUTPUT_BUFFER_FULL != 0 {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+pub fn send_controller_command(command: u8) -> Result<(), Ps2Error> {
+    wait_for_input_buffer_empty()?;
+    write_port_u8(STATUS_COMMAND_PORT, command);
+    Ok(())
+}
+
+pub fn write_data(value: u8) -> Result<(), Ps2Error> {
+    wait_for_input_buffer_empty()?;
+    write_port_u8(DATA_PORT, value);
+    Ok(())
+}
+
+pub fn read_data() -> Result<u8, Ps2Error> {
+    wait_for_output_buffer_full()?;
+    Ok(read_port_u8(DATA_PORT))
+}
+
+/// Throws away whatever the controller has in its output buffer, e.g. bytes sent by devices before they were
+/// configured.
+pub fn flush_output_buffer() {
+    for _ in 0..POLL_ITERATIONS {
+        if read_status() & STATUS_OUTPUT_BUFFER_FULL == 0 {
+            break;
+        }
+        read_port_u8(DATA_PORT);
+    }
+}
+
+pub fn read_config_byte() -> Result<u8, Ps2Error> {
+    send_controller_command(COMMAND_READ_CONFIG_BYTE)?;
+    read_data()
+}
+
+pub fn write_config_byte(config: u8) -> Result<(), Ps2Error> {
+    send_controller_command(COMMAND_WRITE_CONFIG_BYTE)?;
+    write_data(config)
+}
+
+/// Sends a byte to the device on the auxiliary port and waits for it to be acknowledged, resending it a few
+/// times if the device asks for it.
+pub fn send_to_aux_device(value: u8) -> Result<(), Ps2Error> {
+    for _ in 0..3 {
+        send_controller_command(COMMAND_WRITE_TO_AUX_PORT)?;
+        write_data(value)?;
+        match read_data()? {
+            DEVICE_ACKNOWLEDGE => return Ok(()),
+            DEVICE_RESEND => continue,
+            _ => return Err(Ps2Error::NoAcknowledge)
+        }
+    }
+    Err(Ps2Error::NoAcknowledge)
+}
+
+/// Enables the auxiliary port of the controller along with its interrupt (IRQ 12). Interrupts from the
+/// devices are disabled while the controller is being reconfigured, so that nothing is lost into an interrupt
+/// handler during the process.
+pub fn enable_aux_port() -> Result<(), Ps2Error> {
+    send_controller_command(COMMAND_DISABLE_FIRST_PORT)?;
+    send_controller_command(COMMAND_DISABLE_AUX_PORT)?;
+    flush_output_buffer();
+
+    let mut config = read_config_byte()?;
+    let first_port_interrupt = config & CONFIG_FIRST_PORT_INTERRUPT;
+    config &= !(CONFIG_FIRST_PORT_INTERRUPT | CONFIG_AUX_PORT_INTERRUPT);
+    write_config_byte(config)?;
+
+    send_controller_command(COMMAND_ENABLE_AUX_PORT)?;
+    config &= !CONFIG_AUX_PORT_CLOCK_DISABLED;
+    config |= CONFIG_AUX_PORT_INTERRUPT | first_port_interrupt;
+    write_config_byte(config)?;
+    send_controller_command(COMMAND_ENABLE_FIRST_PORT)
+}
@@ -0,0 +1,88 @@
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arrayvec::ArrayString;
+use spin::Once;
+
+use crate::block::{self, BlockDevice, BlockError, RequestQueue};
+use crate::memory::{allocate_frames, PhysicalFrames, FRAME_SIZE};
+
+const MAX_RAM_DISKS: usize = 8;
+const BLOCK_SIZE: u32 = 512;
+
+/// A block device backed by physically contiguous memory, whose contents are lost on reboot.
+pub struct RamDisk {
+    name: ArrayString<16>,
+    frames: PhysicalFrames,
+    request_queue: RequestQueue
+}
+
+impl RamDisk {
+    #[inline]
+    fn block_ptr(&self, block: u64) -> *mut u8 {
+        unsafe {
+            self.frames
+                .as_ptr::<u8>()
+                .add((block * BLOCK_SIZE as u64) as usize)
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> u32 {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.frames.size() / BLOCK_SIZE as u64
+    }
+
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        unsafe { ptr::copy_nonoverlapping(self.block_ptr(first_block), buffer.as_mut_ptr(), buffer.len()) };
+        Ok(())
+    }
+
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        unsafe { ptr::copy_nonoverlapping(buffer.as_ptr(), self.block_ptr(first_block), buffer.len()) };
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn request_queue(&self) -> &RequestQueue {
+        &self.request_queue
+    }
+}
+
+static RAM_DISKS: [Once<RamDisk>; MAX_RAM_DISKS] = [const { Once::new() }; MAX_RAM_DISKS];
+static RAM_DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates a zeroed RAM disk of at least `size` bytes and registers it as a block device. The memory is
+/// never freed.
+pub fn create(size: u64) -> Result<&'static RamDisk, BlockError> {
+    let frames = allocate_frames(size.div_ceil(FRAME_SIZE).max(1)).ok_or(BlockError::OutOfMemory)?;
+    let index = RAM_DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+    let Some(slot) = RAM_DISKS.get(index)
+    else {
+        crate::memory::free_frames(frames);
+        return Err(BlockError::OutOfMemory);
+    };
+    let mut name = ArrayString::new();
+    let _ = write!(name, "ram{}", index);
+    let disk = slot.call_once(|| RamDisk {
+        name,
+        frames,
+        request_queue: RequestQueue::new()
+    });
+    block::register_device(disk)?;
+    Ok(disk)
+}
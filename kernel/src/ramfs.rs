@@ -0,0 +1,423 @@
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::slice;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::{Lazy, Mutex};
+
+use crate::block::BlockDevice;
+use crate::memory::{allocate_frames, free_frames, PhysicalFrames, FRAME_SIZE};
+use crate::vfs::{
+    DirectoryEntry, FileSystem, FileSystemType, FileSystemUsage, FileType, Inode, Stat, VfsError
+};
+
+const PAGE_SIZE: usize = FRAME_SIZE as usize;
+/// What every inode is charged on top of its contents, which roughly covers the inode itself and its entry
+/// in the parent directory
+const INODE_OVERHEAD: usize = 256;
+const SYMLINK_MODE: u16 = 0o777;
+
+/// A page of file data, which comes straight from the frame allocator rather than the heap so that large
+/// files don't need large physically contiguous allocations.
+struct Page(PhysicalFrames);
+
+impl Page {
+    fn new() -> Option<Page> {
+        allocate_frames(1).map(Page)
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.0.as_ptr(), PAGE_SIZE) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.0.as_ptr(), PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        free_frames(PhysicalFrames {
+            physical_address: self.0.physical_address,
+            count: self.0.count
+        });
+    }
+}
+
+enum Content {
+    /// Pages which were never written to are left out, and read as zeroes
+    File {
+        size: u64,
+        pages: BTreeMap<u64, Page>,
+        /// Set once a page has been handed out for mapping, after which no page is freed before the file is
+        /// gone, as some address space may still have it mapped
+        mapped: bool
+    },
+    Directory(BTreeMap<String, Arc<RamfsInode>>),
+    Symlink(String),
+    /// Whatever is written to a FIFO goes through a pipe, so there's nothing to keep
+    Fifo
+}
+
+impl Content {
+    fn file_type(&self) -> FileType {
+        match self {
+            Content::File { .. } => FileType::Regular,
+            Content::Directory(_) => FileType::Directory,
+            Content::Symlink(_) => FileType::Symlink,
+            Content::Fifo => FileType::Fifo
+        }
+    }
+
+    /// How many bytes of the file system's memory the contents are charged for.
+    fn charged_bytes(&self) -> usize {
+        match self {
+            Content::File { pages, .. } => pages.len() * PAGE_SIZE,
+            Content::Directory(_) | Content::Fifo => 0,
+            Content::Symlink(target) => target.len()
+        }
+    }
+}
+
+/// The memory used by a ramfs, which every inode is charged to and which may be capped.
+struct Usage {
+    used_bytes: AtomicUsize,
+    limit: Option<usize>
+}
+
+impl Usage {
+    fn charge(&self, bytes: usize) -> Result<(), VfsError> {
+        self.used_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let new = used.checked_add(bytes)?;
+                match self.limit {
+                    Some(limit) if new > limit => None,
+                    _ => Some(new)
+                }
+            })
+            .map(|_| ())
+            .map_err(|_| VfsError::NoSpace)
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// A file, directory or symbolic link which only exists in memory. Removed files stay around for as long as
+/// someone has them open, and give their memory back once they're gone.
+struct RamfsInode {
+    id: u64,
+    mode: u16,
+    usage: Arc<Usage>,
+    content: Mutex<Content>
+}
+
+/// A file system living entirely in memory, which starts out empty. Mounted with "size=<bytes>" it stops
+/// growing once its files take up that much memory, where the size may end with k, m or g.
+struct Ramfs {
+    root: Arc<RamfsInode>,
+    usage: Arc<Usage>
+}
+
+/// Inode numbers are unique across every ramfs, which doesn't hurt and is simpler
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+/// What the files of `create_anonymous_file()` are charged to, as they're on no mounted ramfs
+static ANONYMOUS_USAGE: Lazy<Arc<Usage>> = Lazy::new(|| {
+    Arc::new(Usage {
+        used_bytes: AtomicUsize::new(0),
+        limit: None
+    })
+});
+
+impl RamfsInode {
+    fn new(usage: &Arc<Usage>, content: Content, mode: u16) -> Result<Arc<RamfsInode>, VfsError> {
+        usage.charge(INODE_OVERHEAD + content.charged_bytes())?;
+        Ok(Arc::new(RamfsInode {
+            id: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            mode: mode & 0o7777,
+            usage: usage.clone(),
+            content: Mutex::new(content)
+        }))
+    }
+
+    /// Adds an entry to a directory, creating the inode with `content` only if the name is free.
+    fn add_entry(&self, name: &str, content: Content, mode: u16) -> Result<Arc<RamfsInode>, VfsError> {
+        let Content::Directory(entries) = &mut *self.content.lock()
+        else {
+            return Err(VfsError::NotADirectory);
+        };
+        if entries.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        let inode = RamfsInode::new(&self.usage, content, mode)?;
+        entries.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+
+    /// The page of a file at `index`, which is allocated and charged for if the file has none there yet.
+    fn page_mut<'a>(&self, pages: &'a mut BTreeMap<u64, Page>, index: u64) -> Result<&'a mut Page, VfsError> {
+        match pages.entry(index) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                self.usage.charge(PAGE_SIZE)?;
+                let Some(page) = Page::new()
+                else {
+                    self.usage.release(PAGE_SIZE);
+                    return Err(VfsError::NoSpace);
+                };
+                Ok(entry.insert(page))
+            }
+        }
+    }
+}
+
+impl Drop for RamfsInode {
+    fn drop(&mut self) {
+        let bytes = INODE_OVERHEAD + self.content.get_mut().charged_bytes();
+        self.usage.release(bytes);
+    }
+}
+
+impl Inode for RamfsInode {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        let content = self.content.lock();
+        let (size, link_count) = match &*content {
+            Content::File { size, .. } => (*size, 1),
+            Content::Directory(entries) => (entries.len() as u64, 2),
+            Content::Symlink(target) => (target.len() as u64, 1),
+            Content::Fifo => (0, 1)
+        };
+        Ok(Stat {
+            inode: self.id,
+            device: 0,
+            file_type: content.file_type(),
+            mode: self.mode,
+            link_count,
+            uid: 0,
+            gid: 0,
+            size,
+            block_size: PAGE_SIZE as u32,
+            blocks: content.charged_bytes().div_ceil(512) as u64
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        match &*self.content.lock() {
+            Content::Directory(entries) => match entries.get(name) {
+                Some(inode) => Ok(inode.clone()),
+                None => Err(VfsError::NotFound)
+            },
+            _ => Err(VfsError::NotADirectory)
+        }
+    }
+
+    fn read_directory(
+        &self,
+        start: usize,
+        f: &mut dyn FnMut(DirectoryEntry) -> bool
+    ) -> Result<(), VfsError> {
+        // Collected first, so that `f` can do anything with the directory
+        let entries: Vec<_> = match &*self.content.lock() {
+            Content::Directory(entries) => entries
+                .iter()
+                .skip(start)
+                .map(|(name, inode)| (name.clone(), inode.clone()))
+                .collect(),
+            _ => return Err(VfsError::NotADirectory)
+        };
+        for (name, inode) in entries {
+            let file_type = inode.content.lock().file_type();
+            if !f(DirectoryEntry {
+                name,
+                inode: inode.id,
+                file_type
+            }) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u16) -> Result<Arc<dyn Inode>, VfsError> {
+        let content = match file_type {
+            FileType::Regular => Content::File {
+                size: 0,
+                pages: BTreeMap::new(),
+                mapped: false
+            },
+            FileType::Directory => Content::Directory(BTreeMap::new()),
+            FileType::Fifo => Content::Fifo,
+            _ => return Err(VfsError::NotSupported)
+        };
+        Ok(self.add_entry(name, content, mode)?)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Ok(self.add_entry(name, Content::Symlink(target.to_string()), SYMLINK_MODE)?)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        let Content::Directory(entries) = &mut *self.content.lock()
+        else {
+            return Err(VfsError::NotADirectory);
+        };
+        let inode = entries.get(name).ok_or(VfsError::NotFound)?;
+        if let Content::Directory(children) = &*inode.content.lock() {
+            if !children.is_empty() {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, VfsError> {
+        match &*self.content.lock() {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(VfsError::InvalidArgument)
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let Content::File { size, pages, .. } = &*self.content.lock()
+        else {
+            return Err(VfsError::IsADirectory);
+        };
+        let length = buffer.len().min(size.saturating_sub(offset) as usize);
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let in_page = (position % FRAME_SIZE) as usize;
+            let chunk_length = (PAGE_SIZE - in_page).min(length - done);
+            let chunk = &mut buffer[done..done + chunk_length];
+            match pages.get(&(position / FRAME_SIZE)) {
+                Some(page) => chunk.copy_from_slice(&page.data()[in_page..in_page + chunk_length]),
+                None => chunk.fill(0)
+            }
+            done += chunk_length;
+        }
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let Content::File { size, pages, .. } = &mut *self.content.lock()
+        else {
+            return Err(VfsError::IsADirectory);
+        };
+        if offset.checked_add(data.len() as u64).is_none() {
+            return Err(VfsError::FileTooLarge);
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let in_page = (position % FRAME_SIZE) as usize;
+            let chunk_length = (PAGE_SIZE - in_page).min(data.len() - done);
+            let page = self.page_mut(pages, position / FRAME_SIZE)?;
+            page.data_mut()[in_page..in_page + chunk_length]
+                .copy_from_slice(&data[done..done + chunk_length]);
+            done += chunk_length;
+            // Kept up to date as it goes, so that what was written before running out of space is there
+            *size = (*size).max(position + chunk_length as u64);
+        }
+        Ok(data.len())
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), VfsError> {
+        let Content::File { size, pages, mapped } = &mut *self.content.lock()
+        else {
+            return Err(VfsError::IsADirectory);
+        };
+        if new_size < *size {
+            let first_removed = new_size.div_ceil(FRAME_SIZE);
+            if *mapped {
+                for (_, page) in pages.range_mut(first_removed..) {
+                    page.data_mut().fill(0);
+                }
+            }
+            else {
+                let removed = pages.split_off(&first_removed);
+                self.usage.release(removed.len() * PAGE_SIZE);
+            }
+            // What's left of the last page has to read as zeroes if the file grows again
+            let in_page = (new_size % FRAME_SIZE) as usize;
+            if in_page != 0 {
+                if let Some(page) = pages.get_mut(&(new_size / FRAME_SIZE)) {
+                    page.data_mut()[in_page..].fill(0);
+                }
+            }
+        }
+        *size = new_size;
+        Ok(())
+    }
+
+    /// Hands out the pages of files for shared mappings, allocating them as needed. Pages past the end of
+    /// the file can't be mapped, like on Linux, where touching them is a bus error.
+    fn physical_page(&self, offset: u64) -> Result<u64, VfsError> {
+        let Content::File { size, pages, mapped } = &mut *self.content.lock()
+        else {
+            return Err(VfsError::NotSupported);
+        };
+        if offset >= *size {
+            return Err(VfsError::InvalidArgument);
+        }
+        let physical_address = self.page_mut(pages, offset / FRAME_SIZE)?.0.physical_address;
+        *mapped = true;
+        Ok(physical_address)
+    }
+}
+
+impl FileSystem for Ramfs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn usage(&self) -> Option<FileSystemUsage> {
+        Some(FileSystemUsage {
+            used_bytes: self.usage.used_bytes.load(Ordering::Relaxed) as u64,
+            total_bytes: self.usage.limit.map(|limit| limit as u64)
+        })
+    }
+}
+
+/// Parses a size such as "4096", "64k" or "16m".
+fn parse_size(text: &str) -> Option<usize> {
+    let (number, multiplier) = match text.as_bytes().last()?.to_ascii_lowercase() {
+        b'k' => (&text[..text.len() - 1], 1 << 10),
+        b'm' => (&text[..text.len() - 1], 1 << 20),
+        b'g' => (&text[..text.len() - 1], 1 << 30),
+        _ => (text, 1)
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+fn mount(_device: Option<&'static dyn BlockDevice>, options: &str) -> Result<Arc<dyn FileSystem>, VfsError> {
+    let mut limit = None;
+    for option in options.split(',').filter(|o| !o.is_empty()) {
+        match option.split_once('=') {
+            Some(("size", size)) => limit = Some(parse_size(size).ok_or(VfsError::InvalidArgument)?),
+            _ => return Err(VfsError::InvalidArgument)
+        }
+    }
+    let usage = Arc::new(Usage {
+        used_bytes: AtomicUsize::new(0),
+        limit
+    });
+    let root = RamfsInode::new(&usage, Content::Directory(BTreeMap::new()), 0o755)?;
+    Ok(Arc::new(Ramfs { root, usage }))
+}
+
+/// Creates an empty file that's in no directory and no mounted ramfs, for `memfd_create()`.
+pub fn create_anonymous_file(mode: u16) -> Result<Arc<dyn Inode>, VfsError> {
+    let content = Content::File {
+        size: 0,
+        pages: BTreeMap::new(),
+        mapped: false
+    };
+    Ok(RamfsInode::new(&ANONYMOUS_USAGE, content, mode)?)
+}
+
+pub static RAMFS_FILE_SYSTEM: FileSystemType = FileSystemType { name: "ramfs", mount };
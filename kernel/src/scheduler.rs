@@ -0,0 +1,411 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::fpu::{self, FpuState};
+use crate::interrupts_general::{
+    disable_interrupts, enable_interrupts, enable_interrupts_and_halt, without_interrupts
+};
+use crate::memory::{allocate_frames, free_frames, physical_to_virtual, PhysicalFrames, FRAME_SIZE};
+use crate::process::Process;
+use crate::syscall::Errno;
+use crate::{gdt, paging, signal};
+
+/// The size of each thread's kernel stack, which system calls and interrupts from user mode run on
+const KERNEL_STACK_FRAMES: u64 = 16;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ThreadState {
+    Running,
+    /// Waiting in the ready queue for its turn
+    Ready,
+    /// Waiting in a `WaitQueue`
+    Blocked,
+    Exited
+}
+
+/// A thread of execution with its own kernel stack. Threads of processes run user code and enter the kernel
+/// on their kernel stack, while kernel threads only run kernel code.
+pub struct Thread {
+    id: u64,
+    name: String,
+    process: Option<Arc<Process>>,
+    state: Mutex<ThreadState>,
+    /// `None` for the boot thread, which runs on the stack it was started with
+    kernel_stack: Option<PhysicalFrames>,
+    /// Where `switch_context` left the stack pointer while the thread doesn't run
+    stack_pointer: AtomicU64,
+    /// Only touched while switching threads with interrupts disabled, by the thread switching away
+    fpu_state: UnsafeCell<FpuState>,
+    /// What a new thread runs, taken out when it starts
+    entry: Mutex<Option<Box<dyn FnOnce() + Send>>>
+}
+
+// The FPU state is only accessed by the thread itself and with interrupts disabled
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The process the thread belongs to, or `None` for kernel threads.
+    #[inline]
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        self.process.as_ref()
+    }
+
+    pub fn state(&self) -> ThreadState {
+        without_interrupts(|| *self.state.lock())
+    }
+
+    /// The top of the kernel stack, or 0 for the boot thread.
+    fn kernel_stack_top(&self) -> u64 {
+        match &self.kernel_stack {
+            Some(stack) => physical_to_virtual(stack.physical_address) + stack.count * FRAME_SIZE,
+            None => 0
+        }
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        without_interrupts(|| THREADS.lock().remove(&self.id));
+        if let Some(stack) = self.kernel_stack.take() {
+            free_frames(stack);
+        }
+    }
+}
+
+struct Scheduler {
+    current: Option<Arc<Thread>>,
+    ready: VecDeque<Arc<Thread>>,
+    /// The boot thread, which runs when no other thread is ready and is never in the ready queue
+    idle: Option<Arc<Thread>>,
+    /// A thread that has exited, which is dropped once it's no longer running on its stack
+    dead: Option<Arc<Thread>>
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    current: None,
+    ready: VecDeque::new(),
+    idle: None,
+    dead: None
+});
+/// Every thread by ID, for listing them
+static THREADS: Mutex<BTreeMap<u64, Weak<Thread>>> = Mutex::new(BTreeMap::new());
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+// `switch_context` saves the registers the System V ABI has callees preserve on the current stack, stores
+// the stack pointer where RDI points to, and switches to the stack pointer in RSI, restoring the registers
+// the other thread saved there before returning into it.
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret"
+);
+
+extern "C" {
+    fn switch_context(stack_pointer: *mut u64, new_stack_pointer: u64);
+}
+
+/// Turns the code that's running into thread 0, the idle thread, and enables the FPU for user programs.
+pub fn init() {
+    fpu::init();
+    let thread = Arc::new(Thread {
+        id: 0,
+        name: String::from("kernel"),
+        process: None,
+        state: Mutex::new(ThreadState::Running),
+        kernel_stack: None,
+        stack_pointer: AtomicU64::new(0),
+        fpu_state: UnsafeCell::new(FpuState::new()),
+        entry: Mutex::new(None)
+    });
+    without_interrupts(|| {
+        THREADS.lock().insert(0, Arc::downgrade(&thread));
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.idle = Some(thread.clone());
+        scheduler.current = Some(thread);
+    });
+}
+
+/// The thread that's running.
+pub fn current_thread() -> Arc<Thread> {
+    without_interrupts(|| SCHEDULER.lock().current.clone()).expect("Scheduler not initialized")
+}
+
+/// Creates a thread running `entry` with `fpu_state` in its x87 and SSE registers, and puts it in the ready
+/// queue. The thread exits when `entry` returns.
+pub fn spawn(
+    name: &str,
+    process: Option<Arc<Process>>,
+    fpu_state: FpuState,
+    entry: impl FnOnce() + Send + 'static
+) -> Option<Arc<Thread>> {
+    let stack = allocate_frames(KERNEL_STACK_FRAMES)?;
+    let thread = Arc::new(Thread {
+        id: NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed),
+        name: String::from(name),
+        process,
+        state: Mutex::new(ThreadState::Ready),
+        kernel_stack: Some(stack),
+        stack_pointer: AtomicU64::new(0),
+        fpu_state: UnsafeCell::new(fpu_state),
+        entry: Mutex::new(Some(Box::new(entry)))
+    });
+    // The stack is set up the way `switch_context` leaves it, with zeros for the saved registers and
+    // `thread_start` as the return address. The slot above keeps the stack aligned like after a call.
+    let top = thread.kernel_stack_top() as *mut u64;
+    unsafe {
+        top.sub(1).write(0);
+        top.sub(2).write(thread_start as usize as u64);
+        for register in 3..=8 {
+            top.sub(register).write(0);
+        }
+        thread.stack_pointer.store(top.sub(8) as u64, Ordering::Relaxed);
+    }
+    without_interrupts(|| {
+        THREADS.lock().insert(thread.id, Arc::downgrade(&thread));
+        SCHEDULER.lock().ready.push_back(thread.clone());
+    });
+    Some(thread)
+}
+
+/// Where new threads start, on their own stack, in the middle of `schedule()`.
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    enable_interrupts();
+    let entry = current_thread().entry.lock().take();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit_current_thread();
+}
+
+/// Ends the thread that's running. Anything it owns on its stack is leaked, so it should be dropped first.
+pub fn exit_current_thread() -> ! {
+    disable_interrupts();
+    schedule(ThreadState::Exited);
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// Lets the other ready threads run before the current one continues.
+pub fn yield_now() {
+    without_interrupts(|| schedule(ThreadState::Ready));
+}
+
+/// Called by the timer interrupt to switch away from a thread that has been running user code for a whole
+/// tick, with interrupts disabled.
+pub fn preempt() {
+    schedule(ThreadState::Ready);
+}
+
+/// Runs the ready threads, or halts until the next interrupt if there are none. This is for the idle
+/// thread's loop.
+pub fn idle() {
+    disable_interrupts();
+    if SCHEDULER.lock().ready.is_empty() {
+        enable_interrupts_and_halt();
+    }
+    else {
+        schedule(ThreadState::Ready);
+        enable_interrupts();
+    }
+}
+
+/// Switches to the next ready thread, leaving the current one in `state`. A `Ready` thread is put back in
+/// the ready queue, while a `Blocked` one has to be in a wait queue already. This must be called with
+/// interrupts disabled.
+fn schedule(state: ThreadState) {
+    let (stack_pointer, new_stack_pointer) = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current.clone().expect("Scheduler not initialized");
+        let is_idle = scheduler
+            .idle
+            .as_ref()
+            .is_some_and(|idle| Arc::ptr_eq(idle, &current));
+        if is_idle && state != ThreadState::Ready {
+            panic!("The idle thread can't block or exit");
+        }
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            // Nothing else to do, so the thread keeps running
+            None if state == ThreadState::Ready => return,
+            None => scheduler.idle.clone().expect("Scheduler not initialized")
+        };
+        *current.state.lock() = state;
+        match state {
+            ThreadState::Ready if !is_idle => scheduler.ready.push_back(current.clone()),
+            ThreadState::Exited => scheduler.dead = Some(current.clone()),
+            _ => {}
+        }
+        *next.state.lock() = ThreadState::Running;
+        if next.kernel_stack.is_some() {
+            gdt::set_kernel_stack(next.kernel_stack_top());
+        }
+        // Kernel threads run on whatever page tables are loaded, as they only use the higher half
+        if let Some(page_tables) = next.process.as_ref().and_then(|process| process.page_tables()) {
+            paging::activate_page_tables(page_tables);
+        }
+        unsafe {
+            (*current.fpu_state.get()).save();
+            (*next.fpu_state.get()).restore();
+        }
+        scheduler.current = Some(next.clone());
+        // The threads stay alive without these references, in the scheduler, a wait queue, or as the dead
+        // thread, so no reference is left behind on a stack that may never run again
+        (
+            current.stack_pointer.as_ptr(),
+            next.stack_pointer.load(Ordering::Relaxed)
+        )
+    };
+    unsafe { switch_context(stack_pointer, new_stack_pointer) };
+    finish_switch();
+}
+
+/// Frees the thread that exited, if the switch was away from one.
+fn finish_switch() {
+    let dead = SCHEDULER.lock().dead.take();
+    drop(dead);
+}
+
+/// Puts a blocked thread back in the ready queue.
+fn wake(thread: Arc<Thread>) {
+    let mut state = thread.state.lock();
+    if *state == ThreadState::Blocked {
+        *state = ThreadState::Ready;
+        drop(state);
+        SCHEDULER.lock().ready.push_back(thread);
+    }
+}
+
+/// Threads waiting for something to happen, e.g. for input to arrive or a child process to exit.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Arc<Thread>>>
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new())
+        }
+    }
+
+    /// Blocks until `condition` returns true. The condition is checked with interrupts disabled, so that a
+    /// `wake_all()` from an interrupt handler can't get in between checking it and blocking.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        without_interrupts(|| {
+            while !condition() {
+                self.enqueue_current();
+                schedule(ThreadState::Blocked);
+            }
+        });
+    }
+
+    /// Like `wait_until()`, but gives up with `Errno::Interrupted` once a signal can be delivered to the
+    /// process, so that it's handled before the wait goes on, if at all.
+    pub fn wait_until_interruptible(&self, mut condition: impl FnMut() -> bool) -> Result<(), Errno> {
+        let current = current_thread();
+        let result = without_interrupts(|| {
+            loop {
+                if condition() {
+                    return Ok(());
+                }
+                if current.process.as_ref().is_some_and(signal::has_deliverable) {
+                    return Err(Errno::Interrupted);
+                }
+                self.enqueue_current();
+                schedule(ThreadState::Blocked);
+            }
+        });
+        // A thread woken by `interrupt()` is still in the queue
+        without_interrupts(|| {
+            self.waiters
+                .lock()
+                .retain(|thread| !Arc::ptr_eq(thread, &current))
+        });
+        result
+    }
+
+    /// Adds the current thread to the waiters, unless it's still there from a wakeup that didn't come from
+    /// this queue.
+    fn enqueue_current(&self) {
+        let current = SCHEDULER
+            .lock()
+            .current
+            .clone()
+            .expect("Scheduler not initialized");
+        let mut waiters = self.waiters.lock();
+        if !waiters.iter().any(|thread| Arc::ptr_eq(thread, &current)) {
+            waiters.push_back(current);
+        }
+    }
+
+    /// Makes every waiting thread ready to check its condition again.
+    pub fn wake_all(&self) {
+        without_interrupts(|| {
+            let waiters: Vec<Arc<Thread>> = self.waiters.lock().drain(..).collect();
+            for thread in waiters {
+                wake(thread);
+            }
+        });
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wakes the blocked threads of `process` without taking them out of their wait queues, so that they notice
+/// a signal. Threads in an uninterruptible wait just go back to waiting.
+pub fn interrupt(process: &Arc<Process>) {
+    let threads: Vec<Arc<Thread>> = threads()
+        .into_iter()
+        .filter(|thread| {
+            thread
+                .process
+                .as_ref()
+                .is_some_and(|other| Arc::ptr_eq(other, process))
+        })
+        .collect();
+    without_interrupts(|| {
+        for thread in threads {
+            wake(thread);
+        }
+    });
+}
+
+/// Every thread, ordered by ID.
+pub fn threads() -> Vec<Arc<Thread>> {
+    without_interrupts(|| THREADS.lock().values().filter_map(Weak::upgrade).collect())
+}
@@ -0,0 +1,119 @@
+use arrayvec::ArrayVec;
+use spin::{Mutex, Once};
+
+use crate::port_io::{read_port_u8, write_port_u8};
+
+/// The I/O ports of COM1 to COM4, which are at the same places on every PC
+const PORT_BASES: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+const BAUD_RATE: u32 = 115200;
+/// The clock of the UART divided by 16
+const BASE_BAUD_RATE: u32 = 115200;
+
+// Register offsets from the base port. The first two are the divisor latch while DLAB is set.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+const LINE_CONTROL_8N1: u8 = 0x03;
+const LINE_CONTROL_DLAB: u8 = 0x80;
+/// Enables and clears the FIFOs, with an interrupt threshold of 14 bytes
+const FIFO_CONTROL_ENABLE: u8 = 0xc7;
+/// DTR, RTS and OUT2, which connects the interrupt line
+const MODEM_CONTROL_NORMAL: u8 = 0x0b;
+/// Like normal, but with the transmitter wired to the receiver
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1e;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+const LOOPBACK_TEST_BYTE: u8 = 0xae;
+/// How long to wait for the transmitter before giving up on a byte, e.g. if the port stopped responding
+const TRANSMIT_TIMEOUT_ITERATIONS: u32 = 100000;
+
+/// A 16550 compatible UART, which is used by polling. Reads return what has been received so far.
+pub struct SerialPort {
+    name: &'static str,
+    base: u16,
+    /// Keeps the bytes of writes from different threads from being interleaved
+    transmit_lock: Mutex<()>
+}
+
+impl SerialPort {
+    /// Sets the port up for 115200 baud 8N1, and returns `None` if there's no working UART at `base`.
+    fn probe(name: &'static str, base: u16) -> Option<SerialPort> {
+        // Nothing answers reads of ports without a device, which then read as all ones
+        write_port_u8(base + SCRATCH, 0x5a);
+        if read_port_u8(base + SCRATCH) != 0x5a {
+            return None;
+        }
+        let divisor = (BASE_BAUD_RATE / BAUD_RATE) as u16;
+        write_port_u8(base + INTERRUPT_ENABLE, 0);
+        write_port_u8(base + LINE_CONTROL, LINE_CONTROL_DLAB);
+        write_port_u8(base + DATA, divisor as u8);
+        write_port_u8(base + INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        write_port_u8(base + LINE_CONTROL, LINE_CONTROL_8N1);
+        write_port_u8(base + FIFO_CONTROL, FIFO_CONTROL_ENABLE);
+
+        write_port_u8(base + MODEM_CONTROL, MODEM_CONTROL_LOOPBACK);
+        write_port_u8(base + DATA, LOOPBACK_TEST_BYTE);
+        if read_port_u8(base + DATA) != LOOPBACK_TEST_BYTE {
+            return None;
+        }
+        write_port_u8(base + MODEM_CONTROL, MODEM_CONTROL_NORMAL);
+        Some(SerialPort {
+            name,
+            base,
+            transmit_lock: Mutex::new(())
+        })
+    }
+
+    /// The name of the port, e.g. "ttyS0" for COM1.
+    #[inline]
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// Reads the bytes received so far into `buffer`, returning how many there were.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buffer.len() && read_port_u8(self.base + LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+            buffer[count] = read_port_u8(self.base + DATA);
+            count += 1;
+        }
+        count
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        let _lock = self.transmit_lock.lock();
+        for &byte in data {
+            for _ in 0..TRANSMIT_TIMEOUT_ITERATIONS {
+                if read_port_u8(self.base + LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY != 0 {
+                    break;
+                }
+            }
+            write_port_u8(self.base + DATA, byte);
+        }
+    }
+}
+
+static PORTS: Once<ArrayVec<SerialPort, { PORT_BASES.len() }>> = Once::new();
+
+/// Looks for the standard COM ports and sets up those which exist.
+pub fn init() {
+    const NAMES: [&str; PORT_BASES.len()] = ["ttyS0", "ttyS1", "ttyS2", "ttyS3"];
+    PORTS.call_once(|| {
+        PORT_BASES
+            .iter()
+            .zip(NAMES)
+            .filter_map(|(&base, name)| SerialPort::probe(name, base))
+            .collect()
+    });
+}
+
+/// The serial ports found by `init`.
+pub fn ports() -> &'static [SerialPort] {
+    PORTS.get().map_or(&[], |ports| ports.as_slice())
+}
@@ -0,0 +1,849 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
+
+use spin::Lazy;
+
+use crate::fpu::{FpuState, FPU_STATE_SIZE};
+use crate::memory::{allocate_frames, physical_to_virtual, FRAME_SIZE};
+use crate::paging::PagingError;
+use crate::process::{self, Process, INIT_PID};
+use crate::syscall::{Errno, UserContext, RFLAGS_DIRECTION, RFLAGS_TRAP};
+use crate::user_memory::{copy_from_user, copy_to_user, read_user, write_user, USER_SPACE_END};
+use crate::vfs::{FileType, Inode, Stat, VfsError};
+use crate::virtual_memory::{Backing, ProcessMemory, Protection, Region, MMAP_TOP};
+use crate::{println, scheduler};
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGSTKFLT: u32 = 16;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGXCPU: u32 = 24;
+pub const SIGXFSZ: u32 = 25;
+pub const SIGVTALRM: u32 = 26;
+pub const SIGPROF: u32 = 27;
+pub const SIGWINCH: u32 = 28;
+pub const SIGIO: u32 = 29;
+pub const SIGPWR: u32 = 30;
+pub const SIGSYS: u32 = 31;
+/// Signals are numbered from 1 up to this, where the ones from 32 on are the real-time signals
+const SIGNAL_COUNT: usize = 64;
+
+/// The `si_code` of signals sent with `kill()`
+pub const SI_USER: i32 = 0;
+/// The `si_code` of signals the kernel sends for other reasons, e.g. a general protection fault
+pub const SI_KERNEL: i32 = 0x80;
+/// The `si_code` of signals sent with `tkill()` and `tgkill()`
+const SI_TKILL: i32 = -6;
+pub const ILL_ILLOPN: i32 = 2;
+pub const FPE_INTDIV: i32 = 1;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+pub const BUS_ADRALN: i32 = 1;
+pub const TRAP_BRKPT: i32 = 1;
+pub const TRAP_TRACE: i32 = 2;
+/// The `si_code` of SIGCHLD, which says what happened to the child
+pub const CLD_EXITED: i32 = 1;
+pub const CLD_KILLED: i32 = 2;
+pub const CLD_STOPPED: i32 = 5;
+pub const CLD_CONTINUED: i32 = 6;
+
+/// The handlers that stand for the default action and for ignoring the signal
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+/// Don't send SIGCHLD when a child stops or continues
+const SA_NOCLDSTOP: u64 = 0x1;
+/// Children are reaped as soon as they exit instead of being kept until they're waited for
+const SA_NOCLDWAIT: u64 = 0x2;
+/// The handler takes the `siginfo_t` and `ucontext_t` arguments, which it always gets here
+const SA_SIGINFO: u64 = 0x4;
+/// The handler returns into the `restorer` of the action instead of the kernel's trampoline
+const SA_RESTORER: u64 = 0x04000000;
+const SA_ONSTACK: u64 = 0x08000000;
+/// System calls the signal interrupts are restarted after the handler returns instead of failing with EINTR
+const SA_RESTART: u64 = 0x10000000;
+/// The signal isn't blocked while its handler runs
+const SA_NODEFER: u64 = 0x40000000;
+/// The action is reset to the default once the handler runs
+const SA_RESETHAND: u64 = 0x80000000;
+const SA_KNOWN_FLAGS: u64 = SA_NOCLDSTOP
+    | SA_NOCLDWAIT
+    | SA_SIGINFO
+    | SA_RESTORER
+    | SA_ONSTACK
+    | SA_RESTART
+    | SA_NODEFER
+    | SA_RESETHAND;
+
+/// How `rt_sigprocmask()` changes the blocked signals
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+
+/// The wait status `wait4()` reports for a child that was continued
+pub const CONTINUED_STATUS: u32 = 0xffff;
+/// The low byte of the wait status of a stopped child, which has the signal in the byte above it
+const STOPPED_STATUS: u32 = 0x7f;
+
+/// User mode code below the stack pointer may use this much of the stack, which the signal frame skips
+const RED_ZONE_SIZE: u64 = 128;
+/// Where the signal trampoline is mapped in every program, right below the memory `mmap()` hands out
+const TRAMPOLINE_ADDRESS: u64 = MMAP_TOP - FRAME_SIZE;
+/// `mov eax, SYS_RT_SIGRETURN; syscall; ud2`, which handlers without `SA_RESTORER` return into
+const TRAMPOLINE_CODE: [u8; 9] = [0xb8, 0x0f, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x0f, 0x0b];
+
+/// The signals that can't be caught, blocked or ignored
+const UNBLOCKABLE: u64 = bit(SIGKILL) | bit(SIGSTOP);
+/// The signals whose default action is to stop the process, which SIGCONT cancels and the other way round
+const STOP_SIGNALS: u64 = bit(SIGSTOP) | bit(SIGTSTP) | bit(SIGTTIN) | bit(SIGTTOU);
+
+/// The bit of `signal` in a signal set.
+#[inline]
+const fn bit(signal: u32) -> u64 {
+    1 << (signal - 1)
+}
+
+/// What happens to a process when it gets a signal it has no handler for.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum DefaultAction {
+    Terminate,
+    /// Terminate, which is where a core dump would be written, which there isn't any support for
+    CoreDump,
+    Ignore,
+    Stop,
+    Continue
+}
+
+fn default_action(signal: u32) -> DefaultAction {
+    match signal {
+        SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU | SIGXFSZ | SIGSYS => {
+            DefaultAction::CoreDump
+        },
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        _ => DefaultAction::Terminate
+    }
+}
+
+/// What a process does with a signal, in the layout `rt_sigaction()` takes it in.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct SignalAction {
+    /// The handler's address, or `SIG_DFL` or `SIG_IGN`
+    handler: u64,
+    flags: u64,
+    /// Where the handler returns to with `SA_RESTORER`, which should call `rt_sigreturn()`
+    restorer: u64,
+    /// The signals that are blocked in addition while the handler runs
+    mask: u64
+}
+
+/// Where a signal came from, which a handler gets in a `siginfo_t`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignalInfo {
+    /// What caused the signal, e.g. `SI_USER` for `kill()` or `SEGV_MAPERR` for a page fault
+    pub code: i32,
+    /// The fields that follow the code in a `siginfo_t`, which depend on the signal: the address of a fault,
+    /// or the process ID and user ID of the sender, followed by a child's status for SIGCHLD
+    pub fields: [u64; 2]
+}
+
+impl SignalInfo {
+    /// A signal sent by the process `pid`, or about a child `pid` with `status` for SIGCHLD.
+    pub fn from_process(code: i32, pid: u64, status: u32) -> SignalInfo {
+        SignalInfo {
+            code,
+            fields: [pid, status as u64]
+        }
+    }
+
+    /// A signal for an exception at `address`, which is where the faulting memory access or instruction is.
+    pub fn fault(code: i32, address: u64) -> SignalInfo {
+        SignalInfo {
+            code,
+            fields: [address, 0]
+        }
+    }
+}
+
+/// The signal state of a process: what it does with each signal, and the signals that are pending or
+/// blocked. There is only one thread per process, so both are kept per process.
+pub struct Signals {
+    actions: [SignalAction; SIGNAL_COUNT],
+    pending: u64,
+    /// Where each pending signal came from
+    info: [SignalInfo; SIGNAL_COUNT],
+    blocked: u64,
+    /// Whether the process has been stopped and waits for SIGCONT
+    stopped: bool,
+    /// The wait status of a stop or continue that the parent hasn't collected with `wait4()` yet
+    state_change: Option<u32>
+}
+
+impl Signals {
+    pub fn new() -> Signals {
+        Signals {
+            actions: [SignalAction::default(); SIGNAL_COUNT],
+            pending: 0,
+            info: [SignalInfo::default(); SIGNAL_COUNT],
+            blocked: 0,
+            stopped: false,
+            state_change: None
+        }
+    }
+
+    /// The signal state of a forked child, which has the same actions and blocked signals but nothing
+    /// pending.
+    pub fn fork(&self) -> Signals {
+        Signals {
+            actions: self.actions,
+            blocked: self.blocked,
+            ..Signals::new()
+        }
+    }
+
+    /// Resets the handlers to the default action when a new program is run, as their addresses mean nothing
+    /// to it. Ignored signals stay ignored.
+    pub fn reset_handlers(&mut self) {
+        for action in &mut self.actions {
+            if action.handler != SIG_IGN {
+                *action = SignalAction::default();
+            }
+        }
+    }
+
+    /// Whether children are reaped right when they exit, which ignoring SIGCHLD or setting `SA_NOCLDWAIT`
+    /// on it asks for. `wait4()` can't collect their exit status then.
+    pub fn reaps_children(&self) -> bool {
+        let action = &self.actions[SIGCHLD as usize - 1];
+        action.handler == SIG_IGN || action.flags & SA_NOCLDWAIT != 0
+    }
+
+    /// Whether `signal` is thrown away right when it's sent.
+    fn is_ignored(&self, signal: u32) -> bool {
+        match self.actions[signal as usize - 1].handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(
+                default_action(signal),
+                DefaultAction::Ignore | DefaultAction::Continue
+            ),
+            _ => false
+        }
+    }
+
+    /// The lowest pending signal that isn't blocked.
+    fn next_deliverable(&self) -> Option<u32> {
+        match self.pending & !self.blocked {
+            0 => None,
+            deliverable => Some(deliverable.trailing_zeros() + 1)
+        }
+    }
+
+    /// Takes the wait status of a stop, if `stopped` is set, or a continue, if `continued` is set, that the
+    /// parent hasn't collected yet.
+    pub fn take_state_change(&mut self, stopped: bool, continued: bool) -> Option<u32> {
+        let wanted = match self.state_change? {
+            CONTINUED_STATUS => continued,
+            _ => stopped
+        };
+        match wanted {
+            true => self.state_change.take(),
+            false => None
+        }
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The registers as saved in a signal frame, i.e. Linux's `struct sigcontext`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SignalContext {
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rdi: u64,
+    rsi: u64,
+    rbp: u64,
+    rbx: u64,
+    rdx: u64,
+    rax: u64,
+    rcx: u64,
+    rsp: u64,
+    rip: u64,
+    rflags: u64,
+    /// CS, GS, FS and SS, 16 bits each
+    segments: u64,
+    error_code: u64,
+    trap_number: u64,
+    /// The blocked signals before the handler ran
+    old_mask: u64,
+    cr2: u64,
+    /// The address of the `fxsave` image of the FPU state
+    fpu_state: u64,
+    reserved: [u64; 8]
+}
+
+/// Linux's `ucontext_t`, which handlers get as their third argument.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct UserSignalContext {
+    flags: u64,
+    link: u64,
+    /// The alternate signal stack, which isn't supported
+    stack: [u64; 3],
+    context: SignalContext,
+    /// The blocked signals to restore
+    mask: u64
+}
+
+/// Linux's `siginfo_t`, which handlers get as their second argument.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct UserSignalInfo {
+    signal: i32,
+    errno: i32,
+    code: i32,
+    padding: i32,
+    fields: [u64; 14]
+}
+
+/// What's pushed on the user stack to run a handler, laid out like Linux's `struct rt_sigframe`, with the
+/// FPU state above it. The handler starts with the stack pointer at the return address.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SignalFrame {
+    return_address: u64,
+    context: UserSignalContext,
+    info: UserSignalInfo
+}
+
+/// The page with the trampoline code, which is mapped into every program like a device's memory.
+struct Trampoline {
+    physical_address: u64
+}
+
+impl Inode for Trampoline {
+    fn stat(&self) -> Result<Stat, VfsError> {
+        Ok(Stat {
+            inode: 0,
+            device: 0,
+            file_type: FileType::Regular,
+            mode: 0o555,
+            link_count: 0,
+            uid: 0,
+            gid: 0,
+            size: FRAME_SIZE,
+            block_size: FRAME_SIZE as u32,
+            blocks: FRAME_SIZE / 512
+        })
+    }
+
+    fn physical_page(&self, offset: u64) -> Result<u64, VfsError> {
+        match offset {
+            0 => Ok(self.physical_address),
+            _ => Err(VfsError::InvalidArgument)
+        }
+    }
+}
+
+static TRAMPOLINE: Lazy<Option<Arc<Trampoline>>> = Lazy::new(|| {
+    let frame = allocate_frames(1)?;
+    let page = physical_to_virtual(frame.physical_address) as *mut u8;
+    unsafe { page.copy_from_nonoverlapping(TRAMPOLINE_CODE.as_ptr(), TRAMPOLINE_CODE.len()) };
+    Some(Arc::new(Trampoline {
+        physical_address: frame.physical_address
+    }))
+});
+
+/// Maps the signal trampoline into a new program's memory.
+pub fn map_trampoline(memory: &mut ProcessMemory) -> Result<(), PagingError> {
+    let trampoline = TRAMPOLINE.clone().ok_or(PagingError::OutOfMemory)?;
+    memory.add_region(Region {
+        start: TRAMPOLINE_ADDRESS,
+        end: TRAMPOLINE_ADDRESS + FRAME_SIZE,
+        protection: Protection::READ | Protection::EXECUTE,
+        backing: Backing::Device {
+            inode: trampoline,
+            offset: 0
+        },
+        grows_down: false,
+        shared: true,
+        may_write: false
+    })
+}
+
+/// Whether a signal is waiting for `process` that it doesn't block, so that waiting should be interrupted.
+pub fn has_deliverable(process: &Arc<Process>) -> bool {
+    process.signals().next_deliverable().is_some()
+}
+
+/// Makes `signal` pending for `process`, unless it's ignored. SIGKILL and SIGCONT resume a stopped process,
+/// and stop signals and SIGCONT throw away each other when they're pending.
+pub fn send(process: &Arc<Process>, signal: u32, info: SignalInfo) {
+    send_signal(process, signal, info, false);
+}
+
+/// Like Linux, init only gets the signals it has handlers for, unless they're `forced` for an exception.
+fn send_signal(process: &Arc<Process>, signal: u32, info: SignalInfo, forced: bool) {
+    if process.exit_status().is_some() {
+        return;
+    }
+    let mut signals = process.signals();
+    if process.id() == INIT_PID && !forced && signals.actions[signal as usize - 1].handler == SIG_DFL {
+        return;
+    }
+    let mut resumed = false;
+    match signal {
+        SIGCONT | SIGKILL => {
+            signals.pending &= !STOP_SIGNALS;
+            if signals.stopped {
+                signals.stopped = false;
+                resumed = true;
+                if signal == SIGCONT {
+                    signals.state_change = Some(CONTINUED_STATUS);
+                }
+            }
+        },
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => signals.pending &= !bit(SIGCONT),
+        _ => {}
+    }
+    if !signals.is_ignored(signal) {
+        signals.pending |= bit(signal);
+        signals.info[signal as usize - 1] = info;
+    }
+    let deliverable = signals.next_deliverable().is_some();
+    drop(signals);
+    if resumed {
+        process.resumed().wake_all();
+        if signal == SIGCONT {
+            process.notify_parent(CLD_CONTINUED, SIGCONT);
+        }
+    }
+    if deliverable {
+        scheduler::interrupt(process);
+    }
+}
+
+/// Sends a signal for an exception the current process caused. If the process blocks or ignores it, the
+/// default action is taken instead, as the instruction would just fault again.
+pub fn force(signal: u32, info: SignalInfo) {
+    let process = process::current();
+    {
+        let mut signals = process.signals();
+        if signals.actions[signal as usize - 1].handler == SIG_IGN || signals.blocked & bit(signal) != 0 {
+            signals.actions[signal as usize - 1] = SignalAction::default();
+            signals.blocked &= !bit(signal);
+        }
+    }
+    send_signal(&process, signal, info, true);
+}
+
+/// Tells the parent of the process that a child's state changed, unless it asked not to be told about
+/// children stopping and continuing.
+pub fn send_child_status(parent: &Arc<Process>, child_id: u64, code: i32, status: u32) {
+    let flags = parent.signals().actions[SIGCHLD as usize - 1].flags;
+    if flags & SA_NOCLDSTOP != 0 && matches!(code, CLD_STOPPED | CLD_CONTINUED) {
+        return;
+    }
+    send(parent, SIGCHLD, SignalInfo::from_process(code, child_id, status));
+}
+
+/// Handles the pending signals of the current process before it returns to user mode with `context`: takes
+/// the default actions, or sets up the stack so that a handler runs first. `interrupted_syscall` is the
+/// number of a system call that failed with EINTR, which is restarted unless a handler without
+/// `SA_RESTART` runs.
+pub fn deliver_pending(context: &mut UserContext, interrupted_syscall: Option<u64>) {
+    let mut restart = interrupted_syscall;
+    loop {
+        let process = process::current();
+        let mut signals = process.signals();
+        let Some(signal) = signals.next_deliverable()
+        else {
+            break;
+        };
+        signals.pending &= !bit(signal);
+        let action = signals.actions[signal as usize - 1];
+        let info = signals.info[signal as usize - 1];
+        let old_mask = signals.blocked;
+        drop(signals);
+        let default = match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL => default_action(signal),
+            _ => {
+                if let Some(number) = restart.take() {
+                    if action.flags & SA_RESTART != 0 {
+                        restart_syscall(context, number);
+                    }
+                }
+                if set_up_frame(context, signal, &action, &info, old_mask).is_err() {
+                    drop(process);
+                    terminate(context, SIGSEGV);
+                }
+                let mut signals = process.signals();
+                signals.blocked |= action.mask & !UNBLOCKABLE;
+                if action.flags & SA_NODEFER == 0 {
+                    signals.blocked |= bit(signal);
+                }
+                if action.flags & SA_RESETHAND != 0 {
+                    signals.actions[signal as usize - 1] = SignalAction::default();
+                }
+                return;
+            }
+        };
+        match default {
+            DefaultAction::Ignore | DefaultAction::Continue => {},
+            DefaultAction::Stop => stop(&process, signal),
+            DefaultAction::Terminate | DefaultAction::CoreDump => {
+                // Nothing may be left on the stack, as the thread never returns
+                drop(process);
+                terminate(context, signal);
+            }
+        }
+    }
+    if let Some(number) = restart {
+        restart_syscall(context, number);
+    }
+}
+
+/// Makes the thread run the `syscall` instruction again when it returns to user mode.
+fn restart_syscall(context: &mut UserContext, number: u64) {
+    context.rip -= 2;
+    context.rax = number;
+}
+
+/// Ends the current process for `signal`. Signals that would dump core are logged, as they're usually
+/// crashes.
+fn terminate(context: &UserContext, signal: u32) -> ! {
+    if default_action(signal) == DefaultAction::CoreDump {
+        let process = process::current();
+        println!(
+            "Process {} ({}) killed by signal {} at {:#x}",
+            process.id(),
+            process.name(),
+            signal,
+            context.rip
+        );
+    }
+    process::exit(signal);
+}
+
+/// Stops the process until it gets SIGCONT or SIGKILL.
+fn stop(process: &Arc<Process>, signal: u32) {
+    {
+        let mut signals = process.signals();
+        signals.stopped = true;
+        signals.state_change = Some(signal << 8 | STOPPED_STATUS);
+    }
+    process.notify_parent(CLD_STOPPED, signal);
+    process.resumed().wait_until(|| {
+        let signals = process.signals();
+        !signals.stopped || signals.pending & bit(SIGKILL) != 0
+    });
+}
+
+/// Pushes the signal frame and the FPU state on the user stack, and changes `context` to run the handler.
+fn set_up_frame(
+    context: &mut UserContext,
+    signal: u32,
+    action: &SignalAction,
+    info: &SignalInfo,
+    old_mask: u64
+) -> Result<(), Errno> {
+    let fpu_address = context
+        .rsp
+        .checked_sub(RED_ZONE_SIZE + FPU_STATE_SIZE as u64)
+        .ok_or(Errno::Fault)?
+        & !0x3f;
+    // The stack pointer is 8 below a multiple of 16 at the start of a function, as after a call
+    let frame_address = (fpu_address
+        .checked_sub(size_of::<SignalFrame>() as u64)
+        .ok_or(Errno::Fault)?
+        & !0xf)
+        .checked_sub(8)
+        .ok_or(Errno::Fault)?;
+    let return_address = match action.flags & SA_RESTORER {
+        0 => TRAMPOLINE_ADDRESS,
+        _ => action.restorer
+    };
+    let fault_address = match signal {
+        SIGSEGV | SIGBUS => info.fields[0],
+        _ => 0
+    };
+    let mut fields = [0; 14];
+    fields[..2].copy_from_slice(&info.fields);
+    let frame = SignalFrame {
+        return_address,
+        context: UserSignalContext {
+            context: SignalContext {
+                r8: context.r8,
+                r9: context.r9,
+                r10: context.r10,
+                r11: context.r11,
+                r12: context.r12,
+                r13: context.r13,
+                r14: context.r14,
+                r15: context.r15,
+                rdi: context.rdi,
+                rsi: context.rsi,
+                rbp: context.rbp,
+                rbx: context.rbx,
+                rdx: context.rdx,
+                rax: context.rax,
+                rcx: context.rcx,
+                rsp: context.rsp,
+                rip: context.rip,
+                rflags: context.rflags,
+                segments: context.cs | context.ss << 48,
+                old_mask,
+                cr2: fault_address,
+                fpu_state: fpu_address,
+                ..Default::default()
+            },
+            mask: old_mask,
+            ..Default::default()
+        },
+        info: UserSignalInfo {
+            signal: signal as i32,
+            code: info.code,
+            fields,
+            ..Default::default()
+        }
+    };
+    let mut fpu_state = FpuState::new();
+    fpu_state.save();
+    copy_to_user(fpu_address, fpu_state.as_bytes())?;
+    write_user(frame_address, frame)?;
+    // Handlers start with a clean FPU state
+    FpuState::new().restore();
+
+    context.rip = action.handler;
+    context.rsp = frame_address;
+    context.rdi = signal as u64;
+    context.rsi = frame_address + offset_of!(SignalFrame, info) as u64;
+    context.rdx = frame_address + offset_of!(SignalFrame, context) as u64;
+    context.rax = 0;
+    context.rflags &= !(RFLAGS_DIRECTION | RFLAGS_TRAP);
+    Ok(())
+}
+
+/// Checks a signal number from user mode, which may be 0 where only the target is checked.
+fn signal_number(signal: u64) -> Result<u32, Errno> {
+    match signal {
+        0..=64 => Ok(signal as u32),
+        _ => Err(Errno::InvalidArgument)
+    }
+}
+
+/// Sets what the process does with a signal, and stores the previous action. Handlers always get the
+/// `siginfo_t` and `ucontext_t` arguments, whether `SA_SIGINFO` is set or not, as it only makes a
+/// difference to the handler's signature. There are no alternate signal stacks, so `SA_ONSTACK` is ignored.
+pub fn sys_rt_sigaction(
+    _context: &mut UserContext,
+    [signal, action_address, old_action_address, set_size, ..]: [u64; 6]
+) -> Result<u64, Errno> {
+    let signal = signal_number(signal)?;
+    if signal == 0 || set_size != size_of::<u64>() as u64 {
+        return Err(Errno::InvalidArgument);
+    }
+    let action = match action_address {
+        0 => None,
+        address => Some(read_user::<SignalAction>(address)?)
+    };
+    if action.is_some() && bit(signal) & UNBLOCKABLE != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    // A handler outside of user space, which may not even be canonical, can't be returned to
+    if action.is_some_and(|action| action.handler >= USER_SPACE_END) {
+        return Err(Errno::InvalidArgument);
+    }
+    let process = process::current();
+    let mut signals = process.signals();
+    let old_action = signals.actions[signal as usize - 1];
+    if let Some(mut action) = action {
+        action.flags &= SA_KNOWN_FLAGS;
+        action.mask &= !UNBLOCKABLE;
+        signals.actions[signal as usize - 1] = action;
+        if signals.is_ignored(signal) {
+            signals.pending &= !bit(signal);
+        }
+    }
+    drop(signals);
+    if old_action_address != 0 {
+        write_user(old_action_address, old_action)?;
+    }
+    Ok(0)
+}
+
+/// Blocks or unblocks signals, and stores the previously blocked ones. SIGKILL and SIGSTOP stay unblocked.
+pub fn sys_rt_sigprocmask(
+    _context: &mut UserContext,
+    [how, set_address, old_set_address, set_size, ..]: [u64; 6]
+) -> Result<u64, Errno> {
+    if set_size != size_of::<u64>() as u64 {
+        return Err(Errno::InvalidArgument);
+    }
+    let set = match set_address {
+        0 => None,
+        address => Some(read_user::<u64>(address)?)
+    };
+    let process = process::current();
+    let mut signals = process.signals();
+    let old_set = signals.blocked;
+    if let Some(set) = set {
+        let blocked = match how {
+            SIG_BLOCK => old_set | set,
+            SIG_UNBLOCK => old_set & !set,
+            SIG_SETMASK => set,
+            _ => return Err(Errno::InvalidArgument)
+        };
+        signals.blocked = blocked & !UNBLOCKABLE;
+    }
+    drop(signals);
+    if old_set_address != 0 {
+        write_user(old_set_address, old_set)?;
+    }
+    Ok(0)
+}
+
+/// Returns from a signal handler through the trampoline, restoring the registers, FPU state and blocked
+/// signals from the signal frame. A frame that can't be read, or that would return outside of user space,
+/// kills the process with SIGSEGV.
+pub fn sys_rt_sigreturn(context: &mut UserContext, _arguments: [u64; 6]) -> Result<u64, Errno> {
+    // The handler's `ret` popped the return address off the frame
+    let frame = context
+        .rsp
+        .checked_sub(size_of::<u64>() as u64)
+        .and_then(|frame_address| frame_address.checked_add(offset_of!(SignalFrame, context) as u64))
+        .map(read_user::<UserSignalContext>);
+    let frame = match frame {
+        Some(Ok(frame)) if frame.context.rip < USER_SPACE_END => frame,
+        _ => {
+            force(SIGSEGV, SignalInfo::fault(SI_KERNEL, 0));
+            return Err(Errno::Fault);
+        }
+    };
+    let saved = &frame.context;
+    let mut fpu_state = [0; FPU_STATE_SIZE];
+    if saved.fpu_state != 0 && copy_from_user(&mut fpu_state, saved.fpu_state).is_err() {
+        force(SIGSEGV, SignalInfo::fault(SI_KERNEL, 0));
+        return Err(Errno::Fault);
+    }
+    match saved.fpu_state {
+        0 => FpuState::new().restore(),
+        _ => FpuState::from_bytes(fpu_state).restore()
+    }
+    process::current().signals().blocked = frame.mask & !UNBLOCKABLE;
+    *context = UserContext {
+        rax: saved.rax,
+        rbx: saved.rbx,
+        rcx: saved.rcx,
+        rdx: saved.rdx,
+        rsi: saved.rsi,
+        rdi: saved.rdi,
+        rbp: saved.rbp,
+        r8: saved.r8,
+        r9: saved.r9,
+        r10: saved.r10,
+        r11: saved.r11,
+        r12: saved.r12,
+        r13: saved.r13,
+        r14: saved.r14,
+        r15: saved.r15,
+        rip: saved.rip,
+        rflags: saved.rflags,
+        rsp: saved.rsp,
+        ..Default::default()
+    }
+    .sanitized();
+    // The system call returns the restored RAX, so that it isn't overwritten with a result
+    Ok(context.rax)
+}
+
+/// Sends a signal to the process `pid`. Every process is in a process group of its own, so 0 stands for the
+/// calling process and other negative IDs for the process `-pid`, except for -1, which sends the signal to
+/// every process but init. Signal 0 only checks that the process exists.
+pub fn sys_kill(_context: &mut UserContext, [pid, signal, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let signal = signal_number(signal)?;
+    let sender = process::current();
+    let targets: Vec<Arc<Process>> = match pid as i32 {
+        0 => Vec::from([sender.clone()]),
+        -1 => process::processes()
+            .into_iter()
+            .filter(|process| process.id() != INIT_PID && process.exit_status().is_none())
+            .collect(),
+        pid => process::find(pid.unsigned_abs() as u64).into_iter().collect()
+    };
+    if targets.is_empty() {
+        return Err(Errno::NoProcess);
+    }
+    if signal != 0 {
+        let info = SignalInfo::from_process(SI_USER, sender.id(), 0);
+        for target in &targets {
+            send(target, signal, info);
+        }
+    }
+    Ok(0)
+}
+
+/// The ID of the current thread, which is what `tkill()` takes.
+pub fn sys_gettid(_context: &mut UserContext, _arguments: [u64; 6]) -> Result<u64, Errno> {
+    Ok(scheduler::current_thread().id())
+}
+
+/// Sends a signal to the process of the thread `tid`, as the signals are shared by the whole process.
+pub fn sys_tkill(_context: &mut UserContext, [tid, signal, ..]: [u64; 6]) -> Result<u64, Errno> {
+    send_to_thread(None, tid, signal)
+}
+
+/// Like `tkill()`, but only if the thread belongs to the process `tgid`.
+pub fn sys_tgkill(_context: &mut UserContext, [tgid, tid, signal, ..]: [u64; 6]) -> Result<u64, Errno> {
+    send_to_thread(Some(tgid), tid, signal)
+}
+
+fn send_to_thread(process_id: Option<u64>, tid: u64, signal: u64) -> Result<u64, Errno> {
+    let signal = signal_number(signal)?;
+    if tid as i32 <= 0 || process_id.is_some_and(|id| id as i32 <= 0) {
+        return Err(Errno::InvalidArgument);
+    }
+    let target = scheduler::threads()
+        .into_iter()
+        .find(|thread| thread.id() == tid)
+        .and_then(|thread| thread.process().cloned())
+        .filter(|process| process_id.is_none_or(|id| process.id() == id))
+        .ok_or(Errno::NoProcess)?;
+    if signal != 0 {
+        let info = SignalInfo::from_process(SI_TKILL, process::current().id(), 0);
+        send(&target, signal, info);
+    }
+    Ok(0)
+}
@@ -0,0 +1,758 @@
+use alloc::sync::Arc;
+use alloc::{format, vec};
+use core::arch::{asm, global_asm};
+use core::mem::size_of;
+use core::slice;
+
+use crate::elf::ElfError;
+use crate::gdt::{self, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::interrupts_general::{disable_interrupts, enable_interrupts};
+use crate::msr::{read_msr, write_msr};
+use crate::paging::PagingError;
+use crate::signal::{SignalInfo, SIGPIPE, SI_USER};
+use crate::user_memory::{copy_from_user, copy_to_user, read_user_path, read_user_string, write_user};
+use crate::vfs::{self, FileType, OpenFile, OpenFlags, SeekFrom, Stat, VfsError};
+use crate::{pipe, process, ramfs, signal, virtual_memory};
+
+const MSR_EFER: u32 = 0xc0000080;
+const MSR_STAR: u32 = 0xc0000081;
+const MSR_LSTAR: u32 = 0xc0000082;
+const MSR_SFMASK: u32 = 0xc0000084;
+/// System call extensions, which enable SYSCALL and SYSRET
+const EFER_SCE: u64 = 1 << 0;
+
+/// Always set
+const RFLAGS_RESERVED: u64 = 1 << 1;
+pub const RFLAGS_TRAP: u64 = 1 << 8;
+pub const RFLAGS_INTERRUPT_ENABLE: u64 = 1 << 9;
+pub const RFLAGS_DIRECTION: u64 = 1 << 10;
+const RFLAGS_ALIGNMENT_CHECK: u64 = 1 << 18;
+/// The carry, parity, adjust, zero, sign and overflow flags along with trap, direction and alignment check
+const RFLAGS_USER_CHANGEABLE: u64 = 0x40dd5;
+
+/// How much is read or written at a time by `read` and `write`, which go through a kernel buffer
+const TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The error numbers of Linux, which system calls return negated.
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Errno {
+    NotPermitted = 1,
+    NoEntry = 2,
+    NoProcess = 3,
+    Interrupted = 4,
+    Io = 5,
+    NoDeviceOrAddress = 6,
+    ArgumentListTooLong = 7,
+    ExecFormat = 8,
+    BadFileDescriptor = 9,
+    NoChild = 10,
+    TryAgain = 11,
+    OutOfMemory = 12,
+    AccessDenied = 13,
+    Fault = 14,
+    Busy = 16,
+    Exists = 17,
+    NoDevice = 19,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    InvalidArgument = 22,
+    TooManyOpenFiles = 24,
+    NotATerminal = 25,
+    FileTooLarge = 27,
+    NoSpace = 28,
+    IllegalSeek = 29,
+    ReadOnly = 30,
+    BrokenPipe = 32,
+    NameTooLong = 36,
+    NoSystemCall = 38,
+    DirectoryNotEmpty = 39,
+    TooManySymlinks = 40,
+    NotSupported = 95
+}
+
+impl From<VfsError> for Errno {
+    fn from(error: VfsError) -> Errno {
+        match error {
+            VfsError::NotFound => Errno::NoEntry,
+            VfsError::NotADirectory => Errno::NotADirectory,
+            VfsError::IsADirectory => Errno::IsADirectory,
+            VfsError::AlreadyExists => Errno::Exists,
+            VfsError::DirectoryNotEmpty => Errno::DirectoryNotEmpty,
+            VfsError::InvalidName | VfsError::InvalidArgument => Errno::InvalidArgument,
+            VfsError::NameTooLong => Errno::NameTooLong,
+            VfsError::TooManySymlinks => Errno::TooManySymlinks,
+            VfsError::NoSpace => Errno::NoSpace,
+            VfsError::ReadOnly => Errno::ReadOnly,
+            VfsError::NotSupported => Errno::NotSupported,
+            VfsError::Busy => Errno::Busy,
+            VfsError::FileTooLarge => Errno::FileTooLarge,
+            VfsError::BadFileDescriptor => Errno::BadFileDescriptor,
+            VfsError::TooManyOpenFiles => Errno::TooManyOpenFiles,
+            VfsError::UnknownFileSystem => Errno::NoDevice,
+            VfsError::Corrupted | VfsError::DeviceError => Errno::Io,
+            VfsError::Interrupted => Errno::Interrupted,
+            VfsError::BrokenPipe => Errno::BrokenPipe,
+            VfsError::WouldBlock => Errno::TryAgain,
+            VfsError::NoReaders => Errno::NoDeviceOrAddress,
+            VfsError::NotSeekable => Errno::IllegalSeek
+        }
+    }
+}
+
+impl From<PagingError> for Errno {
+    fn from(error: PagingError) -> Errno {
+        match error {
+            PagingError::OutOfMemory => Errno::OutOfMemory,
+            PagingError::AlreadyMapped | PagingError::NotMapped | PagingError::InvalidAddress => Errno::Fault
+        }
+    }
+}
+
+impl From<ElfError> for Errno {
+    fn from(error: ElfError) -> Errno {
+        match error {
+            ElfError::NotElf | ElfError::Unsupported | ElfError::NeedsInterpreter | ElfError::Malformed => {
+                Errno::ExecFormat
+            },
+            ElfError::ArgumentsTooLong => Errno::ArgumentListTooLong,
+            ElfError::OutOfMemory => Errno::OutOfMemory,
+            ElfError::FileSystemError(e) => e.into()
+        }
+    }
+}
+
+/// The registers of a thread running in user mode, as saved on the kernel stack when it enters the kernel.
+/// The last five are laid out the way `iretq` expects them.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct UserContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64
+}
+
+impl UserContext {
+    /// The registers for starting to run user code at `entry` with the stack pointer `stack`.
+    pub fn new(entry: u64, stack: u64) -> UserContext {
+        UserContext {
+            rip: entry,
+            cs: USER_CODE_SELECTOR as u64,
+            rflags: RFLAGS_INTERRUPT_ENABLE,
+            rsp: stack,
+            ss: USER_DATA_SELECTOR as u64,
+            ..Default::default()
+        }
+    }
+
+    /// A copy that's safe to return to user mode with, whatever the registers were changed to. The segments
+    /// are always the user ones, and user mode only gets to choose the arithmetic, trap, direction and
+    /// alignment check flags.
+    pub fn sanitized(&self) -> UserContext {
+        UserContext {
+            cs: USER_CODE_SELECTOR as u64,
+            ss: USER_DATA_SELECTOR as u64,
+            rflags: self.rflags & RFLAGS_USER_CHANGEABLE | RFLAGS_RESERVED | RFLAGS_INTERRUPT_ENABLE,
+            ..self.clone()
+        }
+    }
+}
+
+// SYSCALL leaves the return address in RCX and RFLAGS in R11, and doesn't switch stacks, so the entry code
+// has to get to the kernel stack through the per-CPU data before it can save anything. The way back is
+// SYSRET when RCX and R11 still match the saved RIP and RFLAGS, as they do after ordinary system calls, and
+// IRETQ when the kernel changed them, e.g. to start a new program. SYSRET is also avoided for non-canonical
+// return addresses, as Intel CPUs raise the resulting #GP in ring 0, on the user stack.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    "push {user_data}",
+    "push qword ptr gs:[{user_stack}]",
+    "swapgs",
+    "push r11",
+    "push {user_code}",
+    "push rcx",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rbx",
+    "push rax",
+    "mov rdi, rsp",
+    "call {handler}",
+    ".global return_to_user_mode_from_stack",
+    "return_to_user_mode_from_stack:",
+    "cli",
+    "pop rax",
+    "pop rbx",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "cmp rcx, [rsp]",
+    "jne 2f",
+    "cmp r11, [rsp + 16]",
+    "jne 2f",
+    "push rcx",
+    "shr rcx, 47",
+    "pop rcx",
+    "jnz 2f",
+    "mov rsp, [rsp + 24]",
+    "sysretq",
+    "2:",
+    "iretq",
+    user_stack = const gdt::CPU_LOCAL_USER_STACK,
+    kernel_stack = const gdt::CPU_LOCAL_KERNEL_STACK,
+    user_data = const USER_DATA_SELECTOR,
+    user_code = const USER_CODE_SELECTOR,
+    handler = sym handle_syscall
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Enables SYSCALL and points it at the entry code. The GDT has to be set up first.
+pub fn init() {
+    write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_SCE);
+    // SYSCALL loads CS from bits 32 to 47 and SS from 8 bytes above, SYSRET takes the user segments from
+    // bits 48 to 63 as described in gdt.rs
+    let star = (gdt::KERNEL_CODE_SELECTOR as u64) << 32 | (gdt::KERNEL_DATA_SELECTOR as u64) << 48;
+    write_msr(MSR_STAR, star);
+    write_msr(MSR_LSTAR, syscall_entry as usize as u64);
+    // The entry code runs with interrupts off until it's on the kernel stack
+    write_msr(
+        MSR_SFMASK,
+        RFLAGS_INTERRUPT_ENABLE | RFLAGS_TRAP | RFLAGS_DIRECTION | RFLAGS_ALIGNMENT_CHECK
+    );
+}
+
+/// Switches to user mode with the registers in `context`, never to return.
+pub fn return_to_user_mode(context: &UserContext) -> ! {
+    let context = context.sanitized();
+    unsafe {
+        asm!(
+            "mov rsp, {context}",
+            "jmp return_to_user_mode_from_stack",
+            context = in(reg) &context,
+            options(noreturn)
+        );
+    }
+}
+
+/// A system call, called with the six arguments in the order user mode passes them in, i.e. in RDI, RSI,
+/// RDX, R10, R8 and R9. It can change the registers user mode returns to through the context.
+type Syscall = fn(&mut UserContext, [u64; 6]) -> Result<u64, Errno>;
+
+pub const SYS_READ: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_OPEN: usize = 2;
+pub const SYS_CLOSE: usize = 3;
+pub const SYS_STAT: usize = 4;
+pub const SYS_FSTAT: usize = 5;
+pub const SYS_LSTAT: usize = 6;
+pub const SYS_LSEEK: usize = 8;
+pub const SYS_MMAP: usize = 9;
+pub const SYS_MPROTECT: usize = 10;
+pub const SYS_MUNMAP: usize = 11;
+pub const SYS_RT_SIGACTION: usize = 13;
+pub const SYS_RT_SIGPROCMASK: usize = 14;
+pub const SYS_RT_SIGRETURN: usize = 15;
+pub const SYS_PIPE: usize = 22;
+pub const SYS_SCHED_YIELD: usize = 24;
+pub const SYS_GETPID: usize = 39;
+pub const SYS_FORK: usize = 57;
+pub const SYS_VFORK: usize = 58;
+pub const SYS_EXECVE: usize = 59;
+pub const SYS_EXIT: usize = 60;
+pub const SYS_WAIT4: usize = 61;
+pub const SYS_KILL: usize = 62;
+pub const SYS_FTRUNCATE: usize = 77;
+pub const SYS_MKDIR: usize = 83;
+pub const SYS_RMDIR: usize = 84;
+pub const SYS_UNLINK: usize = 87;
+pub const SYS_SYMLINK: usize = 88;
+pub const SYS_READLINK: usize = 89;
+pub const SYS_GETPPID: usize = 110;
+pub const SYS_MKNOD: usize = 133;
+pub const SYS_GETTID: usize = 186;
+pub const SYS_TKILL: usize = 200;
+pub const SYS_GETDENTS64: usize = 217;
+pub const SYS_EXIT_GROUP: usize = 231;
+pub const SYS_TGKILL: usize = 234;
+pub const SYS_PIPE2: usize = 293;
+pub const SYS_MEMFD_CREATE: usize = 319;
+/// Every system call number is below this
+const SYSCALL_COUNT: usize = 336;
+
+/// The system calls by number, which are the same as on x86-64 Linux.
+const SYSCALL_TABLE: [Option<Syscall>; SYSCALL_COUNT] = {
+    let mut table: [Option<Syscall>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[SYS_READ] = Some(sys_read);
+    table[SYS_WRITE] = Some(sys_write);
+    table[SYS_OPEN] = Some(sys_open);
+    table[SYS_CLOSE] = Some(sys_close);
+    table[SYS_STAT] = Some(sys_stat);
+    table[SYS_FSTAT] = Some(sys_fstat);
+    table[SYS_LSTAT] = Some(sys_lstat);
+    table[SYS_LSEEK] = Some(sys_lseek);
+    table[SYS_MMAP] = Some(virtual_memory::sys_mmap);
+    table[SYS_MPROTECT] = Some(virtual_memory::sys_mprotect);
+    table[SYS_MUNMAP] = Some(virtual_memory::sys_munmap);
+    table[SYS_RT_SIGACTION] = Some(signal::sys_rt_sigaction);
+    table[SYS_RT_SIGPROCMASK] = Some(signal::sys_rt_sigprocmask);
+    table[SYS_RT_SIGRETURN] = Some(signal::sys_rt_sigreturn);
+    table[SYS_PIPE] = Some(sys_pipe);
+    table[SYS_SCHED_YIELD] = Some(process::sys_sched_yield);
+    table[SYS_GETPID] = Some(process::sys_getpid);
+    table[SYS_FORK] = Some(process::sys_fork);
+    table[SYS_VFORK] = Some(process::sys_fork);
+    table[SYS_EXECVE] = Some(process::sys_execve);
+    table[SYS_EXIT] = Some(process::sys_exit);
+    table[SYS_WAIT4] = Some(process::sys_wait4);
+    table[SYS_KILL] = Some(signal::sys_kill);
+    table[SYS_FTRUNCATE] = Some(sys_ftruncate);
+    table[SYS_MKDIR] = Some(sys_mkdir);
+    table[SYS_RMDIR] = Some(sys_rmdir);
+    table[SYS_UNLINK] = Some(sys_unlink);
+    table[SYS_SYMLINK] = Some(sys_symlink);
+    table[SYS_READLINK] = Some(sys_readlink);
+    table[SYS_GETPPID] = Some(process::sys_getppid);
+    table[SYS_MKNOD] = Some(sys_mknod);
+    table[SYS_GETTID] = Some(signal::sys_gettid);
+    table[SYS_TKILL] = Some(signal::sys_tkill);
+    table[SYS_GETDENTS64] = Some(sys_getdents64);
+    table[SYS_EXIT_GROUP] = Some(process::sys_exit);
+    table[SYS_TGKILL] = Some(signal::sys_tgkill);
+    table[SYS_PIPE2] = Some(sys_pipe2);
+    table[SYS_MEMFD_CREATE] = Some(sys_memfd_create);
+    table
+};
+
+/// Called by the entry code with the registers user mode made the system call with. The number is in RAX,
+/// which is also where the result goes, with errors returned as negative error numbers.
+extern "C" fn handle_syscall(context: &mut UserContext) {
+    enable_interrupts();
+    let arguments = [
+        context.rdi,
+        context.rsi,
+        context.rdx,
+        context.r10,
+        context.r8,
+        context.r9
+    ];
+    let number = context.rax;
+    let syscall = SYSCALL_TABLE.get(number as usize).copied().flatten();
+    let result = match syscall {
+        Some(syscall) => syscall(context, arguments),
+        None => Err(Errno::NoSystemCall)
+    };
+    context.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64
+    };
+    let interrupted = result == Err(Errno::Interrupted);
+    signal::deliver_pending(context, interrupted.then_some(number));
+    disable_interrupts();
+}
+
+/// The open file behind a file descriptor. The table isn't kept locked, so that other system calls can go
+/// on while one waits for a file.
+fn file(fd: u64) -> Result<Arc<OpenFile>, Errno> {
+    Ok(process::current().files().get(fd as usize)?.clone())
+}
+
+fn sys_read(_context: &mut UserContext, [fd, address, length, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let file = file(fd)?;
+    let length = length as usize;
+    let mut buffer = vec![0; length.min(TRANSFER_CHUNK_SIZE)];
+    let mut total = 0;
+    while total < length {
+        let chunk_length = (length - total).min(TRANSFER_CHUNK_SIZE);
+        let read = match file.read(&mut buffer[..chunk_length]) {
+            Ok(read) => read,
+            Err(_) if total > 0 => break,
+            Err(e) => return Err(e.into())
+        };
+        copy_to_user(address + total as u64, &buffer[..read])?;
+        total += read;
+        if read < chunk_length {
+            break;
+        }
+    }
+    Ok(total as u64)
+}
+
+fn sys_write(_context: &mut UserContext, [fd, address, length, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let file = file(fd)?;
+    let length = length as usize;
+    let mut buffer = vec![0; length.min(TRANSFER_CHUNK_SIZE)];
+    let mut total = 0;
+    while total < length {
+        let chunk = &mut buffer[..(length - total).min(TRANSFER_CHUNK_SIZE)];
+        copy_from_user(chunk, address + total as u64)?;
+        let written = match file.write(chunk) {
+            Ok(written) => written,
+            Err(_) if total > 0 => break,
+            Err(VfsError::BrokenPipe) => {
+                // Like on Linux, writing to a pipe nobody reads from also raises SIGPIPE, which ends the
+                // process unless it's handled or ignored
+                let process = process::current();
+                signal::send(
+                    &process,
+                    SIGPIPE,
+                    SignalInfo::from_process(SI_USER, process.id(), 0)
+                );
+                return Err(Errno::BrokenPipe);
+            },
+            Err(e) => return Err(e.into())
+        };
+        total += written;
+        if written < chunk.len() {
+            break;
+        }
+    }
+    Ok(total as u64)
+}
+
+// The flags of `open`, as on Linux
+const O_ACCESS_MODE: u64 = 0o3;
+const O_RDONLY: u64 = 0o0;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_NONBLOCK: u64 = 0o4000;
+const O_DIRECTORY: u64 = 0o200000;
+const O_NOFOLLOW: u64 = 0o400000;
+const O_CLOEXEC: u64 = 0o2000000;
+
+fn sys_open(_context: &mut UserContext, [path, flags, mode, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let path = read_user_path(path)?;
+    let mut open_flags = match flags & O_ACCESS_MODE {
+        O_RDONLY => OpenFlags::READ,
+        O_WRONLY => OpenFlags::WRITE,
+        O_RDWR => OpenFlags::READ | OpenFlags::WRITE,
+        _ => return Err(Errno::InvalidArgument)
+    };
+    let flag_pairs = [
+        (O_CREAT, OpenFlags::CREATE),
+        (O_EXCL, OpenFlags::EXCLUSIVE),
+        (O_TRUNC, OpenFlags::TRUNCATE),
+        (O_APPEND, OpenFlags::APPEND),
+        (O_NONBLOCK, OpenFlags::NON_BLOCK),
+        (O_DIRECTORY, OpenFlags::DIRECTORY),
+        (O_NOFOLLOW, OpenFlags::NO_FOLLOW)
+    ];
+    for (flag, open_flag) in flag_pairs {
+        if flags & flag != 0 {
+            open_flags |= open_flag;
+        }
+    }
+    // Opened first, so that the table isn't locked while e.g. the file is being created
+    let file = OpenFile::open(&path, open_flags, mode as u16 & 0o7777)?;
+    let close_on_exec = flags & O_CLOEXEC != 0;
+    Ok(process::current().files().insert(file, close_on_exec)? as u64)
+}
+
+/// Creates a pipe, writing the file descriptors of its read end and write end to `address`.
+fn sys_pipe2(_context: &mut UserContext, [address, flags, ..]: [u64; 6]) -> Result<u64, Errno> {
+    if flags & !(O_NONBLOCK | O_CLOEXEC) != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let open_flags = match flags & O_NONBLOCK {
+        0 => OpenFlags::empty(),
+        _ => OpenFlags::NON_BLOCK
+    };
+    let (reader, writer) = pipe::create(open_flags)?;
+    let close_on_exec = flags & O_CLOEXEC != 0;
+    let process = process::current();
+    let read_fd = process.files().insert(reader, close_on_exec)?;
+    let write_fd = match process.files().insert(writer, close_on_exec) {
+        Ok(fd) => fd,
+        Err(e) => {
+            process.files().close(read_fd)?;
+            return Err(e.into());
+        }
+    };
+    if let Err(e) = write_user(address, [read_fd as i32, write_fd as i32]) {
+        process.files().close(read_fd)?;
+        process.files().close(write_fd)?;
+        return Err(e);
+    }
+    Ok(0)
+}
+
+fn sys_pipe(context: &mut UserContext, [address, ..]: [u64; 6]) -> Result<u64, Errno> {
+    sys_pipe2(context, [address, 0, 0, 0, 0, 0])
+}
+
+fn sys_close(_context: &mut UserContext, [fd, ..]: [u64; 6]) -> Result<u64, Errno> {
+    process::current().files().close(fd as usize)?;
+    Ok(0)
+}
+
+/// `struct stat` of x86-64 Linux.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UserStat {
+    device: u64,
+    inode: u64,
+    link_count: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    padding: u32,
+    represented_device: u64,
+    size: i64,
+    block_size: i64,
+    blocks: i64,
+    /// Access, modification and status change times as seconds and nanoseconds, which aren't kept track of
+    times: [u64; 6],
+    reserved: [u64; 3]
+}
+
+/// The bits of `st_mode` giving the file type.
+fn file_type_mode(file_type: FileType) -> u32 {
+    match file_type {
+        FileType::Fifo => 0o010000,
+        FileType::CharacterDevice => 0o020000,
+        FileType::Directory => 0o040000,
+        FileType::BlockDevice => 0o060000,
+        FileType::Regular => 0o100000,
+        FileType::Symlink => 0o120000
+    }
+}
+
+fn write_stat(address: u64, stat: Stat) -> Result<u64, Errno> {
+    let user_stat = UserStat {
+        device: stat.device,
+        inode: stat.inode,
+        link_count: stat.link_count as u64,
+        mode: file_type_mode(stat.file_type) | stat.mode as u32,
+        uid: stat.uid,
+        gid: stat.gid,
+        padding: 0,
+        represented_device: 0,
+        size: stat.size as i64,
+        block_size: stat.block_size as i64,
+        blocks: stat.blocks as i64,
+        times: [0; 6],
+        reserved: [0; 3]
+    };
+    write_user(address, user_stat)?;
+    Ok(0)
+}
+
+fn sys_stat(_context: &mut UserContext, [path, address, ..]: [u64; 6]) -> Result<u64, Errno> {
+    write_stat(address, vfs::stat(&read_user_path(path)?)?)
+}
+
+fn sys_fstat(_context: &mut UserContext, [fd, address, ..]: [u64; 6]) -> Result<u64, Errno> {
+    write_stat(address, file(fd)?.stat()?)
+}
+
+fn sys_lstat(_context: &mut UserContext, [path, address, ..]: [u64; 6]) -> Result<u64, Errno> {
+    write_stat(address, vfs::lstat(&read_user_path(path)?)?)
+}
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+fn sys_lseek(_context: &mut UserContext, [fd, offset, whence, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let seek_from = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(Errno::InvalidArgument)
+    };
+    Ok(file(fd)?.seek(seek_from)?)
+}
+
+fn sys_ftruncate(_context: &mut UserContext, [fd, length, ..]: [u64; 6]) -> Result<u64, Errno> {
+    if (length as i64) < 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    file(fd)?.truncate(length)?;
+    Ok(0)
+}
+
+/// The bits of `st_mode` and of the mode of `mknod()` giving the file type
+const S_IFMT: u32 = 0o170000;
+
+/// Creates a FIFO or an empty regular file. Devices only exist in /dev, so they can't be created.
+fn sys_mknod(_context: &mut UserContext, [path, mode, _device, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let file_type = match mode as u32 & S_IFMT {
+        0 => FileType::Regular,
+        bits if bits == file_type_mode(FileType::Regular) => FileType::Regular,
+        bits if bits == file_type_mode(FileType::Fifo) => FileType::Fifo,
+        bits if bits == file_type_mode(FileType::CharacterDevice) => return Err(Errno::NotPermitted),
+        bits if bits == file_type_mode(FileType::BlockDevice) => return Err(Errno::NotPermitted),
+        _ => return Err(Errno::InvalidArgument)
+    };
+    vfs::create_node(&read_user_path(path)?, file_type, mode as u16 & 0o7777)?;
+    Ok(0)
+}
+
+fn sys_mkdir(_context: &mut UserContext, [path, mode, ..]: [u64; 6]) -> Result<u64, Errno> {
+    vfs::create_directory(&read_user_path(path)?, mode as u16 & 0o7777)?;
+    Ok(0)
+}
+
+fn sys_rmdir(_context: &mut UserContext, [path, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let path = read_user_path(path)?;
+    if vfs::lstat(&path)?.file_type != FileType::Directory {
+        return Err(Errno::NotADirectory);
+    }
+    vfs::unlink(&path)?;
+    Ok(0)
+}
+
+fn sys_unlink(_context: &mut UserContext, [path, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let path = read_user_path(path)?;
+    if vfs::lstat(&path)?.file_type == FileType::Directory {
+        return Err(Errno::IsADirectory);
+    }
+    vfs::unlink(&path)?;
+    Ok(0)
+}
+
+fn sys_symlink(_context: &mut UserContext, [target, path, ..]: [u64; 6]) -> Result<u64, Errno> {
+    vfs::symlink(&read_user_path(target)?, &read_user_path(path)?)?;
+    Ok(0)
+}
+
+/// Like on Linux, the target is truncated to fit the buffer and isn't NUL terminated.
+fn sys_readlink(_context: &mut UserContext, [path, address, length, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let target = vfs::read_link(&read_user_path(path)?)?;
+    let length = target.len().min(length as usize);
+    copy_to_user(address, &target.as_bytes()[..length])?;
+    Ok(length as u64)
+}
+
+/// The fixed part of `struct linux_dirent64`, which is followed by the NUL terminated name and padded to a
+/// multiple of 8 bytes.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct UserDirectoryEntry {
+    inode: u64,
+    /// The position of the next entry
+    offset: i64,
+    record_length: u16,
+    file_type: u8
+}
+
+/// The `d_type` of a directory entry.
+fn directory_entry_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Fifo => 1,
+        FileType::CharacterDevice => 2,
+        FileType::Directory => 4,
+        FileType::BlockDevice => 6,
+        FileType::Regular => 8,
+        FileType::Symlink => 10
+    }
+}
+
+/// Fills the buffer with as many directory entries as fit, returning how many bytes they take up.
+fn sys_getdents64(_context: &mut UserContext, [fd, address, length, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let file = file(fd)?;
+    let length = length as usize;
+    let mut used = 0;
+    while let Some(entry) = file.read_directory()? {
+        let header_length = size_of::<UserDirectoryEntry>();
+        let record_length = (header_length + entry.name.len() + 1).next_multiple_of(8);
+        if used + record_length > length {
+            // Left for the next call
+            file.seek(SeekFrom::Current(-1))?;
+            if used == 0 {
+                return Err(Errno::InvalidArgument);
+            }
+            break;
+        }
+        let header = UserDirectoryEntry {
+            inode: entry.inode,
+            offset: file.seek(SeekFrom::Current(0))? as i64,
+            record_length: record_length as u16,
+            file_type: directory_entry_type(entry.file_type)
+        };
+        let mut record = vec![0; record_length];
+        let header_bytes = unsafe {
+            slice::from_raw_parts(&header as *const UserDirectoryEntry as *const u8, header_length)
+        };
+        record[..header_length].copy_from_slice(header_bytes);
+        record[header_length..header_length + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+        copy_to_user(address + used as u64, &record)?;
+        used += record_length;
+    }
+    Ok(used as u64)
+}
+
+/// The only flag of `memfd_create()` that's supported, as on Linux. There are no seals, so
+/// `MFD_ALLOW_SEALING` fails with EINVAL like any other flag.
+const MFD_CLOEXEC: u64 = 1;
+/// The longest name `memfd_create()` takes, not counting the NUL
+const MEMFD_MAX_NAME_LENGTH: usize = 249;
+
+/// Creates a file in memory that's in no directory, for sharing memory: processes that get the file
+/// descriptor, e.g. by forking, share the file's pages when they map it with `MAP_SHARED`. The name only
+/// shows in the file's path.
+fn sys_memfd_create(_context: &mut UserContext, [name, flags, ..]: [u64; 6]) -> Result<u64, Errno> {
+    if flags & !MFD_CLOEXEC != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let name = match read_user_string(name, MEMFD_MAX_NAME_LENGTH + 1) {
+        Err(Errno::NameTooLong) => return Err(Errno::InvalidArgument),
+        name => name?
+    };
+    let inode = ramfs::create_anonymous_file(0o600)?;
+    let file = OpenFile::anonymous(
+        &format!("/memfd:{name}"),
+        inode,
+        OpenFlags::READ | OpenFlags::WRITE,
+        None
+    )?;
+    let close_on_exec = flags & MFD_CLOEXEC != 0;
+    Ok(process::current().files().insert(file, close_on_exec)? as u64)
+}
@@ -58,6 +58,7 @@ pub struct TextRendererSettings<'a> {
 
 impl core::fmt::Write for TextRenderer<'_> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        crate::kernel_log::append(s);
         self.add_text(*crate::FRAMEBUFFER.lock(), s);
         core::fmt::Result::Ok(())
     }
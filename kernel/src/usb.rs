@@ -0,0 +1,460 @@
+use core::mem::size_of;
+use core::ptr;
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use crate::xhci;
+
+pub const MAX_CONFIGURATION_DESCRIPTOR_SIZE: usize = 1024;
+const MAX_INTERFACE_ENDPOINTS: usize = 16;
+const MAX_DRIVERS: usize = 16;
+
+pub const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+pub const DESCRIPTOR_TYPE_CONFIGURATION: u8 = 0x02;
+pub const DESCRIPTOR_TYPE_STRING: u8 = 0x03;
+pub const DESCRIPTOR_TYPE_INTERFACE: u8 = 0x04;
+pub const DESCRIPTOR_TYPE_ENDPOINT: u8 = 0x05;
+pub const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+
+pub const REQUEST_GET_STATUS: u8 = 0x00;
+pub const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+pub const REQUEST_SET_FEATURE: u8 = 0x03;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+pub const REQUEST_GET_CONFIGURATION: u8 = 0x08;
+pub const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+pub const REQUEST_SET_INTERFACE: u8 = 0x0b;
+
+pub const FEATURE_ENDPOINT_HALT: u16 = 0x00;
+
+/// Bits of `SetupPacket::request_type`
+pub const REQUEST_TYPE_DEVICE_TO_HOST: u8 = 0x80;
+pub const REQUEST_TYPE_STANDARD: u8 = 0x00;
+pub const REQUEST_TYPE_CLASS: u8 = 0x20;
+pub const REQUEST_TYPE_VENDOR: u8 = 0x40;
+pub const REQUEST_RECIPIENT_DEVICE: u8 = 0x00;
+pub const REQUEST_RECIPIENT_INTERFACE: u8 = 0x01;
+pub const REQUEST_RECIPIENT_ENDPOINT: u8 = 0x02;
+
+pub const ENDPOINT_ADDRESS_DIRECTION_IN: u8 = 0x80;
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum UsbError {
+    /// The endpoint returned a STALL handshake, e.g. because the request isn't supported
+    Stall,
+    TransferFailed,
+    Timeout,
+    NoSuchDevice,
+    /// The endpoint hasn't been configured with `UsbDevice::configure_endpoint()`
+    EndpointNotConfigured,
+    InvalidDescriptor,
+    OutOfMemory,
+    BufferTooLarge,
+    Unsupported
+}
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum UsbSpeed {
+    Low,
+    Full,
+    High,
+    Super,
+    SuperPlus
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16
+}
+
+impl SetupPacket {
+    pub const fn get_descriptor(descriptor_type: u8, descriptor_index: u8, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: REQUEST_TYPE_DEVICE_TO_HOST | REQUEST_TYPE_STANDARD | REQUEST_RECIPIENT_DEVICE,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: ((descriptor_type as u16) << 8) | descriptor_index as u16,
+            index: 0,
+            length
+        }
+    }
+
+    #[inline]
+    pub fn is_device_to_host(&self) -> bool {
+        self.request_type & REQUEST_TYPE_DEVICE_TO_HOST != 0
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub usb_version: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub max_packet_size_0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub manufacturer_string_index: u8,
+    pub product_string_index: u8,
+    pub serial_number_string_index: u8,
+    pub configuration_count: u8
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigurationDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub total_length: u16,
+    pub interface_count: u8,
+    pub configuration_value: u8,
+    pub configuration_string_index: u8,
+    pub attributes: u8,
+    pub max_power: u8
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct InterfaceDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub endpoint_count: u8,
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    pub interface_string_index: u8
+}
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum EndpointTransferType {
+    Control = 0,
+    Isochronous = 1,
+    Bulk = 2,
+    Interrupt = 3
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct EndpointDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub endpoint_address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8
+}
+
+impl EndpointDescriptor {
+    #[inline]
+    pub fn is_in(&self) -> bool {
+        self.endpoint_address & ENDPOINT_ADDRESS_DIRECTION_IN != 0
+    }
+
+    #[inline]
+    pub fn number(&self) -> u8 {
+        self.endpoint_address & 0x0f
+    }
+
+    #[inline]
+    pub fn transfer_type(&self) -> EndpointTransferType {
+        match self.attributes & 0b11 {
+            0 => EndpointTransferType::Control,
+            1 => EndpointTransferType::Isochronous,
+            2 => EndpointTransferType::Bulk,
+            _ => EndpointTransferType::Interrupt
+        }
+    }
+
+    /// Only bits 10:0 are the packet size, high speed endpoints keep the number of additional transactions
+    /// per microframe in bits 12:11.
+    #[inline]
+    pub fn max_packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7ff
+    }
+}
+
+/// Reads a descriptor struct from the start of `bytes`, if the descriptor there is long enough and of the
+/// right type.
+pub fn read_descriptor<T: Copy>(bytes: &[u8], descriptor_type: u8) -> Option<T> {
+    if bytes.len() < size_of::<T>() || (bytes[0] as usize) < size_of::<T>() || bytes[1] != descriptor_type {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Iterates over the descriptors in a configuration descriptor blob, yielding each descriptor's bytes.
+pub struct DescriptorIterator<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> DescriptorIterator<'a> {
+    pub fn new(bytes: &'a [u8]) -> DescriptorIterator<'a> {
+        DescriptorIterator { bytes }
+    }
+}
+
+impl<'a> Iterator for DescriptorIterator<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.len() < 2 {
+            return None;
+        }
+        let length = self.bytes[0] as usize;
+        if length < 2 || length > self.bytes.len() {
+            return None;
+        }
+        let (descriptor, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Some(descriptor)
+    }
+}
+
+/// An interface of the active configuration along with its endpoints and the class-specific descriptors
+/// that came between the interface descriptor and its endpoints, as found in the configuration descriptor.
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo<'a> {
+    pub descriptor: InterfaceDescriptor,
+    pub endpoints: ArrayVec<EndpointDescriptor, MAX_INTERFACE_ENDPOINTS>,
+    pub class_descriptors: &'a [u8]
+}
+
+/// Returns every interface (alternate setting 0 only) described in a configuration descriptor blob.
+pub fn parse_interfaces(configuration: &[u8]) -> ArrayVec<InterfaceInfo<'_>, 16> {
+    let mut interfaces: ArrayVec<InterfaceInfo<'_>, 16> = ArrayVec::new();
+    let mut offset = 0;
+    // Where the descriptors following the current interface descriptor start
+    let mut interface_end = 0;
+    let mut in_alternate_setting = false;
+    for descriptor in DescriptorIterator::new(configuration) {
+        offset += descriptor.len();
+        match descriptor[1] {
+            DESCRIPTOR_TYPE_INTERFACE => {
+                let Some(interface) =
+                    read_descriptor::<InterfaceDescriptor>(descriptor, DESCRIPTOR_TYPE_INTERFACE)
+                else {
+                    continue;
+                };
+                in_alternate_setting = interface.alternate_setting != 0;
+                interface_end = offset;
+                if in_alternate_setting || interfaces.is_full() {
+                    continue;
+                }
+                interfaces.push(InterfaceInfo {
+                    descriptor: interface,
+                    endpoints: ArrayVec::new(),
+                    class_descriptors: &[]
+                });
+            },
+            DESCRIPTOR_TYPE_ENDPOINT => {
+                if in_alternate_setting {
+                    continue;
+                }
+                let (Some(interface), Some(endpoint)) = (
+                    interfaces.last_mut(),
+                    read_descriptor::<EndpointDescriptor>(descriptor, DESCRIPTOR_TYPE_ENDPOINT)
+                )
+                else {
+                    continue;
+                };
+                let _ = interface.endpoints.try_push(endpoint);
+            },
+            _ => {
+                if in_alternate_setting {
+                    continue;
+                }
+                // Class-specific descriptors (e.g. the HID descriptor) before the first endpoint belong to
+                // the interface
+                if let Some(interface) = interfaces.last_mut() {
+                    if interface.endpoints.is_empty() {
+                        interface.class_descriptors = &configuration[interface_end..offset];
+                    }
+                }
+            }
+        }
+    }
+    interfaces
+}
+
+/// Called from the host controller's interrupt handler with the data of each completed interrupt transfer,
+/// along with the context given when the transfers were started.
+pub type InterruptTransferHandler = fn(data: &[u8], context: u64);
+
+/// A handle to a device attached to a USB host controller. Class drivers do everything through this, so that
+/// they don't need to know anything about the host controller.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct UsbDevice {
+    pub controller: usize,
+    pub slot_id: u8
+}
+
+impl UsbDevice {
+    pub fn device_descriptor(&self) -> Result<DeviceDescriptor, UsbError> {
+        xhci::with_device(*self, |device| device.device_descriptor)
+    }
+
+    pub fn speed(&self) -> Result<UsbSpeed, UsbError> {
+        xhci::with_device(*self, |device| device.speed)
+    }
+
+    /// Copies the configuration descriptor of the active configuration, including every interface, endpoint
+    /// and class-specific descriptor that follows it, into `buffer`. Returns the number of bytes copied.
+    pub fn configuration_descriptor(&self, buffer: &mut [u8]) -> Result<usize, UsbError> {
+        xhci::with_device(*self, |device| {
+            let length = device.configuration_descriptor.len().min(buffer.len());
+            buffer[..length].copy_from_slice(&device.configuration_descriptor[..length]);
+            length
+        })
+    }
+
+    /// Performs a control transfer on the default control endpoint. `data` is read from or written into
+    /// depending on the direction of the request, and its length should match `setup.length`. Returns the
+    /// number of bytes actually transferred in the data stage.
+    pub fn control_transfer(&self, setup: SetupPacket, data: &mut [u8]) -> Result<usize, UsbError> {
+        xhci::control_transfer(*self, setup, data)
+    }
+
+    /// Sets up the host controller side of an endpoint of the active configuration, so that transfers to it
+    /// can be made.
+    pub fn configure_endpoint(&self, endpoint: &EndpointDescriptor) -> Result<(), UsbError> {
+        xhci::configure_endpoint(*self, endpoint)
+    }
+
+    /// Performs a bulk or interrupt transfer from a configured IN endpoint into `data`. Returns the number of
+    /// bytes actually transferred, which is less than the length of `data` if the device ended the transfer
+    /// with a short packet.
+    pub fn transfer_in(&self, endpoint_address: u8, data: &mut [u8]) -> Result<usize, UsbError> {
+        xhci::transfer_in(*self, endpoint_address, data)
+    }
+
+    /// Performs a bulk or interrupt transfer of `data` to a configured OUT endpoint.
+    pub fn transfer_out(&self, endpoint_address: u8, data: &[u8]) -> Result<usize, UsbError> {
+        xhci::transfer_out(*self, endpoint_address, data)
+    }
+
+    /// Starts polling a configured interrupt IN endpoint, with transfers of up to `length` bytes. `handler`
+    /// is called from interrupt context with the data of every transfer, so it should only hand the data
+    /// off (e.g. into an event queue) and mustn't make any transfers itself.
+    pub fn start_interrupt_transfers(
+        &self,
+        endpoint_address: u8,
+        length: usize,
+        handler: InterruptTransferHandler,
+        context: u64
+    ) -> Result<(), UsbError> {
+        xhci::start_interrupt_transfers(*self, endpoint_address, length, handler, context)
+    }
+
+    /// Clears a halt (stall) condition on an endpoint, both on the device and in the host controller.
+    pub fn clear_halt(&self, endpoint_address: u8) -> Result<(), UsbError> {
+        xhci::reset_endpoint(*self, endpoint_address)?;
+        let setup = SetupPacket {
+            request_type: REQUEST_TYPE_STANDARD | REQUEST_RECIPIENT_ENDPOINT,
+            request: REQUEST_CLEAR_FEATURE,
+            value: FEATURE_ENDPOINT_HALT,
+            index: endpoint_address as u16,
+            length: 0
+        };
+        self.control_transfer(setup, &mut [])?;
+        Ok(())
+    }
+
+    pub fn set_configuration(&self, configuration_value: u8) -> Result<(), UsbError> {
+        let setup = SetupPacket {
+            request_type: REQUEST_TYPE_STANDARD | REQUEST_RECIPIENT_DEVICE,
+            request: REQUEST_SET_CONFIGURATION,
+            value: configuration_value as u16,
+            index: 0,
+            length: 0
+        };
+        self.control_transfer(setup, &mut [])?;
+        Ok(())
+    }
+}
+
+/// Describes which interfaces a class driver supports. Fields that are `None` match anything.
+#[derive(Debug, Clone, Copy)]
+pub struct UsbInterfaceId {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub protocol: Option<u8>
+}
+
+impl UsbInterfaceId {
+    pub const fn class(class: u8, subclass: Option<u8>, protocol: Option<u8>) -> UsbInterfaceId {
+        UsbInterfaceId {
+            vendor_id: None,
+            product_id: None,
+            class: Some(class),
+            subclass,
+            protocol
+        }
+    }
+
+    pub fn matches(&self, device: &DeviceDescriptor, interface: &InterfaceDescriptor) -> bool {
+        let (vendor_id, product_id) = (device.vendor_id, device.product_id);
+        self.vendor_id.map_or(true, |v| v == vendor_id)
+            && self.product_id.map_or(true, |p| p == product_id)
+            && self.class.map_or(true, |c| c == interface.interface_class)
+            && self.subclass.map_or(true, |s| s == interface.interface_subclass)
+            && self.protocol.map_or(true, |p| p == interface.interface_protocol)
+    }
+}
+
+pub struct UsbDriver {
+    pub name: &'static str,
+    pub ids: &'static [UsbInterfaceId],
+    pub probe: fn(UsbDevice, &InterfaceInfo) -> Result<(), UsbError>
+}
+
+static DRIVERS: Mutex<ArrayVec<&'static UsbDriver, MAX_DRIVERS>> = Mutex::new(ArrayVec::new_const());
+
+pub fn register_driver(driver: &'static UsbDriver) {
+    if DRIVERS.lock().try_push(driver).is_err() {
+        panic!("Too many USB drivers registered");
+    }
+}
+
+/// Gives every interface of a newly addressed and configured device to the first class driver that matches
+/// it and accepts it.
+pub fn probe_device(device: UsbDevice) {
+    let Ok(device_descriptor) = device.device_descriptor()
+    else {
+        return;
+    };
+    let mut configuration = [0; MAX_CONFIGURATION_DESCRIPTOR_SIZE];
+    let Ok(length) = device.configuration_descriptor(&mut configuration)
+    else {
+        return;
+    };
+    let drivers = DRIVERS.lock().clone();
+    for interface in parse_interfaces(&configuration[..length]) {
+        for driver in drivers.iter() {
+            if !driver
+                .ids
+                .iter()
+                .any(|id| id.matches(&device_descriptor, &interface.descriptor))
+            {
+                continue;
+            }
+            if (driver.probe)(device, &interface).is_ok() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arrayvec::ArrayString;
+use as_slice_of::as_slice_of;
+use spin::{Mutex, Once};
+
+use crate::block::{self, BlockDevice, BlockError, RequestQueue};
+use crate::usb::{
+    EndpointTransferType, InterfaceInfo, SetupPacket, UsbDevice, UsbDriver, UsbError, UsbInterfaceId,
+    REQUEST_RECIPIENT_INTERFACE, REQUEST_TYPE_CLASS, REQUEST_TYPE_DEVICE_TO_HOST
+};
+
+const MAX_TRANSPORTS: usize = 8;
+const MAX_DISKS: usize = 16;
+
+const CLASS_MASS_STORAGE: u8 = 0x08;
+const SUBCLASS_SCSI_TRANSPARENT: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const REQUEST_BULK_ONLY_RESET: u8 = 0xff;
+const REQUEST_GET_MAX_LUN: u8 = 0xfe;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CBW_FLAGS_DATA_IN: u8 = 0x80;
+const CSW_SIZE: usize = 13;
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2a;
+const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const SCSI_READ_16: u8 = 0x88;
+const SCSI_WRITE_16: u8 = 0x8a;
+const SCSI_SERVICE_ACTION_IN_16: u8 = 0x9e;
+const SERVICE_ACTION_READ_CAPACITY_16: u8 = 0x10;
+
+const INQUIRY_LENGTH: usize = 36;
+const REQUEST_SENSE_LENGTH: usize = 18;
+const PERIPHERAL_DEVICE_TYPE_DIRECT_ACCESS: u8 = 0x00;
+
+const SENSE_KEY_NOT_READY: u8 = 0x02;
+const SENSE_KEY_UNIT_ATTENTION: u8 = 0x06;
+const SENSE_KEY_DATA_PROTECT: u8 = 0x07;
+
+/// Each READ or WRITE command transfers at most this many bytes, which is also the most the xHCI driver can
+/// transfer in one go.
+const MAX_COMMAND_TRANSFER_SIZE: usize = 64 * 1024;
+/// Devices are often still spinning up or report a unit attention right after being reset, so commands are
+/// retried a few times before giving up on them.
+const COMMAND_ATTEMPTS: u32 = 8;
+
+/// The command block wrapper, which starts every command
+#[repr(C, packed)]
+struct CommandBlockWrapper {
+    signature: u32,
+    tag: u32,
+    data_transfer_length: u32,
+    flags: u8,
+    lun: u8,
+    command_length: u8,
+    command: [u8; 16]
+}
+
+enum DataStage<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8])
+}
+
+impl DataStage<'_> {
+    fn len(&self) -> usize {
+        match self {
+            DataStage::None => 0,
+            DataStage::In(data) => data.len(),
+            DataStage::Out(data) => data.len()
+        }
+    }
+
+    fn reborrow(&mut self) -> DataStage<'_> {
+        match self {
+            DataStage::None => DataStage::None,
+            DataStage::In(data) => DataStage::In(data),
+            DataStage::Out(data) => DataStage::Out(data)
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum CommandError {
+    /// The command was delivered, but the device reported it as failed. The reason can be asked for with
+    /// REQUEST SENSE.
+    Failed,
+    Transport(UsbError)
+}
+
+impl From<UsbError> for CommandError {
+    fn from(error: UsbError) -> Self {
+        CommandError::Transport(error)
+    }
+}
+
+/// A mass storage interface using the Bulk-Only Transport, which wraps each SCSI command in a command block
+/// wrapper sent on the bulk OUT endpoint, followed by the data stage and a command status wrapper read from
+/// the bulk IN endpoint.
+struct BulkOnlyTransport {
+    device: UsbDevice,
+    interface_number: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    /// Only one command can be in flight, the lock is held for the whole of it. Holds the next tag, which
+    /// the device echoes back in the status wrapper.
+    next_tag: Mutex<u32>
+}
+
+impl BulkOnlyTransport {
+    fn class_request(&self, request: u8, device_to_host: bool, data: &mut [u8]) -> Result<usize, UsbError> {
+        let direction = if device_to_host { REQUEST_TYPE_DEVICE_TO_HOST } else { 0 };
+        let setup = SetupPacket {
+            request_type: direction | REQUEST_TYPE_CLASS | REQUEST_RECIPIENT_INTERFACE,
+            request,
+            value: 0,
+            index: self.interface_number as u16,
+            length: data.len() as u16
+        };
+        self.device.control_transfer(setup, data)
+    }
+
+    /// Devices with a single LUN are allowed to stall the request.
+    fn max_lun(&self) -> u8 {
+        let mut max_lun = [0];
+        match self.class_request(REQUEST_GET_MAX_LUN, true, &mut max_lun) {
+            Ok(1) => max_lun[0].min(15),
+            _ => 0
+        }
+    }
+
+    /// Brings the device and both bulk endpoints back into a known state after the device got confused about
+    /// which stage of a command it's in.
+    fn reset_recovery(&self) -> Result<(), UsbError> {
+        self.class_request(REQUEST_BULK_ONLY_RESET, false, &mut [])?;
+        self.device.clear_halt(self.bulk_in)?;
+        self.device.clear_halt(self.bulk_out)
+    }
+
+    /// Sends a SCSI command to a LUN and performs its data stage. Returns the number of bytes transferred in
+    /// the data stage.
+    fn command(&self, lun: u8, command: &[u8], mut data: DataStage) -> Result<usize, CommandError> {
+        let mut next_tag = self.next_tag.lock();
+        let tag = *next_tag;
+        *next_tag = next_tag.wrapping_add(1);
+
+        let mut command_block = [0; 16];
+        command_block[..command.len()].copy_from_slice(command);
+        let wrapper = CommandBlockWrapper {
+            signature: CBW_SIGNATURE,
+            tag,
+            data_transfer_length: data.len() as u32,
+            flags: if matches!(data, DataStage::In(_)) { CBW_FLAGS_DATA_IN } else { 0 },
+            lun,
+            command_length: command.len() as u8,
+            command: command_block
+        };
+        let wrapper_bytes: &[u8; 31] = as_slice_of(&wrapper);
+        if let Err(e) = self.device.transfer_out(self.bulk_out, wrapper_bytes) {
+            let _ = self.reset_recovery();
+            return Err(e.into());
+        }
+
+        // The device stalls the data endpoint if it has less data than asked for or runs into an error,
+        // which still has to be followed by reading the status
+        let transferred = match &mut data {
+            DataStage::None => Ok(0),
+            DataStage::In(buffer) => self.device.transfer_in(self.bulk_in, buffer),
+            DataStage::Out(buffer) => self.device.transfer_out(self.bulk_out, buffer)
+        };
+        let transferred = match transferred {
+            Ok(transferred) => transferred,
+            Err(UsbError::Stall) => {
+                let endpoint = if matches!(data, DataStage::In(_)) {
+                    self.bulk_in
+                }
+                else {
+                    self.bulk_out
+                };
+                self.device.clear_halt(endpoint)?;
+                0
+            },
+            Err(e) => {
+                let _ = self.reset_recovery();
+                return Err(e.into());
+            }
+        };
+
+        let status = self.read_status()?;
+        let signature = u32::from_le_bytes(status[0..4].try_into().unwrap());
+        let status_tag = u32::from_le_bytes(status[4..8].try_into().unwrap());
+        if signature != CSW_SIGNATURE || status_tag != tag {
+            let _ = self.reset_recovery();
+            return Err(CommandError::Transport(UsbError::TransferFailed));
+        }
+        match status[12] {
+            CSW_STATUS_PASSED => Ok(transferred),
+            CSW_STATUS_FAILED => Err(CommandError::Failed),
+            // Phase error
+            _ => {
+                let _ = self.reset_recovery();
+                Err(CommandError::Transport(UsbError::TransferFailed))
+            }
+        }
+    }
+
+    /// Reads the command status wrapper, retrying once if the bulk IN endpoint was stalled.
+    fn read_status(&self) -> Result<[u8; CSW_SIZE], CommandError> {
+        let mut status = [0; CSW_SIZE];
+        let result = match self.device.transfer_in(self.bulk_in, &mut status) {
+            Err(UsbError::Stall) => {
+                self.device.clear_halt(self.bulk_in)?;
+                self.device.transfer_in(self.bulk_in, &mut status)
+            },
+            result => result
+        };
+        match result {
+            Ok(CSW_SIZE) => Ok(status),
+            Ok(_) => {
+                let _ = self.reset_recovery();
+                Err(CommandError::Transport(UsbError::TransferFailed))
+            },
+            Err(e) => {
+                let _ = self.reset_recovery();
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Asks the device why the previous command failed, returning the sense key.
+    fn request_sense(&self, lun: u8) -> Result<u8, CommandError> {
+        let mut sense = [0; REQUEST_SENSE_LENGTH];
+        let command = [SCSI_REQUEST_SENSE, 0, 0, 0, REQUEST_SENSE_LENGTH as u8, 0];
+        self.command(lun, &command, DataStage::In(&mut sense))?;
+        Ok(sense[2] & 0x0f)
+    }
+}
+
+fn sense_key_to_error(sense_key: u8) -> BlockError {
+    match sense_key {
+        SENSE_KEY_NOT_READY => BlockError::NoMedium,
+        SENSE_KEY_DATA_PROTECT => BlockError::WriteProtected,
+        _ => BlockError::DeviceError
+    }
+}
+
+/// A logical unit of a mass storage device, which is a disk of its own.
+pub struct UsbMassStorage {
+    transport: &'static BulkOnlyTransport,
+    lun: u8,
+    name: ArrayString<16>,
+    block_size: u32,
+    block_count: u64,
+    request_queue: RequestQueue
+}
+
+impl UsbMassStorage {
+    fn command(&self, command: &[u8], mut data: DataStage) -> Result<usize, BlockError> {
+        command_with_retries(self.transport, self.lun, command, &mut data)
+    }
+
+    fn read_write_command(&self, write: bool, first_block: u64, block_count: u32) -> ([u8; 16], usize) {
+        let mut command = [0; 16];
+        if first_block + block_count as u64 <= u32::MAX as u64 && block_count <= u16::MAX as u32 {
+            command[0] = if write { SCSI_WRITE_10 } else { SCSI_READ_10 };
+            command[2..6].copy_from_slice(&(first_block as u32).to_be_bytes());
+            command[7..9].copy_from_slice(&(block_count as u16).to_be_bytes());
+            (command, 10)
+        }
+        else {
+            command[0] = if write { SCSI_WRITE_16 } else { SCSI_READ_16 };
+            command[2..10].copy_from_slice(&first_block.to_be_bytes());
+            command[10..14].copy_from_slice(&block_count.to_be_bytes());
+            (command, 16)
+        }
+    }
+
+    #[inline]
+    fn blocks_per_command(&self) -> usize {
+        (MAX_COMMAND_TRANSFER_SIZE / self.block_size as usize).max(1)
+    }
+}
+
+/// Issues a command, retrying it if it fails for a reason that usually goes away on its own. Failed commands
+/// are turned into errors with the help of the sense data.
+fn command_with_retries(
+    transport: &BulkOnlyTransport,
+    lun: u8,
+    command: &[u8],
+    data: &mut DataStage
+) -> Result<usize, BlockError> {
+    let mut last_error = BlockError::DeviceError;
+    for _ in 0..COMMAND_ATTEMPTS {
+        match transport.command(lun, command, data.reborrow()) {
+            Ok(transferred) => return Ok(transferred),
+            Err(CommandError::Transport(UsbError::Timeout)) => return Err(BlockError::Timeout),
+            Err(CommandError::Transport(_)) => last_error = BlockError::DeviceError,
+            Err(CommandError::Failed) => {
+                let sense_key = transport
+                    .request_sense(lun)
+                    .map_err(|_| BlockError::DeviceError)?;
+                // A unit attention (e.g. the medium having changed or the device having been reset) only
+                // means that the command has to be retried
+                if sense_key != SENSE_KEY_UNIT_ATTENTION {
+                    last_error = sense_key_to_error(sense_key);
+                    if last_error != BlockError::NoMedium {
+                        return Err(last_error);
+                    }
+                }
+            }
+        }
+    }
+    Err(last_error)
+}
+
+impl BlockDevice for UsbMassStorage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        let block_size = self.block_size as usize;
+        let mut block = first_block;
+        for chunk in buffer.chunks_mut(self.blocks_per_command() * block_size) {
+            let block_count = (chunk.len() / block_size) as u32;
+            let (command, length) = self.read_write_command(false, block, block_count);
+            if self.command(&command[..length], DataStage::In(chunk))? != chunk.len() {
+                return Err(BlockError::DeviceError);
+            }
+            block += block_count as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        let block_size = self.block_size as usize;
+        let mut block = first_block;
+        for chunk in buffer.chunks(self.blocks_per_command() * block_size) {
+            let block_count = (chunk.len() / block_size) as u32;
+            let (command, length) = self.read_write_command(true, block, block_count);
+            if self.command(&command[..length], DataStage::Out(chunk))? != chunk.len() {
+                return Err(BlockError::DeviceError);
+            }
+            block += block_count as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let command = [SCSI_SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        self.command(&command, DataStage::None).map(|_| ())
+    }
+
+    fn request_queue(&self) -> &RequestQueue {
+        &self.request_queue
+    }
+}
+
+/// Drivers can't allocate memory dynamically yet, so transports and disks live in fixed pools which are
+/// filled in as devices are probed.
+static TRANSPORTS: [Once<BulkOnlyTransport>; MAX_TRANSPORTS] = [const { Once::new() }; MAX_TRANSPORTS];
+static TRANSPORT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static DISKS: [Once<UsbMassStorage>; MAX_DISKS] = [const { Once::new() }; MAX_DISKS];
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Finds out whether a LUN is a disk and how large it is. Returns `None` for LUNs that aren't disks or
+/// don't have a medium inserted.
+fn probe_lun(transport: &BulkOnlyTransport, lun: u8) -> Option<(u32, u64)> {
+    let mut inquiry = [0; INQUIRY_LENGTH];
+    let command = [SCSI_INQUIRY, 0, 0, 0, INQUIRY_LENGTH as u8, 0];
+    command_with_retries(transport, lun, &command, &mut DataStage::In(&mut inquiry)).ok()?;
+    if inquiry[0] & 0x1f != PERIPHERAL_DEVICE_TYPE_DIRECT_ACCESS {
+        return None;
+    }
+    let command = [SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0];
+    command_with_retries(transport, lun, &command, &mut DataStage::None).ok()?;
+
+    let mut capacity = [0; 8];
+    let command = [SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    command_with_retries(transport, lun, &command, &mut DataStage::In(&mut capacity)).ok()?;
+    let last_block = u32::from_be_bytes(capacity[0..4].try_into().unwrap());
+    let block_size = u32::from_be_bytes(capacity[4..8].try_into().unwrap());
+    if last_block != u32::MAX {
+        return Some((block_size, last_block as u64 + 1)).filter(|(size, _)| *size != 0);
+    }
+
+    // The disk is too large for READ CAPACITY(10) to describe
+    let mut capacity = [0; 32];
+    let mut command = [0; 16];
+    command[0] = SCSI_SERVICE_ACTION_IN_16;
+    command[1] = SERVICE_ACTION_READ_CAPACITY_16;
+    command[10..14].copy_from_slice(&(capacity.len() as u32).to_be_bytes());
+    command_with_retries(transport, lun, &command, &mut DataStage::In(&mut capacity)).ok()?;
+    let last_block = u64::from_be_bytes(capacity[0..8].try_into().unwrap());
+    let block_size = u32::from_be_bytes(capacity[8..12].try_into().unwrap());
+    Some((block_size, last_block + 1)).filter(|(size, _)| *size != 0)
+}
+
+fn probe(device: UsbDevice, interface: &InterfaceInfo) -> Result<(), UsbError> {
+    let bulk_endpoint = |is_in: bool| {
+        interface
+            .endpoints
+            .iter()
+            .find(|e| e.transfer_type() == EndpointTransferType::Bulk && e.is_in() == is_in)
+            .copied()
+            .ok_or(UsbError::InvalidDescriptor)
+    };
+    let (bulk_in, bulk_out) = (bulk_endpoint(true)?, bulk_endpoint(false)?);
+    device.configure_endpoint(&bulk_in)?;
+    device.configure_endpoint(&bulk_out)?;
+
+    let transport_index = TRANSPORT_COUNT.fetch_add(1, Ordering::Relaxed);
+    let Some(transport_slot) = TRANSPORTS.get(transport_index)
+    else {
+        return Err(UsbError::OutOfMemory);
+    };
+    let transport = transport_slot.call_once(|| BulkOnlyTransport {
+        device,
+        interface_number: interface.descriptor.interface_number,
+        bulk_in: bulk_in.endpoint_address,
+        bulk_out: bulk_out.endpoint_address,
+        next_tag: Mutex::new(1)
+    });
+
+    for lun in 0..=transport.max_lun() {
+        let Some((block_size, block_count)) = probe_lun(transport, lun)
+        else {
+            continue;
+        };
+        let disk_index = DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+        let Some(disk_slot) = DISKS.get(disk_index)
+        else {
+            return Err(UsbError::OutOfMemory);
+        };
+        let mut name = ArrayString::new();
+        let _ = write!(name, "usb{}", disk_index);
+        let disk = disk_slot.call_once(|| UsbMassStorage {
+            transport,
+            lun,
+            name,
+            block_size,
+            block_count,
+            request_queue: RequestQueue::new()
+        });
+        block::register_device(disk).map_err(|_| UsbError::OutOfMemory)?;
+    }
+    Ok(())
+}
+
+pub static USB_STORAGE_DRIVER: UsbDriver = UsbDriver {
+    name: "usb-storage",
+    ids: &[UsbInterfaceId::class(
+        CLASS_MASS_STORAGE,
+        Some(SUBCLASS_SCSI_TRANSPARENT),
+        Some(PROTOCOL_BULK_ONLY)
+    )],
+    probe
+};
@@ -0,0 +1,134 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+
+use crate::memory::FRAME_SIZE;
+use crate::paging::{self, PageFlags};
+use crate::process;
+use crate::syscall::Errno;
+use crate::virtual_memory::Access;
+
+/// User space is the lower half of the address space, and everything from here on belongs to the kernel.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+/// The longest path a system call takes, including the terminating NUL, like `PATH_MAX` on Linux
+pub const MAX_PATH_LENGTH: usize = 4096;
+/// The longest single argument or environment string, including the terminating NUL, like `MAX_ARG_STRLEN`
+/// on Linux
+const MAX_ARGUMENT_LENGTH: usize = 32 * FRAME_SIZE as usize;
+/// The most strings an argument or environment array can have
+const MAX_ARGUMENT_COUNT: usize = 0x7fff;
+
+/// Makes sure `length` bytes at `address` are all in user space, so that a program can't get the kernel
+/// to read or write its own memory on its behalf. Null pointers are refused as well, unless the range is
+/// empty, like on Linux, where e.g. `read(fd, NULL, 0)` returns 0.
+pub fn check_user_range(address: u64, length: usize) -> Result<(), Errno> {
+    if length == 0 {
+        return Ok(());
+    }
+    let end = address.checked_add(length as u64).ok_or(Errno::Fault)?;
+    if address == 0 || end > USER_SPACE_END {
+        return Err(Errno::Fault);
+    }
+    Ok(())
+}
+
+/// Makes sure every page of a range is mapped for user mode, and writable if `write` is set, as the kernel
+/// would fault touching any that isn't. Pages that haven't been touched yet are mapped the way the page
+/// fault handler would, and copy-on-write pages are copied before the kernel writes to them.
+fn check_user_pages(address: u64, length: usize, write: bool) -> Result<(), Errno> {
+    check_user_range(address, length)?;
+    let access = match write {
+        true => Access::Write,
+        false => Access::Read
+    };
+    let first_page = address - address % FRAME_SIZE;
+    for page in (first_page..address + length as u64).step_by(FRAME_SIZE as usize) {
+        let accessible = paging::translate_current(page).is_some_and(|(_, flags)| {
+            flags.contains(PageFlags::USER) && (!write || flags.contains(PageFlags::WRITABLE))
+        });
+        if !accessible && !process::resolve_page_fault(page, access) {
+            return Err(Errno::Fault);
+        }
+    }
+    Ok(())
+}
+
+/// Copies `buffer.len()` bytes from user memory at `address` into `buffer`.
+pub fn copy_from_user(buffer: &mut [u8], address: u64) -> Result<(), Errno> {
+    if buffer.is_empty() {
+        return Ok(());
+    }
+    check_user_pages(address, buffer.len(), false)?;
+    unsafe { ptr::copy_nonoverlapping(address as *const u8, buffer.as_mut_ptr(), buffer.len()) };
+    Ok(())
+}
+
+/// Copies `data` to user memory at `address`.
+pub fn copy_to_user(address: u64, data: &[u8]) -> Result<(), Errno> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    check_user_pages(address, data.len(), true)?;
+    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), address as *mut u8, data.len()) };
+    Ok(())
+}
+
+/// Reads a value of a plain type (one that is valid for any bit pattern) from user memory, which needn't be
+/// aligned.
+pub fn read_user<T: Copy>(address: u64) -> Result<T, Errno> {
+    check_user_pages(address, size_of::<T>(), false)?;
+    Ok(unsafe { ptr::read_unaligned(address as *const T) })
+}
+
+pub fn write_user<T: Copy>(address: u64, value: T) -> Result<(), Errno> {
+    check_user_pages(address, size_of::<T>(), true)?;
+    unsafe { ptr::write_unaligned(address as *mut T, value) };
+    Ok(())
+}
+
+/// Reads a NUL terminated string from user memory, which can be at most `max_length` bytes long including
+/// the NUL.
+pub fn read_user_string(address: u64, max_length: usize) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte: u8 = read_user(address + bytes.len() as u64)?;
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+        if bytes.len() == max_length {
+            return Err(Errno::NameTooLong);
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| Errno::InvalidArgument)
+}
+
+/// Reads a path passed to a system call.
+#[inline]
+pub fn read_user_path(address: u64) -> Result<String, Errno> {
+    read_user_string(address, MAX_PATH_LENGTH)
+}
+
+/// Reads an array of pointers to strings terminated by a null pointer, like the arguments and environment
+/// of `execve()`. A null array is read as an empty one.
+pub fn read_user_string_array(address: u64) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if address == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let pointer: u64 = read_user(address + (strings.len() * size_of::<u64>()) as u64)?;
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        if strings.len() == MAX_ARGUMENT_COUNT {
+            return Err(Errno::ArgumentListTooLong);
+        }
+        match read_user_string(pointer, MAX_ARGUMENT_LENGTH) {
+            Ok(string) => strings.push(string),
+            Err(Errno::NameTooLong) => return Err(Errno::ArgumentListTooLong),
+            Err(e) => return Err(e)
+        }
+    }
+}
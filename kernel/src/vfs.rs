@@ -0,0 +1,877 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use arrayvec::ArrayVec;
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::block::{self, BlockDevice};
+use crate::pipe::{self, PipeEnd};
+
+pub const MAX_NAME_LENGTH: usize = 255;
+pub const MAX_PATH_LENGTH: usize = 4096;
+/// How many symbolic links are followed while resolving a single path, which stops links pointing to
+/// themselves from being followed forever
+const MAX_SYMLINK_DEPTH: u32 = 40;
+const MAX_FILE_SYSTEM_TYPES: usize = 16;
+/// How many files a file descriptor table can have open at once
+pub const MAX_OPEN_FILES: usize = 256;
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidName,
+    NameTooLong,
+    TooManySymlinks,
+    /// The file system has no room left
+    NoSpace,
+    ReadOnly,
+    /// The file or file system doesn't support the operation
+    NotSupported,
+    /// The file or file system is in use, e.g. when unmounting a file system with open files
+    Busy,
+    FileTooLarge,
+    InvalidArgument,
+    BadFileDescriptor,
+    TooManyOpenFiles,
+    /// No file system type with the given name has been registered
+    UnknownFileSystem,
+    /// Something on the device doesn't make sense to the file system
+    Corrupted,
+    DeviceError,
+    /// A signal arrived while waiting, e.g. for input
+    Interrupted,
+    /// Writing to a pipe that nobody reads from anymore
+    BrokenPipe,
+    /// A non-blocking read or write would have to wait
+    WouldBlock,
+    /// Opening a FIFO for writing without waiting while nobody has it open for reading
+    NoReaders,
+    /// Seeking in a pipe or FIFO
+    NotSeekable
+}
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharacterDevice,
+    BlockDevice,
+    Fifo
+}
+
+/// What `stat()` returns about a file.
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    /// Identifies the file among the others on the same file system
+    pub inode: u64,
+    /// Identifies the mount the file is on, filled in by the VFS
+    pub device: u64,
+    pub file_type: FileType,
+    /// The permission bits, e.g. 0o644
+    pub mode: u16,
+    pub link_count: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// The preferred size of reads and writes
+    pub block_size: u32,
+    /// How many 512 byte blocks the file takes up on the file system
+    pub blocks: u64
+}
+
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType
+}
+
+/// A file, directory or other object on a file system. Every method has a default which fails the way it
+/// would on a kind of file that doesn't support it, so file systems only implement what they have.
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Result<Stat, VfsError>;
+
+    /// Looks up an entry of a directory. "." and ".." are handled by the VFS and never looked up.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Calls `f` with the entries of a directory other than "." and "..", skipping the first `start`
+    /// ones, until it returns false.
+    fn read_directory(
+        &self,
+        _start: usize,
+        _f: &mut dyn FnMut(DirectoryEntry) -> bool
+    ) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Creates a regular file or directory in a directory. Some file systems also create other types.
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u16) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// Removes an entry of a directory, which has to be empty if it's a directory itself.
+    fn unlink(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// The path a symbolic link points to.
+    fn read_link(&self) -> Result<String, VfsError> {
+        Err(VfsError::InvalidArgument)
+    }
+
+    /// Reads from `offset`, returning how many bytes were read, which is zero at the end of the file.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// Writes at `offset`, extending the file if needed, and returns how many bytes were written.
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// The physical address of the page at `offset`, a multiple of the page size, for devices whose memory
+    /// can be mapped straight into an address space, such as a framebuffer, and for files kept in memory,
+    /// which processes share by mapping them.
+    fn physical_page(&self, _offset: u64) -> Result<u64, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+}
+
+/// How much space a file system takes up.
+#[derive(Debug, Clone, Copy)]
+pub struct FileSystemUsage {
+    pub used_bytes: u64,
+    /// How large the file system can grow, if it's limited
+    pub total_bytes: Option<u64>
+}
+
+/// A mounted instance of a file system.
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// How much space is in use, if the file system keeps track of it.
+    fn usage(&self) -> Option<FileSystemUsage> {
+        None
+    }
+
+    /// Writes everything cached in memory back to the device.
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+/// Creates a file system on `device`, or without one for those which don't live on a device. `options` is a
+/// comma separated list of settings understood by the file system type, such as "size=16m".
+pub type MountFunction =
+    fn(device: Option<&'static dyn BlockDevice>, options: &str) -> Result<Arc<dyn FileSystem>, VfsError>;
+
+/// A kind of file system, which file systems are mounted by the name of.
+pub struct FileSystemType {
+    pub name: &'static str,
+    pub mount: MountFunction
+}
+
+static FILE_SYSTEM_TYPES: Mutex<ArrayVec<&'static FileSystemType, MAX_FILE_SYSTEM_TYPES>> =
+    Mutex::new(ArrayVec::new_const());
+
+pub fn register_file_system(file_system_type: &'static FileSystemType) {
+    if FILE_SYSTEM_TYPES.lock().try_push(file_system_type).is_err() {
+        panic!("Too many file system types registered");
+    }
+}
+
+/// A name in the directory tree, caching the inode it refers to. Dentries of directories keep their parent
+/// alive, and are kept alive in turn by their children, open files and mounts, while a parent only keeps weak
+/// references to its children.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    file_type: FileType,
+    /// The parent directory. The root of a mounted file system has the parent of its mount point, so ".."
+    /// leaves the file system.
+    parent: Option<Arc<Dentry>>,
+    /// The id of the mount the dentry is on
+    mount_id: u64,
+    children: Mutex<BTreeMap<String, Weak<Dentry>>>,
+    /// The root of the file system mounted on top of this dentry, which hides it
+    mounted: Mutex<Option<Arc<Dentry>>>
+}
+
+impl Dentry {
+    fn new(
+        name: &str,
+        inode: Arc<dyn Inode>,
+        parent: Option<Arc<Dentry>>,
+        mount_id: u64
+    ) -> Result<Arc<Dentry>, VfsError> {
+        let file_type = inode.stat()?.file_type;
+        Ok(Arc::new(Dentry {
+            name: name.to_string(),
+            inode,
+            file_type,
+            parent,
+            mount_id,
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None)
+        }))
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    #[inline]
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn stat(&self) -> Result<Stat, VfsError> {
+        let mut stat = self.inode.stat()?;
+        stat.device = self.mount_id;
+        Ok(stat)
+    }
+
+    /// The absolute path of the dentry, e.g. for showing to users.
+    pub fn path(&self) -> String {
+        let mut components = Vec::new();
+        let mut dentry = self;
+        while let Some(parent) = &dentry.parent {
+            components.push(dentry.name.as_str());
+            dentry = parent;
+        }
+        if components.is_empty() {
+            // Dentries of anonymous files have a name but no parent, e.g. "pipe:[1]"
+            return match self.name.is_empty() {
+                true => String::from("/"),
+                false => self.name.clone()
+            };
+        }
+        components
+            .iter()
+            .rev()
+            .fold(String::new(), |path, component| path + "/" + component)
+    }
+
+    /// The root of whatever is mounted on top of the dentry, or the dentry itself.
+    fn follow_mounts(self: Arc<Dentry>) -> Arc<Dentry> {
+        let mut dentry = self;
+        loop {
+            let mounted = dentry.mounted.lock().clone();
+            match mounted {
+                Some(root) => dentry = root,
+                None => return dentry
+            }
+        }
+    }
+
+    fn lookup_child(self: &Arc<Dentry>, name: &str) -> Result<Arc<Dentry>, VfsError> {
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(VfsError::NameTooLong);
+        }
+        if let Some(child) = self.children.lock().get(name).and_then(Weak::upgrade) {
+            return Ok(child.follow_mounts());
+        }
+        let inode = self.inode.lookup(name)?;
+        let child = Dentry::new(name, inode, Some(self.clone()), self.mount_id)?;
+        let mut children = self.children.lock();
+        // Someone else may have looked up the same name in the meantime, in which case theirs is used
+        if let Some(existing) = children.get(name).and_then(Weak::upgrade) {
+            return Ok(existing.follow_mounts());
+        }
+        children.retain(|_, child| child.strong_count() > 0);
+        children.insert(name.to_string(), Arc::downgrade(&child));
+        Ok(child)
+    }
+}
+
+struct Mount {
+    id: u64,
+    path: String,
+    source: String,
+    file_system_type: &'static str,
+    file_system: Arc<dyn FileSystem>,
+    root: Arc<Dentry>,
+    /// The dentry the file system is mounted on, or `None` for the first file system mounted on "/"
+    mountpoint: Option<Arc<Dentry>>
+}
+
+struct MountTable {
+    mounts: Vec<Mount>,
+    next_id: u64
+}
+
+static MOUNTS: Mutex<MountTable> = Mutex::new(MountTable {
+    mounts: Vec::new(),
+    next_id: 1
+});
+/// The dentry the first file system was mounted on, which paths are resolved from
+static ROOT: Mutex<Option<Arc<Dentry>>> = Mutex::new(None);
+
+/// Information about a mounted file system, as shown to users.
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub id: u64,
+    pub path: String,
+    /// The name of the block device the file system is on, or "none"
+    pub source: String,
+    pub file_system_type: &'static str,
+    pub usage: Option<FileSystemUsage>
+}
+
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS
+        .lock()
+        .mounts
+        .iter()
+        .map(|mount| MountInfo {
+            id: mount.id,
+            path: mount.path.clone(),
+            source: mount.source.clone(),
+            file_system_type: mount.file_system_type,
+            usage: mount.file_system.usage()
+        })
+        .collect()
+}
+
+fn root() -> Result<Arc<Dentry>, VfsError> {
+    let root = ROOT.lock().clone().ok_or(VfsError::NotFound)?;
+    Ok(root.follow_mounts())
+}
+
+/// Resolves `path` starting from `base`, or from the root if it's absolute. The last component is only
+/// followed if it's a symbolic link when `follow_last` is set.
+fn walk(
+    base: Arc<Dentry>,
+    path: &str,
+    follow_last: bool,
+    symlinks_followed: &mut u32
+) -> Result<Arc<Dentry>, VfsError> {
+    if path.len() > MAX_PATH_LENGTH {
+        return Err(VfsError::NameTooLong);
+    }
+    let mut current = match path.starts_with('/') {
+        true => root()?,
+        false => base
+    };
+    let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+    while let Some(component) = components.next() {
+        if current.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        let last = components.peek().is_none();
+        current = match component {
+            "." => current,
+            ".." => current.parent.clone().unwrap_or(current),
+            name => {
+                let child = current.lookup_child(name)?;
+                if child.file_type != FileType::Symlink || (last && !follow_last) {
+                    child
+                }
+                else {
+                    *symlinks_followed += 1;
+                    if *symlinks_followed > MAX_SYMLINK_DEPTH {
+                        return Err(VfsError::TooManySymlinks);
+                    }
+                    let target = child.inode.read_link()?;
+                    walk(current, &target, true, symlinks_followed)?
+                }
+            }
+        };
+    }
+    Ok(current)
+}
+
+/// Resolves an absolute path to its dentry, following symbolic links.
+pub fn lookup(path: &str) -> Result<Arc<Dentry>, VfsError> {
+    walk(root()?, path, true, &mut 0)
+}
+
+/// Like `lookup()`, but a symbolic link at the end of the path is returned rather than followed.
+pub fn lookup_no_follow(path: &str) -> Result<Arc<Dentry>, VfsError> {
+    walk(root()?, path, false, &mut 0)
+}
+
+/// Resolves everything but the last component of a path, returning the parent directory and the name of
+/// the last component.
+fn lookup_parent(path: &str) -> Result<(Arc<Dentry>, &str), VfsError> {
+    let path = path.trim_end_matches('/');
+    let (parent_path, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent_path, name)) => (parent_path, name),
+        None => (".", path)
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(VfsError::InvalidName);
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(VfsError::NameTooLong);
+    }
+    let parent = lookup(parent_path)?;
+    if parent.file_type != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    Ok((parent, name))
+}
+
+pub fn stat(path: &str) -> Result<Stat, VfsError> {
+    lookup(path)?.stat()
+}
+
+pub fn lstat(path: &str) -> Result<Stat, VfsError> {
+    lookup_no_follow(path)?.stat()
+}
+
+pub fn create_directory(path: &str, mode: u16) -> Result<(), VfsError> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.inode.create(name, FileType::Directory, mode)?;
+    Ok(())
+}
+
+/// Creates a file that isn't a directory or symbolic link, e.g. a FIFO for `mknod()`.
+pub fn create_node(path: &str, file_type: FileType, mode: u16) -> Result<(), VfsError> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.inode.create(name, file_type, mode)?;
+    Ok(())
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<(), VfsError> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.inode.symlink(name, target)?;
+    Ok(())
+}
+
+pub fn read_link(path: &str) -> Result<String, VfsError> {
+    lookup_no_follow(path)?.inode.read_link()
+}
+
+/// Removes a file, symbolic link or empty directory.
+pub fn unlink(path: &str) -> Result<(), VfsError> {
+    let (parent, name) = lookup_parent(path)?;
+    // Whatever is mounted somewhere has to be unmounted first
+    if let Some(child) = parent.children.lock().get(name).and_then(Weak::upgrade) {
+        if child.mounted.lock().is_some() {
+            return Err(VfsError::Busy);
+        }
+    }
+    parent.inode.unlink(name)?;
+    parent.children.lock().remove(name);
+    Ok(())
+}
+
+/// Mounts a file system of the registered type named `file_system_type` at `path`, which has to be an
+/// existing directory unless it's the first file system mounted, which is mounted on "/". `options` are
+/// passed on to the file system.
+pub fn mount(
+    source: Option<&str>,
+    path: &str,
+    file_system_type: &str,
+    options: &str
+) -> Result<(), VfsError> {
+    let device = match source {
+        Some(name) => Some(block::find_device(name).ok_or(VfsError::NotFound)?),
+        None => None
+    };
+    let file_system_type = FILE_SYSTEM_TYPES
+        .lock()
+        .iter()
+        .find(|t| t.name == file_system_type)
+        .copied()
+        .ok_or(VfsError::UnknownFileSystem)?;
+    let has_root = ROOT.lock().is_some();
+    let mountpoint = match has_root {
+        true => Some(lookup(path)?),
+        false if path == "/" => None,
+        false => return Err(VfsError::NotFound)
+    };
+    if let Some(mountpoint) = &mountpoint {
+        if mountpoint.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+    }
+    let file_system = (file_system_type.mount)(device, options)?;
+
+    let mut table = MOUNTS.lock();
+    let id = table.next_id;
+    let parent = mountpoint.as_ref().and_then(|m| m.parent.clone());
+    let name = mountpoint.as_ref().map_or("", |m| m.name.as_str());
+    let root = Dentry::new(name, file_system.root(), parent, id)?;
+    match &mountpoint {
+        Some(mountpoint) => *mountpoint.mounted.lock() = Some(root.clone()),
+        None => *ROOT.lock() = Some(root.clone())
+    }
+    table.next_id += 1;
+    table.mounts.push(Mount {
+        id,
+        path: mountpoint.as_ref().map_or(String::from("/"), |m| m.path()),
+        source: source.unwrap_or("none").to_string(),
+        file_system_type: file_system_type.name,
+        file_system,
+        root,
+        mountpoint
+    });
+    Ok(())
+}
+
+/// Unmounts the file system mounted at `path`, after writing back everything cached. Fails if anything on
+/// it is still in use.
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    let root = lookup(path)?;
+    let mut table = MOUNTS.lock();
+    let index = table
+        .mounts
+        .iter()
+        .position(|mount| Arc::ptr_eq(&mount.root, &root))
+        .ok_or(VfsError::InvalidArgument)?;
+    let mount = &table.mounts[index];
+    // The mount table, the mount point and the reference held here are the only ones allowed. Dentries
+    // below the root, e.g. of open files, would hold another.
+    let Some(mountpoint) = &mount.mountpoint
+    else {
+        return Err(VfsError::Busy);
+    };
+    if Arc::strong_count(&root) > 3 {
+        return Err(VfsError::Busy);
+    }
+    mount.file_system.sync()?;
+    *mountpoint.mounted.lock() = None;
+    table.mounts.remove(index);
+    Ok(())
+}
+
+/// Writes back everything every mounted file system has cached.
+pub fn sync() -> Result<(), VfsError> {
+    let file_systems: Vec<_> = MOUNTS
+        .lock()
+        .mounts
+        .iter()
+        .map(|mount| mount.file_system.clone())
+        .collect();
+    file_systems.iter().try_for_each(|file_system| file_system.sync())
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct OpenFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        /// Creates the file if it doesn't exist
+        const CREATE = 1 << 2;
+        /// Together with `CREATE`, fails if the file already exists
+        const EXCLUSIVE = 1 << 3;
+        /// Truncates the file to zero length
+        const TRUNCATE = 1 << 4;
+        /// Every write goes to the end of the file
+        const APPEND = 1 << 5;
+        /// Fails unless the file is a directory
+        const DIRECTORY = 1 << 6;
+        /// Fails if the file is a symbolic link, rather than following it
+        const NO_FOLLOW = 1 << 7;
+        /// Reads and writes fail with `WouldBlock` rather than waiting, e.g. on pipes
+        const NON_BLOCK = 1 << 8;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64)
+}
+
+/// An open file, which may be shared by several file descriptors.
+pub struct OpenFile {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    /// The offset of the next read or write, or the index of the next entry for directories
+    position: Mutex<u64>,
+    /// The end of the pipe that reads and writes go to, for pipes and FIFOs
+    pipe: Option<PipeEnd>
+}
+
+impl OpenFile {
+    /// Opens `path`, creating it as a regular file with permissions `mode` if asked to.
+    pub fn open(path: &str, flags: OpenFlags, mode: u16) -> Result<Arc<OpenFile>, VfsError> {
+        let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+        let dentry = match walk(root()?, path, follow, &mut 0) {
+            Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+                return Err(VfsError::AlreadyExists);
+            },
+            Ok(dentry) => dentry,
+            Err(VfsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                let (parent, name) = lookup_parent(path)?;
+                parent.inode.create(name, FileType::Regular, mode)?;
+                parent.lookup_child(name)?
+            },
+            Err(e) => return Err(e)
+        };
+        match dentry.file_type {
+            FileType::Symlink => return Err(VfsError::TooManySymlinks),
+            FileType::Directory if flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE) => {
+                return Err(VfsError::IsADirectory);
+            },
+            FileType::Directory => {},
+            _ if flags.contains(OpenFlags::DIRECTORY) => return Err(VfsError::NotADirectory),
+            _ => {}
+        }
+        // Opening devices for truncation is allowed, but leaves them alone like on other Unix systems
+        let truncate = flags.contains(OpenFlags::TRUNCATE | OpenFlags::WRITE);
+        if truncate && dentry.file_type == FileType::Regular {
+            dentry.inode.truncate(0)?;
+        }
+        let pipe = match dentry.file_type {
+            FileType::Fifo => Some(pipe::open_fifo(&dentry, flags)?),
+            _ => None
+        };
+        Ok(Arc::new(OpenFile {
+            dentry,
+            flags,
+            position: Mutex::new(0),
+            pipe
+        }))
+    }
+
+    /// Opens a file that isn't in any directory, e.g. the end of a pipe. `name` is what its path shows.
+    pub fn anonymous(
+        name: &str,
+        inode: Arc<dyn Inode>,
+        flags: OpenFlags,
+        pipe: Option<PipeEnd>
+    ) -> Result<Arc<OpenFile>, VfsError> {
+        Ok(Arc::new(OpenFile {
+            dentry: Dentry::new(name, inode, None, 0)?,
+            flags,
+            position: Mutex::new(0),
+            pipe
+        }))
+    }
+
+    #[inline]
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    #[inline]
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(VfsError::BadFileDescriptor);
+        }
+        if self.dentry.file_type == FileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        if let Some(pipe) = &self.pipe {
+            return pipe.read(buffer, self.flags.contains(OpenFlags::NON_BLOCK));
+        }
+        let mut position = self.position.lock();
+        let length = self.dentry.inode.read_at(*position, buffer)?;
+        *position += length as u64;
+        Ok(length)
+    }
+
+    pub fn write(&self, data: &[u8]) -> Result<usize, VfsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(VfsError::BadFileDescriptor);
+        }
+        if let Some(pipe) = &self.pipe {
+            return pipe.write(data, self.flags.contains(OpenFlags::NON_BLOCK));
+        }
+        let mut position = self.position.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *position = self.dentry.inode.stat()?.size;
+        }
+        let length = self.dentry.inode.write_at(*position, data)?;
+        *position += length as u64;
+        Ok(length)
+    }
+
+    /// Moves the position of the next read or write, returning the new position. The position of a directory
+    /// is the index of the next entry, so it can't be moved relative to the end.
+    pub fn seek(&self, seek_from: SeekFrom) -> Result<u64, VfsError> {
+        if self.pipe.is_some() {
+            return Err(VfsError::NotSeekable);
+        }
+        let mut position = self.position.lock();
+        let new_position = match seek_from {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+            SeekFrom::End(_) if self.dentry.file_type == FileType::Directory => None,
+            SeekFrom::End(offset) => self.dentry.inode.stat()?.size.checked_add_signed(offset)
+        };
+        let new_position = new_position.ok_or(VfsError::InvalidArgument)?;
+        *position = new_position;
+        Ok(new_position)
+    }
+
+    pub fn stat(&self) -> Result<Stat, VfsError> {
+        self.dentry.stat()
+    }
+
+    pub fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(VfsError::BadFileDescriptor);
+        }
+        self.dentry.inode.truncate(size)
+    }
+
+    /// Returns the next entry of a directory, starting with "." and "..", or `None` once every entry has
+    /// been returned.
+    pub fn read_directory(&self) -> Result<Option<DirectoryEntry>, VfsError> {
+        if self.dentry.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        let mut position = self.position.lock();
+        let entry = match *position {
+            0 => Some(DirectoryEntry {
+                name: String::from("."),
+                inode: self.dentry.inode.stat()?.inode,
+                file_type: FileType::Directory
+            }),
+            1 => {
+                let parent = self.dentry.parent.as_ref().unwrap_or(&self.dentry);
+                Some(DirectoryEntry {
+                    name: String::from(".."),
+                    inode: parent.inode.stat()?.inode,
+                    file_type: FileType::Directory
+                })
+            },
+            index => {
+                let mut entry = None;
+                self.dentry.inode.read_directory(index as usize - 2, &mut |e| {
+                    entry = Some(e);
+                    false
+                })?;
+                entry
+            }
+        };
+        if entry.is_some() {
+            *position += 1;
+        }
+        Ok(entry)
+    }
+}
+
+/// An entry of a file descriptor table.
+#[derive(Clone)]
+struct FileDescriptor {
+    file: Arc<OpenFile>,
+    /// Closed by `execve()` rather than handed on to the new program
+    close_on_exec: bool
+}
+
+/// The files a process has open, indexed by file descriptor.
+#[derive(Clone)]
+pub struct FileDescriptorTable {
+    files: Vec<Option<FileDescriptor>>
+}
+
+impl FileDescriptorTable {
+    pub const fn new() -> FileDescriptorTable {
+        FileDescriptorTable { files: Vec::new() }
+    }
+
+    /// Adds an open file under the lowest free file descriptor, which is returned. With `close_on_exec`, the
+    /// file descriptor is closed by `close_on_exec()`.
+    pub fn insert(&mut self, file: Arc<OpenFile>, close_on_exec: bool) -> Result<usize, VfsError> {
+        let descriptor = FileDescriptor { file, close_on_exec };
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(descriptor);
+            return Ok(fd);
+        }
+        if self.files.len() == MAX_OPEN_FILES {
+            return Err(VfsError::TooManyOpenFiles);
+        }
+        self.files.push(Some(descriptor));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd: usize) -> Result<&Arc<OpenFile>, VfsError> {
+        self.files
+            .get(fd)
+            .and_then(Option::as_ref)
+            .map(|descriptor| &descriptor.file)
+            .ok_or(VfsError::BadFileDescriptor)
+    }
+
+    pub fn open(&mut self, path: &str, flags: OpenFlags, mode: u16) -> Result<usize, VfsError> {
+        let file = OpenFile::open(path, flags, mode)?;
+        self.insert(file, false)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), VfsError> {
+        let file = self.files.get_mut(fd).ok_or(VfsError::BadFileDescriptor)?;
+        file.take().ok_or(VfsError::BadFileDescriptor)?;
+        self.remove_trailing_free();
+        Ok(())
+    }
+
+    /// Closes every file descriptor that was opened close-on-exec, for `execve()`.
+    pub fn close_on_exec(&mut self) {
+        for descriptor in &mut self.files {
+            if descriptor.as_ref().is_some_and(|d| d.close_on_exec) {
+                *descriptor = None;
+            }
+        }
+        self.remove_trailing_free();
+    }
+
+    fn remove_trailing_free(&mut self) {
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+    }
+
+    pub fn read(&self, fd: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        self.get(fd)?.read(buffer)
+    }
+
+    pub fn write(&self, fd: usize, data: &[u8]) -> Result<usize, VfsError> {
+        self.get(fd)?.write(data)
+    }
+
+    pub fn seek(&self, fd: usize, seek_from: SeekFrom) -> Result<u64, VfsError> {
+        self.get(fd)?.seek(seek_from)
+    }
+
+    pub fn stat(&self, fd: usize) -> Result<Stat, VfsError> {
+        self.get(fd)?.stat()
+    }
+
+    pub fn read_directory(&self, fd: usize) -> Result<Option<DirectoryEntry>, VfsError> {
+        self.get(fd)?.read_directory()
+    }
+}
+
+impl Default for FileDescriptorTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
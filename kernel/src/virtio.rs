@@ -0,0 +1,468 @@
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::memory::{allocate_frames, free_frames, mmio_to_virtual, PhysicalFrames, FRAME_SIZE};
+use crate::pci::{Bar, PciDevice, CAPABILITY_ID_VENDOR_SPECIFIC};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+/// Modern devices have this plus their device type as their PCI device id. Transitional devices have ids
+/// starting from 0x1000 instead, which don't follow the device types.
+pub const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+pub const DEVICE_TYPE_BLOCK: u16 = 2;
+pub const TRANSITIONAL_DEVICE_ID_BLOCK: u16 = 0x1001;
+
+pub const FEATURE_RING_EVENT_INDEX: u64 = 1 << 29;
+/// Set by devices which comply with version 1.0 of the specification or later, which this transport requires
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// Queues have at most this many entries, which makes the descriptor table and both rings fit into a frame.
+const MAX_QUEUE_SIZE: u16 = 128;
+/// How many times the device status is polled while resetting before giving up.
+const POLL_ITERATIONS: u32 = 10000000;
+/// Written as an MSI-X vector to not use MSI-X for a queue or configuration changes
+pub const NO_MSIX_VECTOR: u16 = 0xffff;
+
+const CAP_TYPE_COMMON_CONFIG: u8 = 1;
+const CAP_TYPE_NOTIFY_CONFIG: u8 = 2;
+const CAP_TYPE_DEVICE_CONFIG: u8 = 4;
+
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 7;
+
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_DEVICE_WRITABLE: u16 = 1 << 1;
+/// Set by the device in the used ring when it doesn't need to be notified of new buffers, if the event index
+/// isn't in use
+const USED_RING_NO_NOTIFY: u16 = 1 << 0;
+
+#[repr(u8)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum VirtioError {
+    /// A required capability is missing or points outside of its BAR
+    InvalidCapabilities,
+    /// The device doesn't support a feature the driver requires, or didn't accept the negotiated ones
+    FeaturesRejected,
+    /// The queue doesn't exist, is already in use or has a size of zero
+    QueueUnavailable,
+    OutOfMemory,
+    Timeout
+}
+
+/// The common configuration structure, through which features are negotiated and queues set up.
+#[repr(C)]
+struct CommonConfig {
+    device_feature_select: u32,
+    device_feature: u32,
+    driver_feature_select: u32,
+    driver_feature: u32,
+    msix_config: u16,
+    num_queues: u16,
+    device_status: u8,
+    config_generation: u8,
+    queue_select: u16,
+    queue_size: u16,
+    queue_msix_vector: u16,
+    queue_enable: u16,
+    queue_notify_off: u16,
+    queue_desc: u64,
+    queue_driver: u64,
+    queue_device: u64
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElement {
+    id: u32,
+    length: u32
+}
+
+/// Reads a field of a structure in device memory.
+macro_rules! read_field {
+    ($pointer:expr, $field:ident) => {
+        unsafe { ptr::read_volatile(ptr::addr_of!((*$pointer).$field)) }
+    };
+}
+
+macro_rules! write_field {
+    ($pointer:expr, $field:ident, $value:expr) => {
+        unsafe { ptr::write_volatile(ptr::addr_of_mut!((*$pointer).$field), $value) }
+    };
+}
+
+/// A buffer that is part of a descriptor chain. Device writable buffers have to come after the ones the
+/// device only reads.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainBuffer {
+    pub physical_address: u64,
+    pub length: u32,
+    pub device_writable: bool
+}
+
+/// A split virtqueue, made of a descriptor table, the available ring the driver puts descriptor chains into
+/// for the device, and the used ring the device returns them through once it's done with them. All three
+/// are in a single frame.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    frame: PhysicalFrames,
+    available_offset: usize,
+    used_offset: usize,
+    /// The head of the list of free descriptors, linked through their `next` fields
+    free_head: u16,
+    free_count: u16,
+    /// The available ring index as of the last notification
+    notified_index: u16,
+    /// How far the used ring has been processed
+    last_used_index: u16,
+    event_index: bool,
+    notify_address: u64
+}
+
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    #[inline]
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe { self.frame.as_ptr::<Descriptor>().add(index as usize) }
+    }
+
+    /// The ring fields are `u16`s: flags, index, the ring itself, and an event index after it.
+    #[inline]
+    fn available_ring(&self, index: usize) -> *mut u16 {
+        unsafe {
+            self.frame
+                .as_ptr::<u8>()
+                .add(self.available_offset)
+                .cast::<u16>()
+                .add(index)
+        }
+    }
+
+    /// The flags and index of the used ring are `u16`s, followed by the ring of `UsedElement`s and an event
+    /// index.
+    #[inline]
+    fn used_ring(&self, index: usize) -> *mut u16 {
+        unsafe {
+            self.frame
+                .as_ptr::<u8>()
+                .add(self.used_offset)
+                .cast::<u16>()
+                .add(index)
+        }
+    }
+
+    #[inline]
+    fn used_element(&self, index: u16) -> *mut UsedElement {
+        unsafe {
+            self.frame
+                .as_ptr::<u8>()
+                .add(self.used_offset + 4)
+                .cast::<UsedElement>()
+                .add((index % self.size) as usize)
+        }
+    }
+
+    /// Puts a chain of descriptors for `buffers` into the available ring, returning the index of its head
+    /// descriptor, which is how the device refers to the chain once it's used. The device is only told about
+    /// it by `notify()`.
+    pub fn push(&mut self, buffers: &[ChainBuffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(index);
+            let next = read_field!(descriptor, next);
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESCRIPTOR_DEVICE_WRITABLE;
+            }
+            if i + 1 < buffers.len() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            write_field!(descriptor, address, buffer.physical_address);
+            write_field!(descriptor, length, buffer.length);
+            write_field!(descriptor, flags, flags);
+            if i + 1 < buffers.len() {
+                index = next;
+            }
+            else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let available_index = unsafe { ptr::read_volatile(self.available_ring(1)) };
+        unsafe {
+            ptr::write_volatile(
+                self.available_ring(2 + (available_index % self.size) as usize),
+                head
+            )
+        };
+        // The descriptors have to be in memory before the device can see the new index
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.available_ring(1), available_index.wrapping_add(1)) };
+        Some(head)
+    }
+
+    /// Tells the device about the chains pushed since the last notification, unless it said it doesn't need
+    /// to know.
+    pub fn notify(&mut self) {
+        fence(Ordering::SeqCst);
+        let new_index = unsafe { ptr::read_volatile(self.available_ring(1)) };
+        let old_index = self.notified_index;
+        self.notified_index = new_index;
+        let needed = match self.event_index {
+            true => {
+                let event = unsafe { ptr::read_volatile(self.used_ring(2 + 4 * self.size as usize)) };
+                // Whether the device's event index was passed by this batch of chains
+                new_index.wrapping_sub(event).wrapping_sub(1) < new_index.wrapping_sub(old_index)
+            },
+            false => (unsafe { ptr::read_volatile(self.used_ring(0)) } & USED_RING_NO_NOTIFY) == 0
+        };
+        if needed {
+            unsafe { ptr::write_volatile(self.notify_address as *mut u16, self.index) };
+        }
+    }
+
+    /// Takes the next chain the device is done with off the used ring, freeing its descriptors. Returns the
+    /// index of the chain's head descriptor and how many bytes the device wrote into it.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = unsafe { ptr::read_volatile(self.used_ring(1)) };
+        if used_index == self.last_used_index {
+            return None;
+        }
+        // The element mustn't be read before the index
+        fence(Ordering::SeqCst);
+        let element = unsafe { ptr::read_volatile(self.used_element(self.last_used_index)) };
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+        if self.event_index {
+            // Asks for an interrupt as soon as the next chain is used
+            unsafe { ptr::write_volatile(self.available_ring(2 + self.size as usize), self.last_used_index) };
+        }
+
+        let head = element.id as u16;
+        let mut index = head;
+        self.free_count += 1;
+        while read_field!(self.descriptor(index), flags) & DESCRIPTOR_NEXT != 0 {
+            index = read_field!(self.descriptor(index), next);
+            self.free_count += 1;
+        }
+        write_field!(self.descriptor(index), next, self.free_head);
+        self.free_head = head;
+        Some((head, element.length))
+    }
+}
+
+/// The modern virtio over PCI transport, where the device's configuration structures are in memory BARs
+/// pointed to by vendor specific capabilities. The ISR status structure is left alone, as it's only needed
+/// for legacy interrupts, which aren't supported.
+pub struct VirtioPciTransport {
+    common: *mut CommonConfig,
+    notify_base: u64,
+    notify_offset_multiplier: u32,
+    device_config: *mut u8,
+    features: u64
+}
+
+unsafe impl Send for VirtioPciTransport {}
+unsafe impl Sync for VirtioPciTransport {}
+
+/// Where a configuration structure is, along with the capability it was found in.
+struct ConfigLocation {
+    address: u64,
+    capability_offset: u16
+}
+
+fn find_config(pci_device: &PciDevice, config_type: u8) -> Option<ConfigLocation> {
+    let address = pci_device.address;
+    // The first capability of a type is the preferred one
+    let capability = pci_device.capabilities().find(|c| {
+        c.id == CAPABILITY_ID_VENDOR_SPECIFIC && address.read_config_u8(c.offset + 3) == config_type
+    })?;
+    let bar = address.read_config_u8(capability.offset + 4);
+    let offset = address.read_config_u32(capability.offset + 8) as u64;
+    let length = address.read_config_u32(capability.offset + 12) as u64;
+    let Some(Some(Bar::Memory { address, size, .. })) = pci_device.bars.get(bar as usize)
+    else {
+        return None;
+    };
+    if offset + length > *size {
+        return None;
+    }
+    Some(ConfigLocation {
+        address: mmio_to_virtual(address + offset, length)?,
+        capability_offset: capability.offset
+    })
+}
+
+impl VirtioPciTransport {
+    /// Resets the device and negotiates features, accepting the ones in `supported_features` the device
+    /// offers. `FEATURE_VERSION_1` is always required. Afterwards, the driver sets up the queues and calls
+    /// `finish_initialization()`.
+    pub fn new(pci_device: &PciDevice, supported_features: u64) -> Result<VirtioPciTransport, VirtioError> {
+        let common =
+            find_config(pci_device, CAP_TYPE_COMMON_CONFIG).ok_or(VirtioError::InvalidCapabilities)?;
+        let notify =
+            find_config(pci_device, CAP_TYPE_NOTIFY_CONFIG).ok_or(VirtioError::InvalidCapabilities)?;
+        // Only devices with device specific configuration have one
+        let device_config = find_config(pci_device, CAP_TYPE_DEVICE_CONFIG);
+        pci_device.enable_memory_and_bus_mastering();
+
+        let mut transport = VirtioPciTransport {
+            common: common.address as *mut CommonConfig,
+            notify_base: notify.address,
+            notify_offset_multiplier: pci_device.address.read_config_u32(notify.capability_offset + 16),
+            device_config: device_config.map_or(ptr::null_mut(), |c| c.address as *mut u8),
+            features: 0
+        };
+        let common = transport.common;
+        write_field!(common, device_status, 0);
+        let mut reset = false;
+        for _ in 0..POLL_ITERATIONS {
+            if read_field!(common, device_status) == 0 {
+                reset = true;
+                break;
+            }
+        }
+        if !reset {
+            return Err(VirtioError::Timeout);
+        }
+        transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut device_features = 0;
+        for half in 0..2 {
+            write_field!(common, device_feature_select, half);
+            device_features |= (read_field!(common, device_feature) as u64) << (32 * half);
+        }
+        if device_features & FEATURE_VERSION_1 == 0 {
+            transport.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        let features = device_features & (supported_features | FEATURE_VERSION_1);
+        for half in 0..2 {
+            write_field!(common, driver_feature_select, half);
+            write_field!(common, driver_feature, (features >> (32 * half)) as u32);
+        }
+        transport.add_status(STATUS_FEATURES_OK);
+        if read_field!(common, device_status) & STATUS_FEATURES_OK == 0 {
+            transport.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        transport.features = features;
+        // Configuration change interrupts aren't used
+        write_field!(common, msix_config, NO_MSIX_VECTOR);
+        Ok(transport)
+    }
+
+    fn add_status(&self, status: u8) {
+        let current = read_field!(self.common, device_status);
+        write_field!(self.common, device_status, current | status);
+    }
+
+    /// The features both the device and driver support.
+    #[inline]
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Sets up and enables queue `index` with at most `MAX_QUEUE_SIZE` entries. Its interrupts are delivered
+    /// through the given MSI-X table entry, if any.
+    pub fn setup_queue(&self, index: u16, msix_vector: Option<u16>) -> Result<Virtqueue, VirtioError> {
+        let common = self.common;
+        if index >= read_field!(common, num_queues) {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        write_field!(common, queue_select, index);
+        let device_size = read_field!(common, queue_size);
+        if device_size == 0 || read_field!(common, queue_enable) != 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        // Split queue sizes don't have to be powers of two, but a smaller size has to be
+        let size = device_size.min(MAX_QUEUE_SIZE);
+        let frame = allocate_frames(1).ok_or(VirtioError::OutOfMemory)?;
+
+        // The descriptor table needs 16 byte alignment, the available ring 2 and the used ring 4
+        let available_offset = size as usize * size_of::<Descriptor>();
+        let used_offset = (available_offset + (3 + size as usize) * 2).next_multiple_of(4);
+        debug_assert!(used_offset + 6 + size as usize * size_of::<UsedElement>() <= FRAME_SIZE as usize);
+        let mut queue = Virtqueue {
+            index,
+            size,
+            frame,
+            available_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            notified_index: 0,
+            last_used_index: 0,
+            event_index: self.features & FEATURE_RING_EVENT_INDEX != 0,
+            notify_address: 0
+        };
+        for i in 0..size {
+            write_field!(queue.descriptor(i), next, (i + 1) % size);
+        }
+
+        write_field!(common, queue_size, size);
+        write_field!(common, queue_msix_vector, msix_vector.unwrap_or(NO_MSIX_VECTOR));
+        // The device reports failing to allocate resources for the vector by reading back no vector
+        if msix_vector.is_some() && read_field!(common, queue_msix_vector) == NO_MSIX_VECTOR {
+            free_frames(queue.frame);
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let physical_address = queue.frame.physical_address;
+        write_field!(common, queue_desc, physical_address);
+        write_field!(common, queue_driver, physical_address + available_offset as u64);
+        write_field!(common, queue_device, physical_address + used_offset as u64);
+        let notify_offset = read_field!(common, queue_notify_off) as u64;
+        queue.notify_address = self.notify_base + notify_offset * self.notify_offset_multiplier as u64;
+        write_field!(common, queue_enable, 1);
+        Ok(queue)
+    }
+
+    /// Tells the device that the driver is ready, after which it starts processing its queues.
+    pub fn finish_initialization(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Marks the device as unusable, e.g. when setting up its queues failed.
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    /// Reads a value from the device specific configuration. The device may change the configuration while
+    /// it's being read, so values spanning several fields should be read with
+    /// `read_device_config_consistent`.
+    pub fn read_device_config<T: Copy>(&self, offset: usize) -> Option<T> {
+        if self.device_config.is_null() {
+            return None;
+        }
+        Some(unsafe { ptr::read_volatile(self.device_config.add(offset) as *const T) })
+    }
+
+    /// Reads a value from the device specific configuration, retrying until the configuration didn't change
+    /// while reading.
+    pub fn read_device_config_consistent<T: Copy>(&self, offset: usize) -> Option<T> {
+        loop {
+            let generation = read_field!(self.common, config_generation);
+            let value = self.read_device_config(offset)?;
+            if read_field!(self.common, config_generation) == generation {
+                return Some(value);
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arrayvec::ArrayString;
+use spin::{Mutex, Once};
+
+use crate::apic::local_apic_id;
+use crate::block::{self, BlockDevice, BlockError, BlockOperation, RequestQueue, MAX_BLOCK_SIZE};
+use crate::interrupts_general::{
+    are_interrupts_enabled, disable_interrupts, enable_interrupts, enable_interrupts_and_halt
+};
+use crate::memory::{allocate_frames, PhysicalFrames, FRAME_SIZE};
+use crate::msi::MsixTable;
+use crate::pci::{PciDevice, PciDeviceId, PciDriver, ProbeError};
+use crate::virtio::{self, ChainBuffer, VirtioPciTransport, Virtqueue, FEATURE_RING_EVENT_INDEX};
+
+const MAX_DISKS: usize = 8;
+/// Transfers are bounced through this many frames of DMA memory per disk, and split into requests of at most
+/// this size.
+const TRANSFER_BUFFER_FRAMES: u64 = 16;
+const TRANSFER_BUFFER_SIZE: usize = (TRANSFER_BUFFER_FRAMES * FRAME_SIZE) as usize;
+/// How many times the used ring is polled before a request is considered to have timed out. When waiting
+/// for an interrupt, every iteration halts instead of spinning.
+const POLL_ITERATIONS: u32 = 10000000;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_BLOCK_SIZE: u64 = 1 << 6;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+/// Byte offsets into the device configuration
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_BLOCK_SIZE: usize = 20;
+
+const REQUEST_TYPE_IN: u32 = 0;
+const REQUEST_TYPE_OUT: u32 = 1;
+const REQUEST_TYPE_FLUSH: u32 = 4;
+const REQUEST_STATUS_OK: u8 = 0;
+
+/// Sector numbers in requests and the capacity are always in units of 512 bytes, whatever the block size.
+const SECTOR_SIZE: u64 = 512;
+
+/// The part of a request the device reads before the data. The status byte the device writes comes after
+/// the data.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64
+}
+
+/// The request queue of a disk and the memory requests are made of. Only one request is in progress at a
+/// time.
+struct RequestState {
+    queue: Virtqueue,
+    /// The request header at the start, followed by the status byte
+    header: PhysicalFrames,
+    buffer: PhysicalFrames
+}
+
+unsafe impl Send for RequestState {}
+
+impl RequestState {
+    const STATUS_OFFSET: u64 = size_of::<RequestHeader>() as u64;
+
+    /// Sends a request made of a header, `length` bytes of data in the buffer and a status byte, and waits
+    /// for the device to complete it.
+    fn run(
+        &mut self,
+        request_type: u32,
+        sector: u64,
+        length: usize,
+        interrupt_apic_id: Option<u32>
+    ) -> Result<(), BlockError> {
+        let header = RequestHeader {
+            request_type,
+            reserved: 0,
+            sector
+        };
+        let status = unsafe { self.header.as_ptr::<u8>().add(Self::STATUS_OFFSET as usize) };
+        unsafe {
+            ptr::write_volatile(self.header.as_ptr::<RequestHeader>(), header);
+            ptr::write_volatile(status, u8::MAX);
+        }
+        let header_buffer = ChainBuffer {
+            physical_address: self.header.physical_address,
+            length: size_of::<RequestHeader>() as u32,
+            device_writable: false
+        };
+        let data_buffer = ChainBuffer {
+            physical_address: self.buffer.physical_address,
+            length: length as u32,
+            device_writable: request_type == REQUEST_TYPE_IN
+        };
+        let status_buffer = ChainBuffer {
+            physical_address: self.header.physical_address + Self::STATUS_OFFSET,
+            length: 1,
+            device_writable: true
+        };
+        let pushed = match length {
+            0 => self.queue.push(&[header_buffer, status_buffer]),
+            _ => self.queue.push(&[header_buffer, data_buffer, status_buffer])
+        };
+        let head = pushed.ok_or(BlockError::DeviceError)?;
+        self.queue.notify();
+
+        let (used_head, _) = self.wait_for_used(interrupt_apic_id)?;
+        if used_head != head || unsafe { ptr::read_volatile(status) } != REQUEST_STATUS_OK {
+            return Err(BlockError::DeviceError);
+        }
+        Ok(())
+    }
+
+    /// Waits for the device to return the request. If the queue's interrupts go to the current CPU, the CPU
+    /// is halted in between checks, which it is woken up from by the interrupt.
+    fn wait_for_used(&mut self, interrupt_apic_id: Option<u32>) -> Result<(u16, u32), BlockError> {
+        let halt = interrupt_apic_id == Some(local_apic_id()) && are_interrupts_enabled();
+        for _ in 0..POLL_ITERATIONS {
+            if !halt {
+                if let Some(used) = self.queue.pop_used() {
+                    return Ok(used);
+                }
+                continue;
+            }
+            // Checking with interrupts disabled makes sure the interrupt can't arrive before halting
+            disable_interrupts();
+            if let Some(used) = self.queue.pop_used() {
+                enable_interrupts();
+                return Ok(used);
+            }
+            enable_interrupts_and_halt();
+        }
+        Err(BlockError::Timeout)
+    }
+}
+
+pub struct VirtioBlockDevice {
+    name: ArrayString<16>,
+    state: Mutex<RequestState>,
+    block_size: u32,
+    block_count: u64,
+    read_only: bool,
+    /// Whether the device has a write cache, which needs flushing
+    flush: bool,
+    /// The local APIC id of the CPU the queue's interrupts go to, if it has interrupts
+    interrupt_apic_id: Option<u32>,
+    request_queue: RequestQueue
+}
+
+impl VirtioBlockDevice {
+    /// Reads or writes `length` bytes at `buffer` starting from `first_block`, in requests of at most
+    /// `TRANSFER_BUFFER_SIZE` bytes.
+    fn transfer(
+        &self,
+        operation: BlockOperation,
+        first_block: u64,
+        buffer: *mut u8,
+        length: usize
+    ) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        let sectors_per_block = self.block_size as u64 / SECTOR_SIZE;
+        let mut done = 0;
+        while done < length {
+            let chunk_length = (length - done).min(TRANSFER_BUFFER_SIZE);
+            let sector = (first_block + (done / self.block_size as usize) as u64) * sectors_per_block;
+            let buffer_chunk = unsafe { buffer.add(done) };
+            let request_type = match operation {
+                BlockOperation::Read => REQUEST_TYPE_IN,
+                BlockOperation::Write => {
+                    unsafe { ptr::copy_nonoverlapping(buffer_chunk, state.buffer.as_ptr(), chunk_length) };
+                    REQUEST_TYPE_OUT
+                }
+            };
+            state.run(request_type, sector, chunk_length, self.interrupt_apic_id)?;
+            if operation == BlockOperation::Read {
+                unsafe { ptr::copy_nonoverlapping(state.buffer.as_ptr(), buffer_chunk, chunk_length) };
+            }
+            done += chunk_length;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlockDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, first_block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        self.transfer(
+            BlockOperation::Read,
+            first_block,
+            buffer.as_mut_ptr(),
+            buffer.len()
+        )
+    }
+
+    fn write_blocks(&self, first_block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::validate_request(self, first_block, buffer.len())?;
+        if self.read_only {
+            return Err(BlockError::WriteProtected);
+        }
+        // Only read from, despite the pointer being mutable
+        self.transfer(
+            BlockOperation::Write,
+            first_block,
+            buffer.as_ptr() as *mut u8,
+            buffer.len()
+        )
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.flush {
+            return Ok(());
+        }
+        self.state
+            .lock()
+            .run(REQUEST_TYPE_FLUSH, 0, 0, self.interrupt_apic_id)
+    }
+
+    fn request_queue(&self) -> &RequestQueue {
+        &self.request_queue
+    }
+}
+
+static DISKS: [Once<VirtioBlockDevice>; MAX_DISKS] = [const { Once::new() }; MAX_DISKS];
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Does nothing, as the interrupt only wakes up whoever is waiting for a request, which checks the used ring
+/// by themselves.
+fn handle_interrupt(_vector: u8, _context: u64) {}
+
+/// Sets up the request queue, with its interrupts going to the current CPU through MSI-X if possible.
+/// Returns the queue and the local APIC id its interrupts go to.
+fn setup_queue(
+    pci_device: &'static PciDevice,
+    transport: &VirtioPciTransport
+) -> Result<(Virtqueue, Option<u32>), BlockError> {
+    let apic_id = local_apic_id();
+    // The table doesn't need to be kept around, as MSI-X is never disabled again
+    let interrupts = MsixTable::enable(pci_device)
+        .ok()
+        .and_then(|mut table| table.set_handler(0, apic_id, handle_interrupt, 0).ok());
+    let queue = match interrupts {
+        Some(_) => transport.setup_queue(0, Some(0)),
+        None => transport.setup_queue(0, None)
+    };
+    let queue = queue.map_err(|_| BlockError::DeviceError)?;
+    Ok((queue, interrupts.map(|_| apic_id)))
+}
+
+fn probe(pci_device: &'static PciDevice) -> Result<(), ProbeError> {
+    let index = DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+    let slot = DISKS.get(index).ok_or(ProbeError::Unsupported)?;
+    let supported_features =
+        FEATURE_READ_ONLY | FEATURE_BLOCK_SIZE | FEATURE_FLUSH | FEATURE_RING_EVENT_INDEX;
+    let transport = VirtioPciTransport::new(pci_device, supported_features)
+        .map_err(|_| ProbeError::InitializationFailed)?;
+    let features = transport.features();
+    let capacity: u64 = transport
+        .read_device_config_consistent(CONFIG_CAPACITY)
+        .ok_or(ProbeError::InitializationFailed)?;
+    let block_size = match features & FEATURE_BLOCK_SIZE {
+        0 => SECTOR_SIZE as u32,
+        _ => transport
+            .read_device_config(CONFIG_BLOCK_SIZE)
+            .ok_or(ProbeError::InitializationFailed)?
+    };
+    if block_size as u64 % SECTOR_SIZE != 0 || block_size as usize > MAX_BLOCK_SIZE {
+        transport.fail();
+        return Err(ProbeError::Unsupported);
+    }
+
+    let (queue, interrupt_apic_id) = match setup_queue(pci_device, &transport) {
+        Ok(queue) => queue,
+        Err(_) => {
+            transport.fail();
+            return Err(ProbeError::InitializationFailed);
+        }
+    };
+    let (Some(header), Some(buffer)) = (allocate_frames(1), allocate_frames(TRANSFER_BUFFER_FRAMES))
+    else {
+        transport.fail();
+        return Err(ProbeError::InitializationFailed);
+    };
+    transport.finish_initialization();
+
+    let mut name = ArrayString::new();
+    let _ = write!(name, "virtio{}", index);
+    let disk = slot.call_once(|| VirtioBlockDevice {
+        name,
+        state: Mutex::new(RequestState {
+            queue,
+            header,
+            buffer
+        }),
+        block_size,
+        block_count: capacity * SECTOR_SIZE / block_size as u64,
+        read_only: features & FEATURE_READ_ONLY != 0,
+        flush: features & FEATURE_FLUSH != 0,
+        interrupt_apic_id,
+        request_queue: RequestQueue::new()
+    });
+    block::register_device(disk).map_err(|_| ProbeError::InitializationFailed)
+}
+
+pub static VIRTIO_BLOCK_DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    ids: &[
+        PciDeviceId::device(virtio::VENDOR_ID, virtio::TRANSITIONAL_DEVICE_ID_BLOCK),
+        PciDeviceId::device(
+            virtio::VENDOR_ID,
+            virtio::MODERN_DEVICE_ID_BASE + virtio::DEVICE_TYPE_BLOCK
+        )
+    ],
+    probe
+};
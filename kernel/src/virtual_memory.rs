@@ -0,0 +1,643 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::slice;
+
+use bitflags::bitflags;
+
+use crate::elf::USER_STACK_TOP;
+use crate::memory::{physical_to_virtual_ptr, release_frame, FRAME_SIZE};
+use crate::paging::{self, AddressSpace, PageFlags, PagingError};
+use crate::process;
+use crate::syscall::{Errno, UserContext};
+use crate::user_memory::USER_SPACE_END;
+use crate::vfs::{FileType, Inode, OpenFlags};
+
+/// How far a stack region can grow down from its top, like the default stack size limit on Linux
+pub const MAX_STACK_SIZE: u64 = 8 * 1024 * 1024;
+/// `mmap()` puts mappings below this if it gets to choose, from the top down, which leaves room for the stack
+/// to grow
+pub const MMAP_TOP: u64 = USER_STACK_TOP - MAX_STACK_SIZE - FRAME_SIZE;
+/// Nothing is mapped below this, so that null pointers with an offset still fault
+const MMAP_BOTTOM: u64 = 16 * FRAME_SIZE;
+
+// Flags of `mmap()`
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+/// Like `MAP_SHARED`, but fails on unknown flags, which this kernel always does
+const MAP_SHARED_VALIDATE: u64 = 0x03;
+const MAP_TYPE: u64 = 0x0f;
+/// Maps at exactly the address given, replacing whatever is mapped there
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_GROWSDOWN: u64 = 0x100;
+/// Only a hint, as nothing is reserved for mappings anyway
+const MAP_NORESERVE: u64 = 0x4000;
+/// Maps every page right away instead of on first touch
+const MAP_POPULATE: u64 = 0x8000;
+/// A hint that the mapping is a stack, which makes no difference
+const MAP_STACK: u64 = 0x20000;
+/// Like `MAP_FIXED`, but fails if anything is mapped there already
+const MAP_FIXED_NOREPLACE: u64 = 0x100000;
+const MAP_KNOWN_FLAGS: u64 = MAP_TYPE
+    | MAP_FIXED
+    | MAP_ANONYMOUS
+    | MAP_GROWSDOWN
+    | MAP_NORESERVE
+    | MAP_POPULATE
+    | MAP_STACK
+    | MAP_FIXED_NOREPLACE;
+
+bitflags! {
+    /// What a region's pages may be used for. Pages can't be made write-only on x86, so writable or
+    /// executable pages are always readable as well.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct Protection: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+/// The kind of memory access that caused a page fault.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    /// An instruction fetch, which is only reported as such if no-execute pages are supported
+    Execute
+}
+
+/// Where the contents of a region's pages come from when they're first touched.
+#[derive(Clone)]
+pub enum Backing {
+    /// Zero-filled memory
+    Anonymous,
+    /// The first `length` bytes of the region are read from the file starting at `offset`, and the rest is
+    /// zero-filled, which is how the `.bss` at the end of a program's data segment is set up. Pages past the
+    /// end of the file are zero-filled as well.
+    File {
+        inode: Arc<dyn Inode>,
+        offset: u64,
+        length: u64
+    },
+    /// Memory of a device, such as a framebuffer, or the pages of a file kept in memory, which are mapped
+    /// directly instead of being copied, see `Inode::physical_page()`
+    Device { inode: Arc<dyn Inode>, offset: u64 }
+}
+
+impl Backing {
+    /// The backing of the part of a region that starts `distance` bytes into it.
+    fn advanced(&self, distance: u64) -> Backing {
+        match self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File {
+                inode,
+                offset,
+                length
+            } => Backing::File {
+                inode: inode.clone(),
+                offset: offset + distance,
+                length: length.saturating_sub(distance)
+            },
+            Backing::Device { inode, offset } => Backing::Device {
+                inode: inode.clone(),
+                offset: offset + distance
+            }
+        }
+    }
+}
+
+/// A page aligned range of virtual memory of a process with the same protection and backing, whose pages are
+/// only mapped once they're accessed.
+#[derive(Clone)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub protection: Protection,
+    pub backing: Backing,
+    /// A stack, which is extended downwards to a page just below it when that page is touched
+    pub grows_down: bool,
+    /// Whether the pages are shared with the file or with forked processes, rather than private copies.
+    /// Shared regions are mapped in whole when they're created, so that forked processes share every page.
+    /// Changes to a shared file mapping are written back when its pages are unmapped, as there is no page
+    /// cache to make them visible to other processes mapping the file right away.
+    pub shared: bool,
+    /// Whether `mprotect()` can make the region writable, which a shared mapping of a file that was opened
+    /// read-only can't be
+    pub may_write: bool
+}
+
+impl Region {
+    /// The flags the region's pages are mapped with. Pages of regions that can't be accessed at all stay
+    /// mapped when they have been touched before, but only for the kernel.
+    fn page_flags(&self) -> PageFlags {
+        let mut flags = match self.protection.is_empty() {
+            true => PageFlags::empty(),
+            false => PageFlags::USER
+        };
+        if self.protection.contains(Protection::WRITE) {
+            flags |= PageFlags::WRITABLE;
+        }
+        paging::set_no_execute(flags, !self.protection.contains(Protection::EXECUTE))
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => !self.protection.is_empty(),
+            Access::Write => self.protection.contains(Protection::WRITE),
+            Access::Execute => self.protection.contains(Protection::EXECUTE)
+        }
+    }
+}
+
+/// The user space memory of a process: the page tables, and the regions that describe what the pages of the
+/// lower half should be, which the page fault handler maps in on demand.
+pub struct ProcessMemory {
+    address_space: AddressSpace,
+    /// By start address. Regions never overlap.
+    regions: BTreeMap<u64, Region>
+}
+
+impl ProcessMemory {
+    pub fn new() -> Result<ProcessMemory, PagingError> {
+        Ok(ProcessMemory {
+            address_space: AddressSpace::new()?,
+            regions: BTreeMap::new()
+        })
+    }
+
+    #[inline]
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    #[inline]
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    /// Adds a region, which must be page aligned, in user space, and not overlap any other region.
+    pub fn add_region(&mut self, region: Region) -> Result<(), PagingError> {
+        if region.start % FRAME_SIZE != 0
+            || region.end % FRAME_SIZE != 0
+            || region.start >= region.end
+            || region.start == 0
+            || region.end > USER_SPACE_END
+        {
+            return Err(PagingError::InvalidAddress);
+        }
+        let overlaps_previous = self
+            .regions
+            .range(..region.end)
+            .next_back()
+            .is_some_and(|(_, previous)| previous.end > region.start);
+        if overlaps_previous {
+            return Err(PagingError::AlreadyMapped);
+        }
+        self.regions.insert(region.start, region);
+        Ok(())
+    }
+
+    /// The region containing `address`.
+    pub fn region(&self, address: u64) -> Option<&Region> {
+        self.regions
+            .range(..=address)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| address < region.end)
+    }
+
+    /// The regions that overlap the range from `start` to `end`.
+    pub fn regions_in(&self, start: u64, end: u64) -> impl Iterator<Item = &Region> {
+        self.regions
+            .range(..end)
+            .map(|(_, region)| region)
+            .filter(move |region| region.end > start)
+    }
+
+    /// Whether no region overlaps the range from `start` to `end`.
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        self.regions_in(start, end).next().is_none()
+    }
+
+    /// Finds the highest free range of `length` bytes below `MMAP_TOP` for `mmap()` to put a mapping.
+    pub fn find_free_range(&self, length: u64) -> Option<u64> {
+        let mut end = MMAP_TOP;
+        for region in self.regions.range(..MMAP_TOP).map(|(_, region)| region).rev() {
+            if region.end <= end && end - region.end >= length {
+                break;
+            }
+            end = end.min(region.start);
+        }
+        end.checked_sub(length).filter(|&start| start >= MMAP_BOTTOM)
+    }
+
+    /// Creates a copy for a forked process, which shares the frames copy-on-write, except for those of
+    /// shared regions, which stay writable for both.
+    pub fn fork(&mut self) -> Result<ProcessMemory, PagingError> {
+        let mut copy = ProcessMemory {
+            address_space: self.address_space.fork()?,
+            regions: self.regions.clone()
+        };
+        for region in self.regions.values().filter(|region| region.shared) {
+            keep_shared(&mut self.address_space, region)?;
+            keep_shared(&mut copy.address_space, region)?;
+        }
+        Ok(copy)
+    }
+
+    /// Removes the regions and pages from `start` to `end`, splitting regions that are partly in the range.
+    pub fn unmap_range(&mut self, start: u64, end: u64) {
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<u64> = self.regions.range(start..end).map(|(&start, _)| start).collect();
+        for start in starts {
+            let Some(region) = self.regions.remove(&start)
+            else {
+                continue;
+            };
+            for page in (region.start..region.end).step_by(FRAME_SIZE as usize) {
+                let Ok((frame, flags)) = self.address_space.unmap(page)
+                else {
+                    continue;
+                };
+                if flags.contains(PageFlags::NOT_OWNED) {
+                    continue;
+                }
+                if flags.contains(PageFlags::DIRTY) {
+                    write_back(&region, page, frame);
+                }
+                release_frame(frame);
+            }
+        }
+    }
+
+    /// Changes the protection of the pages from `start` to `end`, which must all be in regions, splitting
+    /// regions that are partly in the range. Copy-on-write pages stay read-only until they're written to.
+    pub fn protect_range(&mut self, start: u64, end: u64, protection: Protection) -> Result<(), PagingError> {
+        let mut address = start;
+        while address < end {
+            address = self.region(address).ok_or(PagingError::NotMapped)?.end;
+        }
+        self.split_at(start);
+        self.split_at(end);
+        let kept_flags =
+            PageFlags::NOT_OWNED | PageFlags::COPY_ON_WRITE | PageFlags::ACCESSED | PageFlags::DIRTY;
+        for region in self.regions.range_mut(start..end).map(|(_, region)| region) {
+            region.protection = protection;
+            let page_flags = region.page_flags();
+            for page in (region.start..region.end).step_by(FRAME_SIZE as usize) {
+                let Some((_, old_flags)) = self.address_space.translate(page)
+                else {
+                    continue;
+                };
+                let mut flags = page_flags | (old_flags & kept_flags);
+                if flags.contains(PageFlags::COPY_ON_WRITE) {
+                    flags -= PageFlags::WRITABLE;
+                }
+                self.address_space.set_flags(page, flags)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the region containing `address` in two at `address`, which must be page aligned.
+    fn split_at(&mut self, address: u64) {
+        let Some(start) = self
+            .region(address)
+            .map(|region| region.start)
+            .filter(|&start| start != address)
+        else {
+            return;
+        };
+        let Some(lower) = self.regions.get_mut(&start)
+        else {
+            return;
+        };
+        let mut upper = lower.clone();
+        lower.end = address;
+        upper.start = address;
+        upper.backing = lower.backing.advanced(address - start);
+        self.regions.insert(address, upper);
+    }
+
+    /// Resolves a page fault at `address` by mapping the page the way its region describes, copying a
+    /// copy-on-write page that's written to, or growing a stack. Returns false if the access isn't allowed,
+    /// which is a genuine segmentation fault, or if there isn't enough memory to resolve it.
+    pub fn handle_page_fault(&mut self, address: u64, access: Access) -> bool {
+        let page = address - address % FRAME_SIZE;
+        let Some(start) = self.region_start_or_grow(page)
+        else {
+            return false;
+        };
+        if !self.regions[&start].allows(access) {
+            return false;
+        }
+        match self.address_space.translate(page) {
+            Some((_, flags)) => match access {
+                Access::Write if !flags.contains(PageFlags::WRITABLE) => {
+                    self.address_space.copy_on_write(page) == Ok(true)
+                },
+                Access::Execute if flags.contains(PageFlags::NO_EXECUTE) => false,
+                // The page already allows the access, e.g. because it was mapped after the fault
+                _ => true
+            },
+            None => self.populate(start, page)
+        }
+    }
+
+    /// Maps every page from `start` to `end` that isn't mapped yet, as if they had been touched.
+    pub fn populate_range(&mut self, start: u64, end: u64) -> Result<(), PagingError> {
+        let first_page = start - start % FRAME_SIZE;
+        for page in (first_page..end).step_by(FRAME_SIZE as usize) {
+            if self.address_space.translate(page).is_some() {
+                continue;
+            }
+            let region_start = self.region(page).ok_or(PagingError::NotMapped)?.start;
+            if !self.populate(region_start, page) {
+                return Err(PagingError::OutOfMemory);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `address` regardless of the protection, mapping the pages first if they haven't been
+    /// touched yet. This is for setting up a program, e.g. its stack.
+    pub fn write(&mut self, address: u64, data: &[u8]) -> Result<(), PagingError> {
+        self.populate_range(address, address + data.len() as u64)?;
+        self.address_space.write(address, data)
+    }
+
+    /// Returns the start of the region containing `page`, extending a stack region down to the page if it's
+    /// just below one. The stack can't grow past `MAX_STACK_SIZE`, or closer than a page to the region below.
+    fn region_start_or_grow(&mut self, page: u64) -> Option<u64> {
+        if let Some(region) = self.region(page) {
+            return Some(region.start);
+        }
+        let (&start, stack) = self.regions.range(page..).next()?;
+        if !stack.grows_down || stack.end - page > MAX_STACK_SIZE {
+            return None;
+        }
+        let below = self.regions.range(..page).next_back();
+        if below.is_some_and(|(_, below)| below.end + FRAME_SIZE > page) {
+            return None;
+        }
+        let mut stack = self.regions.remove(&start)?;
+        stack.start = page;
+        self.regions.insert(page, stack);
+        Some(page)
+    }
+
+    /// Maps a new frame for a page of the region at `start`, filling it from the file if it's file-backed, or
+    /// maps the device's memory for the page.
+    fn populate(&mut self, start: u64, page: u64) -> bool {
+        let region = &self.regions[&start];
+        if let Backing::Device { inode, offset } = &region.backing {
+            let Ok(physical_address) = inode.physical_page(offset + (page - region.start))
+            else {
+                return false;
+            };
+            let flags = region.page_flags() | PageFlags::NOT_OWNED;
+            return self.address_space.map(page, physical_address, flags).is_ok();
+        }
+        let Ok(frame) = self.address_space.map_new(page, region.page_flags())
+        else {
+            return false;
+        };
+        let Backing::File {
+            inode,
+            offset,
+            length
+        } = &region.backing
+        else {
+            return true;
+        };
+        let position = page - region.start;
+        if position >= *length {
+            return true;
+        }
+        let count = (*length - position).min(FRAME_SIZE) as usize;
+        let buffer = unsafe { slice::from_raw_parts_mut(physical_to_virtual_ptr::<u8>(frame), count) };
+        let mut read = 0;
+        // Past the end of the file, the page stays zero-filled
+        while read < count {
+            match inode.read_at(offset + position + read as u64, &mut buffer[read..]) {
+                Ok(0) => break,
+                Ok(length) => read += length,
+                Err(_) => {
+                    let _ = self.address_space.unmap(page);
+                    release_frame(frame);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Drop for ProcessMemory {
+    /// Writes back the changes to shared file mappings. The pages themselves go with the address space.
+    fn drop(&mut self) {
+        for region in self.regions.values().filter(|region| region.shared) {
+            for page in (region.start..region.end).step_by(FRAME_SIZE as usize) {
+                if let Some((frame, flags)) = self.address_space.translate(page) {
+                    if flags.contains(PageFlags::DIRTY) && !flags.contains(PageFlags::NOT_OWNED) {
+                        write_back(region, page, frame);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Turns the copy-on-write pages of a shared region, as left by `AddressSpace::fork()`, back into writable
+/// ones, so that writes go to the frame the forked processes share.
+fn keep_shared(address_space: &mut AddressSpace, region: &Region) -> Result<(), PagingError> {
+    for page in (region.start..region.end).step_by(FRAME_SIZE as usize) {
+        if let Some((_, flags)) = address_space.translate(page) {
+            if flags.contains(PageFlags::COPY_ON_WRITE) {
+                address_space.set_flags(page, (flags - PageFlags::COPY_ON_WRITE) | PageFlags::WRITABLE)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes a changed page of a shared file mapping back to the file, without making the file any longer.
+fn write_back(region: &Region, page: u64, frame: u64) {
+    let Backing::File { inode, offset, .. } = &region.backing
+    else {
+        return;
+    };
+    if !region.shared {
+        return;
+    }
+    let position = offset + (page - region.start);
+    let Ok(stat) = inode.stat()
+    else {
+        return;
+    };
+    if position >= stat.size {
+        return;
+    }
+    let length = (stat.size - position).min(FRAME_SIZE) as usize;
+    let data = unsafe { slice::from_raw_parts(physical_to_virtual_ptr::<u8>(frame), length) };
+    // Like a failed write-back on Linux, there's no one left to report the error to
+    let _ = inode.write_at(position, data);
+}
+
+/// The pages needed for `length` bytes, as a length in bytes, or `None` if that's more than user space has.
+fn page_length(length: u64) -> Option<u64> {
+    length
+        .checked_next_multiple_of(FRAME_SIZE)
+        .filter(|&length| length <= USER_SPACE_END)
+}
+
+/// Maps anonymous memory, or a file or device opened as `fd` from `offset` on, and returns where. Without
+/// `MAP_FIXED`, the address is only a hint, and a free range is picked if it's taken.
+pub fn sys_mmap(
+    _context: &mut UserContext,
+    [address, length, protection, flags, fd, offset]: [u64; 6]
+) -> Result<u64, Errno> {
+    let protection = Protection::from_bits(protection as u32).ok_or(Errno::InvalidArgument)?;
+    let shared = match flags & MAP_TYPE {
+        MAP_SHARED | MAP_SHARED_VALIDATE => true,
+        MAP_PRIVATE => false,
+        _ => return Err(Errno::InvalidArgument)
+    };
+    if flags & !MAP_KNOWN_FLAGS != 0 && flags & MAP_TYPE == MAP_SHARED_VALIDATE {
+        return Err(Errno::NotSupported);
+    }
+    if length == 0 || offset % FRAME_SIZE != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let length = page_length(length).ok_or(Errno::OutOfMemory)?;
+    // Every page of the mapping has to have an offset in the file, so that faults can work it out
+    if offset.checked_add(length).is_none() {
+        return Err(Errno::InvalidArgument);
+    }
+    let process = process::current();
+
+    let mut may_write = true;
+    let backing = match flags & MAP_ANONYMOUS {
+        0 => {
+            let file = process.files().get(fd as usize)?.clone();
+            if !file.flags().contains(OpenFlags::READ) {
+                return Err(Errno::AccessDenied);
+            }
+            may_write = !shared || file.flags().contains(OpenFlags::WRITE);
+            if protection.contains(Protection::WRITE) && !may_write {
+                return Err(Errno::AccessDenied);
+            }
+            let inode = file.dentry().inode().clone();
+            match file.stat()?.file_type {
+                // Shared memory: processes mapping the same file in memory all see each other's writes
+                FileType::Regular if shared && inode.physical_page(offset).is_ok() => {
+                    Backing::Device { inode, offset }
+                },
+                FileType::Regular => Backing::File {
+                    inode,
+                    offset,
+                    length
+                },
+                FileType::CharacterDevice | FileType::BlockDevice if inode.physical_page(offset).is_ok() => {
+                    Backing::Device { inode, offset }
+                },
+                _ => return Err(Errno::NoDevice)
+            }
+        },
+        _ => Backing::Anonymous
+    };
+    let is_device = matches!(backing, Backing::Device { .. });
+
+    let mut memory = process.memory();
+    let memory = memory.as_mut().expect("Running process has no memory");
+    let fixed = flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0;
+    let end = address.checked_add(length).filter(|&end| end <= USER_SPACE_END);
+    let start = match end {
+        Some(end) if fixed => {
+            if address % FRAME_SIZE != 0 || address < MMAP_BOTTOM {
+                return Err(Errno::InvalidArgument);
+            }
+            if flags & MAP_FIXED_NOREPLACE != 0 && !memory.is_free(address, end) {
+                return Err(Errno::Exists);
+            }
+            memory.unmap_range(address, end);
+            address
+        },
+        None if fixed => return Err(Errno::InvalidArgument),
+        Some(end) if address % FRAME_SIZE == 0 && address >= MMAP_BOTTOM && memory.is_free(address, end) => {
+            address
+        },
+        _ => memory.find_free_range(length).ok_or(Errno::OutOfMemory)?
+    };
+    memory
+        .add_region(Region {
+            start,
+            end: start + length,
+            protection,
+            backing,
+            grows_down: flags & MAP_GROWSDOWN != 0,
+            shared,
+            may_write
+        })
+        .map_err(|_| Errno::InvalidArgument)?;
+    if (shared && !is_device) || flags & MAP_POPULATE != 0 {
+        if let Err(e) = memory.populate_range(start, start + length) {
+            memory.unmap_range(start, start + length);
+            return Err(e.into());
+        }
+    }
+    Ok(start)
+}
+
+/// Removes the mappings from `address` on for `length` bytes, which needn't all be mapped.
+pub fn sys_munmap(_context: &mut UserContext, [address, length, ..]: [u64; 6]) -> Result<u64, Errno> {
+    let length = page_length(length).ok_or(Errno::InvalidArgument)?;
+    let end = address
+        .checked_add(length)
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(Errno::InvalidArgument)?;
+    if address % FRAME_SIZE != 0 || length == 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let process = process::current();
+    let mut memory = process.memory();
+    memory
+        .as_mut()
+        .expect("Running process has no memory")
+        .unmap_range(address, end);
+    Ok(0)
+}
+
+/// Changes the protection of the mappings from `address` on for `length` bytes, which all have to be mapped.
+pub fn sys_mprotect(
+    _context: &mut UserContext,
+    [address, length, protection, ..]: [u64; 6]
+) -> Result<u64, Errno> {
+    let protection = Protection::from_bits(protection as u32).ok_or(Errno::InvalidArgument)?;
+    let length = page_length(length).ok_or(Errno::OutOfMemory)?;
+    let end = address
+        .checked_add(length)
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(Errno::OutOfMemory)?;
+    if address % FRAME_SIZE != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let process = process::current();
+    let mut memory = process.memory();
+    let memory = memory.as_mut().expect("Running process has no memory");
+    if protection.contains(Protection::WRITE)
+        && memory.regions_in(address, end).any(|region| !region.may_write)
+    {
+        return Err(Errno::AccessDenied);
+    }
+    memory
+        .protect_range(address, end, protection)
+        .map_err(|e| match e {
+            PagingError::NotMapped => Errno::OutOfMemory,
+            e => e.into()
+        })?;
+    Ok(0)
+}
@@ -0,0 +1,1308 @@
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use arrayvec::ArrayVec;
+use spin::Mutex;
+
+use crate::apic::local_apic_id;
+use crate::interrupts_general::without_interrupts;
+use crate::memory::{allocate_frames, allocate_frames_aligned, mmio_to_virtual, PhysicalFrames, FRAME_SIZE};
+use crate::msi::{Msi, MsixTable};
+use crate::pci::{Bar, PciDevice, PciDeviceId, PciDriver, ProbeError};
+use crate::usb::{
+    self, ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, EndpointTransferType,
+    InterruptTransferHandler, SetupPacket, UsbDevice, UsbError, UsbSpeed, DESCRIPTOR_TYPE_CONFIGURATION,
+    DESCRIPTOR_TYPE_DEVICE, MAX_CONFIGURATION_DESCRIPTOR_SIZE
+};
+
+const MAX_CONTROLLERS: usize = 4;
+/// The number of device slots enabled on each controller, which limits how many devices can be attached to
+/// it.
+const MAX_SLOTS: usize = 32;
+/// Every ring is a single page of TRBs, the last of which links back to the start.
+const RING_SIZE: usize = FRAME_SIZE as usize / size_of::<Trb>();
+/// Transfers are bounced through this many frames of DMA memory per device, and split into transfers of at
+/// most this size. A single TRB can transfer at most 64 KiB, without crossing a 64 KiB boundary.
+const TRANSFER_BUFFER_FRAMES: u64 = 16;
+const TRANSFER_BUFFER_SIZE: usize = (TRANSFER_BUFFER_FRAMES * FRAME_SIZE) as usize;
+const MAX_PENDING_TRANSFER_COMPLETIONS: usize = 16;
+/// How many times the controller's registers or the event ring are polled before an operation is
+/// considered to have timed out. Like with the PS/2 controller, there's no timer to measure real time with.
+const POLL_ITERATIONS: u32 = 10000000;
+
+const CAP_REG_CAPLENGTH: u64 = 0x00;
+const CAP_REG_HCSPARAMS1: u64 = 0x04;
+const CAP_REG_HCSPARAMS2: u64 = 0x08;
+const CAP_REG_HCCPARAMS1: u64 = 0x10;
+const CAP_REG_DBOFF: u64 = 0x14;
+const CAP_REG_RTSOFF: u64 = 0x18;
+
+const OP_REG_USBCMD: u64 = 0x00;
+const OP_REG_USBSTS: u64 = 0x04;
+const OP_REG_CRCR: u64 = 0x18;
+const OP_REG_DCBAAP: u64 = 0x30;
+const OP_REG_CONFIG: u64 = 0x38;
+const OP_REG_PORTSC_BASE: u64 = 0x400;
+
+const RUNTIME_REG_INTERRUPTER_0: u64 = 0x20;
+const INTERRUPTER_REG_IMAN: u64 = 0x00;
+const INTERRUPTER_REG_ERSTSZ: u64 = 0x08;
+const INTERRUPTER_REG_ERSTBA: u64 = 0x10;
+const INTERRUPTER_REG_ERDP: u64 = 0x18;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_HOST_CONTROLLER_RESET: u32 = 1 << 1;
+const USBCMD_INTERRUPTER_ENABLE: u32 = 1 << 2;
+const USBSTS_HOST_CONTROLLER_HALTED: u32 = 1 << 0;
+const USBSTS_EVENT_INTERRUPT: u32 = 1 << 3;
+const USBSTS_CONTROLLER_NOT_READY: u32 = 1 << 11;
+const CRCR_RING_CYCLE_STATE: u64 = 1 << 0;
+const IMAN_INTERRUPT_PENDING: u32 = 1 << 0;
+const IMAN_INTERRUPT_ENABLE: u32 = 1 << 1;
+const ERDP_EVENT_HANDLER_BUSY: u64 = 1 << 3;
+
+const PORTSC_CURRENT_CONNECT_STATUS: u32 = 1 << 0;
+const PORTSC_PORT_ENABLED: u32 = 1 << 1;
+const PORTSC_PORT_RESET: u32 = 1 << 4;
+const PORTSC_PORT_POWER: u32 = 1 << 9;
+const PORTSC_SPEED_SHIFT: u32 = 10;
+const PORTSC_CONNECT_STATUS_CHANGE: u32 = 1 << 17;
+const PORTSC_PORT_RESET_CHANGE: u32 = 1 << 21;
+/// Status change bits, which are cleared by writing one to them
+const PORTSC_CHANGE_BITS: u32 = 0b1111111 << 17;
+
+const EXTENDED_CAPABILITY_LEGACY_SUPPORT: u8 = 1;
+const EXTENDED_CAPABILITY_SUPPORTED_PROTOCOL: u8 = 2;
+const LEGACY_SUPPORT_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_SUPPORT_OS_OWNED: u32 = 1 << 24;
+
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_INTERRUPT_ON_SHORT_PACKET: u32 = 1 << 2;
+const TRB_INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+const TRB_IMMEDIATE_DATA: u32 = 1 << 6;
+const TRB_DIRECTION_IN: u32 = 1 << 16;
+const TRB_TYPE_SHIFT: u32 = 10;
+
+const TRB_TYPE_NORMAL: u32 = 1;
+const TRB_TYPE_SETUP_STAGE: u32 = 2;
+const TRB_TYPE_DATA_STAGE: u32 = 3;
+const TRB_TYPE_STATUS_STAGE: u32 = 4;
+const TRB_TYPE_LINK: u32 = 6;
+const TRB_TYPE_ENABLE_SLOT: u32 = 9;
+const TRB_TYPE_ADDRESS_DEVICE: u32 = 11;
+const TRB_TYPE_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_TYPE_EVALUATE_CONTEXT: u32 = 13;
+const TRB_TYPE_RESET_ENDPOINT: u32 = 14;
+const TRB_TYPE_SET_TR_DEQUEUE_POINTER: u32 = 16;
+const TRB_TYPE_TRANSFER_EVENT: u32 = 32;
+const TRB_TYPE_COMMAND_COMPLETION_EVENT: u32 = 33;
+
+const COMPLETION_CODE_SUCCESS: u8 = 1;
+const COMPLETION_CODE_STALL_ERROR: u8 = 6;
+const COMPLETION_CODE_SHORT_PACKET: u8 = 13;
+
+const ENDPOINT_TYPE_CONTROL: u32 = 4;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32
+}
+
+impl Trb {
+    #[inline]
+    fn trb_type(&self) -> u32 {
+        (self.control >> TRB_TYPE_SHIFT) & 0x3f
+    }
+
+    #[inline]
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    #[inline]
+    fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+}
+
+/// A command or transfer ring, which software produces TRBs into and the controller consumes them from.
+struct ProducerRing {
+    frames: PhysicalFrames,
+    enqueue_index: usize,
+    cycle: bool
+}
+
+impl ProducerRing {
+    fn new() -> Result<ProducerRing, UsbError> {
+        let frames = allocate_frames(1).ok_or(UsbError::OutOfMemory)?;
+        let ring = ProducerRing {
+            frames,
+            enqueue_index: 0,
+            cycle: true
+        };
+        let link = Trb {
+            parameter: ring.frames.physical_address,
+            status: 0,
+            control: (TRB_TYPE_LINK << TRB_TYPE_SHIFT) | TRB_TOGGLE_CYCLE
+        };
+        unsafe { ptr::write_volatile(ring.trb(RING_SIZE - 1), link) };
+        Ok(ring)
+    }
+
+    #[inline]
+    fn trb(&self, index: usize) -> *mut Trb {
+        unsafe { self.frames.as_ptr::<Trb>().add(index) }
+    }
+
+    #[inline]
+    fn trb_physical_address(&self, index: usize) -> u64 {
+        self.frames.physical_address + (index * size_of::<Trb>()) as u64
+    }
+
+    /// Writes a TRB into the ring, setting its cycle bit to hand it over to the controller. Returns the
+    /// physical address of the TRB, which completion events refer to it by.
+    fn push(&mut self, mut trb: Trb) -> u64 {
+        let trb_ptr = self.trb(self.enqueue_index);
+        let physical_address = self.trb_physical_address(self.enqueue_index);
+        trb.control = (trb.control & !TRB_CYCLE) | self.cycle as u32;
+        unsafe {
+            ptr::write_volatile(ptr::addr_of_mut!((*trb_ptr).parameter), trb.parameter);
+            ptr::write_volatile(ptr::addr_of_mut!((*trb_ptr).status), trb.status);
+            // The cycle bit must only be flipped once the rest of the TRB is in place
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(ptr::addr_of_mut!((*trb_ptr).control), trb.control);
+        }
+
+        self.enqueue_index += 1;
+        if self.enqueue_index == RING_SIZE - 1 {
+            let link = self.trb(RING_SIZE - 1);
+            unsafe {
+                let control = ptr::read_volatile(ptr::addr_of!((*link).control));
+                fence(Ordering::SeqCst);
+                ptr::write_volatile(
+                    ptr::addr_of_mut!((*link).control),
+                    (control & !TRB_CYCLE) | self.cycle as u32
+                );
+            }
+            self.enqueue_index = 0;
+            self.cycle = !self.cycle;
+        }
+        physical_address
+    }
+
+    /// The dequeue pointer to give the controller when the ring is (re)started from the current position.
+    #[inline]
+    fn enqueue_pointer(&self) -> u64 {
+        self.trb_physical_address(self.enqueue_index) | self.cycle as u64
+    }
+}
+
+/// The event ring of the primary interrupter, consisting of a single segment.
+struct EventRing {
+    segment: PhysicalFrames,
+    segment_table: PhysicalFrames,
+    dequeue_index: usize,
+    cycle: bool
+}
+
+impl EventRing {
+    fn new() -> Result<EventRing, UsbError> {
+        let segment = allocate_frames(1).ok_or(UsbError::OutOfMemory)?;
+        let segment_table = allocate_frames(1).ok_or(UsbError::OutOfMemory)?;
+        let entry = segment_table.as_ptr::<u64>();
+        unsafe {
+            ptr::write_volatile(entry, segment.physical_address);
+            ptr::write_volatile(entry.add(1), RING_SIZE as u64);
+        }
+        Ok(EventRing {
+            segment,
+            segment_table,
+            dequeue_index: 0,
+            cycle: true
+        })
+    }
+
+    fn pop(&mut self) -> Option<Trb> {
+        let trb_ptr = unsafe { self.segment.as_ptr::<Trb>().add(self.dequeue_index) };
+        let control = unsafe { ptr::read_volatile(ptr::addr_of!((*trb_ptr).control)) };
+        if (control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let trb = unsafe { ptr::read_volatile(trb_ptr) };
+        self.dequeue_index += 1;
+        if self.dequeue_index == RING_SIZE {
+            self.dequeue_index = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+
+    #[inline]
+    fn dequeue_pointer(&self) -> u64 {
+        self.segment.physical_address + (self.dequeue_index * size_of::<Trb>()) as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CommandCompletion {
+    trb_address: u64,
+    code: u8,
+    slot_id: u8
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TransferCompletion {
+    trb_address: u64,
+    code: u8,
+    /// How many bytes of the TRB's transfer length weren't transferred
+    residual_length: u32,
+    slot_id: u8,
+    endpoint_id: u8
+}
+
+/// An interrupt IN endpoint which is polled continuously, with every completed transfer handed to `handler`
+/// from the controller's interrupt handler and the next transfer queued right after.
+struct InterruptTransfer {
+    buffer: PhysicalFrames,
+    length: usize,
+    handler: InterruptTransferHandler,
+    context: u64
+}
+
+/// The state the driver keeps for each device it has addressed.
+pub struct DeviceSlot {
+    pub port: u8,
+    pub speed: UsbSpeed,
+    pub device_descriptor: DeviceDescriptor,
+    pub configuration_descriptor: ArrayVec<u8, MAX_CONFIGURATION_DESCRIPTOR_SIZE>,
+    output_context: PhysicalFrames,
+    input_context: PhysicalFrames,
+    /// Indexed by device context index, where index 1 is the default control endpoint
+    transfer_rings: [Option<ProducerRing>; 32],
+    /// Also indexed by device context index
+    interrupt_transfers: [Option<InterruptTransfer>; 32],
+    transfer_buffer: PhysicalFrames
+}
+
+/// How the controller's interrupter signals events. The MSI(-X) configuration is kept around for as long as
+/// the controller runs.
+#[allow(dead_code)]
+enum InterruptSource {
+    Msi(Msi),
+    Msix(MsixTable)
+}
+
+pub struct XhciController {
+    pci_device: &'static PciDevice,
+    operational_base: u64,
+    runtime_base: u64,
+    doorbell_base: u64,
+    max_slots: u8,
+    max_ports: u8,
+    /// Contexts are either 32 or 64 bytes long, depending on the controller
+    context_size: usize,
+    dcbaa: PhysicalFrames,
+    command_ring: ProducerRing,
+    event_ring: EventRing,
+    command_completion: Option<CommandCompletion>,
+    transfer_completions: ArrayVec<TransferCompletion, MAX_PENDING_TRANSFER_COMPLETIONS>,
+    /// The USB major revision (2 or 3) of each root hub port, indexed by port number
+    port_protocols: [u8; 256],
+    devices: [Option<DeviceSlot>; MAX_SLOTS + 1],
+    interrupt_source: Option<InterruptSource>
+}
+
+unsafe impl Send for XhciController {}
+
+#[inline]
+fn read_u32(address: u64) -> u32 {
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
+
+#[inline]
+fn write_u32(address: u64, value: u32) {
+    unsafe { ptr::write_volatile(address as *mut u32, value) }
+}
+
+/// 64-bit registers are written as two dwords, low half first, which works whether or not the controller
+/// supports 64-bit accesses.
+#[inline]
+fn write_u64(address: u64, value: u64) {
+    write_u32(address, value as u32);
+    write_u32(address + 4, (value >> 32) as u32);
+}
+
+fn poll_until(mut condition: impl FnMut() -> bool) -> Result<(), UsbError> {
+    for _ in 0..POLL_ITERATIONS {
+        if condition() {
+            return Ok(());
+        }
+    }
+    Err(UsbError::Timeout)
+}
+
+#[inline]
+fn device_context_index(endpoint_address: u8) -> usize {
+    let number = (endpoint_address & 0x0f) as usize;
+    if number == 0 {
+        return 1;
+    }
+    number * 2 + (endpoint_address & usb::ENDPOINT_ADDRESS_DIRECTION_IN != 0) as usize
+}
+
+fn speed_from_port_speed_id(speed_id: u32) -> Option<UsbSpeed> {
+    match speed_id {
+        1 => Some(UsbSpeed::Full),
+        2 => Some(UsbSpeed::Low),
+        3 => Some(UsbSpeed::High),
+        4 => Some(UsbSpeed::Super),
+        5 => Some(UsbSpeed::SuperPlus),
+        _ => None
+    }
+}
+
+fn port_speed_id(speed: UsbSpeed) -> u32 {
+    match speed {
+        UsbSpeed::Full => 1,
+        UsbSpeed::Low => 2,
+        UsbSpeed::High => 3,
+        UsbSpeed::Super => 4,
+        UsbSpeed::SuperPlus => 5
+    }
+}
+
+/// The max packet size of the default control endpoint before the device descriptor has been read. Full
+/// speed devices can use anything between 8 and 64 bytes, so for those this is just a guess.
+fn default_control_max_packet_size(speed: UsbSpeed) -> u16 {
+    match speed {
+        UsbSpeed::Low | UsbSpeed::Full => 8,
+        UsbSpeed::High => 64,
+        UsbSpeed::Super | UsbSpeed::SuperPlus => 512
+    }
+}
+
+fn completion_code_to_error(code: u8) -> UsbError {
+    match code {
+        COMPLETION_CODE_STALL_ERROR => UsbError::Stall,
+        _ => UsbError::TransferFailed
+    }
+}
+
+impl XhciController {
+    fn new(pci_device: &'static PciDevice) -> Result<XhciController, UsbError> {
+        let Some(Bar::Memory { address, size, .. }) = pci_device.bars[0]
+        else {
+            return Err(UsbError::Unsupported);
+        };
+        let base = mmio_to_virtual(address, size).ok_or(UsbError::Unsupported)?;
+        pci_device.enable_memory_and_bus_mastering();
+
+        let capability_length = read_u32(base + CAP_REG_CAPLENGTH) & 0xff;
+        let hcsparams1 = read_u32(base + CAP_REG_HCSPARAMS1);
+        let hcsparams2 = read_u32(base + CAP_REG_HCSPARAMS2);
+        let hccparams1 = read_u32(base + CAP_REG_HCCPARAMS1);
+        let operational_base = base + capability_length as u64;
+        let mut port_protocols = [2; 256];
+        Self::handle_extended_capabilities(base, hccparams1, &mut port_protocols)?;
+
+        let mut controller = XhciController {
+            pci_device,
+            operational_base,
+            runtime_base: base + (read_u32(base + CAP_REG_RTSOFF) & !0x1f) as u64,
+            doorbell_base: base + (read_u32(base + CAP_REG_DBOFF) & !0b11) as u64,
+            max_slots: (hcsparams1 & 0xff).min(MAX_SLOTS as u32) as u8,
+            max_ports: (hcsparams1 >> 24) as u8,
+            context_size: if hccparams1 & (1 << 2) != 0 { 64 } else { 32 },
+            dcbaa: allocate_frames(1).ok_or(UsbError::OutOfMemory)?,
+            command_ring: ProducerRing::new()?,
+            event_ring: EventRing::new()?,
+            command_completion: None,
+            transfer_completions: ArrayVec::new(),
+            port_protocols,
+            devices: [const { None }; MAX_SLOTS + 1],
+            interrupt_source: None
+        };
+        controller.reset()?;
+        controller.write_operational_u32(OP_REG_CONFIG, controller.max_slots as u32);
+
+        let scratchpad_count = (((hcsparams2 >> 21) & 0x1f) << 5) | ((hcsparams2 >> 27) & 0x1f);
+        if scratchpad_count > 0 {
+            let array_frames = (scratchpad_count as u64 * 8).div_ceil(FRAME_SIZE);
+            let array = allocate_frames(array_frames).ok_or(UsbError::OutOfMemory)?;
+            for i in 0..scratchpad_count as usize {
+                // The scratchpad buffers belong to the controller for as long as it runs, so they're never
+                // freed
+                let buffer = allocate_frames(1).ok_or(UsbError::OutOfMemory)?;
+                unsafe { ptr::write_volatile(array.as_ptr::<u64>().add(i), buffer.physical_address) };
+            }
+            unsafe { ptr::write_volatile(controller.dcbaa.as_ptr::<u64>(), array.physical_address) };
+        }
+        write_u64(
+            controller.operational_base + OP_REG_DCBAAP,
+            controller.dcbaa.physical_address
+        );
+        write_u64(
+            controller.operational_base + OP_REG_CRCR,
+            controller.command_ring.frames.physical_address | CRCR_RING_CYCLE_STATE
+        );
+
+        let interrupter = controller.runtime_base + RUNTIME_REG_INTERRUPTER_0;
+        write_u32(interrupter + INTERRUPTER_REG_ERSTSZ, 1);
+        write_u64(
+            interrupter + INTERRUPTER_REG_ERDP,
+            controller.event_ring.dequeue_pointer()
+        );
+        write_u64(
+            interrupter + INTERRUPTER_REG_ERSTBA,
+            controller.event_ring.segment_table.physical_address
+        );
+
+        controller.write_operational_u32(OP_REG_USBCMD, USBCMD_RUN);
+        poll_until(|| controller.read_operational_u32(OP_REG_USBSTS) & USBSTS_HOST_CONTROLLER_HALTED == 0)?;
+        Ok(controller)
+    }
+
+    /// Takes the controller over from the firmware, and records which USB revision each root hub port
+    /// speaks.
+    fn handle_extended_capabilities(
+        base: u64,
+        hccparams1: u32,
+        port_protocols: &mut [u8; 256]
+    ) -> Result<(), UsbError> {
+        let mut offset = ((hccparams1 >> 16) as u64) << 2;
+        while offset != 0 {
+            let capability = base + offset;
+            let header = read_u32(capability);
+            match header as u8 {
+                EXTENDED_CAPABILITY_LEGACY_SUPPORT => {
+                    write_u32(capability, header | LEGACY_SUPPORT_OS_OWNED);
+                    poll_until(|| read_u32(capability) & LEGACY_SUPPORT_BIOS_OWNED == 0)?;
+                },
+                EXTENDED_CAPABILITY_SUPPORTED_PROTOCOL => {
+                    let major_revision = (header >> 24) as u8;
+                    let ports = read_u32(capability + 8);
+                    let first_port = ports & 0xff;
+                    let port_count = (ports >> 8) & 0xff;
+                    for port in first_port..(first_port + port_count).min(256) {
+                        port_protocols[port as usize] = major_revision;
+                    }
+                },
+                _ => {}
+            }
+            let next = ((header >> 8) & 0xff) as u64;
+            offset = match next {
+                0 => 0,
+                _ => offset + (next << 2)
+            };
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), UsbError> {
+        let command = self.read_operational_u32(OP_REG_USBCMD);
+        self.write_operational_u32(OP_REG_USBCMD, command & !USBCMD_RUN);
+        poll_until(|| self.read_operational_u32(OP_REG_USBSTS) & USBSTS_HOST_CONTROLLER_HALTED != 0)?;
+        self.write_operational_u32(OP_REG_USBCMD, USBCMD_HOST_CONTROLLER_RESET);
+        poll_until(|| {
+            self.read_operational_u32(OP_REG_USBCMD) & USBCMD_HOST_CONTROLLER_RESET == 0
+                && self.read_operational_u32(OP_REG_USBSTS) & USBSTS_CONTROLLER_NOT_READY == 0
+        })
+    }
+
+    #[inline]
+    fn read_operational_u32(&self, offset: u64) -> u32 {
+        read_u32(self.operational_base + offset)
+    }
+
+    #[inline]
+    fn write_operational_u32(&self, offset: u64, value: u32) {
+        write_u32(self.operational_base + offset, value)
+    }
+
+    #[inline]
+    fn port_status_address(&self, port: u8) -> u64 {
+        self.operational_base + OP_REG_PORTSC_BASE + 0x10 * (port as u64 - 1)
+    }
+
+    /// Writes the port status and control register without accidentally disabling the port or clearing
+    /// change bits other than the ones in `bits`.
+    fn write_port_status(&self, port: u8, bits: u32) {
+        let address = self.port_status_address(port);
+        let status = read_u32(address) & !(PORTSC_PORT_ENABLED | PORTSC_CHANGE_BITS);
+        write_u32(address, status | bits);
+    }
+
+    #[inline]
+    fn ring_doorbell(&self, slot_id: u8, target: u8) {
+        fence(Ordering::SeqCst);
+        write_u32(self.doorbell_base + 4 * slot_id as u64, target as u32);
+    }
+
+    /// Moves every event on the event ring into the pending command or transfer completions, and lets the
+    /// controller know how far the ring has been consumed.
+    fn process_events(&mut self) {
+        let mut processed_any = false;
+        while let Some(event) = self.event_ring.pop() {
+            processed_any = true;
+            match event.trb_type() {
+                TRB_TYPE_COMMAND_COMPLETION_EVENT => {
+                    self.command_completion = Some(CommandCompletion {
+                        trb_address: event.parameter,
+                        code: event.completion_code(),
+                        slot_id: event.slot_id()
+                    });
+                },
+                TRB_TYPE_TRANSFER_EVENT => {
+                    let endpoint_id = ((event.control >> 16) & 0x1f) as u8;
+                    if self.handle_interrupt_transfer(event.slot_id(), endpoint_id, &event) {
+                        continue;
+                    }
+                    if self.transfer_completions.is_full() {
+                        self.transfer_completions.remove(0);
+                    }
+                    self.transfer_completions.push(TransferCompletion {
+                        trb_address: event.parameter,
+                        code: event.completion_code(),
+                        residual_length: event.status & 0xffffff,
+                        slot_id: event.slot_id(),
+                        endpoint_id
+                    });
+                },
+                // Port status change events need no handling, as the ports are only looked at once when the
+                // controller is initialized
+                _ => {}
+            }
+        }
+        if processed_any {
+            let interrupter = self.runtime_base + RUNTIME_REG_INTERRUPTER_0;
+            write_u64(
+                interrupter + INTERRUPTER_REG_ERDP,
+                self.event_ring.dequeue_pointer() | ERDP_EVENT_HANDLER_BUSY
+            );
+        }
+    }
+
+    /// Passes the data of a completed interrupt transfer to its handler and queues the next transfer on the
+    /// endpoint. Returns false if the event isn't for an endpoint with interrupt transfers running.
+    fn handle_interrupt_transfer(&mut self, slot_id: u8, endpoint_id: u8, event: &Trb) -> bool {
+        let Some(slot) = self.devices.get_mut(slot_id as usize).and_then(|s| s.as_mut())
+        else {
+            return false;
+        };
+        let Some(transfer) = slot.interrupt_transfers[endpoint_id as usize].as_ref()
+        else {
+            return false;
+        };
+        match event.completion_code() {
+            COMPLETION_CODE_SUCCESS | COMPLETION_CODE_SHORT_PACKET => {
+                let residual_length = (event.status & 0xffffff) as usize;
+                let length = transfer.length.saturating_sub(residual_length);
+                let data = unsafe { core::slice::from_raw_parts(transfer.buffer.as_ptr::<u8>(), length) };
+                (transfer.handler)(data, transfer.context);
+                self.queue_interrupt_transfer(slot_id, endpoint_id as usize);
+            },
+            // The endpoint is halted, and recovering it would take commands which can't be sent from here,
+            // so the device is given up on
+            _ => slot.interrupt_transfers[endpoint_id as usize] = None
+        }
+        true
+    }
+
+    fn queue_interrupt_transfer(&mut self, slot_id: u8, context_index: usize) {
+        let Some(slot) = self.devices[slot_id as usize].as_mut()
+        else {
+            return;
+        };
+        let (Some(transfer), Some(ring)) = (
+            slot.interrupt_transfers[context_index].as_ref(),
+            slot.transfer_rings[context_index].as_mut()
+        )
+        else {
+            return;
+        };
+        ring.push(Trb {
+            parameter: transfer.buffer.physical_address,
+            status: transfer.length as u32,
+            control: (TRB_TYPE_NORMAL << TRB_TYPE_SHIFT)
+                | TRB_INTERRUPT_ON_COMPLETION
+                | TRB_INTERRUPT_ON_SHORT_PACKET
+        });
+        self.ring_doorbell(slot_id, context_index as u8);
+    }
+
+    fn start_interrupt_transfers(
+        &mut self,
+        slot_id: u8,
+        endpoint_address: u8,
+        length: usize,
+        handler: InterruptTransferHandler,
+        context: u64
+    ) -> Result<(), UsbError> {
+        if endpoint_address & usb::ENDPOINT_ADDRESS_DIRECTION_IN == 0 {
+            return Err(UsbError::Unsupported);
+        }
+        if length > FRAME_SIZE as usize {
+            return Err(UsbError::BufferTooLarge);
+        }
+        if self.interrupt_source.is_none() {
+            return Err(UsbError::Unsupported);
+        }
+        let context_index = device_context_index(endpoint_address);
+        let slot = self.devices[slot_id as usize]
+            .as_mut()
+            .ok_or(UsbError::NoSuchDevice)?;
+        if slot.transfer_rings[context_index].is_none() {
+            return Err(UsbError::EndpointNotConfigured);
+        }
+        slot.interrupt_transfers[context_index] = Some(InterruptTransfer {
+            buffer: allocate_frames(1).ok_or(UsbError::OutOfMemory)?,
+            length,
+            handler,
+            context
+        });
+        self.queue_interrupt_transfer(slot_id, context_index);
+        Ok(())
+    }
+
+    /// Routes the primary interrupter to the current CPU through MSI-X or MSI, whichever the controller
+    /// supports. Without either, interrupt transfers can't be used.
+    fn enable_interrupts(&mut self, controller_index: usize) -> Result<(), UsbError> {
+        let apic_id = local_apic_id();
+        let source = match MsixTable::enable(self.pci_device) {
+            Ok(mut table) => {
+                table
+                    .set_handler(0, apic_id, handle_interrupt, controller_index as u64)
+                    .map_err(|_| UsbError::Unsupported)?;
+                InterruptSource::Msix(table)
+            },
+            Err(_) => Msi::enable(
+                self.pci_device,
+                1,
+                apic_id,
+                handle_interrupt,
+                controller_index as u64
+            )
+            .map(InterruptSource::Msi)
+            .map_err(|_| UsbError::Unsupported)?
+        };
+        self.interrupt_source = Some(source);
+
+        let interrupter = self.runtime_base + RUNTIME_REG_INTERRUPTER_0;
+        write_u32(
+            interrupter + INTERRUPTER_REG_IMAN,
+            IMAN_INTERRUPT_PENDING | IMAN_INTERRUPT_ENABLE
+        );
+        let command = self.read_operational_u32(OP_REG_USBCMD);
+        self.write_operational_u32(OP_REG_USBCMD, command | USBCMD_INTERRUPTER_ENABLE);
+        Ok(())
+    }
+
+    fn send_command(&mut self, trb: Trb) -> Result<CommandCompletion, UsbError> {
+        self.command_completion = None;
+        let trb_address = self.command_ring.push(trb);
+        self.ring_doorbell(0, 0);
+        for _ in 0..POLL_ITERATIONS {
+            self.process_events();
+            if let Some(completion) = self.command_completion.take() {
+                if completion.trb_address != trb_address {
+                    continue;
+                }
+                if completion.code != COMPLETION_CODE_SUCCESS {
+                    return Err(completion_code_to_error(completion.code));
+                }
+                return Ok(completion);
+            }
+        }
+        Err(UsbError::Timeout)
+    }
+
+    /// Waits for the TRB at `final_trb_address` on the given endpoint to complete. Returns the sum of the
+    /// residual lengths of every TRB of the transfer which ended in a short packet.
+    fn wait_for_transfer(
+        &mut self,
+        slot_id: u8,
+        endpoint_id: u8,
+        final_trb_address: u64
+    ) -> Result<u32, UsbError> {
+        let mut residual_length = 0;
+        for _ in 0..POLL_ITERATIONS {
+            self.process_events();
+            let mut i = 0;
+            while i < self.transfer_completions.len() {
+                let completion = self.transfer_completions[i];
+                if completion.slot_id != slot_id || completion.endpoint_id != endpoint_id {
+                    i += 1;
+                    continue;
+                }
+                self.transfer_completions.remove(i);
+                match completion.code {
+                    COMPLETION_CODE_SUCCESS | COMPLETION_CODE_SHORT_PACKET => {
+                        residual_length += completion.residual_length;
+                        if completion.trb_address == final_trb_address {
+                            return Ok(residual_length);
+                        }
+                    },
+                    code => return Err(completion_code_to_error(code))
+                }
+            }
+        }
+        Err(UsbError::Timeout)
+    }
+
+    #[inline]
+    fn context(&self, frames: &PhysicalFrames, index: usize) -> *mut u32 {
+        unsafe { frames.as_ptr::<u8>().add(index * self.context_size) as *mut u32 }
+    }
+
+    /// Context `index` of an input context, where 0 is the input control context, 1 the slot context and
+    /// the rest endpoint contexts by device context index.
+    #[inline]
+    fn input_context(&self, slot: &DeviceSlot, index: usize) -> *mut u32 {
+        self.context(&slot.input_context, index)
+    }
+
+    fn clear_input_context(&self, slot: &DeviceSlot) {
+        unsafe {
+            ptr::write_bytes(
+                slot.input_context.as_ptr::<u8>(),
+                0,
+                slot.input_context.size() as usize
+            )
+        };
+    }
+
+    /// Resets and enables a port, returning the speed of the device connected to it.
+    fn reset_port(&mut self, port: u8) -> Result<UsbSpeed, UsbError> {
+        let status_address = self.port_status_address(port);
+        // USB 3 ports enable themselves once link training has finished, while USB 2 ports need a reset
+        if self.port_protocols[port as usize] < 3 {
+            self.write_port_status(port, PORTSC_PORT_RESET);
+            poll_until(|| read_u32(status_address) & PORTSC_PORT_RESET_CHANGE != 0)?;
+        }
+        poll_until(|| read_u32(status_address) & PORTSC_PORT_ENABLED != 0)?;
+        self.write_port_status(port, PORTSC_PORT_RESET_CHANGE | PORTSC_CONNECT_STATUS_CHANGE);
+        let speed_id = (read_u32(status_address) >> PORTSC_SPEED_SHIFT) & 0xf;
+        speed_from_port_speed_id(speed_id).ok_or(UsbError::Unsupported)
+    }
+
+    /// Powers on every port and addresses the devices connected to them. Returns the slot ids of the
+    /// devices which were successfully set up.
+    fn enumerate_ports(&mut self) -> ArrayVec<u8, MAX_SLOTS> {
+        let mut slot_ids = ArrayVec::new();
+        for port in 1..=self.max_ports {
+            let status_address = self.port_status_address(port);
+            if read_u32(status_address) & PORTSC_PORT_POWER == 0 {
+                self.write_port_status(port, PORTSC_PORT_POWER);
+                crate::sleep(1000000);
+            }
+        }
+        for port in 1..=self.max_ports {
+            if read_u32(self.port_status_address(port)) & PORTSC_CURRENT_CONNECT_STATUS == 0 {
+                continue;
+            }
+            let Ok(speed) = self.reset_port(port)
+            else {
+                continue;
+            };
+            if let Ok(slot_id) = self.address_device(port, speed) {
+                if slot_ids.try_push(slot_id).is_err() {
+                    break;
+                }
+            }
+        }
+        slot_ids
+    }
+
+    /// Enables a device slot for the device on `port`, gives the device an address and reads its
+    /// descriptors. The first configuration of the device is activated.
+    fn address_device(&mut self, port: u8, speed: UsbSpeed) -> Result<u8, UsbError> {
+        let completion = self.send_command(Trb {
+            control: TRB_TYPE_ENABLE_SLOT << TRB_TYPE_SHIFT,
+            ..Default::default()
+        })?;
+        let slot_id = completion.slot_id;
+        if slot_id == 0 || slot_id as usize > MAX_SLOTS {
+            return Err(UsbError::Unsupported);
+        }
+
+        let mut transfer_rings = [const { None }; 32];
+        transfer_rings[1] = Some(ProducerRing::new()?);
+        let slot = DeviceSlot {
+            port,
+            speed,
+            device_descriptor: DeviceDescriptor::default(),
+            configuration_descriptor: ArrayVec::new(),
+            output_context: allocate_frames(1).ok_or(UsbError::OutOfMemory)?,
+            input_context: allocate_frames(1).ok_or(UsbError::OutOfMemory)?,
+            transfer_rings,
+            interrupt_transfers: [const { None }; 32],
+            transfer_buffer: allocate_frames_aligned(TRANSFER_BUFFER_FRAMES, TRANSFER_BUFFER_FRAMES)
+                .ok_or(UsbError::OutOfMemory)?
+        };
+        unsafe {
+            ptr::write_volatile(
+                self.dcbaa.as_ptr::<u64>().add(slot_id as usize),
+                slot.output_context.physical_address
+            )
+        };
+
+        let max_packet_size = default_control_max_packet_size(speed);
+        unsafe {
+            let control = self.input_context(&slot, 0);
+            ptr::write_volatile(control.add(1), 0b11);
+            let slot_context = self.input_context(&slot, 1);
+            ptr::write_volatile(slot_context, (1 << 27) | (port_speed_id(speed) << 20));
+            ptr::write_volatile(slot_context.add(1), (port as u32) << 16);
+            let endpoint_context = self.input_context(&slot, 2);
+            ptr::write_volatile(
+                endpoint_context.add(1),
+                (3 << 1) | (ENDPOINT_TYPE_CONTROL << 3) | ((max_packet_size as u32) << 16)
+            );
+            let dequeue_pointer = slot.transfer_rings[1].as_ref().unwrap().enqueue_pointer();
+            ptr::write_volatile(endpoint_context.add(2), dequeue_pointer as u32);
+            ptr::write_volatile(endpoint_context.add(3), (dequeue_pointer >> 32) as u32);
+            ptr::write_volatile(endpoint_context.add(4), 8);
+        }
+        let input_context_address = slot.input_context.physical_address;
+        self.devices[slot_id as usize] = Some(slot);
+        self.send_command(Trb {
+            parameter: input_context_address,
+            control: (TRB_TYPE_ADDRESS_DEVICE << TRB_TYPE_SHIFT) | ((slot_id as u32) << 24),
+            ..Default::default()
+        })?;
+
+        if speed == UsbSpeed::Full {
+            self.update_control_max_packet_size(slot_id)?;
+        }
+        let mut descriptor = [0; size_of::<DeviceDescriptor>()];
+        self.control_transfer(
+            slot_id,
+            SetupPacket::get_descriptor(DESCRIPTOR_TYPE_DEVICE, 0, descriptor.len() as u16),
+            &mut descriptor
+        )?;
+        let device_descriptor = usb::read_descriptor::<DeviceDescriptor>(&descriptor, DESCRIPTOR_TYPE_DEVICE)
+            .ok_or(UsbError::InvalidDescriptor)?;
+
+        let mut configuration = [0; MAX_CONFIGURATION_DESCRIPTOR_SIZE];
+        self.control_transfer(
+            slot_id,
+            SetupPacket::get_descriptor(
+                DESCRIPTOR_TYPE_CONFIGURATION,
+                0,
+                size_of::<ConfigurationDescriptor>() as u16
+            ),
+            &mut configuration[..size_of::<ConfigurationDescriptor>()]
+        )?;
+        let configuration_descriptor =
+            usb::read_descriptor::<ConfigurationDescriptor>(&configuration, DESCRIPTOR_TYPE_CONFIGURATION)
+                .ok_or(UsbError::InvalidDescriptor)?;
+        let total_length = (configuration_descriptor.total_length as usize).min(configuration.len());
+        let length = self.control_transfer(
+            slot_id,
+            SetupPacket::get_descriptor(DESCRIPTOR_TYPE_CONFIGURATION, 0, total_length as u16),
+            &mut configuration[..total_length]
+        )?;
+
+        let slot = self.devices[slot_id as usize].as_mut().unwrap();
+        slot.device_descriptor = device_descriptor;
+        slot.configuration_descriptor = configuration[..length].iter().copied().collect();
+
+        let setup = SetupPacket {
+            request_type: usb::REQUEST_TYPE_STANDARD | usb::REQUEST_RECIPIENT_DEVICE,
+            request: usb::REQUEST_SET_CONFIGURATION,
+            value: configuration_descriptor.configuration_value as u16,
+            index: 0,
+            length: 0
+        };
+        self.control_transfer(slot_id, setup, &mut [])?;
+        Ok(slot_id)
+    }
+
+    /// Full speed devices can have a default control endpoint max packet size of anything from 8 to 64
+    /// bytes, which is found out by reading the first 8 bytes of the device descriptor.
+    fn update_control_max_packet_size(&mut self, slot_id: u8) -> Result<(), UsbError> {
+        let mut descriptor_start = [0; 8];
+        self.control_transfer(
+            slot_id,
+            SetupPacket::get_descriptor(DESCRIPTOR_TYPE_DEVICE, 0, 8),
+            &mut descriptor_start
+        )?;
+        let max_packet_size = descriptor_start[7] as u32;
+        if max_packet_size == default_control_max_packet_size(UsbSpeed::Full) as u32 || max_packet_size == 0 {
+            return Ok(());
+        }
+        let slot = self.devices[slot_id as usize].as_ref().unwrap();
+        self.clear_input_context(slot);
+        unsafe {
+            let control = self.input_context(slot, 0);
+            ptr::write_volatile(control.add(1), 0b10);
+            let endpoint_context = self.input_context(slot, 2);
+            let output_endpoint_context = self.context(&slot.output_context, 1);
+            for i in 0..5 {
+                ptr::write_volatile(
+                    endpoint_context.add(i),
+                    ptr::read_volatile(output_endpoint_context.add(i))
+                );
+            }
+            let dword_1 = ptr::read_volatile(endpoint_context.add(1));
+            ptr::write_volatile(
+                endpoint_context.add(1),
+                (dword_1 & 0xffff) | (max_packet_size << 16)
+            );
+        }
+        let input_context_address = slot.input_context.physical_address;
+        self.send_command(Trb {
+            parameter: input_context_address,
+            control: (TRB_TYPE_EVALUATE_CONTEXT << TRB_TYPE_SHIFT) | ((slot_id as u32) << 24),
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    fn control_transfer(
+        &mut self,
+        slot_id: u8,
+        setup: SetupPacket,
+        data: &mut [u8]
+    ) -> Result<usize, UsbError> {
+        if data.len() > TRANSFER_BUFFER_SIZE {
+            return Err(UsbError::BufferTooLarge);
+        }
+        let slot = self.devices[slot_id as usize]
+            .as_mut()
+            .ok_or(UsbError::NoSuchDevice)?;
+        let is_in = setup.is_device_to_host();
+        let buffer = slot.transfer_buffer.as_ptr::<u8>();
+        if !is_in {
+            unsafe { ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len()) };
+        }
+        let buffer_address = slot.transfer_buffer.physical_address;
+        let ring = slot.transfer_rings[1]
+            .as_mut()
+            .ok_or(UsbError::EndpointNotConfigured)?;
+
+        let transfer_type = match (data.is_empty(), is_in) {
+            (true, _) => 0,
+            (false, false) => 2,
+            (false, true) => 3
+        };
+        ring.push(Trb {
+            parameter: unsafe { ptr::read_unaligned(&setup as *const SetupPacket as *const u64) },
+            status: 8,
+            control: (TRB_TYPE_SETUP_STAGE << TRB_TYPE_SHIFT) | TRB_IMMEDIATE_DATA | (transfer_type << 16)
+        });
+        if !data.is_empty() {
+            ring.push(Trb {
+                parameter: buffer_address,
+                status: data.len() as u32,
+                control: (TRB_TYPE_DATA_STAGE << TRB_TYPE_SHIFT)
+                    | TRB_INTERRUPT_ON_SHORT_PACKET
+                    | if is_in { TRB_DIRECTION_IN } else { 0 }
+            });
+        }
+        // The status stage goes in the opposite direction of the data stage, or in if there's no data stage
+        let status_direction = if data.is_empty() || !is_in { TRB_DIRECTION_IN } else { 0 };
+        let status_trb = ring.push(Trb {
+            parameter: 0,
+            status: 0,
+            control: (TRB_TYPE_STATUS_STAGE << TRB_TYPE_SHIFT)
+                | TRB_INTERRUPT_ON_COMPLETION
+                | status_direction
+        });
+        self.ring_doorbell(slot_id, 1);
+        let residual_length = self.wait_for_transfer(slot_id, 1, status_trb)?;
+
+        let transferred = data.len().saturating_sub(residual_length as usize);
+        if is_in {
+            unsafe { ptr::copy_nonoverlapping(buffer, data.as_mut_ptr(), transferred) };
+        }
+        Ok(transferred)
+    }
+
+    fn configure_endpoint(&mut self, slot_id: u8, endpoint: &EndpointDescriptor) -> Result<(), UsbError> {
+        let context_index = device_context_index(endpoint.endpoint_address);
+        let transfer_type = endpoint.transfer_type();
+        let endpoint_type = match (transfer_type, endpoint.is_in()) {
+            (EndpointTransferType::Bulk, false) => 2,
+            (EndpointTransferType::Interrupt, false) => 3,
+            (EndpointTransferType::Bulk, true) => 6,
+            (EndpointTransferType::Interrupt, true) => 7,
+            _ => return Err(UsbError::Unsupported)
+        };
+        let ring = ProducerRing::new()?;
+        let dequeue_pointer = ring.enqueue_pointer();
+        let slot = self.devices[slot_id as usize]
+            .as_mut()
+            .ok_or(UsbError::NoSuchDevice)?;
+        slot.transfer_rings[context_index] = Some(ring);
+        let speed = slot.speed;
+        let slot = self.devices[slot_id as usize].as_ref().unwrap();
+
+        // Interrupt endpoint intervals are given in frames (1 ms) for low and full speed devices, and as an
+        // exponent of 125 us microframes for faster ones. The controller wants the latter in both cases.
+        let interval = match (transfer_type, speed) {
+            (EndpointTransferType::Interrupt, UsbSpeed::Low | UsbSpeed::Full) => {
+                let microframes = (endpoint.interval.max(1) as u32) * 8;
+                31 - microframes.leading_zeros()
+            },
+            (EndpointTransferType::Interrupt, _) => (endpoint.interval.clamp(1, 16) - 1) as u32,
+            _ => 0
+        };
+        let max_packet_size = endpoint.max_packet_size() as u32;
+
+        self.clear_input_context(slot);
+        unsafe {
+            let control = self.input_context(slot, 0);
+            ptr::write_volatile(control.add(1), 1 | (1 << context_index));
+            let slot_context = self.input_context(slot, 1);
+            let output_slot_context = self.context(&slot.output_context, 0);
+            for i in 0..4 {
+                ptr::write_volatile(
+                    slot_context.add(i),
+                    ptr::read_volatile(output_slot_context.add(i))
+                );
+            }
+            let dword_0 = ptr::read_volatile(slot_context);
+            let context_entries = (dword_0 >> 27).max(context_index as u32);
+            ptr::write_volatile(slot_context, (dword_0 & !(0x1f << 27)) | (context_entries << 27));
+            // The slot state and device address are output-only fields
+            ptr::write_volatile(slot_context.add(3), 0);
+
+            let endpoint_context = self.input_context(slot, context_index + 1);
+            ptr::write_volatile(endpoint_context, interval << 16);
+            ptr::write_volatile(
+                endpoint_context.add(1),
+                (3 << 1) | (endpoint_type << 3) | (max_packet_size << 16)
+            );
+            ptr::write_volatile(endpoint_context.add(2), dequeue_pointer as u32);
+            ptr::write_volatile(endpoint_context.add(3), (dequeue_pointer >> 32) as u32);
+            let average_trb_length = match transfer_type {
+                EndpointTransferType::Interrupt => max_packet_size,
+                _ => 3072
+            };
+            let max_esit_payload = match transfer_type {
+                EndpointTransferType::Interrupt => max_packet_size,
+                _ => 0
+            };
+            ptr::write_volatile(
+                endpoint_context.add(4),
+                average_trb_length | (max_esit_payload << 16)
+            );
+        }
+        let input_context_address = slot.input_context.physical_address;
+        self.send_command(Trb {
+            parameter: input_context_address,
+            control: (TRB_TYPE_CONFIGURE_ENDPOINT << TRB_TYPE_SHIFT) | ((slot_id as u32) << 24),
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    #[inline]
+    fn transfer_buffer(&self, slot_id: u8) -> Result<*mut u8, UsbError> {
+        let slot = self.devices[slot_id as usize]
+            .as_ref()
+            .ok_or(UsbError::NoSuchDevice)?;
+        Ok(slot.transfer_buffer.as_ptr())
+    }
+
+    /// Transfers the first `length` bytes of the device's transfer buffer to or from an endpoint, in the
+    /// direction of the endpoint. Returns the number of bytes actually transferred.
+    fn transfer_through_buffer(
+        &mut self,
+        slot_id: u8,
+        endpoint_address: u8,
+        length: usize
+    ) -> Result<usize, UsbError> {
+        let context_index = device_context_index(endpoint_address);
+        let slot = self.devices[slot_id as usize]
+            .as_mut()
+            .ok_or(UsbError::NoSuchDevice)?;
+        let buffer_address = slot.transfer_buffer.physical_address;
+        let ring = slot.transfer_rings[context_index]
+            .as_mut()
+            .ok_or(UsbError::EndpointNotConfigured)?;
+        let trb = ring.push(Trb {
+            parameter: buffer_address,
+            status: length as u32,
+            control: (TRB_TYPE_NORMAL << TRB_TYPE_SHIFT)
+                | TRB_INTERRUPT_ON_COMPLETION
+                | TRB_INTERRUPT_ON_SHORT_PACKET
+        });
+        self.ring_doorbell(slot_id, context_index as u8);
+        let residual_length = self.wait_for_transfer(slot_id, context_index as u8, trb)?;
+        Ok(length.saturating_sub(residual_length as usize))
+    }
+
+    fn transfer_in(&mut self, slot_id: u8, endpoint_address: u8, data: &mut [u8]) -> Result<usize, UsbError> {
+        let mut transferred = 0;
+        for chunk in data.chunks_mut(TRANSFER_BUFFER_SIZE) {
+            let buffer = self.transfer_buffer(slot_id)?;
+            let chunk_transferred = self.transfer_through_buffer(slot_id, endpoint_address, chunk.len())?;
+            unsafe { ptr::copy_nonoverlapping(buffer, chunk.as_mut_ptr(), chunk_transferred) };
+            transferred += chunk_transferred;
+            // A short packet ends the transfer
+            if chunk_transferred < chunk.len() {
+                break;
+            }
+        }
+        Ok(transferred)
+    }
+
+    fn transfer_out(&mut self, slot_id: u8, endpoint_address: u8, data: &[u8]) -> Result<usize, UsbError> {
+        let mut transferred = 0;
+        for chunk in data.chunks(TRANSFER_BUFFER_SIZE) {
+            let buffer = self.transfer_buffer(slot_id)?;
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), buffer, chunk.len()) };
+            transferred += self.transfer_through_buffer(slot_id, endpoint_address, chunk.len())?;
+        }
+        Ok(transferred)
+    }
+
+    /// Recovers an endpoint from the halted state it enters after a stall, and moves its dequeue pointer
+    /// past whatever TRBs were left on the ring.
+    fn reset_endpoint(&mut self, slot_id: u8, endpoint_address: u8) -> Result<(), UsbError> {
+        let context_index = device_context_index(endpoint_address) as u32;
+        let slot = self.devices[slot_id as usize]
+            .as_ref()
+            .ok_or(UsbError::NoSuchDevice)?;
+        let dequeue_pointer = slot.transfer_rings[context_index as usize]
+            .as_ref()
+            .ok_or(UsbError::EndpointNotConfigured)?
+            .enqueue_pointer();
+        self.send_command(Trb {
+            control: (TRB_TYPE_RESET_ENDPOINT << TRB_TYPE_SHIFT)
+                | ((slot_id as u32) << 24)
+                | (context_index << 16),
+            ..Default::default()
+        })?;
+        self.send_command(Trb {
+            parameter: dequeue_pointer,
+            control: (TRB_TYPE_SET_TR_DEQUEUE_POINTER << TRB_TYPE_SHIFT)
+                | ((slot_id as u32) << 24)
+                | (context_index << 16),
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+}
+
+static CONTROLLERS: Mutex<ArrayVec<XhciController, MAX_CONTROLLERS>> = Mutex::new(ArrayVec::new_const());
+
+fn with_controller<R>(
+    index: usize,
+    f: impl FnOnce(&mut XhciController) -> Result<R, UsbError>
+) -> Result<R, UsbError> {
+    without_interrupts(|| {
+        let mut controllers = CONTROLLERS.lock();
+        let controller = controllers.get_mut(index).ok_or(UsbError::NoSuchDevice)?;
+        f(controller)
+    })
+}
+
+pub fn with_device<R>(device: UsbDevice, f: impl FnOnce(&DeviceSlot) -> R) -> Result<R, UsbError> {
+    with_controller(device.controller, |controller| {
+        let slot = controller
+            .devices
+            .get(device.slot_id as usize)
+            .and_then(|s| s.as_ref())
+            .ok_or(UsbError::NoSuchDevice)?;
+        Ok(f(slot))
+    })
+}
+
+pub fn control_transfer(device: UsbDevice, setup: SetupPacket, data: &mut [u8]) -> Result<usize, UsbError> {
+    with_controller(device.controller, |controller| {
+        controller.control_transfer(device.slot_id, setup, data)
+    })
+}
+
+pub fn configure_endpoint(device: UsbDevice, endpoint: &EndpointDescriptor) -> Result<(), UsbError> {
+    with_controller(device.controller, |controller| {
+        controller.configure_endpoint(device.slot_id, endpoint)
+    })
+}
+
+pub fn transfer_in(device: UsbDevice, endpoint_address: u8, data: &mut [u8]) -> Result<usize, UsbError> {
+    with_controller(device.controller, |controller| {
+        controller.transfer_in(device.slot_id, endpoint_address, data)
+    })
+}
+
+pub fn transfer_out(device: UsbDevice, endpoint_address: u8, data: &[u8]) -> Result<usize, UsbError> {
+    with_controller(device.controller, |controller| {
+        controller.transfer_out(device.slot_id, endpoint_address, data)
+    })
+}
+
+pub fn start_interrupt_transfers(
+    device: UsbDevice,
+    endpoint_address: u8,
+    length: usize,
+    handler: InterruptTransferHandler,
+    context: u64
+) -> Result<(), UsbError> {
+    with_controller(device.controller, |controller| {
+        controller.start_interrupt_transfers(device.slot_id, endpoint_address, length, handler, context)
+    })
+}
+
+pub fn reset_endpoint(device: UsbDevice, endpoint_address: u8) -> Result<(), UsbError> {
+    with_controller(device.controller, |controller| {
+        controller.reset_endpoint(device.slot_id, endpoint_address)
+    })
+}
+
+/// The MSI(-X) handler of every controller, with the index of the controller as the context.
+fn handle_interrupt(_vector: u8, controller_index: u64) {
+    let mut controllers = CONTROLLERS.lock();
+    let Some(controller) = controllers.get_mut(controller_index as usize)
+    else {
+        return;
+    };
+    // Both bits are cleared by writing one to them
+    controller.write_operational_u32(OP_REG_USBSTS, USBSTS_EVENT_INTERRUPT);
+    let interrupter = controller.runtime_base + RUNTIME_REG_INTERRUPTER_0;
+    write_u32(
+        interrupter + INTERRUPTER_REG_IMAN,
+        read_u32(interrupter + INTERRUPTER_REG_IMAN) | IMAN_INTERRUPT_PENDING
+    );
+    controller.process_events();
+}
+
+/// Initializes the controller and addresses every device connected to its root hub ports, after which the
+/// devices are handed to the USB class drivers. Devices connected later on aren't noticed.
+fn probe(pci_device: &'static PciDevice) -> Result<(), ProbeError> {
+    let controller = XhciController::new(pci_device).map_err(|_| ProbeError::InitializationFailed)?;
+    let (index, slot_ids) = without_interrupts(|| {
+        let mut controllers = CONTROLLERS.lock();
+        let index = controllers.len();
+        if controllers.try_push(controller).is_err() {
+            return Err(ProbeError::Unsupported);
+        }
+        let controller = &mut controllers[index];
+        // Everything but interrupt transfers works by polling, so the controller is still usable without
+        // interrupts
+        let _ = controller.enable_interrupts(index);
+        Ok((index, controller.enumerate_ports()))
+    })?;
+    // Probed without the controller lock held, as the class drivers go through it for every transfer
+    for slot_id in slot_ids {
+        usb::probe_device(UsbDevice {
+            controller: index,
+            slot_id
+        });
+    }
+    Ok(())
+}
+
+pub static XHCI_DRIVER: PciDriver = PciDriver {
+    name: "xhci",
+    ids: &[PciDeviceId::class(0x0c, 0x03, Some(0x30))],
+    probe
+};